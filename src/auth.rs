@@ -0,0 +1,257 @@
+//! Logging in with a Nostr key, when running alongside the SSH server. Browsers
+//! log in once with a NIP-07 extension and get a signed session cookie, while
+//! other clients can sign each request with NIP-98 HTTP auth instead.
+
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use gnit_ssh::{NostrEvent, SshServer, HTTP_AUTH_KIND};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+pub const SESSION_COOKIE: &str = "gnostr_session";
+
+/// How long a login lasts.
+const SESSION_LENGTH: u64 = 30 * 24 * 60 * 60;
+
+/// How far a signed event's timestamp can be from our clock.
+const EVENT_WINDOW: u64 = 60;
+
+tokio::task_local! {
+    static SESSION: Session;
+}
+
+/// Who made the request, for templates to show.
+#[derive(Clone, Default)]
+pub struct Session {
+    /// Whether logging in is possible at all, it isn't without the SSH server
+    /// or in exported pages.
+    pub enabled: bool,
+    pub user: Option<WebUser>,
+}
+
+/// Someone logged in with a Nostr key.
+#[derive(Clone)]
+pub struct WebUser {
+    /// Their public key, in hex.
+    pub pubkey: String,
+    /// The SSH server's user with their npub, or the npub itself.
+    pub name: String,
+}
+
+/// Marks requests made by the exporter, whose pages are the same for everyone.
+#[derive(Clone)]
+pub struct Anonymous;
+
+/// Signs session cookies and login challenges. Logging in is disabled if
+/// there's no SSH server to say who anyone is.
+#[derive(Clone, Default)]
+pub struct Auth(Option<Arc<Login>>);
+
+struct Login {
+    key: [u8; 32],
+    server: SshServer,
+}
+
+impl Auth {
+    /// Enables logging in, with a new key so sessions end when we restart.
+    pub fn new(server: SshServer) -> Self {
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        Self(Some(Arc::new(Login { key, server })))
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    fn sign(&self, data: &str) -> Option<String> {
+        let login = self.0.as_ref()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&login.key).ok()?;
+        mac.update(data.as_bytes());
+        Some(const_hex::encode(mac.finalize().into_bytes()))
+    }
+
+    fn verify(&self, data: &str, signature: &str) -> bool {
+        let Some(login) = &self.0 else {
+            return false;
+        };
+        let Ok(signature) = const_hex::decode(signature) else {
+            return false;
+        };
+        let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(&login.key) else {
+            return false;
+        };
+        mac.update(data.as_bytes());
+        mac.verify_slice(&signature).is_ok()
+    }
+
+    /// A challenge for the login page, only valid for a few minutes.
+    pub fn challenge(&self) -> Option<String> {
+        let issued = now().to_string();
+        let signature = self.sign(&issued)?;
+        Some(format!("{issued}.{signature}"))
+    }
+
+    /// Whether we gave out `challenge` recently.
+    pub fn check_challenge(&self, challenge: &str) -> bool {
+        let Some((issued, signature)) = challenge.split_once('.') else {
+            return false;
+        };
+        issued
+            .parse::<u64>()
+            .is_ok_and(|issued| now().saturating_sub(issued) < 10 * 60)
+            && self.verify(issued, signature)
+    }
+
+    /// A `Set-Cookie` value logging `pubkey` in.
+    pub fn session_cookie(&self, pubkey: &str) -> Option<HeaderValue> {
+        let expires = now() + SESSION_LENGTH;
+        let value = format!("{pubkey}.{expires}");
+        let signature = self.sign(&value)?;
+        HeaderValue::try_from(format!(
+            "{SESSION_COOKIE}={value}.{signature}; Path=/; Max-Age={SESSION_LENGTH}; HttpOnly; SameSite=Lax"
+        ))
+        .ok()
+    }
+
+    /// The public key of whoever's session cookie this is, if it's ours and
+    /// hasn't expired.
+    fn check_session(&self, cookie: &str) -> Option<String> {
+        let (value, signature) = cookie.rsplit_once('.')?;
+        let (pubkey, expires) = value.split_once('.')?;
+        (self.verify(value, signature) && expires.parse::<u64>().ok()? > now())
+            .then(|| pubkey.to_string())
+    }
+
+    /// The public key of who a request is from, by its NIP-98 `Authorization`
+    /// header or its session cookie.
+    fn pubkey(&self, request: &Request) -> Option<String> {
+        // other schemes, like the audit log's basic auth, aren't ours to check
+        let authorization = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Nostr "));
+        match authorization {
+            Some(event) => http_auth(request, event),
+            None => self.check_session(&cookie(request.headers(), SESSION_COOKIE)?),
+        }
+    }
+
+    async fn user(&self, pubkey: String) -> Option<WebUser> {
+        let name = self.0.as_ref()?.server.nostr_user(&pubkey).await;
+        Some(WebUser { pubkey, name })
+    }
+}
+
+/// Works out who each request is from, making it available to handlers as a
+/// `WebUser` extension and to templates through [`session`].
+pub async fn authenticate(
+    Extension(auth): Extension<Auth>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if !auth.enabled() || request.extensions().get::<Anonymous>().is_some() {
+        return SESSION.scope(Session::default(), next.run(request)).await;
+    }
+
+    let user = match auth.pubkey(&request) {
+        Some(pubkey) => auth.user(pubkey).await,
+        None => None,
+    };
+    if let Some(user) = &user {
+        request.extensions_mut().insert(user.clone());
+    }
+
+    let session = Session {
+        enabled: true,
+        user: user.clone(),
+    };
+    let mut response = SESSION.scope(session, next.run(request)).await;
+
+    // pages differ by who's logged in, so mustn't be shared between people
+    let headers = response.headers_mut();
+    headers.insert(
+        header::VARY,
+        HeaderValue::from_static("Cookie, Authorization"),
+    );
+    if user.is_some() {
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("private, no-cache"),
+        );
+    }
+
+    response
+}
+
+/// The current request's session, for templates.
+pub fn session() -> Session {
+    SESSION.try_with(Clone::clone).unwrap_or_default()
+}
+
+/// Checks the base64 event from a NIP-98 `Authorization: Nostr <event>`
+/// header was signed for this request, returning the signer's public key.
+fn http_auth(request: &Request<Body>, event: &str) -> Option<String> {
+    let event = STANDARD.decode(event.trim()).ok()?;
+    let event = NostrEvent::parse(simdutf8::basic::from_utf8(&event).ok()?).ok()?;
+
+    if event.kind != HTTP_AUTH_KIND || event.check_fresh(EVENT_WINDOW).is_err() {
+        return None;
+    }
+    if !event
+        .tag("method")?
+        .eq_ignore_ascii_case(request.method().as_str())
+    {
+        return None;
+    }
+
+    // the scheme isn't checked, as we're usually behind a proxy terminating TLS
+    let url = event.tag("u")?;
+    let url = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))?;
+    let (host, path) = url.split_once('/').unwrap_or((url, ""));
+    let expected_host = request.headers().get(header::HOST)?.to_str().ok()?;
+    let expected_path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |v| v.as_str())
+        .trim_start_matches('/');
+    if !host.eq_ignore_ascii_case(expected_host) || path != expected_path {
+        return None;
+    }
+
+    Some(event.pubkey.to_ascii_lowercase())
+}
+
+/// The value of a cookie sent with a request.
+fn cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|v| v.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |v| v.as_secs())
+}
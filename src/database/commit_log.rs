@@ -0,0 +1,48 @@
+use std::{path::Path, sync::Arc};
+
+use gnit_ssh::{CommitLog, LogEntry};
+
+use crate::database::schema::repository::Repository;
+
+/// Lets the SSH server's terminal UI read logs from our index rather than
+/// running git, when both servers run in one process.
+pub struct IndexedCommitLog(pub Arc<rocksdb::DB>);
+
+impl CommitLog for IndexedCommitLog {
+    fn commits(
+        &self,
+        repo_path: &Path,
+        branch: &str,
+        skip: usize,
+        limit: usize,
+    ) -> anyhow::Result<Option<Vec<LogEntry>>> {
+        let Some(repository) = Repository::open(&self.0, repo_path)? else {
+            return Ok(None);
+        };
+
+        let commits = repository
+            .get()
+            .commit_tree(self.0.clone(), branch)
+            .fetch_latest(limit as u64, skip as u64)?;
+
+        // the index may not have caught up with the branch's first push yet
+        if commits.is_empty() && skip == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            commits
+                .iter()
+                .map(|commit| {
+                    let commit = commit.get();
+                    LogEntry {
+                        id: const_hex::encode(commit.hash),
+                        author: commit.author.name.to_string(),
+                        time: commit.author.time.0.to_native(),
+                        summary: commit.summary.to_string(),
+                    }
+                })
+                .collect(),
+        ))
+    }
+}
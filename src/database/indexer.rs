@@ -4,6 +4,7 @@ use std::{
     fmt::Debug,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Instant,
 };
 
 use anyhow::Context;
@@ -14,10 +15,13 @@ use rocksdb::WriteBatch;
 use time::{OffsetDateTime, UtcOffset};
 use tracing::{error, info, info_span, instrument, warn};
 
-use crate::database::schema::{
-    commit::Commit,
-    repository::{ArchivedRepository, Repository, RepositoryId},
-    tag::{Tag, TagTree},
+use crate::{
+    database::schema::{
+        commit::Commit,
+        repository::{ArchivedRepository, Repository, RepositoryId},
+        tag::{Tag, TagTree},
+    },
+    metrics::METRICS,
 };
 
 pub fn run(scan_path: &Path, db: &Arc<rocksdb::DB>) {
@@ -26,16 +30,71 @@ pub fn run(scan_path: &Path, db: &Arc<rocksdb::DB>) {
 
     info!("Starting index update");
 
-    update_repository_metadata(scan_path, db);
-    update_repository_reflog(scan_path, db.clone());
-    update_repository_tags(scan_path, db.clone());
+    let started = Instant::now();
+    let timed = |stage, f: &dyn Fn()| {
+        let stage_started = Instant::now();
+        f();
+        METRICS.indexer_stage_finished(stage, stage_started.elapsed());
+    };
+
+    timed("metadata", &|| update_repository_metadata(scan_path, db));
+    timed("reflog", &|| update_repository_reflog(scan_path, db.clone()));
+    timed("tags", &|| update_repository_tags(scan_path, db.clone()));
 
     info!("Flushing to disk");
 
+    timed("flush", &|| {
+        if let Err(error) = db.flush() {
+            error!(%error, "Failed to flush database to disk");
+        }
+    });
+
+    METRICS.indexer_stage_finished("total", started.elapsed());
+
+    info!("Finished index update");
+}
+
+/// What the indexer should be woken up to do.
+#[derive(Debug)]
+pub enum Wakeup {
+    /// Rescan the whole scan path.
+    All,
+    /// Reindex one repository, given relative to the scan path, such as after
+    /// a push to it.
+    Repository(PathBuf),
+}
+
+/// Updates the index for a single repository, without rescanning the rest of
+/// the scan path.
+pub fn run_repository(scan_path: &Path, db: &Arc<rocksdb::DB>, relative: &Path) {
+    let span = info_span!("index_update", repository = %relative.display());
+    let _entered = span.enter();
+
+    info!("Starting index update");
+
+    let started = Instant::now();
+
+    index_repository_metadata(scan_path, db, relative);
+
+    let db_repository = match Repository::open(db, relative) {
+        Ok(Some(v)) => v,
+        Ok(None) => return,
+        Err(error) => {
+            error!(%error, "Failed to open repository index {}, please consider nuking database", relative.display());
+            return;
+        }
+    };
+
+    let relative_path = relative.to_string_lossy();
+    index_repository_reflog(scan_path, db, &relative_path, db_repository.get());
+    index_repository_tags(scan_path, db, &relative_path, db_repository.get());
+
     if let Err(error) = db.flush() {
         error!(%error, "Failed to flush database to disk");
     }
 
+    METRICS.indexer_stage_finished("repository", started.elapsed());
+
     info!("Finished index update");
 }
 
@@ -49,56 +108,60 @@ fn update_repository_metadata(scan_path: &Path, db: &rocksdb::DB) {
             continue;
         };
 
-        let id = match Repository::open(db, relative) {
-            Ok(v) => v.map_or_else(RepositoryId::new, |v| {
-                RepositoryId(v.get().id.0.to_native())
-            }),
-            Err(error) => {
-                // maybe we could nuke it ourselves, but we need to instantly trigger
-                // a reindex and we could enter into an infinite loop if there's a bug
-                // or something
-                error!(%error, "Failed to open repository index {}, please consider nuking database", relative.display());
-                continue;
-            }
-        };
+        index_repository_metadata(scan_path, db, relative);
+    }
+}
 
-        let Some(name) = relative.file_name().and_then(OsStr::to_str) else {
-            continue;
-        };
-        let description = std::fs::read(repository.join("description")).unwrap_or_default();
-        let description = String::from_utf8(description)
-            .ok()
-            .filter(|v| !v.is_empty());
+fn index_repository_metadata(scan_path: &Path, db: &rocksdb::DB, relative: &Path) {
+    let id = match Repository::open(db, relative) {
+        Ok(v) => v.map_or_else(RepositoryId::new, |v| {
+            RepositoryId(v.get().id.0.to_native())
+        }),
+        Err(error) => {
+            // maybe we could nuke it ourselves, but we need to instantly trigger
+            // a reindex and we could enter into an infinite loop if there's a bug
+            // or something
+            error!(%error, "Failed to open repository index {}, please consider nuking database", relative.display());
+            return;
+        }
+    };
 
-        let repository_path = scan_path.join(relative);
+    let Some(name) = relative.file_name().and_then(OsStr::to_str) else {
+        return;
+    };
+    let repository_path = scan_path.join(relative);
 
-        let mut git_repository = match gix::open(repository_path.clone()) {
-            Ok(v) => v,
-            Err(error) => {
-                warn!(%error, "Failed to open repository {} to update metadata, skipping", relative.display());
-                continue;
-            }
-        };
+    let description = std::fs::read(repository_path.join("description")).unwrap_or_default();
+    let description = String::from_utf8(description)
+        .ok()
+        .filter(|v| !v.is_empty());
 
-        git_repository.object_cache_size(10 * 1024 * 1024);
-
-        let res = Repository {
-            id,
-            name: name.to_string(),
-            description,
-            owner: find_gitweb_owner(repository_path.as_path()),
-            last_modified: {
-                let r =
-                    find_last_committed_time(&git_repository).unwrap_or(OffsetDateTime::UNIX_EPOCH);
-                (r.unix_timestamp(), r.offset().whole_seconds())
-            },
-            default_branch: find_default_branch(&git_repository).ok().flatten(),
+    let mut git_repository = match gix::open(repository_path.clone()) {
+        Ok(v) => v,
+        Err(error) => {
+            warn!(%error, "Failed to open repository {} to update metadata, skipping", relative.display());
+            return;
         }
-        .insert(db, relative);
+    };
 
-        if let Err(error) = res {
-            warn!(%error, "Failed to insert repository");
-        }
+    git_repository.object_cache_size(10 * 1024 * 1024);
+
+    let res = Repository {
+        id,
+        name: name.to_string(),
+        description,
+        owner: find_gitweb_owner(repository_path.as_path()),
+        last_modified: {
+            let r = find_last_committed_time(&git_repository).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+            (r.unix_timestamp(), r.offset().whole_seconds())
+        },
+        default_branch: find_default_branch(&git_repository).ok().flatten(),
+        mirror_of: find_mirror_upstream(repository_path.as_path()),
+    }
+    .insert(db, relative);
+
+    if let Err(error) = res {
+        warn!(%error, "Failed to insert repository");
     }
 }
 
@@ -141,63 +204,71 @@ fn update_repository_reflog(scan_path: &Path, db: Arc<rocksdb::DB>) {
     };
 
     for (relative_path, db_repository) in repos {
-        let Some(git_repository) = open_repo(scan_path, &relative_path, db_repository.get(), &db)
-        else {
-            continue;
-        };
+        index_repository_reflog(scan_path, &db, &relative_path, db_repository.get());
+    }
+}
 
-        let references = match git_repository.references() {
-            Ok(v) => v,
-            Err(error) => {
-                error!(%error, "Failed to read references for {relative_path}");
-                continue;
-            }
-        };
+fn index_repository_reflog(
+    scan_path: &Path,
+    db: &Arc<rocksdb::DB>,
+    relative_path: &str,
+    db_repository: &ArchivedRepository,
+) {
+    let Some(git_repository) = open_repo(scan_path, relative_path, db_repository, db) else {
+        return;
+    };
+
+    let references = match git_repository.references() {
+        Ok(v) => v,
+        Err(error) => {
+            error!(%error, "Failed to read references for {relative_path}");
+            return;
+        }
+    };
 
-        let references = match references.all() {
+    let references = match references.all() {
+        Ok(v) => v,
+        Err(error) => {
+            error!(%error, "Failed to read references for {relative_path}");
+            return;
+        }
+    };
+
+    let mut valid_references = Vec::new();
+
+    for reference in references {
+        let mut reference = match reference {
             Ok(v) => v,
             Err(error) => {
-                error!(%error, "Failed to read references for {relative_path}");
+                error!(%error, "Failed to read reference for {relative_path}");
                 continue;
             }
         };
 
-        let mut valid_references = Vec::new();
-
-        for reference in references {
-            let mut reference = match reference {
-                Ok(v) => v,
-                Err(error) => {
-                    error!(%error, "Failed to read reference for {relative_path}");
-                    continue;
-                }
-            };
+        let reference_name = reference.name();
+        if !matches!(
+            reference_name.category(),
+            Some(Category::Tag | Category::LocalBranch)
+        ) {
+            continue;
+        }
 
-            let reference_name = reference.name();
-            if !matches!(
-                reference_name.category(),
-                Some(Category::Tag | Category::LocalBranch)
-            ) {
-                continue;
-            }
+        valid_references.push(reference_name.as_bstr().to_string());
 
-            valid_references.push(reference_name.as_bstr().to_string());
-
-            if let Err(error) = branch_index_update(
-                &mut reference,
-                &relative_path,
-                db_repository.get(),
-                db.clone(),
-                &git_repository,
-                false,
-            ) {
-                error!(%error, "Failed to update reflog for {relative_path}@{:?}", valid_references.last());
-            }
+        if let Err(error) = branch_index_update(
+            &mut reference,
+            relative_path,
+            db_repository,
+            db.clone(),
+            &git_repository,
+            false,
+        ) {
+            error!(%error, "Failed to update reflog for {relative_path}@{:?}", valid_references.last());
         }
+    }
 
-        if let Err(error) = db_repository.get().replace_heads(&db, &valid_references) {
-            error!(%error, "Failed to update heads");
-        }
+    if let Err(error) = db_repository.replace_heads(db, &valid_references) {
+        error!(%error, "Failed to update heads");
     }
 }
 
@@ -278,6 +349,8 @@ fn branch_index_update(
         db.write_without_wal(batch)?;
     }
 
+    METRICS.commits_ingested(relative_path, i);
+
     if !seen && !force_reindex {
         warn!("Detected converged history, forcing reindex");
 
@@ -305,19 +378,22 @@ fn update_repository_tags(scan_path: &Path, db: Arc<rocksdb::DB>) {
     };
 
     for (relative_path, db_repository) in repos {
-        let Some(git_repository) = open_repo(scan_path, &relative_path, db_repository.get(), &db)
-        else {
-            continue;
-        };
+        index_repository_tags(scan_path, &db, &relative_path, db_repository.get());
+    }
+}
 
-        if let Err(error) = tag_index_scan(
-            &relative_path,
-            db_repository.get(),
-            db.clone(),
-            &git_repository,
-        ) {
-            error!(%error, "Failed to update tags for {relative_path}");
-        }
+fn index_repository_tags(
+    scan_path: &Path,
+    db: &Arc<rocksdb::DB>,
+    relative_path: &str,
+    db_repository: &ArchivedRepository,
+) {
+    let Some(git_repository) = open_repo(scan_path, relative_path, db_repository, db) else {
+        return;
+    };
+
+    if let Err(error) = tag_index_scan(relative_path, db_repository, db.clone(), &git_repository) {
+        error!(%error, "Failed to update tags for {relative_path}");
     }
 }
 
@@ -446,3 +522,16 @@ fn find_gitweb_owner(repository_path: &Path) -> Option<String> {
         .section_mut(Some("gitweb"))
         .and_then(|section| section.remove("owner"))
 }
+
+fn find_mirror_upstream(repository_path: &Path) -> Option<String> {
+    // Mirrors keep their upstream as the `origin` remote, flagged with `mirror = true` as
+    // `git clone --mirror` would.
+    let mut config = Ini::load_from_file(repository_path.join("config")).ok()?;
+    let remote = config.section_mut(Some(r#"remote "origin""#))?;
+
+    if remote.get("mirror") == Some("true") {
+        remote.remove("url")
+    } else {
+        None
+    }
+}
@@ -1,2 +1,3 @@
+pub mod commit_log;
 pub mod indexer;
 pub mod schema;
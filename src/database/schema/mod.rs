@@ -9,4 +9,4 @@ pub mod tag;
 
 pub type Yoked<T> = Yoke<T, Box<[u8]>>;
 
-pub const SCHEMA_VERSION: &str = "2";
+pub const SCHEMA_VERSION: &str = "3";
@@ -28,6 +28,9 @@ pub struct Repository {
     pub last_modified: (i64, i32),
     /// The default branch for Git operations
     pub default_branch: Option<String>,
+    /// The upstream this repository is a mirror of (`remote.origin.url` in the repository
+    /// configuration, if `remote.origin.mirror` is set)
+    pub mirror_of: Option<String>,
 }
 
 pub type YokedRepository = Yoked<&'static <Repository as Archive>::Archived>;
@@ -165,6 +168,7 @@ impl ArchivedRepository {
 }
 
 #[derive(Serialize, Archive, Debug, Clone, PartialEq, Eq, Hash)]
+#[allow(dead_code)] // only ever read back in its archived form
 pub struct Heads(pub Vec<String>);
 
 #[derive(Serialize, Archive, Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -0,0 +1,538 @@
+//! Exports repositories as plain HTML for static file hosting, in the spirit
+//! of stagit.
+//!
+//! Pages are rendered by the web server's own routes, so they look just as
+//! they do when served, and their links are then rewritten to point at the
+//! files the linked pages were saved as. Only what's reachable from each
+//! repository's HEAD is exported: its summary, readme, refs, log, tree, tags
+//! and every commit along with its patch. Links to anything else, such as
+//! other branches or snapshots, are dropped.
+//!
+//! Commits never change, so their pages are kept between exports, and a
+//! repository is only exported again once its refs have moved.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+    fmt::Write,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, Request},
+    Router,
+};
+use tower::ServiceExt;
+use tracing::{error, info, warn};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::{
+    auth::Anonymous, database::schema::repository::Repository, layers::UnwrapInfallible,
+    methods::repo::AccessControl,
+};
+
+/// Remembers the refs each repository had when it was last exported,
+/// relative to the output directory.
+const STATE_FILE: &str = ".export-state.json";
+
+/// Directories of pages named after a commit, which never need exporting
+/// again.
+const IMMUTABLE_DIRS: [&str; 2] = ["commit", "patch"];
+
+pub struct Exporter {
+    app: Router,
+    db: Arc<rocksdb::DB>,
+    access: AccessControl,
+    scan_path: PathBuf,
+    out: PathBuf,
+}
+
+/// A page to export, as the url it's served from and the file it's saved to.
+struct Page {
+    url: String,
+    file: PathBuf,
+}
+
+/// Where a link found in an exported page leads.
+enum Link {
+    /// Somewhere outside the export, such as another site.
+    Keep,
+    Page(Page),
+    /// A page that isn't exported.
+    Drop,
+}
+
+/// What to do with a link's attribute.
+enum Rewrite {
+    Keep,
+    Replace(String),
+    Remove,
+}
+
+impl Exporter {
+    pub fn new(
+        app: Router,
+        db: Arc<rocksdb::DB>,
+        access: AccessControl,
+        scan_path: PathBuf,
+        out: PathBuf,
+    ) -> Self {
+        Self {
+            app,
+            db,
+            access,
+            scan_path,
+            out,
+        }
+    }
+
+    /// Exports every changed repository, or just `only` if it's given, then
+    /// the index of them all. Repositories that have gone or are no longer
+    /// public are removed from the export.
+    pub async fn run(&self, only: Option<&Path>) -> anyhow::Result<()> {
+        let repositories = self.repositories().await?;
+        let mut state = self.read_state().await;
+
+        let removed: Vec<String> = state
+            .keys()
+            .filter(|v| !repositories.contains(*v))
+            .filter(|v| only.is_none_or(|only| only == Path::new(v)))
+            .cloned()
+            .collect();
+        for repository in removed {
+            info!(repository, "Removing repository from export");
+            remove_dir(&self.out.join(&repository)).await?;
+            state.remove(&repository);
+        }
+
+        for repository in &repositories {
+            if only.is_some_and(|only| only != Path::new(repository)) {
+                continue;
+            }
+
+            let fingerprint = match self.fingerprint(repository).await {
+                Ok(v) => v,
+                Err(error) => {
+                    warn!(repository, "Failed to read refs, skipping: {error:#}");
+                    continue;
+                }
+            };
+            if state.get(repository) == Some(&fingerprint) {
+                continue;
+            }
+
+            info!(repository, "Exporting repository");
+            if let Err(error) = self.export_repository(repository, &repositories).await {
+                error!(repository, "Failed to export repository: {error:#}");
+                continue;
+            }
+
+            state.insert(repository.clone(), fingerprint);
+            self.write_state(&state).await?;
+        }
+
+        self.crawl(
+            Page {
+                url: "/".to_string(),
+                file: PathBuf::from("index.html"),
+            },
+            None,
+            &repositories,
+        )
+        .await?;
+        self.write_state(&state).await?;
+
+        Ok(())
+    }
+
+    /// Every repository anonymous users can read, relative to the scan path.
+    async fn repositories(&self) -> anyhow::Result<BTreeSet<String>> {
+        let db = self.db.clone();
+        let fetched = tokio::task::spawn_blocking(move || Repository::fetch_all(&db))
+            .await
+            .context("Failed to join Tokio task")??;
+
+        let mut repositories = BTreeSet::new();
+        for repository in fetched.into_keys() {
+            if is_safe_path(Path::new(&repository))
+                && self.access.can_read(Path::new(&repository), None).await
+            {
+                repositories.insert(repository);
+            }
+        }
+
+        Ok(repositories)
+    }
+
+    async fn export_repository(
+        &self,
+        repository: &str,
+        repositories: &BTreeSet<String>,
+    ) -> anyhow::Result<()> {
+        // everything but the commits is exported again, so pages for paths
+        // that no longer exist don't linger
+        let dir = self.out.join(repository);
+        if let Ok(mut entries) = tokio::fs::read_dir(&dir).await {
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if !IMMUTABLE_DIRS.iter().any(|v| entry.file_name() == *v) {
+                    remove_dir(&path).await?;
+                }
+            }
+        }
+
+        let start = Page {
+            url: format!("/{repository}"),
+            file: Path::new(repository).join("index.html"),
+        };
+        self.crawl(start, Some(repository), repositories).await
+    }
+
+    /// Exports `start` along with every page of `repository` it leads to.
+    async fn crawl(
+        &self,
+        start: Page,
+        repository: Option<&str>,
+        repositories: &BTreeSet<String>,
+    ) -> anyhow::Result<()> {
+        let mut seen = HashSet::from([start.file.clone()]);
+        let mut queue = VecDeque::from([start]);
+
+        while let Some(page) = queue.pop_front() {
+            let path = self.out.join(&page.file);
+            if is_immutable(&page.file, repository) && tokio::fs::try_exists(&path).await? {
+                continue;
+            }
+
+            let Some((content_type, body)) = self.fetch(&page.url).await else {
+                continue;
+            };
+
+            let body = if content_type.starts_with("text/html") {
+                let html = String::from_utf8_lossy(&body);
+                let html = rewrite_links(&html, |href| {
+                    let target = match resolve(href, &page.url, repositories) {
+                        Link::Keep => return Rewrite::Keep,
+                        Link::Drop => return Rewrite::Remove,
+                        Link::Page(target) => target,
+                    };
+
+                    let link = relative_link(&page.file, &target.file);
+                    let in_scope = repository.is_some_and(|v| target.file.starts_with(v));
+                    if (in_scope || is_asset(&target.file)) && seen.insert(target.file.clone()) {
+                        queue.push_back(target);
+                    }
+
+                    Rewrite::Replace(link)
+                });
+                html.into_bytes()
+            } else {
+                body
+            };
+
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::write(&path, body)
+                .await
+                .with_context(|| format!("Couldn't write {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Renders a page through the web server's routes.
+    async fn fetch(&self, url: &str) -> Option<(String, Vec<u8>)> {
+        let mut request = match Request::get(url).body(Body::empty()) {
+            Ok(v) => v,
+            Err(error) => {
+                warn!(url, %error, "Skipping page that can't be requested");
+                return None;
+            }
+        };
+
+        request.extensions_mut().insert(Anonymous);
+        let response = self.app.clone().oneshot(request).await.unwrap_infallible();
+        if !response.status().is_success() {
+            warn!(url, status = %response.status(), "Skipping page that failed to render");
+            return None;
+        }
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let body = to_bytes(response.into_body(), usize::MAX).await.ok()?;
+
+        Some((content_type, body.to_vec()))
+    }
+
+    /// Summarises a repository's refs, which change with every push.
+    async fn fingerprint(&self, repository: &str) -> anyhow::Result<String> {
+        let path = self.scan_path.join(repository);
+
+        tokio::task::spawn_blocking(move || {
+            let repository = gix::open(path)?;
+            let mut refs = String::new();
+            for reference in repository.references()?.all()? {
+                let reference = reference.map_err(|e| anyhow::anyhow!(e))?;
+                let _res = writeln!(
+                    refs,
+                    "{} {:?}",
+                    reference.name().as_bstr(),
+                    reference.target()
+                );
+            }
+
+            Ok(format!("{:016x}", xxh3_64(refs.as_bytes())))
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    async fn read_state(&self) -> BTreeMap<String, String> {
+        tokio::fs::read(self.out.join(STATE_FILE))
+            .await
+            .ok()
+            .and_then(|v| serde_json::from_slice(&v).ok())
+            .unwrap_or_default()
+    }
+
+    async fn write_state(&self, state: &BTreeMap<String, String>) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.out).await?;
+        tokio::fs::write(self.out.join(STATE_FILE), serde_json::to_vec(state)?)
+            .await
+            .context("Couldn't write the export state")
+    }
+}
+
+/// Works out where a link in the page served at `base` points, and whether
+/// it's exported.
+fn resolve(href: &str, base: &str, repositories: &BTreeSet<String>) -> Link {
+    let url = if href.starts_with('?') {
+        let base = base.split_once('?').map_or(base, |(path, _)| path);
+        format!("{base}{href}")
+    } else if href.starts_with('/') && !href.starts_with("//") {
+        href.to_string()
+    } else {
+        // external, or relative to a readme
+        return Link::Keep;
+    };
+
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let path = path.trim_matches('/');
+    let query: Vec<(&str, &str)> = query
+        .split('&')
+        .filter(|v| !v.is_empty())
+        .map(|v| v.split_once('=').unwrap_or((v, "")))
+        .collect();
+
+    if path.is_empty() && query.is_empty() {
+        return Link::Page(Page {
+            url: "/".to_string(),
+            file: PathBuf::from("index.html"),
+        });
+    }
+
+    if query.is_empty() && is_asset(Path::new(path)) {
+        return Link::Page(Page {
+            url: format!("/{path}"),
+            file: PathBuf::from(path),
+        });
+    }
+
+    let repository = repositories
+        .iter()
+        .filter(|v| path == v.as_str() || path.starts_with(&format!("{v}/")))
+        .max_by_key(|v| v.len());
+    let Some(repository) = repository else {
+        return Link::Keep;
+    };
+
+    let rest = path[repository.len()..].trim_start_matches('/');
+    match repository_page(rest, &query) {
+        Some((url, file)) if is_safe_path(Path::new(&file)) => Link::Page(Page {
+            url: format!("/{repository}{url}"),
+            file: Path::new(repository).join(file),
+        }),
+        _ => Link::Drop,
+    }
+}
+
+/// Maps a route within a repository to the url it's exported from and the
+/// file it's saved as, or none if it isn't exported.
+fn repository_page(rest: &str, query: &[(&str, &str)]) -> Option<(String, String)> {
+    let (route, child) = rest.split_once('/').unwrap_or((rest, ""));
+    let get = |key| query.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+    let only = |keys: &[&str]| query.iter().all(|(k, _)| keys.contains(k));
+
+    match route {
+        "" if query.is_empty() => Some((String::new(), "index.html".to_string())),
+        "about" | "refs" | "log" | "commit" | "diff" if child.is_empty() && query.is_empty() => {
+            Some((format!("/{route}"), format!("{route}.html")))
+        }
+        "log" if child.is_empty() && only(&["ofs"]) => {
+            let offset: usize = get("ofs")?.parse().ok()?;
+            Some((format!("/log?ofs={offset}"), format!("log/{offset}.html")))
+        }
+        // the branch is only carried along for navigation
+        "commit" | "patch" if child.is_empty() && only(&["id", "h"]) => {
+            let id = get("id").filter(|v| is_object_id(v))?;
+            let extension = if route == "patch" { "patch" } else { "html" };
+            Some((
+                format!("/{route}?id={id}"),
+                format!("{route}/{id}.{extension}"),
+            ))
+        }
+        "tree" if query.is_empty() => {
+            let file = if child.is_empty() {
+                "tree.html".to_string()
+            } else {
+                format!("tree/{child}.html")
+            };
+            Some((format!("/tree/{}", encode_path(child)), file))
+        }
+        "tree" if !child.is_empty() && only(&["raw"]) && get("raw") == Some("true") => Some((
+            format!("/tree/{}?raw=true", encode_path(child)),
+            format!("raw/{child}"),
+        )),
+        "tag" if child.is_empty() && only(&["h"]) => {
+            let name = get("h").filter(|v| !v.is_empty())?;
+            Some((
+                format!("/tag?h={}", encode_path(name)),
+                format!("tag/{name}.html"),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Rewrites the `href` and `src` attributes in a rendered page.
+fn rewrite_links(html: &str, mut rewrite: impl FnMut(&str) -> Rewrite) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+
+    loop {
+        let next = [" href=\"", " src=\""]
+            .into_iter()
+            .filter_map(|attribute| rest.find(attribute).map(|i| (i, attribute)))
+            .min();
+        let Some((start, attribute)) = next else {
+            break;
+        };
+        let value_start = start + attribute.len();
+        let Some(len) = rest[value_start..].find('"') else {
+            break;
+        };
+        let value_end = value_start + len;
+
+        out.push_str(&rest[..start]);
+        match rewrite(&unescape(&rest[value_start..value_end])) {
+            Rewrite::Keep => out.push_str(&rest[start..=value_end]),
+            Rewrite::Replace(value) => {
+                out.push_str(attribute);
+                out.push_str(&value);
+                out.push('"');
+            }
+            Rewrite::Remove => {}
+        }
+        rest = &rest[value_end + 1..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Links from one exported file to another, relative so the export works
+/// wherever it's hosted.
+fn relative_link(from: &Path, to: &Path) -> String {
+    let from: Vec<_> = from
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+        .collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut link = "../".repeat(from.len() - common);
+    link.push_str(&encode_path(
+        &to[common..]
+            .iter()
+            .map(|v| v.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+    ));
+    link
+}
+
+fn is_immutable(file: &Path, repository: Option<&str>) -> bool {
+    let in_immutable_dir = repository
+        .and_then(|v| file.strip_prefix(v).ok())
+        .and_then(|v| v.components().next())
+        .is_some_and(|v| IMMUTABLE_DIRS.iter().any(|dir| v.as_os_str() == *dir));
+
+    in_immutable_dir || is_asset(file)
+}
+
+/// The stylesheets and favicon served from the root, which are named after
+/// their content.
+fn is_asset(file: &Path) -> bool {
+    let Some(name) = file.to_str() else {
+        return false;
+    };
+    name == "favicon.ico"
+        || ((name.starts_with("style-") || name.starts_with("highlight-"))
+            && Path::new(name)
+                .extension()
+                .is_some_and(|v| v.eq_ignore_ascii_case("css")))
+}
+
+fn is_object_id(v: &str) -> bool {
+    v.len() == 40 && v.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Checks a path stays inside the directory it's joined to.
+fn is_safe_path(path: &Path) -> bool {
+    path.components().all(|v| matches!(v, Component::Normal(_)))
+}
+
+fn encode_path(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&#x2f;", "/")
+        .replace("&quot;", "\"")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+async fn remove_dir(path: &Path) -> anyhow::Result<()> {
+    let result = if tokio::fs::metadata(path).await.is_ok_and(|v| v.is_dir()) {
+        tokio::fs::remove_dir_all(path).await
+    } else {
+        tokio::fs::remove_file(path).await
+    };
+
+    match result {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Couldn't remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
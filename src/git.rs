@@ -18,10 +18,9 @@ use itertools::Itertools;
 use moka::future::Cache;
 use std::borrow::Cow;
 use std::{
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque},
     ffi::OsStr,
     fmt::{self, Arguments, Write},
-    io::ErrorKind,
     path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
@@ -32,6 +31,7 @@ use time::{OffsetDateTime, UtcOffset};
 use tracing::{error, instrument, warn};
 
 use crate::{
+    metrics::METRICS,
     syntax_highlight::{format_file, format_file_inner, ComrakHighlightAdapter, FileIdentifier},
     unified_diff_builder::{Callback, UnifiedDiffBuilder},
 };
@@ -57,11 +57,24 @@ impl Git {
                 .max_capacity(100)
                 .build(),
             open_repositories: Cache::builder()
-                .time_to_idle(Duration::from_secs(120))
+                .time_to_idle(Duration::from_mins(2))
                 .max_capacity(100)
                 .build(),
         }
     }
+
+    /// Publishes the current size of each cache to the metrics registry.
+    pub async fn record_cache_sizes(&self) {
+        tokio::join!(
+            self.commits.run_pending_tasks(),
+            self.readme_cache.run_pending_tasks(),
+            self.open_repositories.run_pending_tasks(),
+        );
+
+        METRICS.cache_entries("commits", self.commits.entry_count());
+        METRICS.cache_entries("readme", self.readme_cache.entry_count());
+        METRICS.cache_entries("open_repositories", self.open_repositories.entry_count());
+    }
 }
 
 impl Git {
@@ -72,20 +85,23 @@ impl Git {
         branch: Option<Arc<str>>,
     ) -> Result<Arc<OpenRepository>> {
         let repo = repo_path.clone();
+        METRICS.cache_request("open_repositories");
         let repo = self
             .open_repositories
             .try_get_with_by_ref(&repo_path, async move {
+                METRICS.cache_miss("open_repositories");
                 tokio::task::spawn_blocking(move || {
                     gix::open::Options::isolated()
                         .open_path_as_is(true)
                         .open(&repo)
+                        .map_err(Box::new)
                 })
                 .await
                 .context("Failed to join Tokio task")
-                .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?
+                .map_err(std::io::Error::other)?
                 .map_err(|err| {
                     error!("{}", err);
-                    std::io::Error::new(ErrorKind::Other, "Failed to open repository")
+                    std::io::Error::other("Failed to open repository")
                 })
             })
             .await?;
@@ -253,8 +269,6 @@ impl OpenRepository {
                                 url,
                                 oid: item.object_id(),
                             }));
-
-                            continue;
                         }
                     }
                 }
@@ -309,8 +323,10 @@ impl OpenRepository {
 
         let git = self.git.clone();
 
+        METRICS.cache_request("readme");
         git.readme_cache
             .try_get_with((self.cache_key.clone(), self.branch.clone()), async move {
+                METRICS.cache_miss("readme");
                 tokio::task::spawn_blocking(move || {
                     let repo = self.repo.to_thread_local();
 
@@ -371,6 +387,48 @@ impl OpenRepository {
         .context("Failed to join Tokio task")?
     }
 
+    /// Gives a handle to the underlying repository for use on the calling
+    /// thread, for callers that need more than the views provided here.
+    pub fn to_thread_local(&self) -> gix::Repository {
+        self.repo.to_thread_local()
+    }
+
+    /// Resolves the object the requested branch (or `HEAD`) currently points
+    /// at, without peeling annotated tags.
+    pub async fn head_id(self: Arc<Self>) -> Result<ObjectId> {
+        tokio::task::spawn_blocking(move || {
+            let repo = self.repo.to_thread_local();
+
+            let mut head = if let Some(reference) = &self.branch {
+                repo.find_reference(reference.as_ref())?
+            } else {
+                repo.find_reference("HEAD")
+                    .context("Couldn't find HEAD of repository")?
+            };
+
+            Ok(head
+                .follow_to_object()
+                .context("Couldn't resolve reference to an object")?
+                .detach())
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
+    /// Resolves `revision`, which may be a branch name or an abbreviated id, to
+    /// the object it currently names.
+    pub async fn resolve(self: Arc<Self>, revision: String) -> Result<Option<ObjectId>> {
+        tokio::task::spawn_blocking(move || {
+            let repo = self.repo.to_thread_local();
+            Ok(repo
+                .rev_parse_single(revision.as_str())
+                .ok()
+                .map(gix::Id::detach))
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
     #[instrument(skip(self))]
     pub async fn latest_commit(self: Arc<Self>, highlighted: bool) -> Result<Commit> {
         tokio::task::spawn_blocking(move || {
@@ -397,6 +455,56 @@ impl OpenRepository {
         .context("Failed to join Tokio task")?
     }
 
+    /// The commits on `to` that aren't on `from`, newest first, and what they
+    /// changed since `to` forked from `from`, like `git diff from...to`.
+    #[instrument(skip(self))]
+    pub async fn compare(self: Arc<Self>, from: String, to: String) -> Result<Comparison> {
+        const MAX_COMMITS: usize = 250;
+
+        tokio::task::spawn_blocking(move || {
+            let repo = self.repo.to_thread_local();
+
+            let from = repo
+                .rev_parse_single(from.as_str())?
+                .object()?
+                .peel_to_kind(Kind::Commit)?
+                .into_commit();
+            let to = repo
+                .rev_parse_single(to.as_str())?
+                .object()?
+                .peel_to_kind(Kind::Commit)?
+                .into_commit();
+
+            let fork = Fork::find(&repo, from.id, to.id)?;
+
+            let mut commits = Vec::new();
+            for id in fork.only_to.iter().take(MAX_COMMITS) {
+                commits.push(Commit::try_from(repo.find_commit(*id)?)?);
+            }
+            let truncated = fork.only_to.len() > MAX_COMMITS || fork.gave_up;
+
+            let base_tree = match fork.base {
+                Some(id) => repo.find_commit(id)?.tree()?,
+                // too far apart to find where they forked, so fall back to
+                // `git diff from to`
+                None if fork.gave_up => from.tree()?,
+                None => repo.empty_tree(),
+            };
+            let (diff, diff_stats) = diff_trees(&repo, &base_tree, &to.tree()?, true)?;
+
+            Ok(Comparison {
+                from: from.id.to_string(),
+                to: to.id.to_string(),
+                commits,
+                truncated,
+                diff,
+                diff_stats,
+            })
+        })
+        .await
+        .context("Failed to join Tokio task")?
+    }
+
     #[instrument(skip_all)]
     pub async fn archive(
         self: Arc<Self>,
@@ -464,8 +572,10 @@ impl OpenRepository {
 
         let git = self.git.clone();
 
+        METRICS.cache_request("commits");
         git.commits
             .try_get_with((commit, highlighted), async move {
+                METRICS.cache_miss("commits");
                 tokio::task::spawn_blocking(move || {
                     let repo = self.repo.to_thread_local();
 
@@ -497,7 +607,7 @@ pub struct ArchivalVisitor<'a> {
     path: BString,
 }
 
-impl<'a> ArchivalVisitor<'a> {
+impl ArchivalVisitor<'_> {
     fn pop_element(&mut self) {
         if let Some(pos) = self.path.rfind_byte(b'/') {
             self.path.resize(pos, 0);
@@ -514,7 +624,7 @@ impl<'a> ArchivalVisitor<'a> {
     }
 }
 
-impl<'a> gix::traverse::tree::Visit for ArchivalVisitor<'a> {
+impl gix::traverse::tree::Visit for ArchivalVisitor<'_> {
     fn pop_front_tracked_path_and_set_current(&mut self) {
         self.path = self
             .path_deque
@@ -740,6 +850,124 @@ pub struct Commit {
     pub diff: String,
 }
 
+/// Where two commits' histories meet, found by walking back from both newest
+/// first and painting each commit with the sides it's reachable from, like
+/// `git merge-base` does.
+struct Fork {
+    /// The newest commit reachable from both sides.
+    base: Option<ObjectId>,
+    /// The commits only reachable from `to`, newest first.
+    only_to: Vec<ObjectId>,
+    /// Whether the walk stopped at [`Fork::MAX_WALK`] before it was done.
+    gave_up: bool,
+}
+
+impl Fork {
+    /// The most commits to look at, so comparing two unrelated or long
+    /// diverged histories can't walk the whole repository.
+    const MAX_WALK: usize = 10_000;
+
+    const FROM: u8 = 1;
+    const TO: u8 = 2;
+    // reachable from a common ancestor, so not worth walking any further
+    const STALE: u8 = 4;
+
+    fn find(repo: &gix::Repository, from: ObjectId, to: ObjectId) -> Result<Self> {
+        let mut flags = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        let mut fork = Self {
+            base: None,
+            only_to: Vec::new(),
+            gave_up: false,
+        };
+
+        for (id, side) in [(from, Self::FROM), (to, Self::TO)] {
+            *flags.entry(id).or_insert(0) |= side;
+            queue.push((repo.find_commit(id)?.time()?.seconds, id));
+        }
+
+        // commits with the same time can come out in any order, so an older
+        // common ancestor may be found before the newest one
+        let mut candidates = Vec::new();
+        let mut walked = 0;
+        while queue.iter().any(|(_, id)| flags[id] & Self::STALE == 0) {
+            let (_, id) = queue.pop().expect("queue isn't empty");
+
+            walked += 1;
+            if walked > Self::MAX_WALK {
+                fork.gave_up = true;
+                break;
+            }
+
+            let mut side = flags[&id];
+            if side & (Self::FROM | Self::TO) == Self::FROM | Self::TO {
+                if side & Self::STALE == 0 {
+                    candidates.push(id);
+                }
+                side |= Self::STALE;
+                flags.insert(id, side);
+            } else if side == Self::TO {
+                fork.only_to.push(id);
+            }
+
+            for parent in repo.find_commit(id)?.parent_ids() {
+                let parent = parent.detach();
+                let parent_side = flags.entry(parent).or_insert(0);
+                if *parent_side & side == side {
+                    continue;
+                }
+                *parent_side |= side;
+                queue.push((repo.find_commit(parent)?.time()?.seconds, parent));
+            }
+        }
+
+        // commits found from `to` first may turn out to be reachable from
+        // `from` too, and be queued more than once
+        let mut listed = HashSet::new();
+        fork.only_to
+            .retain(|id| flags[id] & Self::FROM == 0 && listed.insert(*id));
+
+        fork.base = Self::newest(repo, &candidates)?;
+
+        Ok(fork)
+    }
+
+    /// Picks the first of the common ancestors that isn't an ancestor of any
+    /// of the others.
+    fn newest(repo: &gix::Repository, candidates: &[ObjectId]) -> Result<Option<ObjectId>> {
+        if candidates.len() < 2 {
+            return Ok(candidates.first().copied());
+        }
+
+        let mut redundant = HashSet::new();
+        for candidate in candidates {
+            for info in repo.rev_walk([*candidate]).all()?.take(Self::MAX_WALK) {
+                let id = info?.id;
+                if id != *candidate && candidates.contains(&id) {
+                    redundant.insert(id);
+                }
+            }
+        }
+
+        Ok(candidates
+            .iter()
+            .find(|id| !redundant.contains(*id))
+            .or(candidates.first())
+            .copied())
+    }
+}
+
+/// What's on one revision but not another, for the compare page.
+pub struct Comparison {
+    pub from: String,
+    pub to: String,
+    pub commits: Vec<Commit>,
+    /// Whether there were too many commits to list them all.
+    pub truncated: bool,
+    pub diff: String,
+    pub diff_stats: String,
+}
+
 impl TryFrom<gix::Commit<'_>> for Commit {
     type Error = anyhow::Error;
 
@@ -796,8 +1024,6 @@ fn fetch_diff_and_stats(
     commit: &gix::Commit<'_>,
     highlight: bool,
 ) -> Result<(String, String)> {
-    const WIDTH: usize = 80;
-
     let current_tree = commit.tree().context("Couldn't get tree for the commit")?;
     let parent_tree = commit
         .ancestors()
@@ -811,6 +1037,19 @@ fn fetch_diff_and_stats(
         .transpose()?
         .unwrap_or_else(|| repo.empty_tree());
 
+    diff_trees(repo, &parent_tree, &current_tree, highlight)
+}
+
+/// Renders the changes from `parent_tree` to `current_tree`, along with a
+/// summary like `git diff --stat` gives.
+fn diff_trees(
+    repo: &gix::Repository,
+    parent_tree: &gix::Tree<'_>,
+    current_tree: &gix::Tree<'_>,
+    highlight: bool,
+) -> Result<(String, String)> {
+    const WIDTH: usize = 80;
+
     let mut diffs = Vec::new();
     let mut diff_output = String::new();
 
@@ -819,7 +1058,7 @@ fn fetch_diff_and_stats(
     let mut changes = parent_tree.changes()?;
     changes.track_path().track_rewrites(None);
     changes.for_each_to_obtain_tree_with_cache(
-        &current_tree,
+        current_tree,
         &mut repo.diff_resource_cache_for_tree_diff()?,
         |change| {
             if highlight {
@@ -943,7 +1182,7 @@ struct DiffBuilder<'a, F> {
     formatter: F,
 }
 
-impl<'a, F: DiffFormatter + Callback> DiffBuilder<'a, F> {
+impl<F: DiffFormatter + Callback> DiffBuilder<'_, F> {
     #[allow(clippy::too_many_lines)]
     fn handle(
         &mut self,
@@ -1124,15 +1363,15 @@ impl<'a> SyntaxHighlightedDiffFormatter<'a> {
     fn write(&self, output: &mut String, class: &str, data: &str) {
         write!(output, r#"<span class="diff-{class}">"#).unwrap();
         format_file_inner(output, data, FileIdentifier::Path(self.path), false).unwrap();
-        write!(output, r#"</span>"#).unwrap();
+        write!(output, r"</span>").unwrap();
     }
 }
 
-impl<'a> DiffFormatter for SyntaxHighlightedDiffFormatter<'a> {
+impl DiffFormatter for SyntaxHighlightedDiffFormatter<'_> {
     fn file_header(&self, output: &mut String, data: Arguments<'_>) {
         write!(output, r#"<span class="diff-file-header">"#).unwrap();
         write!(output, "{data}").unwrap();
-        writeln!(output, r#"</span>"#).unwrap();
+        writeln!(output, r"</span>").unwrap();
     }
 
     fn binary(
@@ -1147,7 +1386,7 @@ impl<'a> DiffFormatter for SyntaxHighlightedDiffFormatter<'a> {
     }
 }
 
-impl<'a> Callback for SyntaxHighlightedDiffFormatter<'a> {
+impl Callback for SyntaxHighlightedDiffFormatter<'_> {
     fn addition(&mut self, data: &str, dst: &mut String) {
         self.write(dst, "add-line", data);
     }
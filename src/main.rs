@@ -5,7 +5,7 @@ use std::{
     fmt::{Display, Formatter},
     future::IntoFuture,
     net::SocketAddr,
-    path::PathBuf,
+    path::{Path, PathBuf},
     str::FromStr,
     sync::{Arc, OnceLock},
     time::Duration,
@@ -17,20 +17,29 @@ use axum::{
     body::Body,
     http,
     http::{HeaderValue, StatusCode},
+    middleware,
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{get, post},
     Extension, Router,
 };
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use const_format::formatcp;
 use database::schema::SCHEMA_VERSION;
+use gnit_ssh::SshServer;
 use rocksdb::{Options, SliceTransform};
 use tokio::{
     net::TcpListener,
     signal::unix::{signal, SignalKind},
-    sync::mpsc,
+    sync::{broadcast, mpsc},
+};
+use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, NotForContentType, Predicate},
+        CompressionLayer,
+    },
+    cors::CorsLayer,
+    timeout::TimeoutLayer,
 };
-use tower_http::{cors::CorsLayer, timeout::TimeoutLayer};
 use tower_layer::layer_fn;
 use tracing::{error, info, instrument, warn};
 use tracing_subscriber::{
@@ -39,19 +48,30 @@ use tracing_subscriber::{
 use xxhash_rust::const_xxh3;
 
 use crate::{
-    database::schema::prefixes::{
-        COMMIT_COUNT_FAMILY, COMMIT_FAMILY, REFERENCE_FAMILY, REPOSITORY_FAMILY, TAG_FAMILY,
+    auth::Auth,
+    database::{
+        commit_log::IndexedCommitLog,
+        indexer::Wakeup,
+        schema::prefixes::{
+            COMMIT_COUNT_FAMILY, COMMIT_FAMILY, REFERENCE_FAMILY, REPOSITORY_FAMILY, TAG_FAMILY,
+        },
     },
+    export::Exporter,
     git::Git,
     layers::logger::LoggingMiddleware,
+    methods::{audit::AdminToken, repo::AccessControl},
     syntax_highlight::prime_highlighters,
     theme::Theme,
 };
 
+mod auth;
 mod database;
+mod export;
 mod git;
 mod layers;
 mod methods;
+mod metrics;
+mod mirror;
 mod syntax_highlight;
 mod theme;
 mod unified_diff_builder;
@@ -86,6 +106,64 @@ pub struct Args {
     /// Configures the request timeout.
     #[clap(long, default_value_t = Duration::from_secs(10).into())]
     request_timeout: humantime::Duration,
+    /// Path to a TOML file declaring upstream repositories to mirror under the scan path
+    ///
+    /// Mirrors can also be declared by a `repo.toml` with a `[mirror]` table in a directory
+    /// under the scan path
+    #[clap(long, value_parser)]
+    mirrors: Option<PathBuf>,
+    /// A password for the admin pages, such as the audit log at `/admin/audit`
+    ///
+    /// The pages are disabled if it isn't set
+    #[clap(long, env = "GNIT_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+    /// Keeps a static HTML export of every public repository in this directory, for hosting
+    /// read-only copies on plain file hosting
+    ///
+    /// Repositories are exported again whenever the indexer sees they've changed
+    #[clap(long, value_parser)]
+    export_path: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Runs the SSH server in the same process, sharing its users and repository access rules
+    ///
+    /// Repositories are only shown on the web if the SSH server would let an anonymous user read
+    /// them, and pushes are indexed as soon as they finish
+    Serve {
+        /// A server config to move into the config repository the first time the server starts
+        #[clap(short, long, value_parser, default_value = gnit_ssh::SERVER_CONFIG_FILE)]
+        config: PathBuf,
+        /// An address for SSH to listen on (eg. "[::]:2222"), may be given more than once.
+        /// Overrides `listen` in server.toml
+        #[clap(short, long)]
+        listen: Vec<SocketAddr>,
+        /// An OpenSSH private host key, may be given more than once for different key types.
+        /// Overrides `host_keys` in server.toml
+        #[clap(long, value_parser)]
+        host_key: Vec<PathBuf>,
+    },
+    /// Indexes and exports every public repository as static HTML, then exits
+    ///
+    /// Running it again only exports the repositories that have changed since
+    Export {
+        /// The directory to write the pages to, will be created if it doesn't already exist
+        out: PathBuf,
+    },
+    /// Checks the SSH server's config files for mistakes without changing anything, then exits
+    ///
+    /// Exits with an error if any were found
+    CheckConfig {
+        /// The server config to check if the config repository doesn't exist yet
+        #[clap(short, long, value_parser, default_value = gnit_ssh::SERVER_CONFIG_FILE)]
+        config: PathBuf,
+        /// Files to check instead, as repository configs if they're called repo.toml and as
+        /// server configs otherwise
+        files: Vec<PathBuf>,
+    },
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -120,7 +198,22 @@ impl FromStr for RefreshInterval {
 #[tokio::main]
 #[allow(clippy::too_many_lines)]
 async fn main() -> Result<(), anyhow::Error> {
-    let args: Args = Args::parse();
+    // the ssh server runs itself as git hooks and for lfs transfers
+    if let Some(code) = gnit_ssh::run_helper() {
+        std::process::exit(code);
+    }
+
+    let mut args: Args = Args::parse();
+    args.make_paths_absolute()?;
+
+    if let Some(Command::CheckConfig { config, files }) = &args.command {
+        std::env::set_current_dir(&args.scan_path)
+            .with_context(|| format!("Couldn't change to {}", args.scan_path.display()))?;
+        if !gnit_ssh::check_config(files, config).await? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "info");
@@ -134,17 +227,153 @@ async fn main() -> Result<(), anyhow::Error> {
         .with(logger_layer)
         .init();
 
+    // exports go by the ssh server's access rules whenever there is one
+    let ssh = match &args.command {
+        Some(Command::Serve { config, .. }) => {
+            Some(load_ssh_server(&args.scan_path, config).await?)
+        }
+        Some(Command::Export { .. })
+            if args.scan_path.join(gnit_ssh::SERVER_CONFIG_REPO).is_dir() =>
+        {
+            let config = Path::new(gnit_ssh::SERVER_CONFIG_FILE);
+            Some(load_ssh_server(&args.scan_path, config).await?)
+        }
+        _ => None,
+    };
+    let access = AccessControl(ssh.clone());
+
     let db = open_db(&args)?;
 
-    let indexer_wakeup_task =
-        run_indexer(db.clone(), args.scan_path.clone(), args.refresh_interval);
+    info!("Priming highlighters...");
+    prime_highlighters();
+
+    let app = build_app(&args, db.clone(), access.clone());
+
+    if let Some(Command::Export { out }) = &args.command {
+        info!("Indexing repositories...");
+        tokio::task::spawn_blocking({
+            let db = db.clone();
+            let scan_path = args.scan_path.clone();
+            move || crate::database::indexer::run(&scan_path, &db)
+        })
+        .await?;
+
+        info!("Exporting to {}...", out.display());
+        return Exporter::new(app, db, access, args.scan_path.clone(), out.clone())
+            .run(None)
+            .await;
+    }
+
+    let exporter = args.export_path.clone().map(|out| {
+        Arc::new(Exporter::new(
+            app.clone(),
+            db.clone(),
+            access,
+            args.scan_path.clone(),
+            out,
+        ))
+    });
+
+    let (indexer_wakeup_send, indexer_wakeup_recv) = mpsc::channel(10);
+
+    let ssh_task = match (&ssh, &args.command) {
+        (
+            Some(server),
+            Some(Command::Serve {
+                listen, host_key, ..
+            }),
+        ) => {
+            server
+                .set_commit_log(Arc::new(IndexedCommitLog(db.clone())))
+                .await;
+            tokio::spawn(forward_pushes(
+                server.pushes().await,
+                indexer_wakeup_send.clone(),
+            ));
+            futures_util::future::Either::Left(server.run(listen.clone(), host_key.clone()))
+        }
+        _ => futures_util::future::Either::Right(futures_util::future::pending()),
+    };
+
+    tokio::spawn(mirror::run(
+        args.scan_path.clone(),
+        args.mirrors.clone(),
+        indexer_wakeup_send.clone(),
+    ));
+
+    let indexer_wakeup_task = run_indexer(
+        db.clone(),
+        args.scan_path.clone(),
+        args.refresh_interval,
+        exporter,
+        indexer_wakeup_send,
+        indexer_wakeup_recv,
+    );
+
+    info!("Server starting up...");
+
+    let listener = TcpListener::bind(&args.bind_address).await?;
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    let server = axum::serve(listener, app).into_future();
+
+    tokio::select! {
+        res = server => res.context("failed to run server"),
+        res = indexer_wakeup_task => res.context("failed to run indexer"),
+        res = ssh_task => res.context("failed to run ssh server"),
+        _ = tokio::signal::ctrl_c() => {
+            info!("Received ctrl-c, shutting down");
+            Ok(())
+        }
+    }
+}
+
+impl Args {
+    /// Makes every path absolute, so they still work once we've moved into
+    /// the scan path for the ssh server.
+    fn make_paths_absolute(&mut self) -> std::io::Result<()> {
+        let absolute = |path: &mut PathBuf| -> std::io::Result<()> {
+            *path = std::path::absolute(&*path)?;
+            Ok(())
+        };
+
+        absolute(&mut self.db_store)?;
+        absolute(&mut self.scan_path)?;
+        self.mirrors.iter_mut().try_for_each(absolute)?;
+        self.export_path.iter_mut().try_for_each(absolute)?;
+
+        match &mut self.command {
+            Some(Command::Serve {
+                config, host_key, ..
+            }) => {
+                absolute(config)?;
+                host_key.iter_mut().try_for_each(absolute)?;
+            }
+            Some(Command::Export { out }) => absolute(out)?,
+            Some(Command::CheckConfig { config, files }) => {
+                absolute(config)?;
+                files.iter_mut().try_for_each(absolute)?;
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+fn build_app(args: &Args, db: Arc<rocksdb::DB>, access: AccessControl) -> Router {
+    // exported pages are the same for everyone, so only serving lets people log in
+    let auth = match (&access.0, &args.command) {
+        (Some(server), Some(Command::Serve { .. })) => Auth::new(server.clone()),
+        _ => Auth::default(),
+    };
 
     let css = {
         let theme = toml::from_str::<Theme>(include_str!("../themes/github_light.toml"))
             .unwrap()
             .build_css();
         let css = Box::leak(
-            format!(r#"@media (prefers-color-scheme: light){{{theme}}}"#)
+            format!(r"@media (prefers-color-scheme: light){{{theme}}}")
                 .into_boxed_str()
                 .into_boxed_bytes(),
         );
@@ -157,7 +386,7 @@ async fn main() -> Result<(), anyhow::Error> {
             .unwrap()
             .build_css();
         let css = Box::leak(
-            format!(r#"@media (prefers-color-scheme: dark){{{theme}}}"#)
+            format!(r"@media (prefers-color-scheme: dark){{{theme}}}")
                 .into_boxed_str()
                 .into_boxed_bytes(),
         );
@@ -183,16 +412,24 @@ async fn main() -> Result<(), anyhow::Error> {
                 http::header::CONTENT_TYPE,
                 HeaderValue::from_static("text/css"),
             );
+            // served from a content-addressed path
+            resp.headers_mut().insert(
+                http::header::CACHE_CONTROL,
+                HeaderValue::from_static("public, max-age=31536000, immutable"),
+            );
             resp
         }
     };
 
-    info!("Priming highlighters...");
-    prime_highlighters();
-    info!("Server starting up...");
-
-    let app = Router::new()
+    Router::new()
         .route("/", get(methods::index::handle))
+        .route("/metrics", get(methods::metrics::handle))
+        .route("/admin/audit", get(methods::audit::handle))
+        .route(
+            "/login",
+            get(methods::login::handle).post(methods::login::handle_post),
+        )
+        .route("/logout", post(methods::login::handle_logout))
         .route(
             formatcp!("/style-{}.css", GLOBAL_CSS_HASH),
             get(static_css(GLOBAL_CSS)),
@@ -214,24 +451,27 @@ async fn main() -> Result<(), anyhow::Error> {
         )
         .fallback(methods::repo::service)
         .layer(TimeoutLayer::new(args.request_timeout.into()))
+        .layer(
+            // snapshots are already gzipped and packfiles are already zlib
+            // compressed, so there's no benefit to compressing them again
+            CompressionLayer::new().compress_when(
+                DefaultPredicate::new()
+                    .and(NotForContentType::const_new("application/gzip"))
+                    .and(NotForContentType::const_new("application/x-git-")),
+            ),
+        )
         .layer(layer_fn(LoggingMiddleware))
+        .layer(middleware::from_fn(metrics::track_requests))
+        .layer(middleware::from_fn(auth::authenticate))
         .layer(Extension(Arc::new(Git::new())))
         .layer(Extension(db))
-        .layer(Extension(Arc::new(args.scan_path)))
-        .layer(CorsLayer::new());
-
-    let listener = TcpListener::bind(&args.bind_address).await?;
-    let app = app.into_make_service_with_connect_info::<SocketAddr>();
-    let server = axum::serve(listener, app).into_future();
-
-    tokio::select! {
-        res = server => res.context("failed to run server"),
-        res = indexer_wakeup_task => res.context("failed to run indexer"),
-        _ = tokio::signal::ctrl_c() => {
-            info!("Received ctrl-c, shutting down");
-            Ok(())
-        }
-    }
+        .layer(Extension(Arc::new(args.scan_path.clone())))
+        .layer(Extension(AdminToken(
+            args.admin_token.as_deref().map(Arc::from),
+        )))
+        .layer(Extension(access))
+        .layer(Extension(auth))
+        .layer(CorsLayer::new())
 }
 
 fn open_db(args: &Args) -> Result<Arc<rocksdb::DB>, anyhow::Error> {
@@ -292,16 +532,39 @@ async fn run_indexer(
     db: Arc<rocksdb::DB>,
     scan_path: PathBuf,
     refresh_interval: RefreshInterval,
+    exporter: Option<Arc<Exporter>>,
+    indexer_wakeup_send: mpsc::Sender<Wakeup>,
+    mut indexer_wakeup_recv: mpsc::Receiver<Wakeup>,
 ) -> Result<(), tokio::task::JoinError> {
-    let (indexer_wakeup_send, mut indexer_wakeup_recv) = mpsc::channel(10);
+    let runtime = tokio::runtime::Handle::current();
+
+    std::thread::spawn(move || {
+        let mut wakeup = Wakeup::All;
+
+        loop {
+            let only = match wakeup {
+                Wakeup::All => {
+                    info!("Running periodic index");
+                    crate::database::indexer::run(&scan_path, &db);
+                    info!("Finished periodic index");
+                    None
+                }
+                Wakeup::Repository(relative) => {
+                    crate::database::indexer::run_repository(&scan_path, &db, &relative);
+                    Some(relative)
+                }
+            };
 
-    std::thread::spawn(move || loop {
-        info!("Running periodic index");
-        crate::database::indexer::run(&scan_path, &db);
-        info!("Finished periodic index");
+            if let Some(exporter) = &exporter {
+                if let Err(error) = runtime.block_on(exporter.run(only.as_deref())) {
+                    error!("Failed to update the static export: {error:#}");
+                }
+            }
 
-        if indexer_wakeup_recv.blocking_recv().is_none() {
-            break;
+            match indexer_wakeup_recv.blocking_recv() {
+                Some(v) => wakeup = v,
+                None => break,
+            }
         }
     });
 
@@ -311,7 +574,7 @@ async fn run_indexer(
             match refresh_interval {
                 RefreshInterval::Never => futures_util::future::pending().await,
                 RefreshInterval::Duration(v) => tokio::time::sleep(v).await,
-            };
+            }
         };
 
         async move {
@@ -321,7 +584,7 @@ async fn run_indexer(
                     () = build_sleeper() => {},
                 }
 
-                if indexer_wakeup_send.send(()).await.is_err() {
+                if indexer_wakeup_send.send(Wakeup::All).await.is_err() {
                     error!("Indexing thread has died and is no longer accepting wakeup messages");
                 }
             }
@@ -330,6 +593,40 @@ async fn run_indexer(
     .await
 }
 
+/// Loads the SSH server's state to run it alongside us, or just to go by its
+/// access rules. It works relative to the scan path, so we move there.
+async fn load_ssh_server(scan_path: &Path, config: &Path) -> Result<SshServer, anyhow::Error> {
+    std::env::set_current_dir(scan_path)
+        .with_context(|| format!("Couldn't change to {}", scan_path.display()))?;
+
+    // the ssh server logs through `log` rather than `tracing`
+    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+
+    info!("Loading SSH server state...");
+    SshServer::load(config).await
+}
+
+/// Reindexes each repository as soon as a push to it over SSH has finished.
+async fn forward_pushes(
+    mut pushes: broadcast::Receiver<PathBuf>,
+    indexer_wakeup_send: mpsc::Sender<Wakeup>,
+) {
+    loop {
+        let wakeup = match pushes.recv().await {
+            Ok(relative) => Wakeup::Repository(relative),
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Missed {missed} pushes, triggering a full reindex");
+                Wakeup::All
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        if indexer_wakeup_send.send(wakeup).await.is_err() {
+            error!("Indexing thread has died and is no longer accepting wakeup messages");
+        }
+    }
+}
+
 #[must_use]
 pub fn build_asset_hash(v: &[u8]) -> Box<str> {
     let hasher = const_xxh3::xxh3_128(v);
@@ -0,0 +1,149 @@
+use std::{fmt::Write, path::PathBuf, sync::Arc};
+
+use askama::Template;
+use axum::{
+    extract::Query,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use gnit_ssh::AuditEntry;
+use serde::Deserialize;
+
+use super::filters;
+use crate::into_response;
+
+const PAGE_SIZE: usize = 100;
+
+/// The token admins log in with, the audit log is hidden if there's none.
+#[derive(Clone)]
+pub struct AdminToken(pub Option<Arc<str>>);
+
+#[derive(Deserialize, Default, Clone)]
+pub struct AuditQuery {
+    user: Option<String>,
+    repo: Option<String>,
+    command: Option<String>,
+    #[serde(default)]
+    page: usize,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        let matches = |filter: &Option<String>, value: Option<&str>| {
+            filter
+                .as_deref()
+                .filter(|v| !v.is_empty())
+                .is_none_or(|v| Some(v) == value)
+        };
+
+        matches(&self.user, entry.user.as_deref())
+            && matches(&self.repo, Some(&entry.repo))
+            && matches(&self.command, Some(&entry.command))
+    }
+
+    /// Links to another page of the same results.
+    fn page_query(&self, page: usize) -> String {
+        let mut query = format!("?page={page}");
+
+        for (name, value) in [
+            ("user", &self.user),
+            ("repo", &self.repo),
+            ("command", &self.command),
+        ] {
+            if let Some(value) = value.as_deref().filter(|v| !v.is_empty()) {
+                let _res = write!(query, "&{name}={}", urlencode(value));
+            }
+        }
+
+        query
+    }
+}
+
+#[derive(Template)]
+#[template(path = "audit.html")]
+pub struct View {
+    pub entries: Vec<AuditEntry>,
+    pub query: AuditQuery,
+    pub previous_page: Option<String>,
+    pub next_page: Option<String>,
+}
+
+pub async fn handle(
+    Extension(scan_path): Extension<Arc<PathBuf>>,
+    Extension(AdminToken(token)): Extension<AdminToken>,
+    Query(query): Query<AuditQuery>,
+    headers: HeaderMap,
+) -> Result<Response, super::repo::Error> {
+    let Some(token) = token else {
+        return Ok((StatusCode::NOT_FOUND, "Not found").into_response());
+    };
+
+    if !authorised(&headers, &token) {
+        return Ok((
+            StatusCode::UNAUTHORIZED,
+            [(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"gnostr admin\""),
+            )],
+            "Unauthorised",
+        )
+            .into_response());
+    }
+
+    // newest first, reading back only as far as this page
+    let filter = query.clone();
+    let mut entries = gnit_ssh::read_audit_log(
+        &scan_path,
+        query.page * PAGE_SIZE,
+        PAGE_SIZE + 1,
+        move |v| filter.matches(v),
+    )
+    .await?;
+
+    let next_page = (entries.len() > PAGE_SIZE).then(|| query.page_query(query.page + 1));
+    let previous_page = query
+        .page
+        .checked_sub(1)
+        .map(|page| query.page_query(page));
+    entries.truncate(PAGE_SIZE);
+
+    Ok(into_response(View {
+        entries,
+        query,
+        previous_page,
+        next_page,
+    })
+    .into_response())
+}
+
+/// Checks for HTTP basic auth with the admin token as the password, whatever
+/// the username.
+fn authorised(headers: &HeaderMap, token: &str) -> bool {
+    let password = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| STANDARD.decode(v.trim()).ok())
+        .and_then(|v| String::from_utf8(v).ok())
+        .and_then(|v| v.split_once(':').map(|(_, password)| password.to_string()));
+
+    password.is_some_and(|password| constant_time_eq(password.as_bytes(), token.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn urlencode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
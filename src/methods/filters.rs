@@ -45,6 +45,10 @@ pub fn hex(s: &[u8]) -> Result<String, askama::Error> {
     Ok(const_hex::encode(s))
 }
 
+pub fn format_size(s: &u64) -> Result<String, askama::Error> {
+    Ok(gnit_ssh::format_size(*s))
+}
+
 pub fn gravatar(email: &str) -> Result<&'static str, askama::Error> {
     static CACHE: LazyLock<ArcSwap<HashMap<&'static str, &'static str>>> =
         LazyLock::new(|| ArcSwap::new(Arc::new(HashMap::new())));
@@ -101,6 +105,21 @@ impl From<&(i64, i32)> for Timestamp {
     }
 }
 
+impl From<u64> for Timestamp {
+    fn from(value: u64) -> Self {
+        Self(
+            OffsetDateTime::from_unix_timestamp(i64::try_from(value).unwrap_or(i64::MAX))
+                .unwrap_or(OffsetDateTime::UNIX_EPOCH),
+        )
+    }
+}
+
+impl From<&u64> for Timestamp {
+    fn from(value: &u64) -> Self {
+        Self::from(*value)
+    }
+}
+
 impl From<OffsetDateTime> for Timestamp {
     fn from(value: OffsetDateTime) -> Self {
         Self(value)
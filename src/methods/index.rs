@@ -1,4 +1,4 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{collections::BTreeMap, path::Path, sync::Arc};
 
 use anyhow::Context;
 use askama::Template;
@@ -6,8 +6,10 @@ use axum::{response::IntoResponse, Extension};
 
 use super::filters;
 use crate::{
+    auth::WebUser,
     database::schema::repository::{Repository, YokedRepository},
     into_response,
+    methods::repo::AccessControl,
 };
 
 #[derive(Template)]
@@ -18,6 +20,8 @@ pub struct View {
 
 pub async fn handle(
     Extension(db): Extension<Arc<rocksdb::DB>>,
+    Extension(access): Extension<AccessControl>,
+    user: Option<Extension<WebUser>>,
 ) -> Result<impl IntoResponse, super::repo::Error> {
     let mut repositories: BTreeMap<Option<String>, Vec<YokedRepository>> = BTreeMap::new();
 
@@ -26,6 +30,13 @@ pub async fn handle(
         .context("Failed to join Tokio task")??;
 
     for (k, v) in fetched {
+        if !access
+            .can_read(Path::new(&k), user.as_ref().map(|v| &v.0))
+            .await
+        {
+            continue;
+        }
+
         // TODO: fixme
         let mut split: Vec<_> = k.split('/').collect();
         split.pop();
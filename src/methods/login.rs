@@ -0,0 +1,92 @@
+use askama::Template;
+use axum::{
+    extract::Query,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Extension,
+};
+use gnit_ssh::{NostrEvent, LOGIN_KIND};
+use serde::Deserialize;
+
+use super::filters;
+use crate::{
+    auth::{Auth, SESSION_COOKIE},
+    into_response,
+};
+
+#[derive(Deserialize, Default)]
+pub struct LoginQuery {
+    next: Option<String>,
+}
+
+impl LoginQuery {
+    /// Where to go once logged in, only ever a page on this site.
+    fn next(&self) -> &str {
+        self.next
+            .as_deref()
+            .filter(|v| v.starts_with('/') && !v.starts_with("//"))
+            .unwrap_or("/")
+    }
+}
+
+#[derive(Template)]
+#[template(path = "login.html")]
+pub struct View {
+    pub challenge: String,
+    pub kind: u64,
+    pub next: String,
+}
+
+pub async fn handle(Extension(auth): Extension<Auth>, Query(query): Query<LoginQuery>) -> Response {
+    let Some(challenge) = auth.challenge() else {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    };
+
+    into_response(View {
+        challenge,
+        kind: LOGIN_KIND,
+        next: query.next().to_string(),
+    })
+    .into_response()
+}
+
+/// Logs in with an event signed by a NIP-07 extension on the login page.
+pub async fn handle_post(Extension(auth): Extension<Auth>, body: String) -> Response {
+    if !auth.enabled() {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    }
+
+    let event = match NostrEvent::parse(&body) {
+        Ok(v) => v,
+        Err(error) => return (StatusCode::BAD_REQUEST, error.to_string()).into_response(),
+    };
+
+    if event.kind != LOGIN_KIND
+        || !event
+            .tag("challenge")
+            .is_some_and(|v| auth.check_challenge(v))
+    {
+        return (StatusCode::BAD_REQUEST, "That isn't a login for this site.").into_response();
+    }
+    if let Err(error) = event.check_fresh(60) {
+        return (StatusCode::BAD_REQUEST, error.to_string()).into_response();
+    }
+
+    match auth.session_cookie(&event.pubkey.to_ascii_lowercase()) {
+        Some(cookie) => (StatusCode::NO_CONTENT, [(header::SET_COOKIE, cookie)]).into_response(),
+        None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+pub async fn handle_logout() -> Response {
+    let cookie = format!("{SESSION_COOKIE}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax");
+
+    (
+        [(
+            header::SET_COOKIE,
+            HeaderValue::try_from(cookie).expect("cookie is a valid header"),
+        )],
+        Redirect::to("/"),
+    )
+        .into_response()
+}
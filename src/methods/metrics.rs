@@ -0,0 +1,44 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
+use axum::{
+    http::{self, HeaderValue},
+    response::IntoResponse,
+    Extension,
+};
+
+use crate::{git::Git, methods::repo::AccessControl, metrics::METRICS};
+
+pub async fn handle(
+    Extension(git): Extension<Arc<Git>>,
+    Extension(db): Extension<Arc<rocksdb::DB>>,
+    Extension(access): Extension<AccessControl>,
+) -> Result<impl IntoResponse, super::repo::Error> {
+    git.record_cache_sizes().await;
+    if let Some(ssh) = &access.0 {
+        METRICS.observe_ssh(&ssh.firewall_stats().await);
+    }
+
+    // anyone can scrape this, so only name the repositories anyone can read
+    let mut readable = METRICS.repositories();
+    for repository in readable.clone() {
+        if !access.can_read(Path::new(&repository), None).await {
+            readable.remove(&repository);
+        }
+    }
+
+    let body = tokio::task::spawn_blocking(move || {
+        METRICS.observe_rocksdb(&db);
+        METRICS.encode(&readable)
+    })
+    .await
+    .context("Failed to join Tokio task")?
+    .context("Failed to encode metrics")?;
+
+    let headers = [(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+    )];
+
+    Ok((headers, body))
+}
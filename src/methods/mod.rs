@@ -1,3 +1,6 @@
+pub mod audit;
 pub mod filters;
 pub mod index;
+pub mod login;
+pub mod metrics;
 pub mod repo;
@@ -0,0 +1,136 @@
+//! Conditional request support for repository views.
+//!
+//! Each view derives a strong `ETag` from the request URI and the objects it
+//! rendered, so clients (and any CDN in front of us) can revalidate with
+//! `If-None-Match` instead of having the page rendered again. Pages of
+//! repositories only some users can read are only cached by their browsers.
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::CRATE_VERSION;
+
+/// How long a response may be reused without revalidating it.
+#[derive(Copy, Clone, Debug)]
+pub enum Freshness {
+    /// The response is addressed by an object id and can never change.
+    Immutable,
+    /// The response follows a branch or the index, so may change on the next
+    /// push.
+    Mutable,
+}
+
+impl Freshness {
+    fn cache_control(self) -> HeaderValue {
+        match self {
+            Self::Immutable => HeaderValue::from_static("public, max-age=31536000, immutable"),
+            Self::Mutable => HeaderValue::from_static("public, max-age=60"),
+        }
+    }
+}
+
+pub struct Validator {
+    etag: HeaderValue,
+    freshness: Freshness,
+}
+
+impl Validator {
+    /// Builds a validator for the page at `uri`, which was rendered from the
+    /// given `revisions` (object ids, or raw index entries for pages served
+    /// from the database).
+    pub fn new<I, R>(freshness: Freshness, uri: &Uri, revisions: I) -> Self
+    where
+        I: IntoIterator<Item = R>,
+        R: AsRef<[u8]>,
+    {
+        let mut hasher = Xxh3::new();
+        hasher.update(CRATE_VERSION.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(
+            uri.path_and_query()
+                .map_or(uri.path(), |v| v.as_str())
+                .as_bytes(),
+        );
+
+        for revision in revisions {
+            hasher.update(b"\0");
+            hasher.update(revision.as_ref());
+        }
+
+        let etag = format!("\"{}\"", const_hex::encode(hasher.digest128().to_be_bytes()));
+
+        Self {
+            etag: HeaderValue::try_from(etag).expect("hex is a valid header value"),
+            freshness,
+        }
+    }
+
+    /// Checks whether the client already holds this exact response.
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        let etag = self.etag.as_bytes();
+
+        headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .any(|v| v == "*" || v.trim_start_matches("W/").as_bytes() == etag)
+    }
+
+    /// Builds a `304 Not Modified` response for a client whose copy is current.
+    pub fn not_modified(self) -> Cached<StatusCode> {
+        self.wrap(StatusCode::NOT_MODIFIED)
+    }
+
+    /// Attaches the validator and caching policy to a response, which is only
+    /// rendered once the response is sent.
+    pub fn wrap<T>(self, response: T) -> Cached<T> {
+        Cached {
+            validator: self,
+            response,
+        }
+    }
+}
+
+pub struct Cached<T> {
+    validator: Validator,
+    response: T,
+}
+
+impl<T: IntoResponse> IntoResponse for Cached<T> {
+    fn into_response(self) -> Response {
+        let mut response = self.response.into_response();
+
+        if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+            let headers = response.headers_mut();
+            headers.insert(header::ETAG, self.validator.etag);
+            headers.insert(
+                header::CACHE_CONTROL,
+                self.validator.freshness.cache_control(),
+            );
+        }
+
+        response
+    }
+}
+
+/// Stops shared caches from storing a response that not everyone may see, by
+/// marking whatever caching policy it has as `private`.
+pub fn make_private(response: &mut Response) {
+    let Some(policy) = response
+        .headers()
+        .get(header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("public"))
+    else {
+        return;
+    };
+
+    let policy = HeaderValue::try_from(format!("private{policy}"))
+        .expect("the rest of a valid header is valid");
+    response.headers_mut().insert(header::CACHE_CONTROL, policy);
+}
@@ -1,7 +1,12 @@
 use std::sync::Arc;
 
 use askama::Template;
-use axum::{extract::Query, response::IntoResponse, Extension};
+use axum::{
+    extract::Query,
+    http::{HeaderMap, Uri},
+    response::IntoResponse,
+    Extension,
+};
 use serde::Deserialize;
 
 use crate::{
@@ -9,9 +14,12 @@ use crate::{
     into_response,
     methods::{
         filters,
-        repo::{Repository, RepositoryPath, Result},
+        repo::{
+            cache::{Freshness, Validator},
+            Repository, RepositoryPath, Result,
+        },
     },
-    Git,
+    Git, ResponseEither,
 };
 
 #[derive(Template)]
@@ -36,21 +44,50 @@ pub async fn handle(
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
     Extension(git): Extension<Arc<Git>>,
     Query(query): Query<UriQuery>,
+    uri: Uri,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse> {
     let open_repo = git.repo(repository_path, query.branch.clone()).await?;
 
+    let validator = validator_for(&uri, query.id.as_deref(), open_repo.clone()).await?;
+    if validator.matches(&headers) {
+        return Ok(ResponseEither::Left(validator.not_modified()));
+    }
+
     let (dl_branch, commit) = tokio::try_join!(
         fetch_dl_branch(query.branch.clone(), open_repo.clone()),
         fetch_commit(query.id.as_deref(), open_repo),
     )?;
 
-    Ok(into_response(View {
+    Ok(ResponseEither::Right(validator.wrap(into_response(View {
         repo,
         commit,
         branch: query.branch,
         id: query.id,
         dl_branch,
-    }))
+    }))))
+}
+
+/// Builds the validator for a page showing either the pinned `commit_id` or
+/// whatever the branch currently points at. Only a full object id pins the
+/// page for good, anything else is resolved to what it names right now.
+pub async fn validator_for(
+    uri: &Uri,
+    commit_id: Option<&str>,
+    open_repo: Arc<OpenRepository>,
+) -> Result<Validator> {
+    let Some(commit_id) = commit_id else {
+        let head = open_repo.head_id().await?;
+        return Ok(Validator::new(Freshness::Mutable, uri, [head.as_bytes()]));
+    };
+
+    Ok(match open_repo.resolve(commit_id.to_string()).await? {
+        Some(id) if id.to_string() == commit_id => {
+            Validator::new(Freshness::Immutable, uri, [id.as_bytes()])
+        }
+        Some(id) => Validator::new(Freshness::Mutable, uri, [id.as_bytes()]),
+        None => Validator::new(Freshness::Mutable, uri, [commit_id]),
+    })
 }
 
 async fn fetch_commit(
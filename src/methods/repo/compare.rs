@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use serde::Deserialize;
+
+use crate::{
+    git::Comparison,
+    into_response,
+    methods::{
+        filters,
+        repo::{Repository, RepositoryPath, Result},
+    },
+    Git,
+};
+
+#[derive(Deserialize)]
+pub struct UriQuery {
+    from: String,
+    to: String,
+}
+
+#[derive(Template)]
+#[template(path = "repo/compare.html")]
+pub struct View {
+    pub repo: Repository,
+    pub comparison: Comparison,
+    pub branch: Option<Arc<str>>,
+}
+
+/// Shows what's on one branch or commit but not another, such as what a
+/// push added.
+pub async fn handle(
+    Extension(repo): Extension<Repository>,
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(git): Extension<Arc<Git>>,
+    Query(query): Query<UriQuery>,
+) -> Result<Response> {
+    let open_repo = git.repo(repository_path, None).await?;
+
+    let Ok(comparison) = open_repo.compare(query.from, query.to).await else {
+        return Ok((StatusCode::NOT_FOUND, "Revision not found").into_response());
+    };
+
+    Ok(into_response(View {
+        repo,
+        comparison,
+        branch: None,
+    })
+    .into_response())
+}
@@ -3,12 +3,11 @@ use std::{fmt::Write, sync::Arc};
 use askama::Template;
 use axum::{
     extract::Query,
-    http::HeaderValue,
-    response::{IntoResponse, Response},
+    http::{HeaderMap, HeaderValue, Uri},
+    response::IntoResponse,
     Extension,
 };
 use bytes::{BufMut, BytesMut};
-use clap::crate_version;
 use time::format_description::well_known::Rfc2822;
 
 use crate::{
@@ -16,9 +15,12 @@ use crate::{
     http, into_response,
     methods::{
         filters,
-        repo::{commit::UriQuery, Repository, RepositoryPath, Result},
+        repo::{
+            commit::{validator_for, UriQuery},
+            Repository, RepositoryPath, Result,
+        },
     },
-    Git,
+    Git, ResponseEither,
 };
 
 #[derive(Template)]
@@ -34,19 +36,27 @@ pub async fn handle(
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
     Extension(git): Extension<Arc<Git>>,
     Query(query): Query<UriQuery>,
+    uri: Uri,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse> {
     let open_repo = git.repo(repository_path, query.branch.clone()).await?;
+
+    let validator = validator_for(&uri, query.id.as_deref(), open_repo.clone()).await?;
+    if validator.matches(&headers) {
+        return Ok(ResponseEither::Left(validator.not_modified()));
+    }
+
     let commit = if let Some(commit) = query.id {
         open_repo.commit(&commit, true).await?
     } else {
         Arc::new(open_repo.latest_commit(true).await?)
     };
 
-    Ok(into_response(View {
+    Ok(ResponseEither::Right(validator.wrap(into_response(View {
         repo,
         commit,
         branch: query.branch,
-    }))
+    }))))
 }
 
 
@@ -56,8 +66,16 @@ pub async fn handle_plain(
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
     Extension(git): Extension<Arc<Git>>,
     Query(query): Query<UriQuery>,
-) -> Result<Response> {
+    uri: Uri,
+    request_headers: HeaderMap,
+) -> Result<impl IntoResponse> {
     let open_repo = git.repo(repository_path, query.branch).await?;
+
+    let validator = validator_for(&uri, query.id.as_deref(), open_repo.clone()).await?;
+    if validator.matches(&request_headers) {
+        return Ok(ResponseEither::Left(validator.not_modified()));
+    }
+
     let commit = if let Some(commit) = query.id {
         open_repo.commit(&commit, false).await?
     } else {
@@ -103,5 +121,5 @@ pub async fn handle_plain(
     //writeln!(data, "--\ngnostr-web {}", crate_version!()).unwrap();
     writeln!(data).unwrap();
 
-    Ok((headers, data.freeze()).into_response())
+    Ok(ResponseEither::Right(validator.wrap((headers, data.freeze()))))
 }
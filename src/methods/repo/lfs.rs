@@ -0,0 +1,246 @@
+//! The download half of the Git LFS batch API, along with serving LFS objects in place of their
+//! pointers. The web server is read-only, so objects are uploaded over SSH with `git-lfs-transfer`.
+
+use std::path::Path;
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use gnit_ssh::{is_lfs_oid, lfs_object_path};
+use serde::{Deserialize, Serialize};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    git::Content,
+    methods::repo::{Repository, RepositoryPath},
+};
+
+const LFS_CONTENT_TYPE: &str = "application/vnd.git-lfs+json";
+const POINTER_VERSION: &str = "version https://git-lfs.github.com/spec/v1";
+
+/// Pointers are small, anything bigger than this is a regular file.
+const MAX_POINTER_SIZE: usize = 1024;
+
+#[derive(Deserialize)]
+struct BatchRequest {
+    operation: String,
+    #[serde(default)]
+    transfers: Vec<String>,
+    objects: Vec<ObjectSpec>,
+    hash_algo: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ObjectSpec {
+    oid: String,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct BatchResponse {
+    transfer: &'static str,
+    objects: Vec<ObjectResponse>,
+    hash_algo: &'static str,
+}
+
+#[derive(Serialize)]
+struct ObjectResponse {
+    oid: String,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actions: Option<Actions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ObjectError>,
+}
+
+#[derive(Serialize)]
+struct Actions {
+    download: Action,
+}
+
+#[derive(Serialize)]
+struct Action {
+    href: String,
+}
+
+#[derive(Serialize)]
+struct ObjectError {
+    code: u16,
+    message: &'static str,
+}
+
+/// Handles `POST /{repo}/info/lfs/objects/batch`.
+pub async fn handle_batch(
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    Extension(Repository(repository)): Extension<Repository>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Ok(request) = serde_json::from_slice::<BatchRequest>(&body) else {
+        return error(StatusCode::UNPROCESSABLE_ENTITY, "Invalid batch request");
+    };
+
+    if request.operation != "download" {
+        return error(
+            StatusCode::FORBIDDEN,
+            "LFS objects can only be uploaded over SSH",
+        );
+    }
+
+    if request.hash_algo.as_deref().is_some_and(|v| v != "sha256") {
+        return error(StatusCode::CONFLICT, "Only sha256 objects are supported");
+    }
+
+    if !request.transfers.is_empty() && !request.transfers.iter().any(|v| v == "basic") {
+        return error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Only the basic transfer adapter is supported",
+        );
+    }
+
+    // the client needs absolute urls, so build them from the address it used to reach us
+    let scheme = headers
+        .get("X-Forwarded-Proto")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("http");
+    let Some(host) = headers.get(header::HOST).and_then(|v| v.to_str().ok()) else {
+        return error(StatusCode::BAD_REQUEST, "Missing Host header");
+    };
+
+    let mut objects = Vec::with_capacity(request.objects.len());
+    for ObjectSpec { oid, size } in request.objects {
+        let stored = is_lfs_oid(&oid)
+            && tokio::fs::metadata(lfs_object_path(&repository_path, &oid))
+                .await
+                .is_ok_and(|v| v.len() == size);
+
+        let (actions, error) = if stored {
+            let href = format!(
+                "{scheme}://{host}/{}/info/lfs/objects/{oid}",
+                repository.display()
+            );
+            (
+                Some(Actions {
+                    download: Action { href },
+                }),
+                None,
+            )
+        } else {
+            (
+                None,
+                Some(ObjectError {
+                    code: 404,
+                    message: "Object does not exist",
+                }),
+            )
+        };
+
+        objects.push(ObjectResponse {
+            oid,
+            size,
+            actions,
+            error,
+        });
+    }
+
+    let response = BatchResponse {
+        transfer: "basic",
+        objects,
+        hash_algo: "sha256",
+    };
+
+    json(StatusCode::OK, &response)
+}
+
+/// Handles `GET /{repo}/info/lfs/objects/{oid}`.
+pub async fn handle_object(
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
+    uri: Uri,
+) -> Response {
+    let oid = uri.path().rsplit('/').next().unwrap_or_default();
+
+    match serve_object(&repository_path, oid).await {
+        Some(response) => response,
+        None => error(StatusCode::NOT_FOUND, "Object does not exist"),
+    }
+}
+
+/// Serves the object a pointer refers to, or the pointer itself if it isn't one or the object
+/// hasn't been uploaded.
+pub async fn resolve_pointer(repository_path: &Path, content: Content) -> Response {
+    let data = match &content {
+        Content::Text(v) => v.as_bytes(),
+        Content::Binary(v) => v.as_slice(),
+    };
+
+    if let Some(oid) = pointer_oid(data) {
+        if let Some(response) = serve_object(repository_path, oid).await {
+            return response;
+        }
+    }
+
+    content.into_response()
+}
+
+/// Parses the oid out of an LFS pointer file.
+fn pointer_oid(data: &[u8]) -> Option<&str> {
+    if data.len() > MAX_POINTER_SIZE {
+        return None;
+    }
+
+    let mut lines = std::str::from_utf8(data).ok()?.lines();
+    if lines.next()? != POINTER_VERSION {
+        return None;
+    }
+
+    lines
+        .find_map(|v| v.strip_prefix("oid sha256:"))
+        .filter(|v| is_lfs_oid(v))
+}
+
+async fn serve_object(repository_path: &Path, oid: &str) -> Option<Response> {
+    if !is_lfs_oid(oid) {
+        return None;
+    }
+
+    let file = tokio::fs::File::open(lfs_object_path(repository_path, oid))
+        .await
+        .ok()?;
+    let len = file.metadata().await.ok()?.len();
+
+    let headers = [
+        (
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        ),
+        (header::CONTENT_LENGTH, HeaderValue::from(len)),
+    ];
+
+    Some((headers, Body::from_stream(ReaderStream::new(file))).into_response())
+}
+
+fn json<T: Serialize>(status: StatusCode, body: &T) -> Response {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+
+    (
+        status,
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(LFS_CONTENT_TYPE),
+        )],
+        body,
+    )
+        .into_response()
+}
+
+fn error(status: StatusCode, message: &'static str) -> Response {
+    #[derive(Serialize)]
+    struct ErrorResponse {
+        message: &'static str,
+    }
+
+    json(status, &ErrorResponse { message })
+}
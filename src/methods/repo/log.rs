@@ -2,7 +2,12 @@ use std::sync::Arc;
 
 use anyhow::Context;
 use askama::Template;
-use axum::{extract::Query, response::IntoResponse, Extension};
+use axum::{
+    extract::Query,
+    http::{HeaderMap, Uri},
+    response::IntoResponse,
+    Extension,
+};
 use serde::Deserialize;
 
 use crate::{
@@ -10,8 +15,12 @@ use crate::{
     into_response,
     methods::{
         filters,
-        repo::{Repository, Result, DEFAULT_BRANCHES},
+        repo::{
+            cache::{Freshness, Validator},
+            Repository, Result, DEFAULT_BRANCHES,
+        },
     },
+    ResponseEither,
 };
 
 #[derive(Deserialize)]
@@ -35,6 +44,8 @@ pub async fn handle(
     Extension(repo): Extension<Repository>,
     Extension(db): Extension<Arc<rocksdb::DB>>,
     Query(query): Query<UriQuery>,
+    uri: Uri,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse> {
     tokio::task::spawn_blocking(move || {
         let offset = query.offset.unwrap_or(0);
@@ -44,6 +55,17 @@ pub async fn handle(
         let mut commits =
             get_branch_commits(&repository, &db, query.branch.as_deref(), 101, offset)?;
 
+        // the extra commit decides whether we link to the next page, so it
+        // forms part of the validator too
+        let validator = Validator::new(
+            Freshness::Mutable,
+            &uri,
+            commits.iter().map(|commit| &**commit.backing_cart()),
+        );
+        if validator.matches(&headers) {
+            return Ok(ResponseEither::Left(validator.not_modified()));
+        }
+
         let next_offset = if commits.len() == 101 {
             commits.pop();
             Some(offset + 100)
@@ -51,12 +73,12 @@ pub async fn handle(
             None
         };
 
-        Ok(into_response(View {
+        Ok(ResponseEither::Right(validator.wrap(into_response(View {
             repo,
             commits,
             next_offset,
             branch: query.branch,
-        }))
+        }))))
     })
     .await
     .context("Failed to attach to tokio task")?
@@ -92,7 +114,7 @@ pub fn get_branch_commits(
         .default_branch
         .as_deref()
         .into_iter()
-        .chain(DEFAULT_BRANCHES.into_iter())
+        .chain(DEFAULT_BRANCHES)
     {
         let commit_tree = repository.get().commit_tree(database.clone(), branch);
         let commits = commit_tree.fetch_latest(amount, offset)?;
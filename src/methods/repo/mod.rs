@@ -1,6 +1,9 @@
 mod about;
+mod cache;
 mod commit;
+mod compare;
 mod diff;
+mod lfs;
 mod log;
 mod refs;
 mod smart_git;
@@ -8,9 +11,11 @@ mod snapshot;
 mod summary;
 mod tag;
 mod tree;
+mod upload_pack;
 
 use std::{
     collections::BTreeMap,
+    net::SocketAddr,
     ops::Deref,
     path::{Path, PathBuf},
     sync::Arc,
@@ -18,17 +23,21 @@ use std::{
 
 use axum::{
     body::Body,
+    extract::ConnectInfo,
     handler::HandlerWithoutStateExt,
     http::{Request, StatusCode},
     response::{IntoResponse, Response},
 };
+use gnit_ssh::{AccessLevel, AuditEntry, PushMirrorStatus, SshServer};
 use path_clean::PathClean;
 use tower::{util::BoxCloneService, Service};
 
 use self::{
     about::handle as handle_about,
     commit::handle as handle_commit,
+    compare::handle as handle_compare,
     diff::{handle as handle_diff, handle_plain as handle_patch},
+    lfs::{handle_batch as handle_lfs_batch, handle_object as handle_lfs_object},
     log::handle as handle_log,
     refs::handle as handle_refs,
     smart_git::handle as handle_smart_git,
@@ -39,14 +48,19 @@ use self::{
 };
 use crate::database::schema::tag::YokedString;
 use crate::{
+    auth::WebUser,
     database::schema::{commit::YokedCommit, tag::YokedTag},
     layers::UnwrapInfallible,
+    metrics::Route,
 };
 
 pub const DEFAULT_BRANCHES: [&str; 2] = ["refs/heads/master", "refs/heads/main"];
 
+const LFS_OBJECTS_PATH: [&str; 3] = ["info", "lfs", "objects"];
+
 // this is some wicked, wicked abuse of axum right here...
 #[allow(clippy::trait_duplication_in_bounds)] // clippy seems a bit.. lost
+#[allow(clippy::too_many_lines)]
 pub async fn service(mut request: Request<Body>) -> Response {
     let scan_path = request
         .extensions()
@@ -63,32 +77,48 @@ pub async fn service(mut request: Request<Body>) -> Response {
 
     let mut child_path = None;
 
+    // fetches and downloads go in the audit log
+    let mut audited = None;
+
     macro_rules! h {
         ($handler:ident) => {
             BoxCloneService::new($handler.into_service())
         };
     }
 
-    let mut service = match uri_parts.pop() {
-        Some("about") => h!(handle_about),
+    let (route, mut service) = match uri_parts.pop() {
+        Some("about") => ("about", h!(handle_about)),
         Some("refs") if uri_parts.last() == Some(&"info") => {
             uri_parts.pop();
-            h!(handle_smart_git)
+            ("smart_git", h!(handle_smart_git))
+        }
+        Some("git-upload-pack") => {
+            audited = Some("git-upload-pack");
+            ("smart_git", h!(handle_smart_git))
         }
-        Some("git-upload-pack") => h!(handle_smart_git),
-        Some("refs") => h!(handle_refs),
-        Some("log") => h!(handle_log),
-        Some("tree") => h!(handle_tree),
-        Some("commit") => h!(handle_commit),
-        Some("diff") => h!(handle_diff),
-        Some("patch") => h!(handle_patch),
-        Some("tag") => h!(handle_tag),
-        Some("snapshot") => h!(handle_snapshot),
+        Some("batch") if uri_parts.ends_with(&LFS_OBJECTS_PATH) => {
+            uri_parts.truncate(uri_parts.len() - LFS_OBJECTS_PATH.len());
+            ("lfs", h!(handle_lfs_batch))
+        }
+        Some(v) if v.len() == 64 && uri_parts.ends_with(&LFS_OBJECTS_PATH) => {
+            uri_parts.truncate(uri_parts.len() - LFS_OBJECTS_PATH.len());
+            audited = Some("lfs-download");
+            ("lfs", h!(handle_lfs_object))
+        }
+        Some("refs") => ("refs", h!(handle_refs)),
+        Some("log") => ("log", h!(handle_log)),
+        Some("tree") => ("tree", h!(handle_tree)),
+        Some("commit") => ("commit", h!(handle_commit)),
+        Some("compare") => ("compare", h!(handle_compare)),
+        Some("diff") => ("diff", h!(handle_diff)),
+        Some("patch") => ("patch", h!(handle_patch)),
+        Some("tag") => ("tag", h!(handle_tag)),
+        Some("snapshot") => ("snapshot", h!(handle_snapshot)),
         Some(v) => {
             uri_parts.push(v);
 
             // match tree children
-            if uri_parts.iter().any(|v| *v == "tree") {
+            if uri_parts.contains(&"tree") {
                 // TODO: this needs fixing up so it doesn't accidentally match repos that have
                 //  `tree` in their path
                 let mut reconstructed_path = Vec::new();
@@ -104,9 +134,9 @@ pub async fn service(mut request: Request<Body>) -> Response {
 
                 child_path = Some(reconstructed_path.into_iter().collect::<PathBuf>().clean());
 
-                h!(handle_tree)
+                ("tree", h!(handle_tree))
             } else {
-                h!(handle_summary)
+                ("summary", h!(handle_summary))
             }
         }
         None => panic!("not found"),
@@ -119,21 +149,93 @@ pub async fn service(mut request: Request<Body>) -> Response {
         .extensions()
         .get::<Arc<rocksdb::DB>>()
         .expect("db extension missing");
+    let access = request
+        .extensions()
+        .get::<AccessControl>()
+        .expect("access control extension missing");
+    let user = request.extensions().get::<WebUser>();
     if path.as_os_str().is_empty()
         || !crate::database::schema::repository::Repository::exists(db, &uri).unwrap_or_default()
+        || !access.can_read(&uri, user).await
     {
-        return RepositoryNotFound.into_response();
+        let mut response = RepositoryNotFound.into_response();
+        response.extensions_mut().insert(Route(route));
+        return response;
     }
+    let public = user.is_none() || access.can_read(&uri, None).await;
+
+    let audit = audited.map(|command| {
+        let address = request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|v| v.0);
+        (AuditEntry::http(address, command, &uri), scan_path.clone())
+    });
 
     request.extensions_mut().insert(ChildPath(child_path));
     request.extensions_mut().insert(Repository(uri));
     request.extensions_mut().insert(RepositoryPath(path));
 
-    service
+    let mut response = service
         .call(request)
         .await
         .unwrap_infallible()
-        .into_response()
+        .into_response();
+    response.extensions_mut().insert(Route(route));
+    if !public {
+        cache::make_private(&mut response);
+    }
+
+    if let Some((mut entry, scan_path)) = audit {
+        entry.status = Some(u32::from(response.status().as_u16()));
+        entry.record_in(&scan_path).await;
+    }
+
+    response
+}
+
+/// Decides which repositories can be seen over the web. Only set when running
+/// alongside the SSH server, otherwise everything under the scan path is public.
+#[derive(Clone, Default)]
+pub struct AccessControl(pub Option<SshServer>);
+
+impl AccessControl {
+    /// Whether `user`, or an anonymous user if they haven't logged in, can
+    /// read the repository at `repository` relative to the scan path.
+    pub async fn can_read(&self, repository: &Path, user: Option<&WebUser>) -> bool {
+        match &self.0 {
+            Some(server) => {
+                let username = user.map(|v| v.name.as_str());
+                server.access_level(repository, username).await >= AccessLevel::Read
+            }
+            None => true,
+        }
+    }
+
+    /// The most space the repository's objects may take up, if the SSH
+    /// server gives it a quota.
+    pub async fn size_limit(&self, repository: &Path) -> Option<u64> {
+        self.0.as_ref()?.repo_size_limit(repository).await
+    }
+
+    /// How pushing the repository at `path` to its mirrors went, only shown to
+    /// those who can push to it themselves.
+    pub async fn push_mirrors(
+        &self,
+        repository: &Path,
+        path: &Path,
+        user: Option<&WebUser>,
+    ) -> Vec<PushMirrorStatus> {
+        let Some(server) = &self.0 else {
+            return Vec::new();
+        };
+
+        let username = user.map(|v| v.name.as_str());
+        if server.access_level(repository, username).await < AccessLevel::Write {
+            return Vec::new();
+        }
+        gnit_ssh::push_mirror_status(path)
+    }
 }
 
 #[derive(Clone)]
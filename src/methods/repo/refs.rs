@@ -4,12 +4,20 @@ use crate::{
     into_response,
     methods::{
         filters,
-        repo::{Refs, Repository, Result},
+        repo::{
+            cache::{Freshness, Validator},
+            Refs, Repository, Result,
+        },
     },
+    ResponseEither,
 };
 use anyhow::Context;
 use askama::Template;
-use axum::{response::IntoResponse, Extension};
+use axum::{
+    http::{HeaderMap, Uri},
+    response::IntoResponse,
+    Extension,
+};
 use rkyv::string::ArchivedString;
 use yoke::Yoke;
 
@@ -24,6 +32,8 @@ pub struct View {
 pub async fn handle(
     Extension(repo): Extension<Repository>,
     Extension(db): Extension<Arc<rocksdb::DB>>,
+    uri: Uri,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse> {
     tokio::task::spawn_blocking(move || {
         let repository = crate::database::schema::repository::Repository::open(&db, &*repo)?
@@ -52,11 +62,25 @@ pub async fn handle(
 
         let tags = repository.tag_tree(db).fetch_all()?;
 
-        Ok(into_response(View {
+        let validator = Validator::new(
+            Freshness::Mutable,
+            &uri,
+            heads
+                .iter()
+                .flat_map(|(name, commit)| [name.as_bytes(), &**commit.backing_cart()])
+                .chain(tags.iter().flat_map(|(name, tag)| {
+                    [&**name.backing_cart(), &**tag.backing_cart()]
+                })),
+        );
+        if validator.matches(&headers) {
+            return Ok(ResponseEither::Left(validator.not_modified()));
+        }
+
+        Ok(ResponseEither::Right(validator.wrap(into_response(View {
             repo,
             refs: Refs { heads, tags },
             branch: None,
-        }))
+        }))))
     })
     .await
     .context("Failed to attach to tokio task")?
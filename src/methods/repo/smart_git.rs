@@ -1,4 +1,4 @@
-use std::{io, io::ErrorKind, path::Path, process::Stdio, str::FromStr};
+use std::{io, path::Path, process::Stdio, str::FromStr, sync::Arc, time::Instant};
 
 use anyhow::{anyhow, Context};
 use axum::{
@@ -22,21 +22,44 @@ use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::io::StreamReader;
 use tracing::{debug, error, info_span, warn, Instrument};
 
+use super::upload_pack;
 use crate::{
+    git::Git,
     methods::repo::{Repository, RepositoryPath, Result},
+    metrics::METRICS,
     StatusCode,
 };
 
-#[allow(clippy::unused_async)]
 pub async fn handle(
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
     Extension(Repository(repository)): Extension<Repository>,
+    Extension(git): Extension<Arc<Git>>,
     method: Method,
     uri: Uri,
     headers: HeaderMap,
     body: Body,
-) -> Result<impl IntoResponse> {
+) -> Result<Response> {
     let path = extract_path(&uri, &repository)?;
+    let service = service_name(path);
+
+    // protocol v2 fetches are served in-process, the CGI is left to handle
+    // older clients and pushes
+    if upload_pack::is_v2(&headers) {
+        let upload_pack_query = uri
+            .query()
+            .is_some_and(|v| v.split('&').any(|v| v == "service=git-upload-pack"));
+
+        match (service, &method) {
+            ("info-refs", &Method::GET) if upload_pack_query => {
+                return Ok(upload_pack::advertise());
+            }
+            ("git-upload-pack", &Method::POST) => {
+                let repo = git.repo(repository_path, None).await?;
+                return upload_pack::handle(repo, &headers, body).await;
+            }
+            _ => {}
+        }
+    }
 
     let mut command = Command::new("git");
 
@@ -61,16 +84,15 @@ pub async fn handle(
         .kill_on_drop(true)
         .spawn()
         .context("Failed to spawn git http-backend")?;
+    let started = Instant::now();
+    METRICS.git_backend_spawned(service);
 
     let mut stdout = child.stdout.take().context("Stdout already taken")?;
     let mut stderr = child.stderr.take().context("Stderr already taken")?;
     let mut stdin = child.stdin.take().context("Stdin already taken")?;
 
     // read request body and forward to stdin
-    let mut body = StreamReader::new(
-        body.into_data_stream()
-            .map_err(|e| std::io::Error::new(ErrorKind::Other, e)),
-    );
+    let mut body = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
     tokio::io::copy_buf(&mut body, &mut stdin)
         .await
         .context("Failed to copy bytes from request to command stdin")?;
@@ -96,18 +118,18 @@ pub async fn handle(
     // headers so there's no reason for us to continue. there may be something in stderr for us
     // though.
     let Some(headers) = headers else {
-        print_status(&mut child, &mut stderr).await;
+        print_status(&mut child, &mut stderr, service, started).await;
         return Err(anyhow!("Received incomplete response from git http-backend").into());
     };
 
     // stream the response back to the client
     let (body_send, body_recv) = mpsc::channel(8);
     tokio::spawn(
-        forward_response_to_client(out_buf, body_send, stdout, stderr, child)
+        forward_response_to_client(out_buf, body_send, stdout, stderr, child, service, started)
             .instrument(info_span!("git http-backend reader")),
     );
 
-    Ok((headers, Body::from_stream(ReceiverStream::new(body_recv))))
+    Ok((headers, Body::from_stream(ReceiverStream::new(body_recv))).into_response())
 }
 
 /// Forwards the entirety of `stdout` to `body_send`, printing subprocess stderr and status on
@@ -118,6 +140,8 @@ async fn forward_response_to_client(
     mut stdout: ChildStdout,
     mut stderr: ChildStderr,
     mut child: Child,
+    service: &'static str,
+    started: Instant,
 ) {
     loop {
         let (out, mut end) = match stdout.read_buf(&mut out_buf).await {
@@ -136,17 +160,45 @@ async fn forward_response_to_client(
         }
     }
 
-    print_status(&mut child, &mut stderr).await;
+    print_status(&mut child, &mut stderr, service, started).await;
 }
 
-/// Prints the exit status of the `git` subprocess.
-async fn print_status(child: &mut Child, stderr: &mut ChildStderr) {
-    match tokio::try_join!(child.wait(), read_stderr(stderr)) {
+/// Prints the exit status of the `git` subprocess and records its lifetime.
+async fn print_status(
+    child: &mut Child,
+    stderr: &mut ChildStderr,
+    service: &'static str,
+    started: Instant,
+) {
+    let success = match tokio::try_join!(child.wait(), read_stderr(stderr)) {
         Ok((status, stderr)) if status.success() => {
             debug!(stderr, "git http-backend successfully shutdown");
+            true
+        }
+        Ok((status, stderr)) => {
+            error!(stderr, "git http-backend exited with status code {status}");
+            false
         }
-        Ok((status, stderr)) => error!(stderr, "git http-backend exited with status code {status}"),
-        Err(e) => error!("Failed to wait on git http-backend shutdown: {e}"),
+        Err(e) => {
+            error!("Failed to wait on git http-backend shutdown: {e}");
+            false
+        }
+    };
+
+    METRICS.git_backend_finished(service, success, started.elapsed());
+}
+
+/// Maps the `PATH_INFO` given to `git http-backend` to a low-cardinality
+/// service name for metrics.
+fn service_name(path: &str) -> &'static str {
+    if path.ends_with("/info/refs") {
+        "info-refs"
+    } else if path.ends_with("/git-upload-pack") {
+        "git-upload-pack"
+    } else if path.ends_with("/git-receive-pack") {
+        "git-receive-pack"
+    } else {
+        "other"
     }
 }
 
@@ -1,13 +1,19 @@
 use std::sync::Arc;
 
 use anyhow::{anyhow, Context};
-use axum::{body::Body, extract::Query, http::Response, Extension};
+use axum::{
+    body::Body,
+    extract::Query,
+    http::{HeaderMap, Response, Uri},
+    response::IntoResponse,
+    Extension,
+};
 use serde::Deserialize;
 use tokio_stream::wrappers::ReceiverStream;
 use tracing::{error, info_span, Instrument};
 
-use super::{RepositoryPath, Result};
-use crate::git::Git;
+use super::{commit::validator_for, RepositoryPath, Result};
+use crate::{git::Git, ResponseEither};
 
 #[derive(Deserialize)]
 pub struct UriQuery {
@@ -20,9 +26,16 @@ pub async fn handle(
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
     Extension(git): Extension<Arc<Git>>,
     Query(query): Query<UriQuery>,
-) -> Result<Response<Body>> {
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse> {
     let open_repo = git.repo(repository_path, query.branch.clone()).await?;
 
+    let validator = validator_for(&uri, query.id.as_deref(), open_repo.clone()).await?;
+    if validator.matches(&headers) {
+        return Ok(ResponseEither::Left(validator.not_modified()));
+    }
+
     // byte stream back to the client
     let (send, recv) = tokio::sync::mpsc::channel(1);
 
@@ -70,12 +83,14 @@ pub async fn handle(
         .or(query.branch.as_deref())
         .unwrap_or("main");
 
-    Ok(Response::builder()
-        .header("Content-Type", "application/gzip")
-        .header(
-            "Content-Disposition",
-            format!("attachment; filename=\"{file_name}.tar.gz\""),
-        )
-        .body(Body::from_stream(ReceiverStream::new(recv)))
-        .context("failed to build response")?)
+    Ok(ResponseEither::Right(validator.wrap(
+        Response::builder()
+            .header("Content-Type", "application/gzip")
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{file_name}.tar.gz\""),
+            )
+            .body(Body::from_stream(ReceiverStream::new(recv)))
+            .context("failed to build response")?,
+    )))
 }
@@ -2,16 +2,26 @@ use std::{collections::BTreeMap, sync::Arc};
 
 use anyhow::Context;
 use askama::Template;
-use axum::{response::IntoResponse, Extension};
+use axum::{
+    http::{HeaderMap, Uri},
+    response::IntoResponse,
+    Extension,
+};
+use gnit_ssh::PushMirrorStatus;
 use rkyv::string::ArchivedString;
 
 use crate::{
+    auth::WebUser,
     database::schema::{commit::YokedCommit, repository::YokedRepository},
     into_response,
     methods::{
         filters,
-        repo::{Refs, Repository, Result, DEFAULT_BRANCHES},
+        repo::{
+            cache::{Freshness, Validator},
+            AccessControl, Refs, Repository, RepositoryPath, Result, DEFAULT_BRANCHES,
+        },
     },
+    ResponseEither,
 };
 
 #[derive(Template)]
@@ -21,12 +31,26 @@ pub struct View {
     refs: Refs,
     commit_list: Vec<YokedCommit>,
     branch: Option<Arc<str>>,
+    mirror_of: Option<String>,
+    size: u64,
+    size_limit: Option<u64>,
+    push_mirrors: Vec<PushMirrorStatus>,
 }
 
 pub async fn handle(
     Extension(repo): Extension<Repository>,
+    Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
     Extension(db): Extension<Arc<rocksdb::DB>>,
+    Extension(access): Extension<AccessControl>,
+    user: Option<Extension<WebUser>>,
+    uri: Uri,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse> {
+    let size_limit = access.size_limit(&repo).await;
+    let push_mirrors = access
+        .push_mirrors(&repo, &repository_path, user.as_ref().map(|v| &v.0))
+        .await;
+
     tokio::task::spawn_blocking(move || {
         let repository = crate::database::schema::repository::Repository::open(&db, &*repo)?
             .context("Repository does not exist")?;
@@ -51,13 +75,44 @@ pub async fn handle(
         }
 
         let tags = repository.get().tag_tree(db).fetch_all()?;
+        let mirror_of = repository.get().mirror_of.as_deref().map(str::to_string);
+        let size = gnit_ssh::repo_size(&repository_path);
+        let usage = [
+            size.to_le_bytes(),
+            size_limit.unwrap_or_default().to_le_bytes(),
+        ];
+        let push_mirrors_state =
+            serde_json::to_vec(&push_mirrors).context("Failed to serialise push mirrors")?;
+
+        let validator = Validator::new(
+            Freshness::Mutable,
+            &uri,
+            commits
+                .iter()
+                .map(|commit| &**commit.backing_cart())
+                .chain(heads.values().map(|commit| &**commit.backing_cart()))
+                .chain(
+                    tags.iter()
+                        .flat_map(|(name, tag)| [&**name.backing_cart(), &**tag.backing_cart()]),
+                )
+                .chain(mirror_of.as_deref().map(str::as_bytes))
+                .chain(usage.iter().map(<[u8; 8]>::as_slice))
+                .chain([push_mirrors_state.as_slice()]),
+        );
+        if validator.matches(&headers) {
+            return Ok(ResponseEither::Left(validator.not_modified()));
+        }
 
-        Ok(into_response(View {
+        Ok(ResponseEither::Right(validator.wrap(into_response(View {
             repo,
             refs: Refs { heads, tags },
             commit_list: commits,
             branch: None,
-        }))
+            mirror_of,
+            size,
+            size_limit,
+            push_mirrors,
+        }))))
     })
     .await
     .context("Failed to attach to tokio task")?
@@ -72,7 +127,7 @@ pub fn get_default_branch_commits(
         .default_branch
         .as_deref()
         .into_iter()
-        .chain(DEFAULT_BRANCHES.into_iter())
+        .chain(DEFAULT_BRANCHES)
     {
         let commit_tree = repository.get().commit_tree(database.clone(), branch);
         let commits = commit_tree.fetch_latest(11, 0)?;
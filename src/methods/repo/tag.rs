@@ -1,7 +1,12 @@
 use std::sync::Arc;
 
 use askama::Template;
-use axum::{extract::Query, response::IntoResponse, Extension};
+use axum::{
+    extract::Query,
+    http::{HeaderMap, Uri},
+    response::IntoResponse,
+    Extension,
+};
 use serde::Deserialize;
 
 use crate::{
@@ -9,9 +14,9 @@ use crate::{
     into_response,
     methods::{
         filters,
-        repo::{Repository, RepositoryPath, Result},
+        repo::{commit::validator_for, Repository, RepositoryPath, Result},
     },
-    Git,
+    Git, ResponseEither,
 };
 
 #[derive(Deserialize)]
@@ -33,13 +38,21 @@ pub async fn handle(
     Extension(RepositoryPath(repository_path)): Extension<RepositoryPath>,
     Extension(git): Extension<Arc<Git>>,
     Query(query): Query<UriQuery>,
+    uri: Uri,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse> {
     let open_repo = git.repo(repository_path, Some(query.name.clone())).await?;
+
+    let validator = validator_for(&uri, None, open_repo.clone()).await?;
+    if validator.matches(&headers) {
+        return Ok(ResponseEither::Left(validator.not_modified()));
+    }
+
     let tag = open_repo.tag_info().await?;
 
-    Ok(into_response(View {
+    Ok(ResponseEither::Right(validator.wrap(into_response(View {
         repo,
         tag,
         branch: Some(query.name),
-    }))
+    }))))
 }
@@ -1,5 +1,10 @@
 use askama::Template;
-use axum::{extract::Query, response::IntoResponse, Extension};
+use axum::{
+    extract::Query,
+    http::{HeaderMap, Uri},
+    response::IntoResponse,
+    Extension,
+};
 use itertools::Itertools;
 use serde::Deserialize;
 use std::path::PathBuf;
@@ -13,7 +18,10 @@ use crate::{
     into_response,
     methods::{
         filters,
-        repo::{ChildPath, Repository, RepositoryPath, Result},
+        repo::{
+            commit::validator_for, lfs::resolve_pointer, ChildPath, Repository, RepositoryPath,
+            Result,
+        },
     },
     Git, ResponseEither,
 };
@@ -70,32 +78,45 @@ pub async fn handle(
     Extension(ChildPath(child_path)): Extension<ChildPath>,
     Extension(git): Extension<Arc<Git>>,
     Query(query): Query<UriQuery>,
+    uri: Uri,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse> {
-    let open_repo = git.repo(repository_path, query.branch.clone()).await?;
+    let open_repo = git
+        .repo(repository_path.clone(), query.branch.clone())
+        .await?;
 
-    Ok(
-        match open_repo
-            .path(child_path.clone(), query.id.as_deref(), !query.raw)
-            .await?
-        {
-            PathDestination::Tree(items) => {
-                ResponseEither::Left(ResponseEither::Left(into_response(TreeView {
-                    repo,
-                    items,
-                    branch: query.branch.clone(),
-                    query,
-                    repo_path: child_path.unwrap_or_default(),
-                })))
-            }
-            PathDestination::File(file) if query.raw => ResponseEither::Right(file.content),
-            PathDestination::File(file) => {
-                ResponseEither::Left(ResponseEither::Right(into_response(FileView {
-                    repo,
-                    file,
-                    branch: query.branch,
-                    repo_path: child_path.unwrap_or_default(),
-                })))
-            }
-        },
-    )
+    let validator = validator_for(&uri, query.id.as_deref(), open_repo.clone()).await?;
+    if validator.matches(&headers) {
+        return Ok(ResponseEither::Left(validator.not_modified()));
+    }
+
+    Ok(ResponseEither::Right(
+        validator.wrap(
+            match open_repo
+                .path(child_path.clone(), query.id.as_deref(), !query.raw)
+                .await?
+            {
+                PathDestination::Tree(items) => {
+                    ResponseEither::Left(ResponseEither::Left(into_response(TreeView {
+                        repo,
+                        items,
+                        branch: query.branch.clone(),
+                        query,
+                        repo_path: child_path.unwrap_or_default(),
+                    })))
+                }
+                PathDestination::File(file) if query.raw => {
+                    ResponseEither::Right(resolve_pointer(&repository_path, file.content).await)
+                }
+                PathDestination::File(file) => {
+                    ResponseEither::Left(ResponseEither::Right(into_response(FileView {
+                        repo,
+                        file,
+                        branch: query.branch,
+                        repo_path: child_path.unwrap_or_default(),
+                    })))
+                }
+            },
+        ),
+    ))
 }
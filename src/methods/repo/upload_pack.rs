@@ -0,0 +1,1162 @@
+//! The server side of git's wire protocol v2 (`ls-refs` and `fetch`), served
+//! in-process from the repository rather than through `git http-backend`.
+//!
+//! Only clients that ask for protocol v2 via the `Git-Protocol` header end up
+//! here, everything else still falls back to the CGI.
+
+use std::{
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    io::{self, Read, Write},
+    sync::{atomic::AtomicBool, Arc},
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Context};
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use const_format::formatcp;
+use flate2::read::GzDecoder;
+use gix::{
+    bstr::{BStr, BString, ByteSlice},
+    hash::ObjectId,
+    head::Kind as HeadKind,
+    objs::Kind,
+    parallel::InOrderIter,
+    progress::Discard,
+};
+use gix_pack::data::{
+    output::{self, count::objects::ObjectExpansion},
+    Version,
+};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, error, info_span};
+
+use crate::{git::OpenRepository, methods::repo::Result, metrics::METRICS, CRATE_VERSION};
+
+const AGENT: &str = formatcp!("agent=gnostr-gnit/{CRATE_VERSION}\n");
+
+/// The capabilities we advertise, in the order git itself sends them.
+const CAPABILITIES: [&str; 6] = [
+    "version 2\n",
+    AGENT,
+    "ls-refs=unborn\n",
+    "fetch=shallow filter\n",
+    "server-option\n",
+    "object-format=sha1\n",
+];
+
+/// Upper bound on the (decompressed) request body, which only ever holds
+/// `want`/`have` lines.
+const MAX_REQUEST_SIZE: usize = 32 * 1024 * 1024;
+
+/// Largest payload that fits into a single pkt-line next to its sideband byte.
+const MAX_SIDEBAND_DATA: usize = 65515;
+
+/// Checks whether the client asked to speak protocol v2.
+pub fn is_v2(headers: &HeaderMap) -> bool {
+    headers
+        .get_all("Git-Protocol")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(':'))
+        .any(|v| v.trim() == "version=2")
+}
+
+/// Responds to `GET info/refs?service=git-upload-pack` with our capabilities,
+/// protocol v2 leaves the ref advertisement to a later `ls-refs` command.
+pub fn advertise() -> Response {
+    let mut out = BytesMut::new();
+
+    for capability in CAPABILITIES {
+        pkt_line(&mut out, capability);
+    }
+    out.put_slice(b"0000");
+
+    METRICS.upload_pack_started("capabilities");
+    response("application/x-git-upload-pack-advertisement", out.freeze())
+}
+
+/// Handles a `POST git-upload-pack` carrying a single protocol v2 command.
+pub async fn handle(
+    repo: Arc<OpenRepository>,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response> {
+    let body = axum::body::to_bytes(body, MAX_REQUEST_SIZE)
+        .await
+        .context("Failed to read request body")?;
+    let body = decode_body(headers, body)?;
+    let request = Request::parse(&body)?;
+
+    // the command is up to the client, so keep unknown ones from adding labels
+    let command: &'static str = match request.command.as_str() {
+        "ls-refs" => "ls-refs",
+        "fetch" => "fetch",
+        _ => "unknown",
+    };
+    METRICS.upload_pack_started(command);
+    let started = Instant::now();
+
+    let (send, recv) = mpsc::channel(8);
+    let span = info_span!("upload-pack", command = %request.command);
+
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        let repo = repo.to_thread_local();
+        let mut out = Output::new(send);
+
+        let result = match request.command.as_str() {
+            "ls-refs" => ls_refs(&repo, &request.args, &mut out),
+            "fetch" => {
+                FetchArgs::parse(&request.args).and_then(|args| fetch(&repo, &args, &mut out))
+            }
+            other => Err(anyhow!("unknown command '{other}'")),
+        };
+
+        let success = match result.and_then(|()| Ok(out.send()?)) {
+            Ok(()) => {
+                debug!("upload-pack successfully finished");
+                true
+            }
+            Err(e) if e.downcast_ref::<io::Error>().is_some() => {
+                debug!("Client went away during upload-pack: {e:#}");
+                false
+            }
+            Err(e) => {
+                error!("upload-pack failed: {e:#}");
+                out.error(&e);
+                false
+            }
+        };
+
+        METRICS.upload_pack_finished(command, success, started.elapsed());
+    });
+
+    Ok(response(
+        "application/x-git-upload-pack-result",
+        Body::from_stream(ReceiverStream::new(recv)),
+    ))
+}
+
+fn response(content_type: &'static str, body: impl Into<Body>) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static(content_type)),
+            (
+                header::CACHE_CONTROL,
+                HeaderValue::from_static("no-cache, max-age=0, must-revalidate"),
+            ),
+        ],
+        body.into(),
+    )
+        .into_response()
+}
+
+/// git compresses request bodies over a kilobyte, undo that before parsing.
+fn decode_body(headers: &HeaderMap, body: Bytes) -> Result<Bytes> {
+    match headers
+        .get(header::CONTENT_ENCODING)
+        .map(HeaderValue::as_bytes)
+    {
+        None | Some(b"identity") => Ok(body),
+        Some(b"gzip" | b"x-gzip") => {
+            let mut out = Vec::new();
+            GzDecoder::new(&body[..])
+                .take(MAX_REQUEST_SIZE as u64)
+                .read_to_end(&mut out)
+                .context("Failed to decompress request body")?;
+            Ok(out.into())
+        }
+        Some(_) => Err(anyhow!("Unsupported Content-Encoding").into()),
+    }
+}
+
+fn pkt_line(out: &mut BytesMut, data: impl AsRef<[u8]>) {
+    let data = data.as_ref();
+    out.put_slice(format!("{:04x}", data.len() + 4).as_bytes());
+    out.put_slice(data);
+}
+
+enum Packet<'a> {
+    Flush,
+    Delimiter,
+    Data(&'a [u8]),
+}
+
+/// Splits a request body into its pkt-lines, stripping trailing newlines.
+fn packets(mut input: &[u8]) -> anyhow::Result<Vec<Packet<'_>>> {
+    let mut packets = Vec::new();
+
+    while !input.is_empty() {
+        let len = input
+            .get(..4)
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| usize::from_str_radix(v, 16).ok())
+            .context("Invalid pkt-line length")?;
+
+        match len {
+            0 => packets.push(Packet::Flush),
+            1 => packets.push(Packet::Delimiter),
+            2 => {}
+            3 => bail!("Invalid pkt-line length"),
+            _ => {
+                let data = input.get(4..len).context("Truncated pkt-line")?;
+                packets.push(Packet::Data(data.strip_suffix(b"\n").unwrap_or(data)));
+            }
+        }
+
+        input = &input[len.max(4)..];
+    }
+
+    Ok(packets)
+}
+
+/// A single protocol v2 command, along with its arguments.
+struct Request {
+    command: String,
+    args: Vec<BString>,
+}
+
+impl Request {
+    fn parse(body: &[u8]) -> anyhow::Result<Self> {
+        let mut packets = packets(body)?.into_iter();
+
+        let Some(Packet::Data(command)) = packets.next() else {
+            bail!("Expected a command");
+        };
+        let command = command
+            .strip_prefix(b"command=")
+            .and_then(|v| v.to_str().ok())
+            .context("Expected a command")?
+            .to_string();
+
+        // capabilities, up until the delimiter before the arguments
+        for packet in packets.by_ref() {
+            match packet {
+                Packet::Data(capability) => {
+                    if let Some(format) = capability.strip_prefix(b"object-format=") {
+                        if format != b"sha1" {
+                            bail!("unsupported object format '{}'", format.as_bstr());
+                        }
+                    }
+                }
+                Packet::Delimiter => break,
+                Packet::Flush => {
+                    return Ok(Self {
+                        command,
+                        args: Vec::new(),
+                    })
+                }
+            }
+        }
+
+        let args = packets
+            .map_while(|packet| match packet {
+                Packet::Data(arg) => Some(arg.into()),
+                Packet::Delimiter | Packet::Flush => None,
+            })
+            .collect();
+
+        Ok(Self { command, args })
+    }
+}
+
+/// Buffers the response, forwarding it to the client in pkt-line framing.
+struct Output {
+    send: mpsc::Sender<Result<Bytes, io::Error>>,
+    buf: BytesMut,
+    in_packfile: bool,
+}
+
+impl Output {
+    fn new(send: mpsc::Sender<Result<Bytes, io::Error>>) -> Self {
+        Self {
+            send,
+            buf: BytesMut::new(),
+            in_packfile: false,
+        }
+    }
+
+    fn line(&mut self, data: impl AsRef<[u8]>) {
+        pkt_line(&mut self.buf, data);
+    }
+
+    fn delimiter(&mut self) {
+        self.buf.put_slice(b"0001");
+    }
+
+    fn flush(&mut self) {
+        self.buf.put_slice(b"0000");
+    }
+
+    /// Writes `data` to the given sideband, chunked to fit pkt-lines.
+    fn sideband(&mut self, band: u8, data: &[u8]) -> io::Result<()> {
+        for chunk in data.chunks(MAX_SIDEBAND_DATA) {
+            self.buf
+                .put_slice(format!("{:04x}", chunk.len() + 5).as_bytes());
+            self.buf.put_u8(band);
+            self.buf.put_slice(chunk);
+        }
+
+        if self.buf.len() >= MAX_SIDEBAND_DATA {
+            self.send()?;
+        }
+
+        Ok(())
+    }
+
+    fn progress(&mut self, message: &str) -> io::Result<()> {
+        self.sideband(2, message.as_bytes())
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        self.send
+            .blocking_send(Ok(self.buf.split().freeze()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))
+    }
+
+    /// Reports an error to the client, either as an `ERR` packet or on the
+    /// error sideband if we're already mid-way through sending the pack.
+    fn error(&mut self, error: &anyhow::Error) {
+        let message = format!("{error:#}");
+
+        if self.in_packfile {
+            let _res = self.sideband(3, format!("error: {message}\n").as_bytes());
+        } else {
+            self.line(format!("ERR {message}\n"));
+        }
+
+        let _res = self.send();
+    }
+}
+
+/// Sends the pack data on sideband 1 in as large chunks as possible.
+struct PackWriter<'a> {
+    out: &'a mut Output,
+    pending: Vec<u8>,
+}
+
+impl Write for PackWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+
+        if self.pending.len() >= MAX_SIDEBAND_DATA {
+            let end = self.pending.len() - self.pending.len() % MAX_SIDEBAND_DATA;
+            self.out.sideband(1, &self.pending[..end])?;
+            self.pending.drain(..end);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.sideband(1, &self.pending)?;
+        self.pending.clear();
+        self.out.send()
+    }
+}
+
+fn ls_refs(repo: &gix::Repository, args: &[BString], out: &mut Output) -> anyhow::Result<()> {
+    let mut symrefs = false;
+    let mut peel = false;
+    let mut unborn = false;
+    let mut prefixes = Vec::new();
+
+    for arg in args {
+        match arg.as_slice() {
+            b"symrefs" => symrefs = true,
+            b"peel" => peel = true,
+            b"unborn" => unborn = true,
+            arg => {
+                if let Some(prefix) = arg.strip_prefix(b"ref-prefix ") {
+                    prefixes.push(prefix);
+                }
+            }
+        }
+    }
+
+    let wanted = |name: &BStr| prefixes.is_empty() || prefixes.iter().any(|p| name.starts_with(p));
+
+    let write_ref = |out: &mut Output,
+                     name: &BStr,
+                     id: ObjectId,
+                     target: Option<&BStr>|
+     -> anyhow::Result<()> {
+        let mut line = BString::from(format!("{id} "));
+        line.extend_from_slice(name);
+
+        if let Some(target) = target.filter(|_| symrefs) {
+            line.extend_from_slice(b" symref-target:");
+            line.extend_from_slice(target);
+        }
+
+        if peel {
+            let peeled = peel_to_end(repo, id)?;
+            if peeled != id {
+                line.extend_from_slice(format!(" peeled:{peeled}").as_bytes());
+            }
+        }
+
+        line.push(b'\n');
+        out.line(line);
+        Ok(())
+    };
+
+    if wanted("HEAD".into()) {
+        let head = repo.head().context("Couldn't read HEAD")?;
+        let target = head.referent_name().map(|v| v.as_bstr().to_owned());
+
+        match head.kind {
+            HeadKind::Unborn(name) if unborn => {
+                let mut line = BString::from("unborn HEAD");
+                if symrefs {
+                    line.extend_from_slice(b" symref-target:");
+                    line.extend_from_slice(name.as_bstr());
+                }
+                line.push(b'\n');
+                out.line(line);
+            }
+            HeadKind::Unborn(_) => {}
+            HeadKind::Symbolic(reference) => {
+                if let Some(id) = reference.target.try_id() {
+                    write_ref(
+                        out,
+                        "HEAD".into(),
+                        id.to_owned(),
+                        target.as_ref().map(AsRef::as_ref),
+                    )?;
+                }
+            }
+            HeadKind::Detached { target, .. } => write_ref(out, "HEAD".into(), target, None)?,
+        }
+    }
+
+    for reference in repo.references()?.all()? {
+        let mut reference = reference.map_err(|e| anyhow!(e))?;
+        let name = reference.name().as_bstr().to_owned();
+
+        if !name.starts_with(b"refs/") || !wanted(name.as_ref()) {
+            continue;
+        }
+
+        let target = match reference.target() {
+            gix::refs::TargetRef::Symbolic(target) => Some(target.as_bstr().to_owned()),
+            gix::refs::TargetRef::Object(_) => None,
+        };
+
+        // dangling symbolic refs aren't advertised, same as git
+        let Ok(id) = reference.follow_to_object() else {
+            continue;
+        };
+
+        write_ref(
+            out,
+            name.as_ref(),
+            id.detach(),
+            target.as_ref().map(AsRef::as_ref),
+        )?;
+    }
+
+    out.flush();
+    Ok(())
+}
+
+/// Resolves `id` through any number of annotated tags.
+fn peel_to_end(repo: &gix::Repository, id: ObjectId) -> anyhow::Result<ObjectId> {
+    if repo.find_header(id)?.kind() != Kind::Tag {
+        return Ok(id);
+    }
+
+    Ok(repo.find_object(id)?.peel_tags_to_end()?.id)
+}
+
+/// Checks the client only wants what we advertised, or objects reachable from
+/// it as git does with `uploadpack.allowReachableSHA1InWant`, so nothing else
+/// in the object database can be fetched just by knowing its id.
+fn check_wants(repo: &gix::Repository, wants: &[ObjectId]) -> anyhow::Result<()> {
+    let mut remaining: HashSet<_> = wants.iter().copied().collect();
+
+    let mut advertised: Vec<_> = repo
+        .head_id()
+        .ok()
+        .map(gix::Id::detach)
+        .into_iter()
+        .collect();
+    for reference in repo.references()?.all()? {
+        let mut reference = reference.map_err(|e| anyhow!(e))?;
+        if !reference.name().as_bstr().starts_with(b"refs/") {
+            continue;
+        }
+        if let Ok(id) = reference.follow_to_object() {
+            advertised.push(id.detach());
+        }
+    }
+
+    // tags are advertised peeled, so their targets are fair game too
+    let mut tips = Vec::new();
+    for mut id in advertised {
+        loop {
+            remaining.remove(&id);
+            match repo.find_header(id)?.kind() {
+                Kind::Tag => id = repo.find_object(id)?.into_tag().target_id()?.detach(),
+                Kind::Commit => break tips.push(id),
+                Kind::Tree | Kind::Blob => break,
+            }
+        }
+    }
+
+    let not_ours = |id: &ObjectId| anyhow!("upload-pack: not our ref {id}");
+    if let Some(id) = remaining.iter().find(|id| !repo.has_object(*id)) {
+        return Err(not_ours(id));
+    }
+    if remaining.is_empty() {
+        return Ok(());
+    }
+
+    // walking every commit's tree is expensive, so only do it when the client
+    // wants something other than commits, such as a partial clone's blobs
+    let mut walk_trees = false;
+    for id in &remaining {
+        walk_trees |= repo.find_header(*id)?.kind() != Kind::Commit;
+    }
+
+    let mut seen = HashSet::new();
+    for info in repo.rev_walk(tips).all()? {
+        let info = info?;
+        remaining.remove(&info.id);
+
+        if walk_trees && !remaining.is_empty() {
+            mark_seen(
+                repo,
+                repo.find_commit(info.id)?.tree_id()?.detach(),
+                &mut seen,
+            )?;
+            remaining.retain(|id| !seen.contains(id));
+        }
+
+        if remaining.is_empty() {
+            return Ok(());
+        }
+    }
+
+    Err(not_ours(remaining.iter().next().expect("not empty")))
+}
+
+enum Filter {
+    BlobNone,
+    BlobLimit(u64),
+    TreeDepth(usize),
+}
+
+impl Filter {
+    fn parse(spec: &BStr) -> anyhow::Result<Self> {
+        let unsupported = || anyhow!("filter '{spec}' not supported");
+        let spec_str = spec.to_str().map_err(|_| unsupported())?;
+
+        if spec_str == "blob:none" {
+            Ok(Self::BlobNone)
+        } else if let Some(limit) = spec_str.strip_prefix("blob:limit=") {
+            let (digits, multiplier) = match limit.as_bytes().last() {
+                Some(b'k' | b'K') => (&limit[..limit.len() - 1], 1024),
+                Some(b'm' | b'M') => (&limit[..limit.len() - 1], 1024 * 1024),
+                Some(b'g' | b'G') => (&limit[..limit.len() - 1], 1024 * 1024 * 1024),
+                _ => (limit, 1),
+            };
+            let limit: u64 = digits.parse().map_err(|_| unsupported())?;
+            Ok(Self::BlobLimit(limit.saturating_mul(multiplier)))
+        } else if let Some(depth) = spec_str.strip_prefix("tree:") {
+            Ok(Self::TreeDepth(depth.parse().map_err(|_| unsupported())?))
+        } else {
+            Err(unsupported())
+        }
+    }
+}
+
+#[derive(Default)]
+#[allow(clippy::struct_excessive_bools)] // one per argument in the protocol
+struct FetchArgs {
+    wants: Vec<ObjectId>,
+    haves: Vec<ObjectId>,
+    done: bool,
+    no_progress: bool,
+    include_tag: bool,
+    shallows: HashSet<ObjectId>,
+    deepen: bool,
+    depth: Option<usize>,
+    deepen_relative: bool,
+    deepen_since: Option<i64>,
+    deepen_not: Vec<BString>,
+    filter: Option<Filter>,
+}
+
+impl FetchArgs {
+    fn parse(args: &[BString]) -> anyhow::Result<Self> {
+        let mut out = Self::default();
+
+        let oid = |v: &[u8]| {
+            ObjectId::from_hex(v).map_err(|_| anyhow!("invalid object id '{}'", v.as_bstr()))
+        };
+
+        for arg in args {
+            let (key, value) = arg
+                .split_once_str(" ")
+                .map_or((arg.as_slice(), None), |(k, v)| (k, Some(v)));
+
+            match (key, value) {
+                (b"want", Some(v)) => out.wants.push(oid(v)?),
+                (b"have", Some(v)) => out.haves.push(oid(v)?),
+                (b"shallow", Some(v)) => {
+                    out.shallows.insert(oid(v)?);
+                }
+                (b"deepen", Some(v)) => {
+                    let depth = v
+                        .to_str()
+                        .ok()
+                        .and_then(|v| v.parse().ok())
+                        .context("invalid deepen")?;
+                    // git asks for an "infinite" depth to unshallow entirely
+                    out.deepen = true;
+                    out.depth = Some(depth).filter(|&v| v < 0x7fff_ffff);
+                }
+                (b"deepen-since", Some(v)) => {
+                    out.deepen_since = Some(
+                        v.to_str()
+                            .ok()
+                            .and_then(|v| v.parse().ok())
+                            .context("invalid deepen-since")?,
+                    );
+                }
+                (b"deepen-not", Some(v)) => out.deepen_not.push(v.into()),
+                (b"filter", Some(v)) => out.filter = Some(Filter::parse(v.as_bstr())?),
+                (b"done", None) => out.done = true,
+                (b"no-progress", None) => out.no_progress = true,
+                (b"include-tag", None) => out.include_tag = true,
+                (b"deepen-relative", None) => out.deepen_relative = true,
+                // we never produce thin packs and always use offset deltas
+                (b"thin-pack" | b"ofs-delta", None) => {}
+                _ => bail!("unexpected line: '{arg}'"),
+            }
+        }
+
+        if out.wants.is_empty() {
+            bail!("expected want");
+        }
+
+        Ok(out)
+    }
+
+    fn deepening(&self) -> bool {
+        self.deepen || self.deepen_since.is_some() || !self.deepen_not.is_empty()
+    }
+}
+
+fn fetch(repo: &gix::Repository, args: &FetchArgs, out: &mut Output) -> anyhow::Result<()> {
+    let common: Vec<_> = args
+        .haves
+        .iter()
+        .copied()
+        .filter(|id| repo.has_object(id))
+        .collect();
+
+    if !args.done {
+        out.line("acknowledgments\n");
+
+        // without anything in common the client should keep sending haves
+        // before we commit to a pack
+        if common.is_empty() && !args.haves.is_empty() {
+            out.line("NAK\n");
+            out.flush();
+            return Ok(());
+        }
+
+        if common.is_empty() {
+            out.line("NAK\n");
+        }
+        for id in &common {
+            out.line(format!("ACK {id}\n"));
+        }
+        out.line("ready\n");
+        out.delimiter();
+    }
+
+    check_wants(repo, &args.wants)?;
+    let plan = Plan::build(repo, args, &common)?;
+
+    if !args.shallows.is_empty() || args.deepening() {
+        out.line("shallow-info\n");
+        for id in &plan.shallow {
+            out.line(format!("shallow {id}\n"));
+        }
+        for id in &plan.unshallow {
+            out.line(format!("unshallow {id}\n"));
+        }
+        out.delimiter();
+    }
+
+    out.line("packfile\n");
+    out.in_packfile = true;
+
+    if !args.no_progress {
+        out.progress(&format!(
+            "Enumerating objects: {}, done.\n",
+            plan.objects.len()
+        ))?;
+    }
+
+    write_pack(
+        repo,
+        plan.objects,
+        &mut PackWriter {
+            out: &mut *out,
+            pending: Vec::new(),
+        },
+    )?;
+
+    out.flush();
+    Ok(())
+}
+
+/// Streams a pack containing exactly `objects` to `out`.
+fn write_pack(
+    repo: &gix::Repository,
+    objects: Vec<ObjectId>,
+    out: &mut PackWriter<'_>,
+) -> anyhow::Result<()> {
+    // entries are copied straight out of existing packs, deltas included,
+    // which needs the packs to stay put while we're reading them
+    let mut db = repo.objects.clone();
+    db.prevent_pack_unload();
+
+    let (counts, _) = output::count::objects_unthreaded(
+        &db,
+        &mut objects.into_iter().map(Ok),
+        &Discard,
+        &AtomicBool::new(false),
+        ObjectExpansion::AsIs,
+    )?;
+    let num_entries = u32::try_from(counts.len())?;
+
+    let entries = InOrderIter::from(output::entry::iter_from_counts(
+        counts,
+        db,
+        Box::new(Discard),
+        output::entry::iter_from_counts::Options {
+            version: Version::V2,
+            ..Default::default()
+        },
+    ));
+
+    let pack = output::bytes::FromEntriesIter::new(
+        entries,
+        &mut *out,
+        num_entries,
+        Version::V2,
+        repo.object_hash(),
+    );
+
+    for written in pack {
+        written?;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+/// The minimum we need to know about a commit to walk the graph.
+struct CommitInfo {
+    tree: ObjectId,
+    parents: Vec<ObjectId>,
+    time: i64,
+}
+
+struct Commits<'a> {
+    repo: &'a gix::Repository,
+    cache: HashMap<ObjectId, Arc<CommitInfo>>,
+}
+
+impl<'a> Commits<'a> {
+    fn new(repo: &'a gix::Repository) -> Self {
+        Self {
+            repo,
+            cache: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, id: ObjectId) -> anyhow::Result<Arc<CommitInfo>> {
+        if let Some(info) = self.cache.get(&id) {
+            return Ok(info.clone());
+        }
+
+        let commit = self.repo.find_commit(id)?;
+        let decoded = commit.decode()?;
+        let info = Arc::new(CommitInfo {
+            tree: decoded.tree(),
+            // parents may be missing if this repository is itself shallow
+            parents: decoded
+                .parents()
+                .filter(|p| self.repo.has_object(p))
+                .collect(),
+            time: decoded.time().seconds,
+        });
+
+        self.cache.insert(id, info.clone());
+        Ok(info)
+    }
+
+    /// Computes the distance of each commit from `tips`, up to `depth`.
+    fn depths(
+        &mut self,
+        tips: &[ObjectId],
+        depth: Option<usize>,
+        mut stop: impl FnMut(&HashMap<ObjectId, usize>) -> bool,
+    ) -> anyhow::Result<HashMap<ObjectId, usize>> {
+        let mut depths = HashMap::new();
+        let mut queue: VecDeque<_> = tips.iter().map(|id| (*id, 1)).collect();
+
+        while let Some((id, distance)) = queue.pop_front() {
+            if depths.contains_key(&id) {
+                continue;
+            }
+            depths.insert(id, distance);
+
+            if stop(&depths) {
+                break;
+            }
+
+            if depth.is_none_or(|depth| distance < depth) {
+                for parent in &self.get(id)?.parents {
+                    queue.push_back((*parent, distance + 1));
+                }
+            }
+        }
+
+        Ok(depths)
+    }
+}
+
+/// Everything going into the response: the objects for the pack and any
+/// changes to the client's shallow boundary.
+struct Plan {
+    objects: Vec<ObjectId>,
+    shallow: Vec<ObjectId>,
+    unshallow: Vec<ObjectId>,
+}
+
+impl Plan {
+    #[allow(clippy::too_many_lines)]
+    fn build(
+        repo: &gix::Repository,
+        args: &FetchArgs,
+        common: &[ObjectId],
+    ) -> anyhow::Result<Self> {
+        let mut commits = Commits::new(repo);
+        let mut objects = Vec::new();
+        let mut tips = Vec::new();
+        let mut trees = Vec::new();
+
+        // peel the wants down to commits, anything else is sent as-is
+        for want in &args.wants {
+            let mut object = repo
+                .find_object(*want)
+                .map_err(|_| anyhow!("upload-pack: not our ref {want}"))?;
+
+            loop {
+                match object.kind {
+                    Kind::Tag => {
+                        objects.push(object.id);
+                        let target = object.into_tag().target_id()?.detach();
+                        object = repo.find_object(target)?;
+                    }
+                    Kind::Commit => break tips.push(object.id),
+                    Kind::Tree => break trees.push(object.id),
+                    Kind::Blob => break objects.push(object.id),
+                }
+            }
+        }
+
+        // work out which commits fall within the requested depth, and which
+        // of the client's shallow commits are now within it
+        let depths = match args.depth {
+            Some(depth) if args.deepen_relative => {
+                let existing = commits.depths(&tips, None, |depths| {
+                    args.shallows.iter().all(|id| depths.contains_key(id))
+                })?;
+                let deepest = args
+                    .shallows
+                    .iter()
+                    .filter_map(|id| existing.get(id))
+                    .max()
+                    .copied()
+                    .unwrap_or(0);
+                Some((
+                    deepest + depth,
+                    commits.depths(&tips, Some(deepest + depth), |_| false)?,
+                ))
+            }
+            Some(depth) => Some((depth, commits.depths(&tips, Some(depth), |_| false)?)),
+            None => None,
+        };
+
+        let mut excluded = HashSet::new();
+        for name in &args.deepen_not {
+            let id = repo
+                .find_reference(name.as_bstr())
+                .map_err(|_| anyhow!("git upload-pack: ambiguous deepen-not: {name}"))?
+                .into_fully_peeled_id()?
+                .detach();
+            excluded.extend(commits.depths(&[id], None, |_| false)?.into_keys());
+        }
+
+        // whether the walk should continue from `id` on to its parents
+        let keep_parents = |commits: &mut Commits<'_>, id: ObjectId| -> anyhow::Result<bool> {
+            if let Some((depth, depths)) = &depths {
+                if depths.get(&id).is_none_or(|v| v >= depth) {
+                    return Ok(false);
+                }
+            }
+
+            for parent in &commits.get(id)?.parents {
+                if excluded.contains(parent) {
+                    return Ok(false);
+                }
+                if let Some(since) = args.deepen_since {
+                    if commits.get(*parent)?.time < since {
+                        return Ok(false);
+                    }
+                }
+            }
+
+            Ok(true)
+        };
+
+        // the client already has everything behind its shallow commits, so
+        // they're uninteresting but their parents may now need sending
+        let mut uninteresting: HashSet<ObjectId> = common.iter().copied().collect();
+        let mut unshallow = Vec::new();
+
+        for id in &args.shallows {
+            if !repo.has_object(id) {
+                continue;
+            }
+
+            uninteresting.insert(*id);
+
+            if args.deepening()
+                && !commits.get(*id)?.parents.is_empty()
+                && keep_parents(&mut commits, *id)?
+            {
+                unshallow.push(*id);
+                tips.extend(commits.get(*id)?.parents.iter().copied());
+            }
+        }
+
+        // walk newest-first, painting the ancestors of the client's commits
+        // uninteresting, until nothing interesting is left in the queue
+        let mut queue = BinaryHeap::new();
+        let mut queued = HashSet::new();
+        let mut pending = HashSet::new();
+
+        for id in uninteresting.iter().chain(&tips) {
+            if queued.insert(*id) && repo.find_header(*id)?.kind() == Kind::Commit {
+                queue.push((commits.get(*id)?.time, *id));
+                if !uninteresting.contains(id) {
+                    pending.insert(*id);
+                }
+            }
+        }
+
+        let mut wanted = Vec::new();
+        let mut shallow = Vec::new();
+
+        while !pending.is_empty() {
+            let Some((_, id)) = queue.pop() else {
+                break;
+            };
+            let info = commits.get(id)?;
+
+            if uninteresting.contains(&id) {
+                // grafts on the client end at its shallow commits
+                if args.shallows.contains(&id) {
+                    continue;
+                }
+
+                for parent in &info.parents {
+                    uninteresting.insert(*parent);
+                    pending.remove(parent);
+
+                    if queued.insert(*parent) {
+                        queue.push((commits.get(*parent)?.time, *parent));
+                    }
+                }
+            } else {
+                pending.remove(&id);
+                wanted.push(id);
+
+                if !keep_parents(&mut commits, id)? {
+                    if !info.parents.is_empty() && !args.shallows.contains(&id) {
+                        shallow.push(id);
+                    }
+                    continue;
+                }
+
+                for parent in &info.parents {
+                    if queued.insert(*parent) {
+                        queue.push((commits.get(*parent)?.time, *parent));
+                        if !uninteresting.contains(parent) {
+                            pending.insert(*parent);
+                        }
+                    }
+                }
+            }
+        }
+
+        // commits may have been painted uninteresting after we visited them
+        wanted.retain(|id| !uninteresting.contains(id));
+        shallow.retain(|id| !uninteresting.contains(id));
+
+        // git reports every commit on the requested depth as shallow, even if
+        // the client already has it
+        if let Some((depth, depths)) = &depths {
+            for (id, _) in depths.iter().filter(|(_, v)| *v == depth) {
+                if !shallow.contains(id)
+                    && !args.shallows.contains(id)
+                    && !commits.get(*id)?.parents.is_empty()
+                {
+                    shallow.push(*id);
+                }
+            }
+        }
+
+        // anything reachable from the commits the client has at the edge of
+        // what we're sending can be skipped
+        let mut seen = HashSet::new();
+        let edges: HashSet<_> = wanted
+            .iter()
+            .flat_map(|id| commits.cache[id].parents.clone())
+            .chain(unshallow.iter().copied())
+            .filter(|id| uninteresting.contains(id))
+            .collect();
+
+        for id in edges {
+            mark_seen(repo, commits.get(id)?.tree, &mut seen)?;
+        }
+
+        let filter = args.filter.as_ref();
+        objects.extend(wanted.iter().copied());
+
+        for tree in wanted
+            .iter()
+            .map(|id| commits.cache[id].tree)
+            .chain(trees)
+            .collect::<Vec<_>>()
+        {
+            add_tree(repo, tree, filter, &mut seen, &mut objects)?;
+        }
+
+        if args.include_tag {
+            let sent: HashSet<_> = objects.iter().copied().collect();
+
+            for reference in repo.references()?.tags()? {
+                let reference = reference.map_err(|e| anyhow!(e))?;
+                let Some(id) = reference.target().try_id().map(ToOwned::to_owned) else {
+                    continue;
+                };
+
+                if sent.contains(&id) || repo.find_header(id)?.kind() != Kind::Tag {
+                    continue;
+                }
+
+                if sent.contains(&peel_to_end(repo, id)?) {
+                    objects.push(id);
+                }
+            }
+        }
+
+        Ok(Self {
+            objects,
+            shallow,
+            unshallow,
+        })
+    }
+}
+
+/// Marks everything reachable from `tree` as already being on the client.
+fn mark_seen(
+    repo: &gix::Repository,
+    tree: ObjectId,
+    seen: &mut HashSet<ObjectId>,
+) -> anyhow::Result<()> {
+    let mut queue = vec![tree];
+
+    while let Some(id) = queue.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+
+        let tree = repo.find_tree(id)?;
+        for entry in tree.decode()?.entries {
+            if entry.mode.is_tree() {
+                queue.push(entry.oid.to_owned());
+            } else if !entry.mode.is_commit() {
+                seen.insert(entry.oid.to_owned());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Collects every object under `tree` not yet seen, honouring the client's
+/// object filter.
+fn add_tree(
+    repo: &gix::Repository,
+    tree: ObjectId,
+    filter: Option<&Filter>,
+    seen: &mut HashSet<ObjectId>,
+    objects: &mut Vec<ObjectId>,
+) -> anyhow::Result<()> {
+    let mut queue = vec![(tree, 0)];
+
+    while let Some((id, depth)) = queue.pop() {
+        if let Some(Filter::TreeDepth(max)) = filter {
+            if depth >= *max {
+                continue;
+            }
+        }
+
+        if !seen.insert(id) {
+            continue;
+        }
+        objects.push(id);
+
+        let tree = repo.find_tree(id)?;
+        for entry in tree.decode()?.entries {
+            let oid = entry.oid.to_owned();
+
+            if entry.mode.is_tree() {
+                queue.push((oid, depth + 1));
+            } else if entry.mode.is_commit() || seen.contains(&oid) {
+                // submodules live in another repository
+            } else if match filter {
+                Some(Filter::BlobNone) => false,
+                Some(Filter::BlobLimit(limit)) => repo.find_header(oid)?.size() < *limit,
+                Some(Filter::TreeDepth(max)) => depth + 1 < *max,
+                None => true,
+            } {
+                seen.insert(oid);
+                objects.push(oid);
+            }
+        }
+    }
+
+    Ok(())
+}
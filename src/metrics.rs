@@ -0,0 +1,401 @@
+//! Prometheus metrics for the web server and indexer, exported on `/metrics`.
+
+use std::{
+    collections::BTreeSet,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use axum::{extract::Request, middleware::Next, response::Response};
+use gnit_ssh::FirewallStats;
+use prometheus::{
+    core::Collector,
+    exponential_buckets,
+    proto::{LabelPair, Metric, MetricFamily},
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use tracing::error;
+
+use crate::database::schema::prefixes::{
+    COMMIT_COUNT_FAMILY, COMMIT_FAMILY, REFERENCE_FAMILY, REPOSITORY_FAMILY, TAG_FAMILY,
+};
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// The label naming the repository a metric is about.
+const REPOSITORY_LABEL: &str = "repository";
+
+/// Name of the route that served a request, attached to the response so the
+/// metrics layer can label requests without high-cardinality paths.
+#[derive(Copy, Clone, Debug)]
+pub struct Route(pub &'static str);
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    git_backend_spawned: IntCounterVec,
+    git_backend_duration: HistogramVec,
+    upload_pack_requests: IntCounterVec,
+    upload_pack_duration: HistogramVec,
+    cache_requests: IntCounterVec,
+    cache_misses: IntCounterVec,
+    cache_entries: IntGaugeVec,
+    indexer_run_duration: HistogramVec,
+    indexer_commits_ingested: IntCounterVec,
+    mirror_fetch_duration: HistogramVec,
+    rocksdb_size: IntGaugeVec,
+    ssh: SshMetrics,
+}
+
+/// What the SSH server running alongside us reports about its clients.
+struct SshMetrics {
+    clients: IntGaugeVec,
+    auth_failures: IntCounter,
+    bans: IntCounter,
+    refused: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("gnostr_web".to_string()), None)
+            .expect("valid registry prefix");
+
+        let latency_buckets = exponential_buckets(0.001, 2.0, 15).expect("valid buckets");
+        let slow_buckets = exponential_buckets(0.01, 2.0, 16).expect("valid buckets");
+
+        Self {
+            http_requests: counter_vec(
+                &registry,
+                "http_requests_total",
+                "Total HTTP requests served",
+                &["route", "method", "status"],
+            ),
+            http_request_duration: histogram_vec(
+                &registry,
+                "http_request_duration_seconds",
+                "Time taken to serve HTTP requests",
+                latency_buckets.clone(),
+                &["route", "method"],
+            ),
+            git_backend_spawned: counter_vec(
+                &registry,
+                "git_http_backend_spawned_total",
+                "Total `git http-backend` subprocesses spawned",
+                &["service"],
+            ),
+            git_backend_duration: histogram_vec(
+                &registry,
+                "git_http_backend_duration_seconds",
+                "Lifetime of `git http-backend` subprocesses",
+                latency_buckets.clone(),
+                &["service", "outcome"],
+            ),
+            upload_pack_requests: counter_vec(
+                &registry,
+                "upload_pack_requests_total",
+                "Total protocol v2 upload-pack requests answered in-process",
+                &["command"],
+            ),
+            upload_pack_duration: histogram_vec(
+                &registry,
+                "upload_pack_duration_seconds",
+                "Time taken to answer in-process upload-pack commands",
+                latency_buckets,
+                &["command", "outcome"],
+            ),
+            cache_requests: counter_vec(
+                &registry,
+                "git_cache_requests_total",
+                "Total lookups against a git cache",
+                &["cache"],
+            ),
+            cache_misses: counter_vec(
+                &registry,
+                "git_cache_misses_total",
+                "Total lookups against a git cache that had to be computed",
+                &["cache"],
+            ),
+            cache_entries: gauge_vec(
+                &registry,
+                "git_cache_entries",
+                "Current number of entries in a git cache",
+                &["cache"],
+            ),
+            indexer_run_duration: histogram_vec(
+                &registry,
+                "indexer_run_duration_seconds",
+                "Time taken for each indexer pass",
+                slow_buckets.clone(),
+                &["stage"],
+            ),
+            indexer_commits_ingested: counter_vec(
+                &registry,
+                "indexer_commits_ingested_total",
+                "Total commits written to the index",
+                &[REPOSITORY_LABEL],
+            ),
+            mirror_fetch_duration: histogram_vec(
+                &registry,
+                "mirror_fetch_duration_seconds",
+                "Time taken to fetch each mirror from its upstream",
+                slow_buckets,
+                &["outcome"],
+            ),
+            rocksdb_size: gauge_vec(
+                &registry,
+                "rocksdb_size_bytes",
+                "Size of each RocksDB column family, by kind of storage",
+                &["column_family", "kind"],
+            ),
+            ssh: SshMetrics::new(&registry),
+            registry,
+        }
+    }
+
+    pub fn git_backend_spawned(&self, service: &str) {
+        self.git_backend_spawned
+            .with_label_values(&[service])
+            .inc();
+    }
+
+    pub fn git_backend_finished(&self, service: &str, success: bool, duration: Duration) {
+        self.git_backend_duration
+            .with_label_values(&[service, if success { "success" } else { "failure" }])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn upload_pack_started(&self, command: &str) {
+        self.upload_pack_requests
+            .with_label_values(&[command])
+            .inc();
+    }
+
+    pub fn upload_pack_finished(&self, command: &str, success: bool, duration: Duration) {
+        self.upload_pack_duration
+            .with_label_values(&[command, if success { "success" } else { "failure" }])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn cache_request(&self, cache: &str) {
+        self.cache_requests.with_label_values(&[cache]).inc();
+    }
+
+    pub fn cache_miss(&self, cache: &str) {
+        self.cache_misses.with_label_values(&[cache]).inc();
+    }
+
+    pub fn cache_entries(&self, cache: &str, entries: u64) {
+        self.cache_entries
+            .with_label_values(&[cache])
+            .set(i64::try_from(entries).unwrap_or(i64::MAX));
+    }
+
+    pub fn indexer_stage_finished(&self, stage: &str, duration: Duration) {
+        self.indexer_run_duration
+            .with_label_values(&[stage])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn commits_ingested(&self, repository: &str, count: u64) {
+        self.indexer_commits_ingested
+            .with_label_values(&[repository])
+            .inc_by(count);
+    }
+
+    pub fn mirror_fetched(&self, success: bool, duration: Duration) {
+        self.mirror_fetch_duration
+            .with_label_values(&[if success { "success" } else { "failure" }])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Refreshes the `RocksDB` column family sizes from the database's own
+    /// property counters.
+    pub fn observe_rocksdb(&self, db: &rocksdb::DB) {
+        const PROPERTIES: [(&str, &str); 3] = [
+            ("sst", "rocksdb.total-sst-files-size"),
+            ("live_data", "rocksdb.estimate-live-data-size"),
+            ("memtable", "rocksdb.cur-size-all-mem-tables"),
+        ];
+
+        for family in [
+            COMMIT_FAMILY,
+            COMMIT_COUNT_FAMILY,
+            REPOSITORY_FAMILY,
+            TAG_FAMILY,
+            REFERENCE_FAMILY,
+        ] {
+            let Some(cf) = db.cf_handle(family) else {
+                continue;
+            };
+
+            for (kind, property) in PROPERTIES {
+                match db.property_int_value_cf(cf, property) {
+                    Ok(Some(v)) => self
+                        .rocksdb_size
+                        .with_label_values(&[family, kind])
+                        .set(i64::try_from(v).unwrap_or(i64::MAX)),
+                    Ok(None) => {}
+                    Err(error) => error!(%error, "Failed to read {property} for {family}"),
+                }
+            }
+        }
+    }
+
+    /// Catches up with the SSH server's connection and ban counts, when it's
+    /// running alongside us.
+    pub fn observe_ssh(&self, stats: &FirewallStats) {
+        for (kind, value) in [
+            ("connections", stats.connections),
+            ("git_processes", stats.git_processes),
+            ("banned_addresses", stats.banned),
+        ] {
+            self.ssh
+                .clients
+                .with_label_values(&[kind])
+                .set(i64::try_from(value).unwrap_or(i64::MAX));
+        }
+
+        // the ssh server keeps the totals, so counters only need moving up to them
+        let catch_up = |counter: &IntCounter, total: u64| {
+            counter.inc_by(total.saturating_sub(counter.get()));
+        };
+        catch_up(&self.ssh.auth_failures, stats.auth_failures);
+        catch_up(&self.ssh.bans, stats.bans);
+        for (reason, total) in [
+            ("denied", stats.refused_denied),
+            ("banned", stats.refused_banned),
+            ("too_many_connections", stats.refused_busy),
+            ("too_many_git_processes", stats.git_processes_refused),
+        ] {
+            catch_up(&self.ssh.refused.with_label_values(&[reason]), total);
+        }
+    }
+
+    /// Every repository named by a metric's labels.
+    pub fn repositories(&self) -> BTreeSet<String> {
+        self.registry
+            .gather()
+            .iter()
+            .flat_map(MetricFamily::get_metric)
+            .filter_map(repository)
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Renders every registered metric in the Prometheus text exposition format,
+    /// leaving out those labelled with a repository that isn't in `readable`, so
+    /// the names of private repositories aren't given away.
+    pub fn encode(&self, readable: &BTreeSet<String>) -> Result<Vec<u8>, prometheus::Error> {
+        let mut families = self.registry.gather();
+        for family in &mut families {
+            family
+                .mut_metric()
+                .retain(|v| repository(v).is_none_or(|v| readable.contains(v)));
+        }
+        families.retain(|v| !v.get_metric().is_empty());
+
+        let mut out = Vec::new();
+        TextEncoder::new().encode(&families, &mut out)?;
+        Ok(out)
+    }
+}
+
+impl SshMetrics {
+    fn new(registry: &Registry) -> Self {
+        Self {
+            clients: gauge_vec(
+                registry,
+                "ssh_clients",
+                "Current SSH connections, running git processes and banned addresses",
+                &["kind"],
+            ),
+            auth_failures: register(
+                registry,
+                IntCounter::new(
+                    "ssh_auth_failures_total",
+                    "Total failed SSH logins, which count towards a ban",
+                ),
+            ),
+            bans: register(
+                registry,
+                IntCounter::new(
+                    "ssh_bans_total",
+                    "Total addresses banned for too many failed SSH logins",
+                ),
+            ),
+            refused: counter_vec(
+                registry,
+                "ssh_refused_total",
+                "Total SSH connections and git commands turned away, by reason",
+                &["reason"],
+            ),
+        }
+    }
+}
+
+fn repository(metric: &Metric) -> Option<&str> {
+    metric
+        .get_label()
+        .iter()
+        .find(|v| v.get_name() == REPOSITORY_LABEL)
+        .map(LabelPair::get_value)
+}
+
+fn counter_vec(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    register(registry, IntCounterVec::new(Opts::new(name, help), labels))
+}
+
+fn gauge_vec(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntGaugeVec {
+    register(registry, IntGaugeVec::new(Opts::new(name, help), labels))
+}
+
+fn histogram_vec(
+    registry: &Registry,
+    name: &str,
+    help: &str,
+    buckets: Vec<f64>,
+    labels: &[&str],
+) -> HistogramVec {
+    let opts = HistogramOpts::new(name, help).buckets(buckets);
+    register(registry, HistogramVec::new(opts, labels))
+}
+
+fn register<T: Collector + Clone + 'static>(
+    registry: &Registry,
+    metric: Result<T, prometheus::Error>,
+) -> T {
+    let metric = metric.expect("valid metric");
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("metric names are unique");
+    metric
+}
+
+/// Records the count and latency of every request, labelled by the [`Route`]
+/// the handler attached to the response.
+pub async fn track_requests(req: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = req.method().clone();
+    let fallback = match req.uri().path() {
+        "/" => "index",
+        "/metrics" => "metrics",
+        _ => "static",
+    };
+
+    let response = next.run(req).await;
+
+    let route = response.extensions().get::<Route>().map_or(fallback, |r| r.0);
+
+    METRICS
+        .http_requests
+        .with_label_values(&[route, method.as_str(), response.status().as_str()])
+        .inc();
+    METRICS
+        .http_request_duration
+        .with_label_values(&[route, method.as_str()])
+        .observe(start.elapsed().as_secs_f64());
+
+    response
+}
@@ -0,0 +1,336 @@
+//! Keeps bare copies of upstream repositories up to date.
+//!
+//! Mirrors are declared either in the file given by `--mirrors`:
+//!
+//! ```toml
+//! [mirrors."vendor/tokio.git"]
+//! url = "https://github.com/tokio-rs/tokio.git"
+//! interval = "1h"
+//! ```
+//!
+//! or by placing a `repo.toml` with a `[mirror]` table (taking the same keys)
+//! inside a directory under the scan path, which will be turned into a bare
+//! repository on the first fetch.
+//!
+//! Only admins can read a mirror unless it says otherwise, since its upstream
+//! may well be private:
+//!
+//! ```toml
+//! [mirrors."vendor/tokio.git"]
+//! url = "https://github.com/tokio-rs/tokio.git"
+//! public = true
+//! members = ["alice", "@vendors"]
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    process::Output,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Deserializer};
+use tokio::{process::Command, sync::mpsc};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{database::indexer::Wakeup, metrics::METRICS};
+
+/// How often we check whether any mirror is due to be fetched.
+const TICK: Duration = Duration::from_secs(5);
+
+/// The per-repository configuration file mirrors can be declared in.
+const REPO_CONFIG_FILE: &str = "repo.toml";
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Mirror {
+    /// Where to fetch from, anything `git fetch` understands.
+    pub url: String,
+    /// The refspecs to fetch, defaulting to all branches and tags.
+    #[serde(default = "default_refspecs")]
+    pub refspecs: Vec<String>,
+    /// How long to wait between fetches.
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub interval: Duration,
+    /// Whether anyone the SSH server lets read public repositories can read
+    /// the mirror.
+    #[serde(default)]
+    pub public: bool,
+    /// Users, or `@group`s, who can read the mirror besides admins.
+    #[serde(default)]
+    pub members: Vec<String>,
+}
+
+fn default_refspecs() -> Vec<String> {
+    vec![
+        "+refs/heads/*:refs/heads/*".to_string(),
+        "+refs/tags/*:refs/tags/*".to_string(),
+    ]
+}
+
+fn default_interval() -> Duration {
+    Duration::from_mins(10)
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(&value).map_err(serde::de::Error::custom)
+}
+
+#[derive(Deserialize, Default)]
+struct MirrorsFile {
+    #[serde(default)]
+    mirrors: BTreeMap<PathBuf, Mirror>,
+}
+
+#[derive(Deserialize)]
+struct RepoConfig {
+    mirror: Option<Mirror>,
+}
+
+/// Fetches every configured mirror when it's due, waking the indexer after
+/// any fetch that changed a ref to reindex that mirror.
+pub async fn run(
+    scan_path: PathBuf,
+    config: Option<PathBuf>,
+    indexer_wakeup: mpsc::Sender<Wakeup>,
+) {
+    let mut last_fetched: HashMap<PathBuf, Instant> = HashMap::new();
+    let mut granted: HashMap<PathBuf, Mirror> = HashMap::new();
+
+    loop {
+        let mirrors = match load(&scan_path, config.as_deref()).await {
+            Ok(v) => v,
+            Err(error) => {
+                error!("Failed to load mirror configuration: {error:#}");
+                BTreeMap::new()
+            }
+        };
+
+        last_fetched.retain(|path, _| mirrors.contains_key(path));
+        granted.retain(|path, _| mirrors.contains_key(path));
+
+        for (path, mirror) in &mirrors {
+            // revoking access shouldn't have to wait for the next fetch
+            let repository_path = scan_path.join(path);
+            if granted.get(path) != Some(mirror) && repository_path.join("HEAD").is_file() {
+                match set_grants(&repository_path, mirror).await {
+                    Ok(()) => {
+                        granted.insert(path.clone(), mirror.clone());
+                    }
+                    Err(error) => {
+                        warn!(path = %path.display(), "Failed to set mirror access: {error:#}");
+                    }
+                }
+            }
+
+            if last_fetched
+                .get(path)
+                .is_some_and(|v| v.elapsed() < mirror.interval)
+            {
+                continue;
+            }
+            last_fetched.insert(path.clone(), Instant::now());
+
+            let started = Instant::now();
+            let result = fetch(&repository_path, mirror).await;
+            METRICS.mirror_fetched(result.is_ok(), started.elapsed());
+
+            match result {
+                Ok(true) => {
+                    info!(path = %path.display(), "Mirror updated, triggering reindex");
+
+                    if indexer_wakeup
+                        .send(Wakeup::Repository(path.clone()))
+                        .await
+                        .is_err()
+                    {
+                        error!(
+                            "Indexing thread has died and is no longer accepting wakeup messages"
+                        );
+                    }
+                }
+                Ok(false) => debug!(path = %path.display(), "Mirror already up to date"),
+                Err(error) => {
+                    warn!(path = %path.display(), "Failed to update mirror: {error:#}");
+                }
+            }
+        }
+
+        tokio::time::sleep(TICK).await;
+    }
+}
+
+/// Collects the mirrors declared in `config` and under `scan_path`, keyed by
+/// their path relative to `scan_path`.
+async fn load(
+    scan_path: &Path,
+    config: Option<&Path>,
+) -> anyhow::Result<BTreeMap<PathBuf, Mirror>> {
+    let mut mirrors = if let Some(config) = config {
+        let text = tokio::fs::read_to_string(config)
+            .await
+            .with_context(|| format!("Couldn't read {}", config.display()))?;
+        toml::from_str::<MirrorsFile>(&text)
+            .with_context(|| format!("Couldn't parse {}", config.display()))?
+            .mirrors
+    } else {
+        BTreeMap::new()
+    };
+
+    for (path, mirror) in tokio::task::spawn_blocking({
+        let scan_path = scan_path.to_path_buf();
+        move || {
+            let mut discovered = Vec::new();
+            discover(&scan_path, &scan_path, &mut discovered);
+            discovered
+        }
+    })
+    .await?
+    {
+        mirrors.entry(path).or_insert(mirror);
+    }
+
+    if let Some(path) = mirrors.keys().find(|path| {
+        path.is_absolute()
+            || path
+                .components()
+                .any(|v| matches!(v, std::path::Component::ParentDir))
+    }) {
+        return Err(anyhow!(
+            "mirror path {} must be relative to the scan path",
+            path.display()
+        ));
+    }
+
+    Ok(mirrors)
+}
+
+/// Recursively finds `repo.toml` files declaring a mirror, without descending
+/// into repositories themselves.
+fn discover(scan_path: &Path, current: &Path, discovered: &mut Vec<(PathBuf, Mirror)>) {
+    let config_path = current.join(REPO_CONFIG_FILE);
+
+    if config_path.is_file() {
+        let parsed = std::fs::read_to_string(&config_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|v| Ok(toml::from_str::<RepoConfig>(&v)?));
+
+        match parsed {
+            Ok(RepoConfig {
+                mirror: Some(mirror),
+            }) => {
+                if let Ok(relative) = current.strip_prefix(scan_path) {
+                    discovered.push((relative.to_path_buf(), mirror));
+                }
+                return;
+            }
+            Ok(_) => {}
+            Err(error) => warn!("Failed to parse {}: {error:#}", config_path.display()),
+        }
+    }
+
+    if current.join("HEAD").is_file() {
+        return;
+    }
+
+    let Ok(entries) = std::fs::read_dir(current) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        if entry.file_type().is_ok_and(|v| v.is_dir()) {
+            discover(scan_path, &entry.path(), discovered);
+        }
+    }
+}
+
+/// Brings the bare copy at `path` up to date with its upstream, creating it
+/// if needed. Returns whether any refs changed.
+#[instrument(skip(mirror), fields(url = mirror.url))]
+async fn fetch(path: &Path, mirror: &Mirror) -> anyhow::Result<bool> {
+    if !path.join("HEAD").is_file() {
+        info!("Initialising new mirror");
+        git(None, &["init", "--bare", "--quiet", path_str(path)?]).await?;
+        set_grants(path, mirror).await?;
+    }
+
+    let path = Some(path);
+
+    git(path, &["config", "remote.origin.url", &mirror.url]).await?;
+    git(path, &["config", "remote.origin.mirror", "true"]).await?;
+    // exits with 5 when there's nothing to unset
+    let _res = git(path, &["config", "--unset-all", "remote.origin.fetch"]).await;
+    for refspec in &mirror.refspecs {
+        git(path, &["config", "--add", "remote.origin.fetch", refspec]).await?;
+    }
+
+    let before = git(path, &["for-each-ref", "--format=%(objectname) %(refname)"]).await?;
+    git(path, &["fetch", "--prune", "--quiet", "origin"]).await?;
+
+    // follow the upstream's default branch so the summary page shows the same
+    // thing the upstream's would
+    let remote_head = git(path, &["ls-remote", "--symref", "origin", "HEAD"]).await?;
+    if let Some(target) = String::from_utf8_lossy(&remote_head.stdout)
+        .lines()
+        .find_map(|v| v.strip_prefix("ref: ")?.strip_suffix("\tHEAD"))
+    {
+        git(path, &["symbolic-ref", "HEAD", target]).await?;
+    }
+
+    // the indexer only picks up repositories with their refs packed
+    git(path, &["pack-refs", "--all"]).await?;
+
+    let after = git(path, &["for-each-ref", "--format=%(objectname) %(refname)"]).await?;
+
+    Ok(before.stdout != after.stdout)
+}
+
+/// Records who can read the mirror in its git config, which is where the SSH
+/// server's access checks look for it.
+async fn set_grants(path: &Path, mirror: &Mirror) -> anyhow::Result<()> {
+    let path = Some(path);
+    let public = if mirror.public { "true" } else { "false" };
+
+    git(path, &["config", "gnit.public", public]).await?;
+    // exits with 5 when there's nothing to unset
+    let _res = git(path, &["config", "--unset-all", "gnit.member"]).await;
+    for member in &mirror.members {
+        git(path, &["config", "--add", "gnit.member", member]).await?;
+    }
+
+    Ok(())
+}
+
+async fn git(dir: Option<&Path>, args: &[&str]) -> anyhow::Result<Output> {
+    let mut command = Command::new("git");
+    if let Some(dir) = dir {
+        command.arg("-C").arg(dir);
+    }
+
+    let output = command
+        .args(args)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .kill_on_drop(true)
+        .output()
+        .await
+        .with_context(|| format!("Failed to spawn git {}", args.join(" ")))?;
+
+    if output.status.success() {
+        Ok(output)
+    } else {
+        Err(anyhow!(
+            "git {} exited with {}: {}",
+            args.first().unwrap_or(&""),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+fn path_str(path: &Path) -> anyhow::Result<&str> {
+    path.to_str().context("Mirror path contains invalid bytes")
+}
@@ -2,7 +2,7 @@ use std::{
     cell::RefCell,
     collections::HashMap,
     fmt::Write as FmtWrite,
-    io::{ErrorKind, Write as IoWrite},
+    io::Write as IoWrite,
     path::Path,
     sync::LazyLock,
 };
@@ -136,7 +136,7 @@ impl SyntaxHighlighterAdapter for ComrakHighlightAdapter {
         code: &str,
     ) -> std::io::Result<()> {
         let out = format_file(code, FileIdentifier::Token(lang.unwrap_or_default()))
-            .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?;
+            .map_err(std::io::Error::other)?;
         output.write_all(out.as_bytes())
     }
 
@@ -145,7 +145,7 @@ impl SyntaxHighlighterAdapter for ComrakHighlightAdapter {
         output: &mut dyn IoWrite,
         _attributes: HashMap<String, String>,
     ) -> std::io::Result<()> {
-        write!(output, r#"<pre>"#)
+        write!(output, r"<pre>")
     }
 
     fn write_code_tag(
@@ -0,0 +1,296 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+
+use crate::vars::*;
+
+const ZERO_OID: &str = "0000000000000000000000000000000000000000";
+
+// Once a log reaches this size it's moved aside to audit.log.1, and so on up to LOG_KEEP old
+// logs. The webhook delivery log is kept the same way.
+const LOG_MAX_SIZE: u64 = 16 * 1024 * 1024;
+const LOG_KEEP: usize = 4;
+
+// How much of the log is read at a time when reading it backwards, and the longest line worth
+// keeping, as no entry comes near it.
+const READ_BLOCK_SIZE: u64 = 64 * 1024;
+const MAX_LINE_SIZE: usize = 1024 * 1024;
+
+// Held while appending, so a log isn't rotated under another entry being written.
+static APPENDING: Mutex<()> = Mutex::const_new(());
+
+// A line in the audit log, which the web server also writes to and can show admins.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub timestamp: u64,
+    // ssh, git or http.
+    pub transport: String,
+    pub user: Option<String>,
+    pub key_fingerprint: Option<String>,
+    pub address: Option<SocketAddr>,
+    pub command: String,
+    pub repo: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub refs: Vec<AuditRef>,
+    // The command's exit status or the HTTP status code, or none if it was refused.
+    pub status: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRef {
+    #[serde(rename = "ref")]
+    pub name: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl AuditEntry {
+    pub fn new(
+        user: Option<String>,
+        key_fingerprint: Option<String>,
+        address: Option<SocketAddr>,
+        command: &str,
+        repo_path: &Path,
+    ) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |v| v.as_secs()),
+            transport: "ssh".to_string(),
+            user,
+            key_fingerprint,
+            address,
+            command: command.to_string(),
+            repo: repo_path.to_string_lossy().into_owned(),
+            refs: Vec::new(),
+            status: None,
+            error: None,
+        }
+    }
+
+    // An anonymous fetch or download from the web server.
+    pub fn http(address: Option<SocketAddr>, command: &str, repo_path: &Path) -> Self {
+        Self {
+            transport: "http".to_string(),
+            ..Self::new(None, None, address, command, repo_path)
+        }
+    }
+
+    // Lists the refs a push changed, given the repo's refs from before and after it.
+    pub fn set_refs(
+        &mut self,
+        before: &BTreeMap<String, String>,
+        after: &BTreeMap<String, String>,
+    ) {
+        let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+        names.sort();
+        names.dedup();
+
+        self.refs = names
+            .into_iter()
+            .filter_map(|name| {
+                let old = before.get(name).map_or(ZERO_OID, String::as_str);
+                let new = after.get(name).map_or(ZERO_OID, String::as_str);
+                (old != new).then(|| AuditRef {
+                    name: name.clone(),
+                    old: old.to_string(),
+                    new: new.to_string(),
+                })
+            })
+            .collect();
+    }
+
+    pub fn refused(mut self, message: &str) -> Self {
+        self.error = Some(message.to_string());
+        self
+    }
+
+    // Appends the entry to the log. Failing to is only logged, as it shouldn't stop anyone
+    // using the server.
+    pub async fn record(&self) {
+        self.record_in(Path::new("")).await;
+    }
+
+    // The same, for the log under a scan path other than the current directory.
+    pub async fn record_in(&self, scan_path: &Path) {
+        if let Err(e) = self.append(&scan_path.join(AUDIT_LOG_FILE)).await {
+            log::error!("Failed to write to the audit log: {:#}", e);
+        }
+    }
+
+    async fn append(&self, path: &Path) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        append_line(path, &line).await
+    }
+}
+
+// Appends a line to the log at `path`, rotating it first if it's full.
+pub async fn append_line(path: &Path, line: &[u8]) -> anyhow::Result<()> {
+    let _appending = APPENDING.lock().await;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let size = tokio::fs::metadata(path).await.map_or(0, |v| v.len());
+    if size >= LOG_MAX_SIZE {
+        rotate(path)
+            .await
+            .with_context(|| format!("Couldn't rotate {}", path.display()))?;
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Couldn't open {}", path.display()))?
+        .write_all(line)
+        .await?;
+
+    Ok(())
+}
+
+// Moves each old log along one, dropping the oldest, and the log itself to e.g. audit.log.1.
+async fn rotate(path: &Path) -> anyhow::Result<()> {
+    for n in (1..=LOG_KEEP).rev() {
+        let from = if n == 1 {
+            path.to_path_buf()
+        } else {
+            old_log(path, n - 1)
+        };
+        match tokio::fs::rename(&from, old_log(path, n)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn old_log(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+// Reads the entries from the log under the scan path and the old ones rotated out of it, newest
+// first, keeping those the filter matches. Only as much of the logs is read as it takes to skip
+// and take the given numbers of entries. Lines that can't be parsed, such as one still being
+// written, are skipped.
+pub async fn read(
+    scan_path: &Path,
+    skip: usize,
+    take: usize,
+    filter: impl Fn(&AuditEntry) -> bool + Send + 'static,
+) -> anyhow::Result<Vec<AuditEntry>> {
+    let path = scan_path.join(AUDIT_LOG_FILE);
+
+    tokio::task::spawn_blocking(move || {
+        let mut logs = vec![path.clone()];
+        logs.extend((1..=LOG_KEEP).map(|n| old_log(&path, n)));
+
+        let mut entries = Vec::new();
+        let mut skipped = 0;
+        for log in logs {
+            let file = match File::open(&log) {
+                Ok(v) => v,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).context("Couldn't open the audit log"),
+            };
+
+            for line in ReverseLines::new(file)? {
+                let Ok(entry) = serde_json::from_slice::<AuditEntry>(&line?) else {
+                    continue;
+                };
+                if !filter(&entry) {
+                    continue;
+                }
+
+                if skipped < skip {
+                    skipped += 1;
+                } else if entries.len() < take {
+                    entries.push(entry);
+                } else {
+                    return Ok(entries);
+                }
+            }
+        }
+
+        Ok(entries)
+    })
+    .await?
+}
+
+// A file's lines from last to first, read a block at a time from the end.
+struct ReverseLines {
+    file: File,
+    // Where in the file the bytes yet to be split into lines start.
+    position: u64,
+    buffer: Vec<u8>,
+    // Whether the end of the buffer is the start of a line too long to keep.
+    overlong: bool,
+}
+
+impl ReverseLines {
+    fn new(mut file: File) -> io::Result<Self> {
+        let position = file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            file,
+            position,
+            buffer: Vec::new(),
+            overlong: false,
+        })
+    }
+}
+
+impl Iterator for ReverseLines {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(end) = self.buffer.iter().rposition(|b| *b == b'\n') {
+                let line = self.buffer.split_off(end + 1);
+                self.buffer.truncate(end);
+                if std::mem::take(&mut self.overlong) || line.is_empty() {
+                    continue;
+                }
+                return Some(Ok(line));
+            }
+
+            if self.position == 0 {
+                let line = std::mem::take(&mut self.buffer);
+                return (!line.is_empty() && !self.overlong).then_some(Ok(line));
+            }
+
+            if self.buffer.len() > MAX_LINE_SIZE {
+                self.buffer.clear();
+                self.overlong = true;
+            }
+
+            let size = self.position.min(READ_BLOCK_SIZE);
+            self.position -= size;
+            let mut block = vec![0; size as usize];
+            if let Err(e) = self
+                .file
+                .seek(SeekFrom::Start(self.position))
+                .and_then(|_| self.file.read_exact(&mut block))
+            {
+                return Some(Err(e));
+            }
+            block.append(&mut self.buffer);
+            self.buffer = block;
+        }
+    }
+}
@@ -0,0 +1,256 @@
+// Logging in with OpenSSH certificates, so that users signed by a certificate authority the
+// server trusts don't need each of their keys registered.
+
+use std::{
+    fs::read,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context};
+use ipnet::IpNet;
+use russh::keys::{
+    ssh_key::{certificate::CertType, public::KeyData},
+    Certificate, HashAlg, PublicKey,
+};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::config::server::ServerConfig;
+
+// The sections of a key revocation list, from OpenSSH's PROTOCOL.krl.
+const KRL_MAGIC: &[u8] = b"SSHKRL\n\0";
+const KRL_FORMAT_VERSION: u32 = 1;
+const KRL_SECTION_CERTIFICATES: u8 = 1;
+const KRL_SECTION_EXPLICIT_KEY: u8 = 2;
+const KRL_SECTION_FINGERPRINT_SHA1: u8 = 3;
+const KRL_SECTION_SIGNATURE: u8 = 4;
+const KRL_SECTION_FINGERPRINT_SHA256: u8 = 5;
+const KRL_SECTION_CERT_SERIAL_LIST: u8 = 0x20;
+const KRL_SECTION_CERT_SERIAL_RANGE: u8 = 0x21;
+const KRL_SECTION_CERT_SERIAL_BITMAP: u8 = 0x22;
+const KRL_SECTION_CERT_KEY_ID: u8 = 0x23;
+
+// The server's [certificates] table. Certificates aren't accepted without one.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct CertificateConfig {
+    // Public keys of the CAs whose user certificates are accepted, like "ssh-ed25519 AAAA... ca".
+    // A certificate logs in as the first of its principals that's a user in [users].
+    #[serde(default)]
+    pub authorities: Vec<String>,
+    // Revoked certificates and keys, like sshd's RevokedKeys: a KRL made with `ssh-keygen -k`,
+    // or a list of public keys. It's read at every login, so changes apply straight away, and
+    // if it can't be read no certificate is accepted.
+    pub revoked_keys: Option<PathBuf>,
+}
+
+// Finds the user `certificate` logs in as, given the address the client connected from, or
+// says why it can't be used.
+pub fn certificate_user(
+    config: &ServerConfig,
+    certificate: &Certificate,
+    address: Option<IpAddr>,
+) -> anyhow::Result<String> {
+    let Some(certificates) = &config.certificates else {
+        bail!("the server doesn't accept certificates");
+    };
+    if certificate.cert_type() != CertType::User {
+        bail!("it isn't a user certificate");
+    }
+
+    // This checks the CA's signature and that the certificate is in its validity window.
+    let authorities: Vec<_> = certificates
+        .authorities
+        .iter()
+        .filter_map(|key| PublicKey::from_openssh(key).ok())
+        .map(|key| key.fingerprint(HashAlg::Sha256))
+        .collect();
+    certificate
+        .validate(&authorities)
+        .map_err(|_| anyhow!("it isn't signed by a trusted authority, or isn't valid now"))?;
+
+    for (name, value) in certificate.critical_options().iter() {
+        match name.as_str() {
+            "source-address" if address.is_some_and(|address| allows(value, address)) => {}
+            "source-address" => bail!("it can't be used from this address"),
+            _ => bail!("the server doesn't support its {} option", name),
+        }
+    }
+
+    if let Some(path) = &certificates.revoked_keys {
+        if is_revoked(path, certificate)? {
+            bail!("it has been revoked");
+        }
+    }
+
+    // An empty list means any principal to OpenSSH, which would be anyone here.
+    certificate
+        .valid_principals()
+        .iter()
+        .find(|principal| config.users.contains_key(principal.as_str()))
+        .cloned()
+        .ok_or_else(|| anyhow!("none of its principals is a user"))
+}
+
+// Whether a source-address option, a list like "10.0.0.0/8,192.168.1.2", allows `address`.
+fn allows(source_address: &str, address: IpAddr) -> bool {
+    source_address.split(',').any(|entry| {
+        let entry = entry.trim();
+        match entry.parse::<IpNet>() {
+            Ok(net) => net.contains(&address),
+            Err(_) => entry.parse::<IpAddr>() == Ok(address),
+        }
+    })
+}
+
+// Whether the revocation list at `path` revokes the certificate, its key or its CA.
+fn is_revoked(path: &Path, certificate: &Certificate) -> anyhow::Result<bool> {
+    let data = read(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    let Some(krl) = data.strip_prefix(KRL_MAGIC) else {
+        let keys = [certificate.public_key(), certificate.signature_key()];
+        return Ok(String::from_utf8_lossy(&data)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| PublicKey::from_openssh(line).ok())
+            .any(|key| keys.contains(&key.key_data())));
+    };
+    krl_revokes(krl, certificate).with_context(|| format!("Couldn't read {}", path.display()))
+}
+
+fn krl_revokes(krl: &[u8], certificate: &Certificate) -> anyhow::Result<bool> {
+    let mut krl = Reader(krl);
+    if krl.u32()? != KRL_FORMAT_VERSION {
+        bail!("Unsupported KRL format version");
+    }
+    // The KRL's version, when it was made, its flags, a reserved field and its comment.
+    krl.u64()?;
+    krl.u64()?;
+    krl.u64()?;
+    krl.string()?;
+    krl.string()?;
+
+    let keys = [
+        key_blob(certificate.public_key())?,
+        key_blob(certificate.signature_key())?,
+    ];
+    while !krl.is_empty() {
+        let section_type = krl.u8()?;
+        let mut section = Reader(krl.string()?);
+        let revoked = match section_type {
+            KRL_SECTION_CERTIFICATES => certificates_revoked(&mut section, certificate, &keys[1])?,
+            KRL_SECTION_EXPLICIT_KEY => {
+                section.any_string(|blob| keys.iter().any(|key| key == blob))?
+            }
+            KRL_SECTION_FINGERPRINT_SHA1 => section
+                .any_string(|hash| keys.iter().any(|key| Sha1::digest(key).as_slice() == hash))?,
+            KRL_SECTION_FINGERPRINT_SHA256 => section.any_string(|hash| {
+                keys.iter()
+                    .any(|key| Sha256::digest(key).as_slice() == hash)
+            })?,
+            // The signatures are only over what comes before them, and the file is the
+            // server's own, so they aren't checked.
+            KRL_SECTION_SIGNATURE => break,
+            _ => bail!("Unknown KRL section {}", section_type),
+        };
+        if revoked {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Checks a section revoking certificates by serial number or key ID, for one CA or any.
+fn certificates_revoked(
+    section: &mut Reader,
+    certificate: &Certificate,
+    ca_key: &[u8],
+) -> anyhow::Result<bool> {
+    let section_ca_key = section.string()?;
+    section.string()?;
+    if !section_ca_key.is_empty() && section_ca_key != ca_key {
+        return Ok(false);
+    }
+
+    let serial = certificate.serial();
+    while !section.is_empty() {
+        let section_type = section.u8()?;
+        let mut data = Reader(section.string()?);
+        let revoked = match section_type {
+            KRL_SECTION_CERT_SERIAL_LIST => {
+                let mut revoked = false;
+                while !data.is_empty() {
+                    revoked |= data.u64()? == serial;
+                }
+                revoked
+            }
+            KRL_SECTION_CERT_SERIAL_RANGE => (data.u64()?..=data.u64()?).contains(&serial),
+            KRL_SECTION_CERT_SERIAL_BITMAP => {
+                let offset = data.u64()?;
+                let bitmap = data.string()?;
+                serial
+                    .checked_sub(offset)
+                    .and_then(|bit| usize::try_from(bit / 8).ok().map(|byte| (byte, bit % 8)))
+                    .filter(|&(byte, _)| byte < bitmap.len())
+                    .is_some_and(|(byte, bit)| bitmap[bitmap.len() - 1 - byte] & (1 << bit) != 0)
+            }
+            KRL_SECTION_CERT_KEY_ID => {
+                data.any_string(|key_id| key_id == certificate.key_id().as_bytes())?
+            }
+            _ => bail!("Unknown KRL certificate section {}", section_type),
+        };
+        if revoked {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn key_blob(key: &KeyData) -> anyhow::Result<Vec<u8>> {
+    Ok(PublicKey::new(key.clone(), "").to_bytes()?)
+}
+
+// Reads the big-endian integers and length-prefixed strings of the SSH wire format.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < len {
+            bail!("The KRL is cut short");
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    fn string(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.u32()?;
+        self.bytes(usize::try_from(len)?)
+    }
+
+    // Reads strings to the end, returning whether any of them matched.
+    fn any_string(&mut self, matches: impl Fn(&[u8]) -> bool) -> anyhow::Result<bool> {
+        let mut matched = false;
+        while !self.is_empty() {
+            matched |= matches(self.string()?);
+        }
+        Ok(matched)
+    }
+}
@@ -0,0 +1,649 @@
+// Checks server.toml and repo.toml before they're used, so that a bad push to a config repo can
+// be refused rather than lock everyone out. The pre-receive hook and `check-config` both go by
+// this.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    str::from_utf8,
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use toml::{Table, Value};
+
+use crate::{
+    config::{
+        repo::RepoConfig,
+        server::{load_server_config, ServerConfig},
+    },
+    git::{find_repos, Repo},
+    import::is_valid_name,
+    nostr::parse_pubkey,
+    policy::parse_size,
+    push_mirrors,
+    quota::Quota,
+    vars::*,
+    webhooks::{self, WebhookConfig, REPO_SECRET_MESSAGE},
+};
+
+// Something wrong with a config file, and where it is if we can tell.
+pub struct Problem {
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+    // Warnings are worth knowing about, but don't stop the config being used.
+    pub is_error: bool,
+}
+
+impl Problem {
+    // Describes the problem like a compiler would, e.g. "server.toml:4:1: error: ...".
+    pub fn describe(&self, file: &str) -> String {
+        let kind = if self.is_error { "error" } else { "warning" };
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => {
+                format!("{}:{}:{}: {}: {}", file, line, column, kind, self.message)
+            }
+            (Some(line), None) => format!("{}:{}: {}: {}", file, line, kind, self.message),
+            _ => format!("{}: {}: {}", file, kind, self.message),
+        }
+    }
+}
+
+// Checks a server config. If `username` is given, it's who's changing it, and they're warned if
+// they'd no longer be an admin.
+pub fn check_server_config(text: &str, username: Option<&str>) -> Vec<Problem> {
+    let mut checker = Checker::new(text);
+    let Some(config) = checker.parse::<ServerConfig>() else {
+        return checker.problems;
+    };
+
+    let mut owners: HashMap<&str, &str> = HashMap::new();
+    for (name, user) in &config.users {
+        let path = ["users", name.as_str()];
+        if !is_valid_name(name) {
+            checker.warning(
+                &path,
+                format!(
+                    "{:?} isn't a valid username, which should only have letters, numbers and ._@+-",
+                    name
+                ),
+            );
+        }
+
+        let keys = user
+            .public_key
+            .iter()
+            .map(|key| (key, "public_key".to_string()))
+            .chain(
+                user.public_keys
+                    .iter()
+                    .enumerate()
+                    .map(|(i, key)| (key, format!("public_keys[{}]", i))),
+            );
+        for (key, field) in keys {
+            let path = ["users", name.as_str(), field.as_str()];
+            let Some(data) = key_data(key) else {
+                checker.error(
+                    &path,
+                    format!(
+                        "{} of {} isn't a public key this server supports, like \
+                         \"ssh-ed25519 AAAA... you@example.com\"",
+                        field, name
+                    ),
+                );
+                continue;
+            };
+            match owners.insert(data, name.as_str()) {
+                Some(owner) if owner != name => checker.error(
+                    &path,
+                    format!("{} of {} is also a key of {}", field, name, owner),
+                ),
+                _ => {}
+            }
+        }
+
+        if let Some(npub) = &user.npub {
+            if parse_pubkey(npub).is_none() {
+                checker.error(
+                    &["users", name.as_str(), "npub"],
+                    format!("The npub of {} isn't an npub or a hex public key", name),
+                );
+            }
+        }
+        let bound = user.bound_keys().len();
+        if bound < user.key_bindings.len() {
+            checker.warning(
+                &["users", name.as_str(), "key_bindings"],
+                format!(
+                    "{} of {}'s key bindings aren't signed by their npub, so they're ignored",
+                    user.key_bindings.len() - bound,
+                    name
+                ),
+            );
+        }
+
+        if let Some(quota) = &user.quota {
+            checker.quota(&["users", name.as_str(), "quota"], quota);
+        }
+    }
+
+    for (group, members) in &config.groups {
+        for member in members {
+            if member.starts_with('@') {
+                checker.warning(
+                    &["groups", group.as_str()],
+                    format!(
+                        "Groups can't contain other groups, so {} is ignored",
+                        member
+                    ),
+                );
+            } else if !config.users.contains_key(member) && parse_pubkey(member).is_none() {
+                checker.warning(
+                    &["groups", group.as_str()],
+                    format!("{} in group {} isn't a user or an npub", member, group),
+                );
+            }
+        }
+    }
+
+    for (i, key) in config
+        .certificates
+        .iter()
+        .flat_map(|certificates| certificates.authorities.iter())
+        .enumerate()
+    {
+        if key_data(key).is_none() {
+            let field = format!("authorities[{}]", i);
+            checker.error(
+                &["certificates", &field],
+                format!(
+                    "certificates.{} isn't a public key this server supports, like \
+                     \"ssh-ed25519 AAAA... ca\"",
+                    field
+                ),
+            );
+        }
+    }
+
+    if let Some(size) = &config.lfs_quota {
+        checker.size(&["lfs_quota"], size);
+    }
+    if let Some(quota) = &config.quota {
+        checker.quota(&["quota"], quota);
+    }
+    if let Some(url) = &config.web_url {
+        checker.url(&["web_url"], url);
+    }
+    checker.webhooks(config.webhooks.as_deref().unwrap_or_default());
+    for (i, webhook) in config.webhooks.iter().flatten().enumerate() {
+        if webhook.secret_name.is_some() {
+            let table = format!("webhooks[{}]", i);
+            checker.warning(
+                &[&table, "secret_name"],
+                "Only repos' webhooks can name a secret, give this one its secret instead"
+                    .to_string(),
+            );
+        }
+    }
+
+    // Only admins can fix the config, so there has to be one who can log in.
+    let admins: Vec<&str> = config
+        .users
+        .iter()
+        .filter(|(_, user)| user.is_admin == Some(true))
+        .filter(|(_, user)| user.keys().any(|key| key_data(key).is_some()))
+        .map(|(name, _)| name.as_str())
+        .collect();
+    if admins.is_empty() {
+        checker.error(
+            &["users"],
+            "No admin has a valid SSH key, so nobody could change the server config again"
+                .to_string(),
+        );
+    }
+    if let Some(username) = username.filter(|username| !config.is_admin(username)) {
+        checker.warning(
+            &["users"],
+            format!("{} won't be an admin any more", username),
+        );
+    }
+
+    checker.problems
+}
+
+// Checks a repo config. Grants and push mirrors are checked against the server config if there
+// is one.
+pub fn check_repo_config(text: &str, server_config: Option<&ServerConfig>) -> Vec<Problem> {
+    let mut checker = Checker::new(text);
+    let Some(config) = checker.parse::<RepoConfig>() else {
+        return checker.problems;
+    };
+
+    if let Some(server_config) = server_config {
+        let grants = [
+            ("members", &config.members),
+            ("read", &config.access.read),
+            ("write", &config.access.write),
+            ("admin", &config.access.admin),
+        ];
+        for (field, entries) in grants {
+            let path: &[&str] = if field == "members" {
+                &["members"]
+            } else {
+                &["access", field]
+            };
+            checker.grants(path, entries, server_config);
+        }
+    }
+
+    if let Some(policy) = &config.policy {
+        if let Some(size) = &policy.max_file_size {
+            checker.size(&["policy", "max_file_size"], size);
+        }
+        for (i, protected) in policy.protect.iter().enumerate() {
+            let table = format!("protect[{}]", i);
+            if protected.refs.trim().is_empty() {
+                checker.error(
+                    &["policy", &table, "refs"],
+                    "Protected refs need a branch or ref name".to_string(),
+                );
+            }
+            if let (Some(pushers), Some(server_config)) = (&protected.pushers, server_config) {
+                checker.grants(&["policy", &table, "pushers"], pushers, server_config);
+            }
+        }
+    }
+
+    if let Some(quota) = &config.quota {
+        checker.quota(&["quota"], quota);
+    }
+    checker.webhooks(config.webhooks.as_deref().unwrap_or_default());
+    for (i, webhook) in config.webhooks.iter().flatten().enumerate() {
+        let table = format!("webhooks[{}]", i);
+        if webhook.secret.is_some() {
+            checker.error(&[&table, "secret"], REPO_SECRET_MESSAGE.to_string());
+        } else if let Some(server_config) = server_config {
+            if let Err(e) = webhooks::for_repo(webhook, server_config) {
+                checker.warning(&[&table], format!("{:#}, so it won't be sent", e));
+            }
+        }
+    }
+
+    let allowed = server_config
+        .and_then(|config| config.push_mirrors.clone())
+        .unwrap_or_default();
+    for (i, mirror) in config.push_mirrors.iter().flatten().enumerate() {
+        let table = format!("push_mirrors[{}]", i);
+        let path = [table.as_str()];
+        if mirror.url.trim().is_empty() {
+            checker.error(&path, "Push mirrors need a URL".to_string());
+        } else if server_config.is_some() {
+            if let Err(e) = push_mirrors::check(mirror, &allowed) {
+                checker.warning(&path, format!("{:#}, so it won't be pushed to", e));
+            }
+        }
+    }
+
+    checker.problems
+}
+
+// Checks config files for `check-config`, printing what's wrong with them. Without any files,
+// it checks the config repo and every repo's config under the current directory, and
+// `initial_config` if the config repo hasn't been made yet. Returns whether there were no
+// errors.
+pub async fn check_config(files: &[PathBuf], initial_config: &Path) -> anyhow::Result<bool> {
+    let mut server_files = Vec::new();
+    let mut repo_files = Vec::new();
+    if files.is_empty() {
+        if Path::new(SERVER_CONFIG_REPO).exists() {
+            let name = Path::new(SERVER_CONFIG_REPO).join(SERVER_CONFIG_FILE);
+            server_files.push((name, load_server_config_text().await?));
+        } else {
+            server_files.push((initial_config.to_path_buf(), read(initial_config)?));
+        }
+
+        let mut repos = Vec::new();
+        find_repos(Path::new("."), Path::new(""), &mut repos);
+        for repo_path in repos {
+            if Repo::mirror_of(&repo_path).await?.is_some() {
+                continue;
+            }
+            let Some(head) = Repo::head_id(&repo_path).await? else {
+                continue;
+            };
+            let data = Repo::read_file(&repo_path, head, Path::new(REPO_CONFIG_FILE)).await?;
+            let text = data
+                .map(|data| from_utf8(&data).map(str::to_string))
+                .transpose()?;
+            repo_files.push((repo_path.join(REPO_CONFIG_FILE), text));
+        }
+    } else {
+        for file in files {
+            let text = read(file)?;
+            if file
+                .file_name()
+                .is_some_and(|name| name == REPO_CONFIG_FILE)
+            {
+                repo_files.push((file.clone(), Some(text)));
+            } else {
+                server_files.push((file.clone(), text));
+            }
+        }
+    }
+
+    let mut server_config = None;
+    let mut errors = 0;
+    let mut warnings = 0;
+    let mut report = |file: &Path, problems: Vec<Problem>| {
+        for problem in &problems {
+            println!("{}", problem.describe(&file.to_string_lossy()));
+            if problem.is_error {
+                errors += 1;
+            } else {
+                warnings += 1;
+            }
+        }
+    };
+
+    for (file, text) in &server_files {
+        report(file, check_server_config(text, None));
+        if server_config.is_none() {
+            server_config = toml::from_str::<ServerConfig>(text).ok();
+        }
+    }
+
+    // Grants can only be checked against a server config, so use the running one if no other
+    // was given.
+    if server_config.is_none() && !repo_files.is_empty() {
+        server_config = load_server_config(None).await.ok();
+    }
+    for (file, text) in &repo_files {
+        let problems = match text {
+            Some(text) => check_repo_config(text, server_config.as_ref()),
+            // Without one, nobody but the server's admins can get at the repo.
+            None => vec![Problem {
+                line: None,
+                column: None,
+                message: "The repository has no repo.toml".to_string(),
+                is_error: true,
+            }],
+        };
+        report(file, problems);
+    }
+
+    let count = |n: usize, what: &str| format!("{} {}{}", n, what, if n == 1 { "" } else { "s" });
+    println!(
+        "Checked {}: {}, {}",
+        count(server_files.len() + repo_files.len(), "file"),
+        count(errors, "error"),
+        count(warnings, "warning")
+    );
+    Ok(errors == 0)
+}
+
+async fn load_server_config_text() -> anyhow::Result<String> {
+    let repo_path = Path::new(SERVER_CONFIG_REPO);
+    let head = Repo::head_id(repo_path)
+        .await?
+        .context("The server config repo has no commits")?;
+    let data = Repo::read_file(repo_path, head, Path::new(SERVER_CONFIG_FILE))
+        .await?
+        .context("The server config repo has no server.toml")?;
+    Ok(from_utf8(&data)?.to_string())
+}
+
+fn read(file: &Path) -> anyhow::Result<String> {
+    std::fs::read_to_string(file).with_context(|| format!("Couldn't read {}", file.display()))
+}
+
+// The base64 data of a public key like "ssh-ed25519 AAAA... you@example.com", if it's a key
+// the server can use.
+fn key_data(key: &str) -> Option<&str> {
+    let data = key.split_whitespace().nth(1)?;
+    russh::keys::parse_public_key_base64(data).ok()?;
+    Some(data)
+}
+
+struct Checker<'a> {
+    text: &'a str,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Checker<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            problems: Vec::new(),
+        }
+    }
+
+    fn error(&mut self, path: &[&str], message: String) {
+        self.push(path, message, true);
+    }
+
+    fn warning(&mut self, path: &[&str], message: String) {
+        self.push(path, message, false);
+    }
+
+    fn push(&mut self, path: &[&str], message: String, is_error: bool) {
+        let (line, column) = match locate(self.text, path) {
+            Some((line, column)) => (Some(line), Some(column)),
+            None => (None, None),
+        };
+        self.problems.push(Problem {
+            line,
+            column,
+            message,
+            is_error,
+        });
+    }
+
+    // Parses the config, noting any syntax errors, fields with the wrong type and fields that
+    // aren't used.
+    fn parse<T: DeserializeOwned + Serialize>(&mut self) -> Option<T> {
+        let given = match toml::from_str::<Table>(self.text) {
+            Ok(given) => given,
+            Err(e) => {
+                self.toml_error(&e);
+                return None;
+            }
+        };
+        let config = match toml::from_str::<T>(self.text) {
+            Ok(config) => config,
+            Err(e) => {
+                self.toml_error(&e);
+                return None;
+            }
+        };
+
+        // Whatever the config has that doesn't survive being read and written again is unused.
+        if let Ok(Value::Table(known)) = Value::try_from(&config) {
+            self.unknown_fields(&given, &known, &mut Vec::new());
+        }
+        Some(config)
+    }
+
+    fn toml_error(&mut self, e: &toml::de::Error) {
+        let (line, column) = match e.span() {
+            Some(span) => {
+                let before = &self.text[..span.start.min(self.text.len())];
+                let line = before.matches('\n').count() + 1;
+                let column = before
+                    .rsplit('\n')
+                    .next()
+                    .unwrap_or_default()
+                    .chars()
+                    .count()
+                    + 1;
+                (Some(line), Some(column))
+            }
+            None => (None, None),
+        };
+        self.problems.push(Problem {
+            line,
+            column,
+            message: e.message().trim().to_string(),
+            is_error: true,
+        });
+    }
+
+    fn unknown_fields(&mut self, given: &Table, known: &Table, path: &mut Vec<String>) {
+        for (key, value) in given {
+            path.push(key.clone());
+            match (value, known.get(key)) {
+                (value, None) if !is_empty(value) => {
+                    let field = path.join(".");
+                    let path: Vec<&str> = path.iter().map(String::as_str).collect();
+                    self.warning(&path, format!("Unknown field {}, which is ignored", field));
+                }
+                (Value::Table(given), Some(Value::Table(known))) => {
+                    self.unknown_fields(given, known, path);
+                }
+                (Value::Array(given), Some(Value::Array(known))) => {
+                    let key = path.pop().unwrap_or_default();
+                    for (i, (given, known)) in given.iter().zip(known).enumerate() {
+                        if let (Value::Table(given), Value::Table(known)) = (given, known) {
+                            path.push(format!("{}[{}]", key, i));
+                            self.unknown_fields(given, known, path);
+                            path.pop();
+                        }
+                    }
+                    path.push(key);
+                }
+                _ => {}
+            }
+            path.pop();
+        }
+    }
+
+    fn size(&mut self, path: &[&str], size: &str) {
+        if let Err(e) = parse_size(size) {
+            self.error(path, format!("{:#}", e));
+        }
+    }
+
+    fn quota(&mut self, path: &[&str], quota: &Quota) {
+        for (field, size) in [
+            ("repo_size", &quota.repo_size),
+            ("blob_size", &quota.blob_size),
+        ] {
+            if let Some(size) = size {
+                let path: Vec<&str> = path.iter().copied().chain([field]).collect();
+                self.size(&path, size);
+            }
+        }
+    }
+
+    fn url(&mut self, path: &[&str], url: &str) {
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            self.error(path, format!("{:?} isn't an HTTP or HTTPS URL", url));
+        }
+    }
+
+    fn webhooks(&mut self, webhooks: &[WebhookConfig]) {
+        for (i, webhook) in webhooks.iter().enumerate() {
+            let table = format!("webhooks[{}]", i);
+            self.url(&[&table, "url"], &webhook.url);
+        }
+    }
+
+    // Entries granting access that don't match anyone do nothing, which is likely a typo.
+    fn grants(&mut self, path: &[&str], entries: &[String], server_config: &ServerConfig) {
+        for entry in entries {
+            let message = match entry.strip_prefix('@') {
+                Some(group) if !server_config.groups.contains_key(group) => {
+                    format!("There's no group called {}", group)
+                }
+                None if !server_config.users.contains_key(entry)
+                    && parse_pubkey(entry).is_none() =>
+                {
+                    format!("{} isn't a user on this server or an npub", entry)
+                }
+                _ => continue,
+            };
+            self.warning(path, message);
+        }
+    }
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Array(array) => array.is_empty(),
+        Value::Table(table) => table.is_empty(),
+        _ => false,
+    }
+}
+
+// Finds the line and column of a field like ["users", "alex", "public_keys[1]"], going by the
+// table headers and keys on each line. Fields in inline tables and arrays are found at the key
+// holding them.
+fn locate(text: &str, path: &[&str]) -> Option<(usize, usize)> {
+    let mut table: Vec<String> = Vec::new();
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    let mut nearest = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let column = line.len() - trimmed.len() + 1;
+
+        let header = if let Some(header) = trimmed.strip_prefix("[[") {
+            let mut keys = split_key(header.split("]]").next().unwrap_or_default());
+            let count = counts.entry(keys.join(".")).or_default();
+            if let Some(last) = keys.last_mut() {
+                *last = format!("{}[{}]", last, count);
+            }
+            *count += 1;
+            Some(keys)
+        } else {
+            trimmed
+                .strip_prefix('[')
+                .map(|header| split_key(header.split(']').next().unwrap_or_default()))
+        };
+
+        if let Some(header) = header {
+            if path.starts_with(&header.iter().map(String::as_str).collect::<Vec<_>>()) {
+                if header.len() == path.len() {
+                    return Some((i + 1, column));
+                }
+                nearest = Some((i + 1, column));
+            }
+            table = header;
+            continue;
+        }
+
+        if trimmed.starts_with('#') {
+            continue;
+        }
+        let Some((key, _)) = trimmed.split_once('=') else {
+            continue;
+        };
+
+        // Below the table header, values in arrays are found at their key.
+        let full: Vec<String> = table.iter().cloned().chain(split_key(key)).collect();
+        let path: Vec<&str> = path
+            .iter()
+            .enumerate()
+            .map(|(i, part)| {
+                if i < table.len() {
+                    *part
+                } else {
+                    part.split('[').next().unwrap_or_default()
+                }
+            })
+            .collect();
+        if path.len() >= full.len() && full.iter().zip(&path).all(|(a, b)| a == b) {
+            return Some((i + 1, column));
+        }
+    }
+
+    nearest
+}
+
+// Splits a dotted key like `users."alex".quota` into its parts.
+fn split_key(key: &str) -> Vec<String> {
+    key.split('.')
+        .map(|part| part.trim().trim_matches(['"', '\'']).to_string())
+        .filter(|part| !part.is_empty())
+        .collect()
+}
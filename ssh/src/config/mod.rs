@@ -1,2 +1,107 @@
+pub mod check;
 pub mod repo;
 pub mod server;
+
+use serde::{de::IntoDeserializer, Deserialize, Serialize};
+use toml_edit::{Document, Item, Table};
+
+// Writes `config` over the TOML in `text`, keeping the comments and layout of everything it
+// doesn't change, so editing a config with a command doesn't undo how an admin wrote it.
+pub fn rewrite<T: Serialize>(text: &str, config: &T) -> anyhow::Result<String> {
+    let new: Document = toml::to_string(config)?.parse()?;
+    let Ok(mut document) = text.parse::<Document>() else {
+        return Ok(new.to_string());
+    };
+
+    merge_table(document.as_table_mut(), new.as_table());
+    Ok(document.to_string())
+}
+
+fn merge_table(old: &mut Table, new: &Table) {
+    old.retain(|key, _| new.contains_key(key));
+
+    for (key, new_item) in new.iter() {
+        match old.get_mut(key) {
+            Some(old_item) => merge_item(old_item, new_item),
+            None => {
+                let mut item = new_item.clone();
+                // New tables go after the table's last one, rather than where they were in the
+                // new document.
+                if let Some(position) = last_position(old) {
+                    set_position(&mut item, position);
+                }
+                old.insert(key, item);
+            }
+        }
+    }
+}
+
+fn merge_item(old: &mut Item, new: &Item) {
+    match (old, new) {
+        (Item::Table(old), Item::Table(new)) => merge_table(old, new),
+        (Item::ArrayOfTables(old), Item::ArrayOfTables(new)) => {
+            while old.len() > new.len() {
+                old.remove(old.len() - 1);
+            }
+            for (i, new) in new.iter().enumerate() {
+                match old.get_mut(i) {
+                    Some(old) => merge_table(old, new),
+                    None => {
+                        let mut table = new.clone();
+                        if let Some(position) = old.iter().filter_map(last_position).max() {
+                            table.set_position(position);
+                        }
+                        old.push(table);
+                    }
+                }
+            }
+        }
+        (Item::Value(old), Item::Value(new)) => {
+            if !same_value(old, new) {
+                let decor = old.decor().clone();
+                *old = new.clone();
+                *old.decor_mut() = decor;
+            }
+        }
+        (old, new) => *old = new.clone(),
+    }
+}
+
+// Compares values by what they hold rather than how they're written.
+fn same_value(a: &toml_edit::Value, b: &toml_edit::Value) -> bool {
+    let parse = |v: &toml_edit::Value| toml::Value::deserialize(v.clone().into_deserializer());
+    matches!((parse(a), parse(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+// Where the last table in `table`, or the table itself, is in the document.
+fn last_position(table: &Table) -> Option<usize> {
+    table
+        .iter()
+        .filter_map(|(_, item)| match item {
+            Item::Table(table) => last_position(table),
+            Item::ArrayOfTables(tables) => tables.iter().filter_map(last_position).max(),
+            _ => None,
+        })
+        .chain(table.position())
+        .max()
+}
+
+fn set_position(item: &mut Item, position: usize) {
+    match item {
+        Item::Table(table) => {
+            table.set_position(position);
+            for (_, item) in table.iter_mut() {
+                set_position(item, position);
+            }
+        }
+        Item::ArrayOfTables(tables) => {
+            for table in tables.iter_mut() {
+                table.set_position(position);
+                for (_, item) in table.iter_mut() {
+                    set_position(item, position);
+                }
+            }
+        }
+        _ => {}
+    }
+}
@@ -1,34 +1,169 @@
 use std::{
     fs::{read_to_string, write},
     path::{Path, PathBuf},
+    str::from_utf8,
 };
 
 use anyhow::Context;
+use gix::ObjectId;
 use serde::{Deserialize, Serialize};
 use tempfile::tempdir;
 use toml::Table;
 
-use crate::{git::Repo, vars::*};
+use crate::{
+    config::{rewrite, server::ServerConfig},
+    git::Repo,
+    policy::PushPolicy,
+    push_mirrors::PushMirror,
+    quota::Quota,
+    vars::*,
+    webhooks::WebhookConfig,
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLevel {
+    None,
+    Read,
+    Write,
+    Admin,
+}
 
-#[derive(Serialize, Deserialize)]
+impl AccessLevel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AccessLevel::None => "none",
+            AccessLevel::Read => "read",
+            AccessLevel::Write => "write",
+            AccessLevel::Admin => "admin",
+        }
+    }
+}
+
+// Explicit grants, each level implying the ones below it. Entries are usernames or "@group".
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct RepoAccess {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub read: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub write: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub admin: Vec<String>,
+    // Overrides the server's anonymous policy for this repo.
+    pub anonymous: Option<AccessLevel>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct RepoConfig {
     pub name: String,
+    // Public repos can be read by every user, and anonymously unless the policy says otherwise.
     pub public: bool,
+    // Shorthand for write access.
+    #[serde(default)]
     pub members: Vec<String>,
     pub failed_push_message: Option<String>,
     pub web_template: Option<String>,
+    pub webhooks: Option<Vec<WebhookConfig>>,
+    // Remotes to push to after every push to the repo, such as backups.
+    pub push_mirrors: Option<Vec<PushMirror>>,
     pub extra: Option<Table>,
+    #[serde(default)]
+    pub access: RepoAccess,
+    pub policy: Option<PushPolicy>,
+    // Stricter limits than the server's quota for this repo.
+    pub quota: Option<Quota>,
 }
 
-pub async fn load_repo_config(repo_path: &Path) -> anyhow::Result<RepoConfig> {
+impl RepoConfig {
+    // A private repo named after its path, with no grants.
+    pub fn new(repo_path: &Path) -> Self {
+        Self {
+            name: repo_path.to_string_lossy().to_string(),
+            public: false,
+            members: Vec::new(),
+            failed_push_message: None,
+            web_template: None,
+            webhooks: None,
+            push_mirrors: None,
+            extra: None,
+            access: RepoAccess::default(),
+            policy: None,
+            quota: None,
+        }
+    }
+
+    // Works out what a user, or an anonymous one if there's no username, can do with the repo.
+    pub fn access_level(
+        &self,
+        server_config: &ServerConfig,
+        username: Option<&str>,
+    ) -> AccessLevel {
+        let anonymous = self.anonymous_access(server_config);
+
+        let Some(username) = username else {
+            return anonymous;
+        };
+
+        let granted = |entries: &[String]| {
+            entries
+                .iter()
+                .any(|entry| server_config.grant_matches(entry, username))
+        };
+
+        let level = if server_config.is_admin(username) || granted(&self.access.admin) {
+            AccessLevel::Admin
+        } else if granted(&self.access.write) || granted(&self.members) {
+            AccessLevel::Write
+        } else if granted(&self.access.read) || self.public {
+            AccessLevel::Read
+        } else {
+            AccessLevel::None
+        };
+
+        level.max(anonymous)
+    }
+
+    fn anonymous_access(&self, server_config: &ServerConfig) -> AccessLevel {
+        let level = match self.access.anonymous {
+            Some(level) => level,
+            None if self.public => server_config.anonymous.unwrap_or(AccessLevel::Read),
+            None => AccessLevel::None,
+        };
+
+        level.min(AccessLevel::Read)
+    }
+}
+
+// Reads the repo config as of `commit`. The server's state keeps a cache of these.
+pub async fn load_repo_config(repo_path: &Path, commit: ObjectId) -> anyhow::Result<RepoConfig> {
+    let data = Repo::read_file(repo_path, commit, Path::new(REPO_CONFIG_FILE))
+        .await?
+        .context("Couldn't find repo.toml")?;
+    let text = from_utf8(&data).context("repo.toml isn't valid UTF-8")?;
+    Ok(toml::from_str(text)?)
+}
+
+// Edits the repo config and commits the change as `author`.
+pub async fn update_repo_config(
+    repo_path: &Path,
+    author: &str,
+    message: &str,
+    update: impl FnOnce(&mut RepoConfig) -> anyhow::Result<()>,
+) -> anyhow::Result<RepoConfig> {
     let config_name = PathBuf::from(REPO_CONFIG_FILE);
 
     let temp_dir = tempdir()?;
     let clone_dir = temp_dir.path().join(repo_path);
-    Repo::clone(repo_path, &clone_dir).await?;
+    let repo = Repo::clone(repo_path, &clone_dir).await?;
 
-    let text = read_to_string(clone_dir.join(&config_name)).context("Couldn't read eejit.toml")?;
-    Ok(toml::from_str(&text)?)
+    let text = read_to_string(clone_dir.join(&config_name)).context("Couldn't read repo.toml")?;
+    let mut config: RepoConfig = toml::from_str(&text)?;
+    update(&mut config)?;
+
+    let text = rewrite(&text, &config)?;
+    write(clone_dir.join(config_name), text).context("Could not write repo config")?;
+    repo.push_changes_by(message, author).await?;
+    Ok(config)
 }
 
 pub async fn new_repo_config(repo_path: &Path, username: &str) -> anyhow::Result<()> {
@@ -38,14 +173,18 @@ pub async fn new_repo_config(repo_path: &Path, username: &str) -> anyhow::Result
     let clone_dir = temp_dir.path().join(repo_path);
     let repo = Repo::clone(repo_path, &clone_dir).await?;
 
-    let config = RepoConfig {
-        name: repo_path.to_str().unwrap().to_string(),
-        public: false,
-        members: vec![username.to_string()],
-        failed_push_message: None,
-        extra: None,
-        web_template: None,
-    };
+    let mut config = RepoConfig::new(repo_path);
+    config.access.admin = vec![username.to_string()];
+
+    // Keep any mirrors the first push listed, so it's mirrored along with the rest.
+    #[derive(Deserialize)]
+    struct Pushed {
+        push_mirrors: Option<Vec<PushMirror>>,
+    }
+    let pushed = std::fs::read_to_string(clone_dir.join(&config_name)).ok();
+    if let Some(pushed) = pushed.and_then(|text| toml::from_str::<Pushed>(&text).ok()) {
+        config.push_mirrors = pushed.push_mirrors;
+    }
 
     let text = toml::to_string(&config)?;
     write(clone_dir.join(config_name), text).context("Could not write default repo config")?;
@@ -1,20 +1,45 @@
 use anyhow::{anyhow, Context};
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    fs::{copy, read_to_string, remove_file},
-    path::PathBuf,
+    collections::BTreeMap,
+    fs::{copy, read_to_string, remove_dir_all, remove_file, write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 use tempfile::tempdir;
 use toml::Table;
 
-use crate::{git::Repo, vars::*};
+use crate::{
+    certificates::CertificateConfig,
+    config::{repo::AccessLevel, rewrite},
+    daemon::GitDaemonConfig,
+    firewall::FirewallConfig,
+    git::Repo,
+    nostr::{parse_pubkey, Event, SSH_KEY_BINDING_KIND},
+    push_mirrors::PushMirrorsConfig,
+    quota::Quota,
+    vars::*,
+    webhooks::WebhookConfig,
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ServerUser {
-    pub public_key: String,
+    // Either field can be used, or both, e.g. to keep an old key while adding new ones.
+    pub public_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub public_keys: Vec<String>,
     pub is_admin: Option<bool>,
     pub can_create_repos: Option<bool>,
+    // The user's Nostr public key, as an npub or hex. It logs them in to the web server, and SSH
+    // keys can be tied to it instead of being listed here.
+    pub npub: Option<String>,
+    // Overrides the server's quota for this user's repos.
+    pub quota: Option<Quota>,
+    // Events signed by the npub, each tying the SSH key in its "ssh-key" tag to the user.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub key_bindings: Vec<Event>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -22,61 +47,262 @@ pub struct ServerConfig {
     pub name: String,
     pub hostname: String,
     pub port: u16,
-    pub users: HashMap<String, ServerUser>,
+    // Kept in order so that rewriting server.toml gives a readable diff.
+    pub users: BTreeMap<String, ServerUser>,
+    // Named sets of users, which repos can grant access to as "@name".
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<String>>,
+    // What users without a known key get on public repos, read by default. Repos can
+    // override this, but anonymous users never get more than read access.
+    pub anonymous: Option<AccessLevel>,
     pub welcome_message: Option<String>,
+    // Addresses to listen on, all IPv4 interfaces on `port` by default.
+    pub listen: Option<Vec<SocketAddr>>,
+    // OpenSSH private host keys. By default `server_key` is used, and generated if needed.
+    pub host_keys: Option<Vec<PathBuf>>,
+    // Connection limits and bans for failed logins, which apply with their defaults if unset.
+    pub firewall: Option<FirewallConfig>,
+    // Certificate authorities whose user certificates log in as the user named by a principal.
+    pub certificates: Option<CertificateConfig>,
+    // How much LFS storage each repo may use, like "10GiB". Unlimited if unset.
+    pub lfs_quota: Option<String>,
+    // Limits on the size and number of repos, which users' own quotas can override.
+    pub quota: Option<Quota>,
+    // A read-only git:// listener for the repos anonymous users can read. Off unless set.
+    pub git_daemon: Option<GitDaemonConfig>,
+    // The web server's public address, like "https://git.example.com", for links in the
+    // summary shown after a push. There are no links if unset.
+    pub web_url: Option<String>,
+    // Called after every push to any repo.
+    pub webhooks: Option<Vec<WebhookConfig>>,
+    // Hosts repos' own webhooks may call. Repos' webhooks to any others aren't sent.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhook_hosts: Vec<String>,
+    // Secrets repos' own webhooks can sign deliveries with, by name, as repos can't keep secrets.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub webhook_secrets: BTreeMap<String, String>,
+    // The local directories and SSH deploy keys repos' push mirrors can use.
+    pub push_mirrors: Option<PushMirrorsConfig>,
     pub exta: Option<Table>,
+    // Each key's owner and type, by its base64 data. Built by `index_keys` when the config is
+    // loaded or changed, so bindings' signatures aren't checked again on every login.
+    #[serde(skip)]
+    key_owners: Arc<BTreeMap<String, (String, String)>>,
 }
 
 // The default for ServerUser is used for guest access.
 impl Default for ServerUser {
     fn default() -> Self {
         Self {
-            public_key: String::from(""),
+            public_key: None,
+            public_keys: Vec::new(),
             is_admin: Some(false),
             can_create_repos: Some(false),
+            npub: None,
+            quota: None,
+            key_bindings: Vec::new(),
         }
     }
 }
 
-pub async fn load_server_config() -> anyhow::Result<ServerConfig> {
+// Reads the server config from its repo, first creating the repo from `initial_config` if it
+// doesn't exist yet.
+pub async fn load_server_config(initial_config: Option<&Path>) -> anyhow::Result<ServerConfig> {
     let repo_name = PathBuf::from(SERVER_CONFIG_REPO);
     let config_name = PathBuf::from(SERVER_CONFIG_FILE);
 
-    let mut new = false;
     if !repo_name.exists() {
-        if !config_name.exists() {
+        let Some(initial_config) = initial_config.filter(|path| path.exists()) else {
             return Err(anyhow!(
                 "There's no server config, and no initial config to move in!"
             ));
-        }
+        };
+
         Repo::create_bare(&repo_name).await?;
-        new = true;
+        if let Err(e) = move_in_initial_config(initial_config).await {
+            // Leave nothing half made behind, so the next start tries again.
+            let _ = remove_dir_all(&repo_name);
+            return Err(e);
+        }
     }
 
+    let temp_dir = tempdir()?;
+    let clone_dir = temp_dir.path().join(&repo_name);
+    Repo::clone(&repo_name, &clone_dir).await?;
+
+    let text = read_to_string(clone_dir.join(&config_name)).context("Couldn't read server.toml")?;
+    let mut config: ServerConfig = toml::from_str(&text)?;
+    config.index_keys();
+    Ok(config)
+}
+
+// Commits `initial_config` to the new, empty config repo. A config kept elsewhere is left where
+// it is, as the operator may still want it, but one in the scan path, where the default is, is
+// moved in, since from then on it's the repo that counts.
+async fn move_in_initial_config(initial_config: &Path) -> anyhow::Result<()> {
+    let repo_name = PathBuf::from(SERVER_CONFIG_REPO);
+
     let temp_dir = tempdir()?;
     let clone_dir = temp_dir.path().join(&repo_name);
     let repo = Repo::clone(&repo_name, &clone_dir).await?;
 
-    if new {
-        copy(&config_name, clone_dir.join(&config_name))?;
-        remove_file(&config_name)?;
-        repo.push_changes("chore: move in initial config").await?;
+    copy(initial_config, clone_dir.join(SERVER_CONFIG_FILE))
+        .with_context(|| format!("Couldn't read {}", initial_config.display()))?;
+    repo.push_changes("chore: move in initial config").await?;
+
+    if std::path::absolute(SERVER_CONFIG_FILE).is_ok_and(|default| initial_config == default) {
+        if let Err(e) = remove_file(initial_config) {
+            warn!(
+                "Couldn't remove {} after moving it in: {}",
+                initial_config.display(),
+                e
+            );
+        }
     }
 
+    Ok(())
+}
+
+// Edits the server config and commits the change as `author`.
+pub async fn update_server_config(
+    author: &str,
+    message: &str,
+    update: impl FnOnce(&mut ServerConfig) -> anyhow::Result<()>,
+) -> anyhow::Result<ServerConfig> {
+    let repo_name = PathBuf::from(SERVER_CONFIG_REPO);
+    let config_name = PathBuf::from(SERVER_CONFIG_FILE);
+
+    let temp_dir = tempdir()?;
+    let clone_dir = temp_dir.path().join(&repo_name);
+    let repo = Repo::clone(&repo_name, &clone_dir).await?;
+
     let text = read_to_string(clone_dir.join(&config_name)).context("Couldn't read server.toml")?;
-    Ok(toml::from_str(&text)?)
+    let mut config: ServerConfig = toml::from_str(&text)?;
+    config.index_keys();
+    update(&mut config)?;
+    config.index_keys();
+
+    let text = rewrite(&text, &config)?;
+    write(clone_dir.join(config_name), text).context("Could not write server config")?;
+    repo.push_changes_by(message, author).await?;
+    Ok(config)
+}
+
+impl ServerUser {
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.public_key.iter().chain(&self.public_keys)
+    }
+
+    // The user's npub in hex, if they've given a valid one.
+    pub fn pubkey(&self) -> Option<String> {
+        self.npub.as_deref().and_then(parse_pubkey)
+    }
+
+    // The SSH keys tied to the user's npub by binding events it signed.
+    pub fn bound_keys(&self) -> Vec<&str> {
+        let Some(pubkey) = self.pubkey() else {
+            return Vec::new();
+        };
+
+        self.key_bindings
+            .iter()
+            .filter(|event| {
+                event.kind == SSH_KEY_BINDING_KIND
+                    && event.pubkey.eq_ignore_ascii_case(&pubkey)
+                    && event.verify().is_ok()
+            })
+            .filter_map(|event| event.tag("ssh-key"))
+            .collect()
+    }
 }
 
 impl ServerConfig {
+    // Finds who owns each key, checking key bindings once. A key listed by more than one user
+    // belongs to the first of them.
+    pub fn index_keys(&mut self) {
+        let mut key_owners = BTreeMap::new();
+        for (name, user) in &self.users {
+            for key in user.keys().map(String::as_str).chain(user.bound_keys()) {
+                let mut parts = key.split_whitespace();
+                if let (Some(kind), Some(data)) = (parts.next(), parts.next()) {
+                    key_owners
+                        .entry(data.to_string())
+                        .or_insert_with(|| (name.clone(), kind.to_string()));
+                }
+            }
+        }
+        self.key_owners = Arc::new(key_owners);
+    }
+
+    // Finds the user owning the given base64 key data.
     pub fn get_user(&self, key: &str) -> Option<(String, ServerUser)> {
-        for user in self.users.keys() {
-            let key_data = self.users[user].public_key.split(' ').nth(1).unwrap();
+        let (name, _) = self.key_owners.get(key)?;
+        let user = self.users.get(name)?;
+        Some((name.clone(), user.clone()))
+    }
 
-            if key == key_data && self.users[user].is_admin.unwrap_or(false) {
-                return Some((user.to_string(), self.users[user].clone()));
-            }
+    // Every known key as its owner, type and base64 data.
+    pub fn owned_keys(&self) -> impl Iterator<Item = (&str, &str, &str)> {
+        self.key_owners
+            .iter()
+            .map(|(data, (name, kind))| (name.as_str(), kind.as_str(), data.as_str()))
+    }
+
+    // How commits made on behalf of a user are attributed.
+    pub fn author(&self, username: &str) -> String {
+        format!("{} <{}@{}>", username, username, self.hostname)
+    }
+
+    // Finds the user whose npub is the given public key, in hex.
+    pub fn user_by_pubkey(&self, pubkey: &str) -> Option<&str> {
+        self.users
+            .iter()
+            .find(|(_, user)| user.pubkey().as_deref() == Some(pubkey))
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn groups_of(&self, username: &str) -> Vec<&str> {
+        let mut groups: Vec<&str> = self
+            .groups
+            .iter()
+            .filter(|(_, members)| members.iter().any(|member| self.names(member, username)))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        groups.sort_unstable();
+        groups
+    }
+
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.users
+            .get(username)
+            .and_then(|user| user.is_admin)
+            .unwrap_or(false)
+    }
+
+    // Whether an entry in a grant list, either a username, an npub or "@group", covers the user.
+    pub fn grant_matches(&self, entry: &str, username: &str) -> bool {
+        match entry.strip_prefix('@') {
+            Some(group) => self
+                .groups
+                .get(group)
+                .is_some_and(|members| members.iter().any(|member| self.names(member, username))),
+            None => self.names(entry, username),
         }
+    }
 
-        None
+    // Whether a username or npub is the user's. People logged in to the web with an npub the
+    // server doesn't know go by the npub itself.
+    fn names(&self, entry: &str, username: &str) -> bool {
+        if entry == username {
+            return true;
+        }
+        let Some(pubkey) = parse_pubkey(entry) else {
+            return false;
+        };
+        let user_pubkey = match self.users.get(username) {
+            Some(user) => user.pubkey(),
+            None => parse_pubkey(username),
+        };
+        user_pubkey == Some(pubkey)
     }
 }
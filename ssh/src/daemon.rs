@@ -0,0 +1,281 @@
+// A read-only git:// listener, speaking the protocol git-daemon does, for clients that can only
+// fetch that way. It serves whatever anonymous users can read over SSH.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    process::Stdio,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use futures::future::try_join_all;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    process::Command,
+    sync::Mutex,
+    time::timeout,
+};
+
+use crate::audit::AuditEntry;
+use crate::config::repo::AccessLevel;
+use crate::ssh::parse_repo_path;
+use crate::state::State;
+use crate::vars::*;
+
+const DEFAULT_PORT: u16 = 9418;
+const DEFAULT_MAX_CONNECTIONS: usize = 64;
+const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 4;
+// How long a client has to say which repo it wants.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// How long git-upload-pack waits on a quiet client before giving up.
+const UPLOAD_PACK_TIMEOUT: u64 = 300;
+
+// The server's [git_daemon] table. The listener only runs if there is one.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct GitDaemonConfig {
+    // Addresses to listen on, all IPv4 interfaces on port 9418 by default.
+    pub listen: Option<Vec<SocketAddr>>,
+    // How many clients can be connected at once, in all and from each IP address.
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+}
+
+// Serves git:// until the listeners fail, or returns straight away if it isn't configured.
+pub async fn run(state: Arc<Mutex<State>>) -> anyhow::Result<()> {
+    let Some(config) = state.lock().await.server_config.git_daemon.clone() else {
+        return Ok(());
+    };
+
+    let listen = config
+        .listen
+        .unwrap_or_else(|| vec![SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT))]);
+    let connections = Arc::new(Connections {
+        max: config.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS),
+        max_per_ip: config
+            .max_connections_per_ip
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_IP),
+        open: StdMutex::new(HashMap::new()),
+    });
+
+    try_join_all(listen.into_iter().map(|addr| {
+        let state = state.clone();
+        let connections = connections.clone();
+        async move {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to listen for git:// on {}", addr))?;
+            info!("Listening for git:// on {}", addr);
+            accept(listener, state, connections).await
+        }
+    }))
+    .await?;
+
+    Ok(())
+}
+
+async fn accept(
+    listener: TcpListener,
+    state: Arc<Mutex<State>>,
+    connections: Arc<Connections>,
+) -> anyhow::Result<()> {
+    loop {
+        let (mut stream, address) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to accept a git:// connection: {}", e);
+                continue;
+            }
+        };
+
+        let Some(slot) = Connections::open(&connections, address.ip()) else {
+            tokio::spawn(async move {
+                let _ = send_error(&mut stream, "Too many connections, try again later.").await;
+            });
+            continue;
+        };
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, address, state).await {
+                error!("git:// connection from {} failed: {:#}", address, e);
+            }
+            drop(slot);
+        });
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+) -> anyhow::Result<()> {
+    let request = timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .context("Timed out waiting for a request")??;
+
+    let repo_path = parse_repo_path(&request.path);
+    let mut audit = AuditEntry::new(
+        None,
+        None,
+        Some(address),
+        &request.command,
+        repo_path.as_deref().unwrap_or(Path::new(&request.path)),
+    );
+    audit.transport = "git".to_string();
+
+    let refusal = if request.command == GIT_PUSH_COMMAND {
+        Some("Pushing isn't possible over git://, use SSH instead.")
+    } else if request.command != GIT_FETCH_COMMAND {
+        Some("Only fetching is possible over git://.")
+    } else {
+        None
+    };
+    if let Some(message) = refusal {
+        send_error(&mut stream, message).await?;
+        audit.refused(message).record().await;
+        return Ok(());
+    }
+
+    // Private repos look the same as missing ones, so nobody can find out which exist.
+    let readable = match &repo_path {
+        Some(repo_path) if repo_path.exists() => {
+            let configs = state.lock().await.configs();
+            let level = configs.access_level(repo_path, None).await;
+            level.is_ok_and(|level| level >= AccessLevel::Read)
+        }
+        _ => false,
+    };
+    let Some(repo_path) = repo_path.filter(|_| readable) else {
+        let message = "That repository doesn't exist.";
+        send_error(&mut stream, message).await?;
+        audit.refused(message).record().await;
+        return Ok(());
+    };
+
+    let mut shell = Command::new(GIT_FETCH_COMMAND)
+        .arg("--strict")
+        .arg(format!("--timeout={}", UPLOAD_PACK_TIMEOUT))
+        .arg(&repo_path)
+        .envs(request.protocol.map(|protocol| ("GIT_PROTOCOL", protocol)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let mut stdin = shell.stdin.take().unwrap();
+    let mut stdout = shell.stdout.take().unwrap();
+    let (mut reader, mut writer) = stream.into_split();
+
+    // git-upload-pack is done once it closes its output, whatever the client is still sending.
+    let input = tokio::spawn(async move { tokio::io::copy(&mut reader, &mut stdin).await });
+    let sent = tokio::io::copy(&mut stdout, &mut writer).await;
+    input.abort();
+
+    let status = shell.wait().await?.code().unwrap_or(128) as u32;
+    audit.status = Some(status);
+    if let Err(e) = sent {
+        audit.error = Some(e.to_string());
+    }
+    audit.record().await;
+    Ok(())
+}
+
+// The first packet of a git:// connection, like "git-upload-pack /repo.git\0host=example.com\0",
+// with extra parameters such as "version=2" after another NUL.
+struct Request {
+    command: String,
+    path: String,
+    protocol: Option<String>,
+}
+
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<Request> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length).await?;
+    let length = std::str::from_utf8(&length)
+        .ok()
+        .and_then(|length| usize::from_str_radix(length, 16).ok())
+        .filter(|length| (5..=65520).contains(length))
+        .context("The request isn't a pkt-line")?;
+
+    let mut data = vec![0; length - 4];
+    stream.read_exact(&mut data).await?;
+    let data = String::from_utf8(data).context("The request isn't UTF-8")?;
+
+    let mut parts = data.split('\0');
+    let Some((command, path)) = parts
+        .next()
+        .and_then(|line| line.trim_end().split_once(' '))
+    else {
+        bail!("The request has no command");
+    };
+
+    // The host comes first, then the extra parameters after an empty part.
+    let extra: Vec<&str> = parts
+        .skip_while(|part| !part.is_empty())
+        .filter(|part| !part.is_empty())
+        .collect();
+
+    Ok(Request {
+        command: command.to_string(),
+        path: path.to_string(),
+        protocol: (!extra.is_empty()).then(|| extra.join(":")),
+    })
+}
+
+// Errors are sent as an "ERR" packet, which git shows as "remote error: ...".
+async fn send_error(stream: &mut TcpStream, message: &str) -> anyhow::Result<()> {
+    let line = format!("ERR {}\n", message);
+    stream
+        .write_all(format!("{:04x}{}", line.len() + 4, line).as_bytes())
+        .await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// Counts the open connections, so one client can't take up all of them.
+struct Connections {
+    max: usize,
+    max_per_ip: usize,
+    open: StdMutex<HashMap<IpAddr, usize>>,
+}
+
+impl Connections {
+    fn open(connections: &Arc<Self>, ip: IpAddr) -> Option<Slot> {
+        let mut open = connections.open.lock().unwrap();
+        let total: usize = open.values().sum();
+        let from_ip = open.get(&ip).copied().unwrap_or(0);
+        if total >= connections.max || from_ip >= connections.max_per_ip {
+            return None;
+        }
+
+        *open.entry(ip).or_default() += 1;
+        Some(Slot {
+            connections: connections.clone(),
+            ip,
+        })
+    }
+}
+
+// An open connection, which frees its place when dropped.
+struct Slot {
+    connections: Arc<Connections>,
+    ip: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,258 @@
+// Keeps scanners and brute-forcers from tying up the SSH server: limits on connections and on git
+// processes, temporary bans for addresses that keep failing to log in, and lists of networks to
+// always let in or keep out.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::audit::AuditEntry;
+
+const DEFAULT_MAX_CONNECTIONS: usize = 512;
+const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 32;
+const DEFAULT_MAX_AUTH_FAILURES: u32 = 5;
+const DEFAULT_BAN_TIME: u64 = 15 * 60;
+const DEFAULT_MAX_GIT_PROCESSES: usize = 64;
+
+// The server's [firewall] table. Everything has a default, so it applies without one too.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct FirewallConfig {
+    // Networks like "10.0.0.0/8" that are never limited or banned, and ones that are always
+    // refused. Being allowed wins, so a hole can be made in a denied network.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<IpNet>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<IpNet>,
+    // How many clients can be connected at once, in all and from each IP address.
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    // How many failed logins get an address banned, and for how many seconds. Failures are
+    // forgotten once the ban time has passed without reaching the limit.
+    pub max_auth_failures: Option<u32>,
+    pub ban_time: Option<u64>,
+    // How many git commands can run at once, for everyone together.
+    pub max_git_processes: Option<usize>,
+}
+
+impl FirewallConfig {
+    fn allows(&self, ip: IpAddr) -> bool {
+        self.allow.iter().any(|net| net.contains(&ip))
+    }
+
+    fn denies(&self, ip: IpAddr) -> bool {
+        self.deny.iter().any(|net| net.contains(&ip))
+    }
+
+    fn ban_time(&self) -> Duration {
+        Duration::from_secs(self.ban_time.unwrap_or(DEFAULT_BAN_TIME))
+    }
+}
+
+// Why a connection was turned away.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    Denied,
+    Banned,
+    TooManyConnections,
+}
+
+// Counts for the web server's metrics. Totals are since the server started.
+#[derive(Clone, Copy, Default)]
+pub struct FirewallStats {
+    pub connections: usize,
+    pub git_processes: usize,
+    pub banned: usize,
+    pub bans: u64,
+    pub auth_failures: u64,
+    pub refused_denied: u64,
+    pub refused_banned: u64,
+    pub refused_busy: u64,
+    pub git_processes_refused: u64,
+}
+
+#[derive(Default)]
+pub struct Firewall {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    connections: HashMap<IpAddr, usize>,
+    // How many times each address has failed to log in, since when.
+    failures: HashMap<IpAddr, (u32, Instant)>,
+    // When each banned address is let back in.
+    bans: HashMap<IpAddr, Instant>,
+    git_processes: usize,
+    stats: FirewallStats,
+}
+
+impl Inner {
+    fn is_banned(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        self.bans.retain(|_, until| *until > now);
+        self.bans.contains_key(&ip)
+    }
+}
+
+impl Firewall {
+    // Lets a client in if it's allowed to connect, returning a slot that keeps its place until
+    // dropped.
+    pub fn connect(
+        firewall: &Arc<Self>,
+        config: &FirewallConfig,
+        ip: IpAddr,
+    ) -> Result<Connection, Refusal> {
+        let ip = ip.to_canonical();
+        let mut inner = firewall.inner.lock().unwrap();
+
+        if !config.allows(ip) {
+            let refusal = if config.denies(ip) {
+                Some(Refusal::Denied)
+            } else if inner.is_banned(ip) {
+                Some(Refusal::Banned)
+            } else {
+                let total: usize = inner.connections.values().sum();
+                let from_ip = inner.connections.get(&ip).copied().unwrap_or(0);
+                let max = config.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS);
+                let max_per_ip = config
+                    .max_connections_per_ip
+                    .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_IP);
+                (total >= max || from_ip >= max_per_ip).then_some(Refusal::TooManyConnections)
+            };
+
+            if let Some(refusal) = refusal {
+                match refusal {
+                    Refusal::Denied => inner.stats.refused_denied += 1,
+                    Refusal::Banned => inner.stats.refused_banned += 1,
+                    Refusal::TooManyConnections => inner.stats.refused_busy += 1,
+                }
+                return Err(refusal);
+            }
+        }
+
+        *inner.connections.entry(ip).or_default() += 1;
+        Ok(Connection {
+            firewall: firewall.clone(),
+            ip,
+        })
+    }
+
+    pub fn is_banned(&self, config: &FirewallConfig, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        !config.allows(ip) && self.inner.lock().unwrap().is_banned(ip)
+    }
+
+    // Counts a failed login from `address`, banning it if that's one too many. Returns whether
+    // it's now banned.
+    pub async fn auth_failed(&self, config: &FirewallConfig, address: SocketAddr) -> bool {
+        let ip = address.ip().to_canonical();
+        let ban_time = config.ban_time();
+        let max_failures = config
+            .max_auth_failures
+            .unwrap_or(DEFAULT_MAX_AUTH_FAILURES);
+
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.stats.auth_failures += 1;
+            if config.allows(ip) {
+                return false;
+            }
+            if inner.is_banned(ip) {
+                return true;
+            }
+
+            let now = Instant::now();
+            inner
+                .failures
+                .retain(|_, (_, since)| now.duration_since(*since) < ban_time);
+            let (failures, _) = inner.failures.entry(ip).or_insert((0, now));
+            *failures += 1;
+            if *failures < max_failures {
+                return false;
+            }
+
+            inner.failures.remove(&ip);
+            inner.bans.insert(ip, now + ban_time);
+            inner.stats.bans += 1;
+        }
+
+        let message = format!(
+            "Banned for {} seconds after too many failed logins",
+            ban_time.as_secs()
+        );
+        warn!("{}: {}", ip, message);
+        AuditEntry::new(None, None, Some(address), "ban", Path::new(""))
+            .refused(&message)
+            .record()
+            .await;
+        true
+    }
+
+    // Makes room for a git command, returning a slot that keeps its place until dropped, or
+    // None if too many are running already.
+    pub fn start_git_process(firewall: &Arc<Self>, config: &FirewallConfig) -> Option<GitProcess> {
+        let max = config
+            .max_git_processes
+            .unwrap_or(DEFAULT_MAX_GIT_PROCESSES);
+        let mut inner = firewall.inner.lock().unwrap();
+        if inner.git_processes >= max {
+            inner.stats.git_processes_refused += 1;
+            return None;
+        }
+
+        inner.git_processes += 1;
+        Some(GitProcess {
+            firewall: firewall.clone(),
+        })
+    }
+
+    pub fn stats(&self) -> FirewallStats {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner.bans.retain(|_, until| *until > now);
+
+        FirewallStats {
+            connections: inner.connections.values().sum(),
+            git_processes: inner.git_processes,
+            banned: inner.bans.len(),
+            ..inner.stats
+        }
+    }
+}
+
+// An open connection, which frees its place when dropped.
+pub struct Connection {
+    firewall: Arc<Firewall>,
+    ip: IpAddr,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut inner = self.firewall.inner.lock().unwrap();
+        if let Some(count) = inner.connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                inner.connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// A running git command, which frees its place when dropped.
+pub struct GitProcess {
+    firewall: Arc<Firewall>,
+}
+
+impl Drop for GitProcess {
+    fn drop(&mut self) {
+        self.firewall.inner.lock().unwrap().git_processes -= 1;
+    }
+}
@@ -1,14 +1,27 @@
 use std::{
+    collections::BTreeMap,
+    fs::read_dir,
     path::{Path, PathBuf},
-    process::ExitStatus,
+    process::{ExitStatus, Output, Stdio},
 };
 
 use anyhow::{anyhow, Context};
+use gix::ObjectId;
+use tokio::io::AsyncWriteExt;
 
 pub struct Repo {
     dir: PathBuf,
 }
 
+/// A repo kept up to date from an upstream, along with whether it's public and who else can
+/// read it, as recorded by the web server's mirror task. Mirrors made any other way have no
+/// grants.
+pub struct Mirror {
+    pub upstream: String,
+    pub public: bool,
+    pub members: Vec<String>,
+}
+
 trait ExitOK {
     fn exit_ok(self) -> anyhow::Result<()>;
 }
@@ -90,7 +103,319 @@ impl Repo {
         })
     }
 
+    /// Returns the upstream URL if the bare repo at `path` is a mirror, as set up by
+    /// `git clone --mirror` or the web server's mirror task.
+    pub async fn mirror_of(path: &Path) -> anyhow::Result<Option<String>> {
+        Ok(Self::mirror(path).await?.map(|mirror| mirror.upstream))
+    }
+
+    /// Returns how the bare repo at `path` is mirrored, if it is. This is read on every access
+    /// check, so goes to the repo's config file in-process rather than asking `git config`.
+    pub async fn mirror(path: &Path) -> anyhow::Result<Option<Mirror>> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            // Only the repo's own config says whether it's a mirror.
+            let repo = gix::open_opts(&path, gix::open::Options::isolated())
+                .context("Failed to open repo")?;
+            let config = repo.config_snapshot();
+            if config.boolean("remote.origin.mirror") != Some(true) {
+                return Ok(None);
+            }
+
+            let upstream = config
+                .string("remote.origin.url")
+                .context("Mirror has no upstream URL")?
+                .to_string();
+            let members = config
+                .plumbing()
+                .strings("gnit.member")
+                .unwrap_or_default()
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+
+            Ok(Some(Mirror {
+                upstream,
+                public: config.boolean("gnit.public").unwrap_or_default(),
+                members,
+            }))
+        })
+        .await?
+    }
+
+    /// Returns the commit HEAD points at in the repo at `path`, or None if there isn't one yet.
+    pub async fn head_id(path: &Path) -> anyhow::Result<Option<ObjectId>> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let repo = gix::open(&path).context("Failed to open repo")?;
+            let head = repo.head().context("Failed to read HEAD")?;
+            Ok(head.id().map(|id| id.detach()))
+        })
+        .await?
+    }
+
+    /// Returns the branch HEAD points at in the repo at `path`, like "refs/heads/main", or None
+    /// if it's detached.
+    pub async fn head_ref(path: &Path) -> anyhow::Result<Option<String>> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let repo = gix::open(&path).context("Failed to open repo")?;
+            let name = repo.head_name().context("Failed to read HEAD")?;
+            Ok(name.map(|name| name.as_bstr().to_string()))
+        })
+        .await?
+    }
+
+    /// Reads `file` from the tree of `commit` straight out of the object database, without
+    /// checking anything out. Returns None if there's no such file.
+    pub async fn read_file(
+        path: &Path,
+        commit: ObjectId,
+        file: &Path,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let path = path.to_path_buf();
+        let file = file.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let repo = gix::open(&path).context("Failed to open repo")?;
+            let tree = repo
+                .find_commit(commit)
+                .context("Failed to find commit")?
+                .tree()
+                .context("Failed to read commit tree")?;
+
+            let mut buf = Vec::new();
+            match tree.lookup_entry_by_path(&file, &mut buf)? {
+                Some(entry) if entry.mode().is_blob() => Ok(Some(entry.object()?.detach().data)),
+                _ => Ok(None),
+            }
+        })
+        .await?
+    }
+
+    /// Lists every ref in the repo at `path` along with the object it points at.
+    pub async fn refs(path: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+        let output = tokio::process::Command::new("git")
+            .current_dir(path)
+            .arg("for-each-ref")
+            .arg("--format=%(objectname) %(refname)")
+            .output()
+            .await?;
+        output.status.exit_ok().context("Failed to list refs")?;
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(id, name)| (name.to_string(), id.to_string()))
+            .collect())
+    }
+
+    /// Lists up to `limit` commits reachable from `new` but none of `exclude`, newest first,
+    /// as `(id, author name, author email, summary)`.
+    pub async fn commits(
+        path: &Path,
+        new: &str,
+        exclude: &[&str],
+        limit: usize,
+    ) -> anyhow::Result<Vec<(String, String, String, String)>> {
+        let mut command = tokio::process::Command::new("git");
+        command
+            .current_dir(path)
+            .arg("log")
+            .arg(format!("--max-count={limit}"))
+            .arg("--format=%H%x00%an%x00%ae%x00%s");
+        let output = with_revisions(command, new, exclude).await?;
+        output.status.exit_ok().context("Failed to list commits")?;
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(4, '\0').map(str::to_string);
+                Some((
+                    fields.next()?,
+                    fields.next()?,
+                    fields.next()?,
+                    fields.next()?,
+                ))
+            })
+            .collect())
+    }
+
+    /// Counts the commits reachable from `new` but none of `exclude`.
+    pub async fn count_commits(path: &Path, new: &str, exclude: &[&str]) -> anyhow::Result<usize> {
+        let mut command = tokio::process::Command::new("git");
+        command.current_dir(path).arg("rev-list").arg("--count");
+        let output = with_revisions(command, new, exclude).await?;
+        output.status.exit_ok().context("Failed to count commits")?;
+
+        String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .context("Failed to count commits")
+    }
+
+    /// Returns whether `ancestor` is reachable from `commit`, i.e. whether moving a branch from
+    /// one to the other is a fast-forward.
+    pub async fn is_ancestor(path: &Path, ancestor: &str, commit: &str) -> anyhow::Result<bool> {
+        let status = tokio::process::Command::new("git")
+            .current_dir(path)
+            .arg("merge-base")
+            .arg("--is-ancestor")
+            .arg(ancestor)
+            .arg(commit)
+            .status()
+            .await?;
+
+        match status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => Err(anyhow!("Failed to compare commits")),
+        }
+    }
+
+    /// Lists up to `limit` commits on HEAD after skipping the first `skip`, newest first, as
+    /// `(id, author name, author time, summary)`.
+    pub async fn log(
+        path: &Path,
+        skip: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(String, String, i64, String)>> {
+        let output = tokio::process::Command::new("git")
+            .current_dir(path)
+            .arg("log")
+            .arg(format!("--skip={skip}"))
+            .arg(format!("--max-count={limit}"))
+            .arg("--format=%H%x00%an%x00%at%x00%s")
+            .arg("HEAD")
+            .arg("--")
+            .output()
+            .await?;
+        output.status.exit_ok().context("Failed to list commits")?;
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(4, '\0');
+                Some((
+                    fields.next()?.to_string(),
+                    fields.next()?.to_string(),
+                    fields.next()?.parse().ok()?,
+                    fields.next()?.to_string(),
+                ))
+            })
+            .collect())
+    }
+
+    /// Lists the directory `dir` in the tree of `commit` as `(type, size, name)`, where the
+    /// type is "blob", "tree", or "commit" for submodules, and only blobs have a size.
+    pub async fn tree(
+        path: &Path,
+        commit: ObjectId,
+        dir: &Path,
+    ) -> anyhow::Result<Vec<(String, Option<u64>, String)>> {
+        let mut command = tokio::process::Command::new("git");
+        command
+            .current_dir(path)
+            .arg("ls-tree")
+            .arg("-z")
+            .arg("--long")
+            .arg(commit.to_string());
+        if dir != Path::new("") {
+            command.arg("--").arg(format!("{}/", dir.display()));
+        }
+        let output = command.output().await?;
+        output.status.exit_ok().context("Failed to list tree")?;
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .split('\0')
+            .filter_map(|entry| {
+                let (info, name) = entry.split_once('\t')?;
+                let mut fields = info.split_whitespace().skip(1);
+                let kind = fields.next()?.to_string();
+                let size = fields.nth(1)?.parse().ok();
+                let name = name.rsplit('/').next()?.to_string();
+                Some((kind, size, name))
+            })
+            .collect())
+    }
+
+    /// Shows `commit` as `git show` would, with a diffstat before the patch.
+    pub async fn show(path: &Path, commit: &str) -> anyhow::Result<String> {
+        let output = tokio::process::Command::new("git")
+            .current_dir(path)
+            .arg("show")
+            .arg("--no-color")
+            .arg("--format=fuller")
+            .arg("--stat")
+            .arg("--patch")
+            .arg(commit)
+            .arg("--")
+            .output()
+            .await?;
+        output.status.exit_ok().context("Failed to show commit")?;
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    /// Makes a bare copy of the repo at `from`, without keeping it as a remote.
+    pub async fn clone_bare(from: &Path, to: &Path) -> anyhow::Result<()> {
+        tokio::process::Command::new("git")
+            .arg("clone")
+            .arg("--bare")
+            .arg(from)
+            .arg(to)
+            .output()
+            .await?
+            .status
+            .exit_ok()
+            .context("Failed to clone repo")?;
+        tokio::process::Command::new("git")
+            .current_dir(to)
+            .arg("remote")
+            .arg("remove")
+            .arg("origin")
+            .output()
+            .await?
+            .status
+            .exit_ok()
+            .context("Failed to remove origin from cloned repo")?;
+        Ok(())
+    }
+
     pub async fn push_changes(&self, message: &str) -> anyhow::Result<()> {
+        self.commit_and_push(message, None).await
+    }
+
+    /// Like `push_changes`, but the commit is authored by `author` ("Name <email>") rather than
+    /// the server.
+    pub async fn push_changes_by(&self, message: &str, author: &str) -> anyhow::Result<()> {
+        self.commit_and_push(message, Some(author)).await
+    }
+
+    /// Shows what `push_changes` would commit, with the paths under `prefix` so diffs of several
+    /// repos can be told apart.
+    pub async fn diff(&self, prefix: &Path) -> anyhow::Result<String> {
+        // Intent-to-add makes new files show up too.
+        tokio::process::Command::new("git")
+            .current_dir(&self.dir)
+            .args(["add", "--intent-to-add", "."])
+            .output()
+            .await?
+            .status
+            .exit_ok()
+            .context("Failed to stage changes")?;
+
+        let prefix = prefix.to_string_lossy();
+        let output = tokio::process::Command::new("git")
+            .current_dir(&self.dir)
+            .arg("diff")
+            .arg(format!("--src-prefix=a/{}/", prefix))
+            .arg(format!("--dst-prefix=b/{}/", prefix))
+            .output()
+            .await?;
+        output.status.exit_ok().context("Failed to diff changes")?;
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    async fn commit_and_push(&self, message: &str, author: Option<&str>) -> anyhow::Result<()> {
         tokio::process::Command::new("git")
             .current_dir(&self.dir)
             .arg("add")
@@ -106,6 +431,7 @@ impl Repo {
             .arg("commit")
             .arg("-m")
             .arg(message)
+            .args(author.map(|author| format!("--author={author}")))
             .output()
             .await?
             .status
@@ -124,3 +450,52 @@ impl Repo {
         Ok(())
     }
 }
+
+// Runs a git command that walks history from `new` but not from any of `exclude`. The revisions
+// go on its stdin, since a repo can have more refs to exclude than fit on a command line.
+async fn with_revisions(
+    mut command: tokio::process::Command,
+    new: &str,
+    exclude: &[&str],
+) -> anyhow::Result<Output> {
+    let mut child = command
+        .arg("--stdin")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // git reads all of its stdin before it starts writing, so this can't block on the output.
+    // Older gits don't take --not there, hence the carets.
+    let mut revisions = format!("{new}\n");
+    for id in exclude {
+        revisions.push_str(&format!("^{id}\n"));
+    }
+    let mut stdin = child.stdin.take().context("No stdin for git")?;
+    stdin.write_all(revisions.as_bytes()).await?;
+    drop(stdin);
+
+    Ok(child.wait_with_output().await?)
+}
+
+// Finds every bare repo under `dir`, skipping hidden directories like the config repo's.
+pub fn find_repos(dir: &Path, relative: &Path, found: &mut Vec<PathBuf>) {
+    let Ok(entries) = read_dir(dir) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') || !entry.path().is_dir() {
+            continue;
+        }
+
+        let path = entry.path();
+        let relative = relative.join(&name);
+        if path.extension().is_some_and(|ext| ext == "git") && path.join("HEAD").is_file() {
+            found.push(relative);
+        } else {
+            find_repos(&path, &relative, found);
+        }
+    }
+}
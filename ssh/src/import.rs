@@ -0,0 +1,845 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{read_dir, read_to_string, write},
+    path::{Path, PathBuf},
+    slice::from_ref,
+};
+
+use anyhow::{bail, Context};
+use log::{info, warn};
+use tempfile::tempdir;
+
+use crate::config::repo::{AccessLevel, RepoConfig};
+use crate::config::rewrite;
+use crate::config::server::{ServerConfig, ServerUser};
+use crate::git::Repo;
+use crate::policy::{glob_matches, ProtectedRefs};
+use crate::vars::*;
+
+// gitolite's users for git-daemon and gitweb, which mean a repo can be read anonymously.
+const GITOLITE_ANONYMOUS_USERS: [&str; 2] = ["daemon", "gitweb"];
+const GITOLITE_ADMIN_REPO: &str = "gitolite-admin";
+
+// Where users and permissions are imported from.
+pub enum ImportSource {
+    // A gitolite-admin checkout, or its gitolite.conf with the keydir next to conf/.
+    Gitolite(PathBuf),
+    // An OpenSSH authorized_keys file, which only has users.
+    AuthorizedKeys(PathBuf),
+}
+
+// Everything found in the source, before it's merged into the configs.
+#[derive(Default)]
+struct Import {
+    // Users along with their public keys.
+    users: BTreeMap<String, Vec<String>>,
+    admins: BTreeSet<String>,
+    groups: BTreeMap<String, BTreeSet<String>>,
+    repos: BTreeMap<PathBuf, Grants>,
+}
+
+#[derive(Default)]
+struct Grants {
+    // Usernames and "@group"s.
+    read: BTreeSet<String>,
+    write: BTreeSet<String>,
+    // Every user with a key can read the repo.
+    users_read: bool,
+    // Anyone can read the repo, without a key.
+    anonymous_read: bool,
+    // Refs that rules limited to some refs let their members push to, by pattern.
+    protect: BTreeMap<String, RefGrant>,
+    // Users and "@group"s that deny rules name, who can't be given write access.
+    denied: BTreeSet<String>,
+    // Whether some rules give write access without force-pushes and deletions ("RW"), and some
+    // with them ("RW+").
+    plain_writes: bool,
+    rewinding_writes: bool,
+}
+
+// Who may push to some refs, and how. The repo's writers are added once all rules are read.
+struct RefGrant {
+    pushers: Option<BTreeSet<String>>,
+    allow_force_push: bool,
+    allow_delete: bool,
+}
+
+// A permission line from gitolite.conf, like "RW+ = alice @devs".
+struct Rule {
+    source: String,
+    repos: Vec<String>,
+    perm: String,
+    // The refs it covers, as regexes matching the start of their names. All of them if empty.
+    refexes: Vec<String>,
+    members: Vec<String>,
+}
+
+// Imports users and permissions into the config repos. Unless `apply` is set, this only prints
+// what would change. Paths are relative to the scan path, like the server's.
+pub async fn import(source: &ImportSource, apply: bool) -> anyhow::Result<()> {
+    if !Path::new(SERVER_CONFIG_REPO).exists() {
+        bail!("There's no server config yet, start the server once to create it.");
+    }
+
+    let (import, message) = match source {
+        ImportSource::Gitolite(path) => (gitolite(path)?, "chore: import from gitolite"),
+        ImportSource::AuthorizedKeys(path) => {
+            (authorized_keys(path)?, "chore: import from authorized_keys")
+        }
+    };
+
+    let temp_dir = tempdir()?;
+    let mut changed = 0;
+
+    let clone_dir = temp_dir.path().join(SERVER_CONFIG_REPO);
+    let repo = Repo::clone(Path::new(SERVER_CONFIG_REPO), &clone_dir).await?;
+    let config_file = clone_dir.join(SERVER_CONFIG_FILE);
+    let text = read_to_string(&config_file).context("Couldn't read server.toml")?;
+    let mut server_config: ServerConfig = toml::from_str(&text)?;
+
+    // Compare the configs rather than the files, so rewriting them in our own style doesn't count.
+    let before = toml::to_string(&server_config)?;
+    import.merge_server_config(&mut server_config);
+    let after = toml::to_string(&server_config)?;
+    let server_changed = before != after;
+    if server_changed {
+        write(&config_file, rewrite(&text, &server_config)?)
+            .context("Could not write server config")?;
+        print!("{}", repo.diff(Path::new(SERVER_CONFIG_REPO)).await?);
+        if apply {
+            repo.push_changes(message).await?;
+        }
+        changed += 1;
+    }
+
+    for (repo_path, grants) in &import.repos {
+        if !repo_path.is_dir() {
+            warn!(
+                "{} isn't on this server, so its permissions weren't imported",
+                repo_path.display()
+            );
+            continue;
+        }
+        if Repo::mirror_of(repo_path).await?.is_some() {
+            warn!(
+                "{} is a mirror, which has no config to import permissions into",
+                repo_path.display()
+            );
+            continue;
+        }
+
+        let clone_dir = temp_dir.path().join(repo_path);
+        let repo = Repo::clone(repo_path, &clone_dir).await?;
+        let config_file = clone_dir.join(REPO_CONFIG_FILE);
+
+        // Repos moved over from elsewhere won't have a config yet.
+        let (text, before, mut config) = match read_to_string(&config_file) {
+            Ok(text) => {
+                let config: RepoConfig = toml::from_str(&text).with_context(|| {
+                    format!("Couldn't parse {}'s repo.toml", repo_path.display())
+                })?;
+                let before = toml::to_string(&config)?;
+                (Some(text), Some(before), config)
+            }
+            Err(_) => (None, None, RepoConfig::new(repo_path)),
+        };
+        grants.merge_repo_config(&mut config);
+        let after = toml::to_string(&config)?;
+        if before.as_ref() == Some(&after) {
+            continue;
+        }
+
+        // Edit the existing file in place, so its comments and layout survive.
+        let after = match text {
+            Some(text) => rewrite(&text, &config)?,
+            None => after,
+        };
+        write(&config_file, after).context("Could not write repo config")?;
+        print!("{}", repo.diff(repo_path).await?);
+        if apply {
+            repo.push_changes(message).await?;
+        }
+        changed += 1;
+    }
+
+    if changed == 0 {
+        info!("Nothing to import, the configs already have everything");
+    } else if apply {
+        info!("Imported into {} config(s)", changed);
+        if server_changed {
+            info!("Restart the server to load the new users");
+        }
+    } else {
+        info!(
+            "This would change {} config(s). Nothing has been committed yet, run the import again with --apply to do that",
+            changed
+        );
+    }
+
+    Ok(())
+}
+
+impl Import {
+    // Adds users, keys, admins and groups, keeping everything that's already there.
+    fn merge_server_config(&self, config: &mut ServerConfig) {
+        config.index_keys();
+        // The keys added so far, which the index only has once it's rebuilt at the end.
+        let mut added: BTreeMap<&str, &String> = BTreeMap::new();
+        for (username, keys) in &self.users {
+            for key in keys {
+                let data = key.split(' ').nth(1).unwrap_or_default();
+                let owner = config
+                    .get_user(data)
+                    .map(|(owner, _)| owner)
+                    .or_else(|| added.get(data).map(|owner| owner.to_string()));
+                if let Some(owner) = owner {
+                    if &owner != username {
+                        warn!(
+                            "A key of {}'s already belongs to {}, so it was skipped",
+                            username, owner
+                        );
+                    }
+                    continue;
+                }
+
+                config
+                    .users
+                    .entry(username.clone())
+                    .or_insert_with(|| ServerUser {
+                        public_key: None,
+                        public_keys: Vec::new(),
+                        is_admin: None,
+                        can_create_repos: None,
+                        npub: None,
+                        quota: None,
+                        key_bindings: Vec::new(),
+                    })
+                    .public_keys
+                    .push(key.clone());
+                added.insert(data, username);
+            }
+        }
+        config.index_keys();
+
+        for admin in &self.admins {
+            match config.users.get_mut(admin) {
+                Some(user) => user.is_admin = Some(true),
+                None => warn!("{} is an admin, but has no keys to import", admin),
+            }
+        }
+
+        for (group, members) in &self.groups {
+            let existing = config.groups.entry(group.clone()).or_default();
+            for member in members {
+                if !existing.contains(member) {
+                    existing.push(member.clone());
+                }
+            }
+        }
+
+        let granted: BTreeSet<&String> = self
+            .repos
+            .values()
+            .flat_map(|grants| grants.read.iter().chain(&grants.write))
+            .filter(|member| !member.starts_with('@'))
+            .chain(self.groups.values().flatten())
+            .collect();
+        for username in granted {
+            if !config.users.contains_key(username) {
+                warn!(
+                    "{} has been granted access, but has no keys to import",
+                    username
+                );
+            }
+        }
+    }
+}
+
+impl Grants {
+    fn merge_repo_config(&self, config: &mut RepoConfig) {
+        let merge = |existing: &mut Vec<String>, members: &BTreeSet<String>| {
+            for member in members {
+                if !existing.contains(member) {
+                    existing.push(member.clone());
+                }
+            }
+        };
+        merge(&mut config.access.read, &self.read);
+        merge(&mut config.access.write, &self.write);
+
+        // Refs that are already protected are left as they are.
+        if !self.protect.is_empty() {
+            let policy = config.policy.get_or_insert_with(Default::default);
+            for (refs, grant) in &self.protect {
+                if policy
+                    .protect
+                    .iter()
+                    .any(|protected| &protected.refs == refs)
+                {
+                    continue;
+                }
+                policy.protect.push(ProtectedRefs {
+                    refs: refs.clone(),
+                    allow_force_push: grant.allow_force_push,
+                    allow_delete: grant.allow_delete,
+                    pushers: grant
+                        .pushers
+                        .as_ref()
+                        .map(|pushers| pushers.iter().cloned().collect()),
+                    require_signed_commits: false,
+                });
+            }
+        }
+
+        if self.users_read || self.anonymous_read {
+            config.public = true;
+            // Public repos can be read anonymously unless the server says otherwise, which
+            // gitolite only allows through its daemon and gitweb users.
+            if !self.anonymous_read && config.access.anonymous.is_none() {
+                config.access.anonymous = Some(AccessLevel::None);
+            }
+        }
+    }
+}
+
+fn gitolite(path: &Path) -> anyhow::Result<Import> {
+    let (conf, keydir) = if path.is_dir() {
+        (path.join("conf/gitolite.conf"), path.join("keydir"))
+    } else {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let keydir = [dir.join("keydir"), dir.join("../keydir")]
+            .into_iter()
+            .find(|keydir| keydir.is_dir())
+            .unwrap_or_else(|| dir.join("keydir"));
+        (path.to_path_buf(), keydir)
+    };
+
+    let mut import = Import::default();
+    if keydir.is_dir() {
+        read_keydir(&keydir, &mut import)?;
+    } else {
+        warn!(
+            "There's no keydir at {}, so no keys were imported",
+            keydir.display()
+        );
+    }
+
+    let mut lines = Vec::new();
+    read_conf(&conf, &mut lines, 0)?;
+
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut rules = Vec::new();
+    let mut repos: Vec<String> = Vec::new();
+    let words =
+        |text: &str| -> Vec<String> { text.split_whitespace().map(str::to_string).collect() };
+    for (source, line) in lines {
+        if let ["repo", names @ ..] = line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            repos = names.iter().map(|name| name.to_string()).collect();
+            continue;
+        }
+
+        let Some((left, right)) = line.split_once('=') else {
+            let keyword = line.split_whitespace().next().unwrap_or_default();
+            warn!(
+                "{}: gitolite's {:?} lines have no equivalent, so this was skipped",
+                source, keyword
+            );
+            continue;
+        };
+
+        let left = words(left);
+        match left.as_slice() {
+            [group] if group.starts_with('@') => {
+                groups
+                    .entry(group[1..].to_string())
+                    .or_default()
+                    .extend(words(right));
+            }
+            [keyword, ..] if keyword == "config" || keyword == "option" => {
+                warn!(
+                    "{}: gitolite's {} lines have no equivalent, so this was skipped",
+                    source, keyword
+                );
+            }
+            [perm, refexes @ ..] if !repos.is_empty() => rules.push(Rule {
+                source,
+                repos: repos.clone(),
+                perm: perm.clone(),
+                refexes: refexes.to_vec(),
+                members: words(right),
+            }),
+            _ => warn!(
+                "{}: couldn't understand this line, so it was skipped",
+                source
+            ),
+        }
+    }
+
+    let expand = |names: &[String]| {
+        let mut expanded = BTreeSet::new();
+        for name in names {
+            expand_group(name, &groups, 0, &mut expanded);
+        }
+        expanded
+    };
+
+    // gitolite's "@all" repo group stands for every repo in the config.
+    let all_repos: BTreeSet<String> = rules
+        .iter()
+        .flat_map(|rule| expand(&rule.repos))
+        .filter(|repo| repo != "@all")
+        .collect();
+
+    let mut used_groups = BTreeSet::new();
+    let mut wild_repos = BTreeSet::new();
+    let mut denied_admins = BTreeSet::new();
+    for rule in &rules {
+        let deny = rule.perm == "-";
+        if !deny && !rule.perm.starts_with('R') {
+            warn!(
+                "{}: unknown permission {:?}, so this was skipped",
+                rule.source, rule.perm
+            );
+            continue;
+        }
+
+        // Write access limited to some refs becomes read access and protected refs, with the
+        // repo's other writers added once everything's been read.
+        let write = rule.perm.contains('W');
+        let rewind = rule.perm.contains('+');
+        let limited = write && !rule.refexes.is_empty();
+        let patterns: Vec<String> = if limited {
+            rule.refexes
+                .iter()
+                .map(|refex| ref_pattern(&rule.source, refex))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let mut repos = expand(&rule.repos);
+        if repos.remove("@all") {
+            repos.extend(all_repos.iter().cloned());
+        }
+
+        for repo in repos {
+            if !is_plain_repo_name(&repo) {
+                if wild_repos.insert(repo.clone()) {
+                    warn!(
+                        "{}: wild repos like {:?} have no equivalent, so were skipped",
+                        rule.source, repo
+                    );
+                }
+                continue;
+            }
+
+            // Whoever can push to the admin repo administers gitolite.
+            if repo == GITOLITE_ADMIN_REPO {
+                if deny {
+                    denied_admins.extend(expand(&rule.members));
+                } else if write {
+                    let users = expand(&rule.members);
+                    import
+                        .admins
+                        .extend(users.into_iter().filter(|user| is_valid_name(user)));
+                }
+                continue;
+            }
+
+            let grants = import
+                .repos
+                .entry(PathBuf::from(format!("{}.git", repo)))
+                .or_default();
+            if deny {
+                grants.denied.extend(rule.members.iter().cloned());
+                continue;
+            }
+            if write {
+                grants.rewinding_writes |= rewind;
+                grants.plain_writes |= !rewind && !limited;
+            }
+            for pattern in &patterns {
+                let grant = grants.protect.entry(pattern.clone()).or_insert(RefGrant {
+                    pushers: Some(BTreeSet::new()),
+                    allow_force_push: true,
+                    allow_delete: true,
+                });
+                grant.allow_force_push &= rewind;
+                grant.allow_delete &= rewind || rule.perm.contains('D');
+            }
+
+            for member in &rule.members {
+                if member == "@all" {
+                    if write {
+                        warn!(
+                            "{}: write access for @all has no equivalent, grant it to a group instead",
+                            rule.source
+                        );
+                    } else {
+                        grants.users_read = true;
+                    }
+                    continue;
+                } else if GITOLITE_ANONYMOUS_USERS.contains(&member.as_str()) {
+                    grants.anonymous_read = true;
+                    continue;
+                } else if let Some(group) = member.strip_prefix('@') {
+                    if !groups.contains_key(group) {
+                        warn!("{}: there's no group called {}", rule.source, group);
+                        continue;
+                    }
+                    used_groups.insert(group.to_string());
+                } else if !is_valid_name(member) {
+                    warn!("{}: {:?} isn't a valid username", rule.source, member);
+                    continue;
+                }
+
+                if write && !limited {
+                    grants.write.insert(member.clone());
+                } else {
+                    grants.read.insert(member.clone());
+                }
+                for pattern in &patterns {
+                    if let Some(pushers) = &mut grants.protect.get_mut(pattern).unwrap().pushers {
+                        pushers.insert(member.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    for admin in &denied_admins {
+        if import.admins.remove(admin) {
+            warn!(
+                "Deny rules have no equivalent, so {} isn't made an admin",
+                admin
+            );
+        }
+    }
+    for (repo_path, grants) in &mut import.repos {
+        restrict(repo_path, grants, &expand);
+    }
+
+    // Groups here can only hold users, so nested groups are flattened.
+    for group in used_groups {
+        let members = expand(&[format!("@{}", group)])
+            .into_iter()
+            .filter(|member| is_valid_name(member))
+            .collect();
+        import.groups.insert(group, members);
+    }
+
+    Ok(import)
+}
+
+// Takes away what can't be granted as narrowly as gitolite did, so nobody gets more access than
+// they had: writers named by deny rules can only read, as can those who could only push to some
+// refs, and force-pushes and deletions are only allowed if every writer could do them.
+fn restrict(
+    repo_path: &Path,
+    grants: &mut Grants,
+    expand: &impl Fn(&[String]) -> BTreeSet<String>,
+) {
+    let repo = repo_path.display();
+    let denied = expand(&grants.denied.iter().cloned().collect::<Vec<_>>());
+    for member in grants.write.clone() {
+        let users = expand(from_ref(&member));
+        if denied.contains("@all") || users.iter().any(|user| denied.contains(user)) {
+            warn!(
+                "{}: deny rules have no equivalent, so {} can only read it",
+                repo, member
+            );
+            grants.write.remove(&member);
+            grants.read.insert(member);
+        }
+    }
+
+    let writers = expand(&grants.write.iter().cloned().collect::<Vec<_>>());
+    let mut limited = BTreeSet::new();
+    for grant in grants.protect.values_mut() {
+        let Some(pushers) = &mut grant.pushers else {
+            continue;
+        };
+        for pusher in pushers.iter() {
+            if !expand(from_ref(pusher)).is_subset(&writers) && limited.insert(pusher.clone()) {
+                warn!(
+                    "{}: {} could only push to some refs, but pushing here needs write access to the whole repo, so they can only read it",
+                    repo, pusher
+                );
+            }
+        }
+        pushers.extend(grants.write.iter().cloned());
+    }
+
+    if grants.plain_writes {
+        if grants.rewinding_writes {
+            warn!(
+                "{}: some writers couldn't force-push or delete refs, which can't be granted per user here, so nobody can",
+                repo
+            );
+        }
+        grants.protect.insert(
+            "refs/*".to_string(),
+            RefGrant {
+                pushers: None,
+                allow_force_push: false,
+                allow_delete: false,
+            },
+        );
+    }
+}
+
+// Turns a gitolite refex, a regex matched against the start of a ref's name, into a pattern for
+// policy.protect. Regex syntax the pattern can't express widens it, so it covers every ref the
+// refex did.
+fn ref_pattern(source: &str, refex: &str) -> String {
+    let full = if refex.starts_with("refs/") {
+        refex.to_string()
+    } else {
+        format!("refs/heads/{}", refex)
+    };
+
+    let is_syntax = |c: char| "\\.^$|?*+()[]{}".contains(c);
+    if let Some(exact) = full
+        .strip_suffix('$')
+        .filter(|exact| !exact.contains(is_syntax))
+    {
+        return exact.to_string();
+    }
+    let open = full.strip_suffix(".*").unwrap_or(&full);
+    if !open.contains(is_syntax) {
+        return format!("{}*", open);
+    }
+
+    // Alternatives could match anything.
+    let mut prefix = if full.contains('|') {
+        "refs/".to_string()
+    } else {
+        full[..full.find(is_syntax).unwrap_or(full.len())].to_string()
+    };
+    // A repeat can leave out the character before it.
+    if full[prefix.len()..].starts_with(['?', '*', '+', '{']) {
+        prefix.pop();
+    }
+    let pattern = format!("{}*", prefix);
+    warn!(
+        "{}: {:?} was widened to {:?} to be protected here",
+        source, refex, pattern
+    );
+    pattern
+}
+
+// Reads the lines of gitolite.conf that mean something, following includes. Each comes with where
+// it's from, to point at in warnings.
+fn read_conf(path: &Path, lines: &mut Vec<(String, String)>, depth: usize) -> anyhow::Result<()> {
+    if depth > 8 {
+        bail!(
+            "{} is included too deeply, does it include itself?",
+            path.display()
+        );
+    }
+
+    let text = read_to_string(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        // Included files are relative to the one including them, and can be globs.
+        if let Some(pattern) = line.strip_prefix("include ") {
+            let pattern = pattern.trim().trim_matches('"');
+            let dir = path.parent().unwrap_or(Path::new("."));
+            for included in matching_files(&dir.join(pattern))? {
+                read_conf(&included, lines, depth + 1)?;
+            }
+            continue;
+        }
+
+        lines.push((format!("{}:{}", path.display(), i + 1), line.to_string()));
+    }
+
+    Ok(())
+}
+
+fn matching_files(pattern: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let (Some(dir), Some(name)) = (
+        pattern.parent(),
+        pattern.file_name().and_then(|n| n.to_str()),
+    ) else {
+        return Ok(Vec::new());
+    };
+    if !name.contains('*') {
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+
+    let mut files: Vec<PathBuf> = read_dir(dir)
+        .with_context(|| format!("Couldn't read {}", dir.display()))?
+        .flatten()
+        .filter(|entry| glob_matches(name, &entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn expand_group(
+    name: &str,
+    groups: &BTreeMap<String, Vec<String>>,
+    depth: usize,
+    expanded: &mut BTreeSet<String>,
+) {
+    match name.strip_prefix('@').and_then(|group| groups.get(group)) {
+        Some(members) if depth < 16 => {
+            for member in members {
+                expand_group(member, groups, depth + 1, expanded);
+            }
+        }
+        _ => {
+            expanded.insert(name.to_string());
+        }
+    }
+}
+
+// Reads every user's keys, which can be in subdirectories. "alice@laptop.pub" is another of
+// alice's keys, while "alice@example.com.pub" is a user named after their email address.
+fn read_keydir(dir: &Path, import: &mut Import) -> anyhow::Result<()> {
+    let entries = read_dir(dir).with_context(|| format!("Couldn't read {}", dir.display()))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            read_keydir(&path, import)?;
+            continue;
+        }
+
+        let Some(name) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".pub"))
+        else {
+            continue;
+        };
+        let username = match name.rsplit_once('@') {
+            Some((username, host)) if !host.contains('.') => username,
+            _ => name,
+        };
+
+        let text =
+            read_to_string(&path).with_context(|| format!("Couldn't read {}", path.display()))?;
+        for line in text.lines().map(str::trim) {
+            if !line.is_empty() && !line.starts_with('#') {
+                add_key(import, username, line, &path.display().to_string());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn authorized_keys(path: &Path) -> anyhow::Result<Import> {
+    let text = read_to_string(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+
+    let mut import = Import::default();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let source = format!("{}:{}", path.display(), i + 1);
+
+        // Keys set up by gitolite name their user in the command they run, otherwise the
+        // comment names them, like "alice@laptop".
+        let (options, key) = split_options(line);
+        let username = gitolite_command_user(options).or_else(|| {
+            key.split_whitespace()
+                .nth(2)
+                .and_then(|comment| comment.split('@').next())
+        });
+        let Some(username) = username else {
+            warn!(
+                "{}: there's no comment or gitolite command to name the user, so this key was skipped",
+                source
+            );
+            continue;
+        };
+
+        add_key(&mut import, username, key, &source);
+    }
+
+    Ok(import)
+}
+
+// Splits the options an authorized_keys line may start with from its key. Options can have
+// quoted spaces, like command="gitolite-shell alice".
+fn split_options(line: &str) -> (&str, &str) {
+    if ["ssh-", "ecdsa-", "sk-"]
+        .iter()
+        .any(|kind| line.starts_with(kind))
+    {
+        return ("", line);
+    }
+
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ' ' | '\t' if !quoted => return (&line[..i], line[i..].trim_start()),
+            _ => {}
+        }
+    }
+
+    ("", line)
+}
+
+fn gitolite_command_user(options: &str) -> Option<&str> {
+    let command = options.split("command=\"").nth(1)?.split('"').next()?;
+    if !command.contains("gitolite-shell") {
+        return None;
+    }
+    command.split_whitespace().last()
+}
+
+fn add_key(import: &mut Import, username: &str, key: &str, source: &str) {
+    if !is_valid_name(username) {
+        warn!("{}: {:?} isn't a valid username", source, username);
+        return;
+    }
+
+    let parts: Vec<&str> = key.split_whitespace().collect();
+    let valid = parts
+        .get(1)
+        .is_some_and(|data| russh::keys::parse_public_key_base64(data).is_ok());
+    if !valid {
+        warn!(
+            "{}: this isn't a public key this server supports, so it was skipped",
+            source
+        );
+        return;
+    }
+
+    let key = parts.join(" ");
+    let keys = import.users.entry(username.to_string()).or_default();
+    if !keys.contains(&key) {
+        keys.push(key);
+    }
+}
+
+// Users end up as directory names, so they're kept to what's safe in one.
+pub fn is_valid_name(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._@+-".contains(c))
+}
+
+// gitolite treats repo names that aren't like this as patterns for wild repos.
+fn is_plain_repo_name(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-._@/+".contains(c))
+        && !name.split('/').any(|part| part.is_empty() || part == "..")
+}
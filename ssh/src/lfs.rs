@@ -0,0 +1,418 @@
+use std::{
+    fs::{create_dir_all, metadata, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+use crate::{quota::dir_size, vars::*};
+
+// The most data one pkt-line can carry.
+const MAX_PKT_DATA: usize = 65516;
+
+// Where an LFS object lives in a repo, laid out the same way as in a client's .git/lfs.
+pub fn object_path(repo_path: &Path, oid: &str) -> PathBuf {
+    repo_path
+        .join(LFS_OBJECTS_DIR)
+        .join(&oid[0..2])
+        .join(&oid[2..4])
+        .join(oid)
+}
+
+// Whether `oid` is an LFS object id, a SHA-256 hash in lowercase hex.
+pub fn is_oid(oid: &str) -> bool {
+    oid.len() == 64
+        && oid
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+// How much space the repo's LFS objects take up.
+pub fn stored_size(repo_path: &Path) -> u64 {
+    dir_size(&repo_path.join(LFS_OBJECTS_DIR))
+}
+
+// Runs as the server side of git-lfs-transfer, returning the exit code. The server has already
+// checked that the user may perform the operation on the repo.
+pub fn transfer() -> i32 {
+    let args: Vec<String> = std::env::args().skip(2).collect();
+    let [repo_path, operation] = args.as_slice() else {
+        eprintln!("Usage: {} <repo> <upload|download>", LFS_TRANSFER_ARG);
+        return 1;
+    };
+
+    let quota = std::env::var(LFS_QUOTA_ENV)
+        .ok()
+        .and_then(|quota| quota.parse().ok());
+
+    let mut session = Session {
+        repo_path: PathBuf::from(repo_path),
+        upload: operation == "upload",
+        quota,
+        input: BufReader::new(io::stdin().lock()),
+        output: BufWriter::new(io::stdout().lock()),
+    };
+
+    match session.run() {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("LFS transfer failed: {:#}", e);
+            1
+        }
+    }
+}
+
+enum Pkt {
+    Flush,
+    Delim,
+    Data(Vec<u8>),
+}
+
+// A command from the client: its first line, arguments up to a delimiter or flush, and whether
+// data follows.
+struct Request {
+    command: String,
+    args: Vec<String>,
+    has_data: bool,
+}
+
+impl Request {
+    fn arg(&self, name: &str) -> Option<&str> {
+        self.args.iter().find_map(|arg| {
+            arg.strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix('='))
+        })
+    }
+
+    fn size(&self) -> anyhow::Result<u64> {
+        self.arg("size")
+            .context("Missing size")?
+            .parse()
+            .context("Invalid size")
+    }
+}
+
+struct Session<R, W> {
+    repo_path: PathBuf,
+    upload: bool,
+    quota: Option<u64>,
+    input: R,
+    output: W,
+}
+
+impl<R: Read, W: Write> Session<R, W> {
+    fn run(&mut self) -> anyhow::Result<()> {
+        // Advertise our capabilities, then wait for the client to pick a version.
+        self.write_line("version=1")?;
+        self.write_flush()?;
+
+        while let Some(request) = self.read_request()? {
+            let (name, rest) = request
+                .command
+                .split_once(' ')
+                .unwrap_or((request.command.as_str(), ""));
+
+            if !matches!(name, "batch" | "put-object") {
+                self.skip_data(&request)?;
+            }
+
+            match name {
+                "version" if rest == "1" => self.respond(200, &[], None)?,
+                "version" => self.error(400, "Only version 1 is supported.")?,
+                "batch" => self.batch(&request)?,
+                "put-object" => self.put_object(rest, &request)?,
+                "verify-object" => self.verify_object(rest, &request)?,
+                "get-object" => self.get_object(rest)?,
+                // There are no locks, so there's nothing to list or that could conflict.
+                "list-lock" => self.respond(200, &[], None)?,
+                "lock" | "unlock" => self.error(501, "File locking isn't supported.")?,
+                "quit" => {
+                    self.respond(200, &[], None)?;
+                    return Ok(());
+                }
+                _ => self.error(400, &format!("Unknown command {:?}.", name))?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn batch(&mut self, request: &Request) -> anyhow::Result<()> {
+        let mut objects = Vec::new();
+        if request.has_data {
+            while let Some(line) = self.read_line()? {
+                objects.push(line);
+            }
+        }
+
+        if request
+            .arg("hash-algo")
+            .is_some_and(|algo| algo != "sha256")
+        {
+            return self.error(409, "Only sha256 objects are supported.");
+        }
+
+        let mut lines = Vec::new();
+        let mut needed = 0;
+        for object in &objects {
+            let mut parts = object.split(' ');
+            let (Some(oid), Some(Ok(size))) = (parts.next(), parts.next().map(str::parse::<u64>))
+            else {
+                return self.error(400, &format!("Invalid object {:?}.", object));
+            };
+            if !is_oid(oid) {
+                return self.error(400, &format!("Invalid object id {:?}.", oid));
+            }
+
+            let stored = metadata(object_path(&self.repo_path, oid)).is_ok_and(|m| m.len() == size);
+            let action = match (self.upload, stored) {
+                (true, true) | (false, false) => "noop",
+                (true, false) => {
+                    needed += size;
+                    "upload"
+                }
+                (false, true) => "download",
+            };
+            lines.push(format!("{} {} {}", oid, size, action));
+        }
+
+        if let Err(message) = self.check_quota(needed) {
+            return self.error(507, &message);
+        }
+
+        self.respond(200, &["hash-algo=sha256".to_string()], Some(&lines))
+    }
+
+    fn put_object(&mut self, oid: &str, request: &Request) -> anyhow::Result<()> {
+        // Everything that doesn't need the data is checked first, and the data is skipped
+        // if the object can't be taken.
+        let path = object_path(&self.repo_path, oid);
+        let checked = if !self.upload {
+            Err((403, "This session can only download objects.".to_string()))
+        } else if !is_oid(oid) {
+            Err((400, format!("Invalid object id {:?}.", oid)))
+        } else {
+            match request.size() {
+                Ok(size) if path.exists() => Ok(size),
+                Ok(size) => self.check_quota(size).map(|()| size).map_err(|e| (507, e)),
+                Err(e) => Err((400, format!("{:#}.", e))),
+            }
+        };
+        let size = match checked {
+            Ok(size) => size,
+            Err((status, message)) => {
+                self.skip_data(request)?;
+                return self.error(status, &message);
+            }
+        };
+
+        let tmp_dir = self.repo_path.join(LFS_TMP_DIR);
+        create_dir_all(&tmp_dir)?;
+        let mut file = NamedTempFile::new_in(&tmp_dir)?;
+        let mut hasher = Sha256::new();
+        let mut received = 0;
+        if request.has_data {
+            while let Some(pkt) = self.read_pkt()? {
+                let Pkt::Data(data) = pkt else {
+                    break;
+                };
+                received += data.len() as u64;
+                if received > size {
+                    self.skip_data(request)?;
+                    return self.error(
+                        400,
+                        &format!("Expected {} bytes for {}, got more.", size, oid),
+                    );
+                }
+                hasher.update(&data);
+                file.write_all(&data)?;
+            }
+        }
+
+        if received != size {
+            return self.error(
+                400,
+                &format!("Expected {} bytes for {}, got {}.", size, oid, received),
+            );
+        }
+        if hex::encode(hasher.finalize()) != oid {
+            return self.error(400, &format!("The data doesn't match {}.", oid));
+        }
+
+        if !path.exists() {
+            create_dir_all(path.parent().unwrap())?;
+            file.persist(&path)
+                .with_context(|| format!("Couldn't store {}", path.display()))?;
+        }
+
+        self.respond(200, &[], None)
+    }
+
+    fn verify_object(&mut self, oid: &str, request: &Request) -> anyhow::Result<()> {
+        let stored = is_oid(oid)
+            && request.size().is_ok_and(|size| {
+                metadata(object_path(&self.repo_path, oid)).is_ok_and(|m| m.len() == size)
+            });
+
+        if stored {
+            self.respond(200, &[], None)
+        } else {
+            self.error(404, &format!("{} isn't stored correctly.", oid))
+        }
+    }
+
+    fn get_object(&mut self, oid: &str) -> anyhow::Result<()> {
+        let file = is_oid(oid)
+            .then(|| File::open(object_path(&self.repo_path, oid)).ok())
+            .flatten();
+        let Some(mut file) = file else {
+            return self.error(404, &format!("{} doesn't exist.", oid));
+        };
+
+        let size = file.metadata()?.len();
+        self.write_line("status 200")?;
+        self.write_line(&format!("size={}", size))?;
+        self.write_delim()?;
+
+        let mut buf = vec![0; MAX_PKT_DATA];
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            self.write_pkt(&buf[..read])?;
+        }
+
+        self.write_flush()
+    }
+
+    fn check_quota(&self, needed: u64) -> Result<(), String> {
+        let Some(quota) = self.quota else {
+            return Ok(());
+        };
+
+        let stored = stored_size(&self.repo_path);
+        if stored + needed > quota {
+            return Err(format!(
+                "This needs {} more bytes of LFS storage, but the repository has used {} of its {} byte quota.",
+                needed, stored, quota
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn respond(
+        &mut self,
+        status: u16,
+        args: &[String],
+        data: Option<&[String]>,
+    ) -> anyhow::Result<()> {
+        self.write_line(&format!("status {}", status))?;
+        for arg in args {
+            self.write_line(arg)?;
+        }
+        if let Some(data) = data {
+            self.write_delim()?;
+            for line in data {
+                self.write_line(line)?;
+            }
+        }
+        self.write_flush()
+    }
+
+    fn error(&mut self, status: u16, message: &str) -> anyhow::Result<()> {
+        self.respond(status, &[], Some(&[message.to_string()]))
+    }
+
+    // Reads the next command, or None once the client has gone.
+    fn read_request(&mut self) -> anyhow::Result<Option<Request>> {
+        let Some(command) = self.read_line()? else {
+            return Ok(None);
+        };
+
+        let mut args = Vec::new();
+        let has_data = loop {
+            match self.read_pkt()? {
+                None | Some(Pkt::Flush) => break false,
+                Some(Pkt::Delim) => break true,
+                Some(Pkt::Data(data)) => args.push(text(data)?),
+            }
+        };
+
+        Ok(Some(Request {
+            command,
+            args,
+            has_data,
+        }))
+    }
+
+    fn skip_data(&mut self, request: &Request) -> anyhow::Result<()> {
+        if request.has_data {
+            while let Some(Pkt::Data(_)) = self.read_pkt()? {}
+        }
+        Ok(())
+    }
+
+    // Reads a text line, or None at a flush or the end of the input.
+    fn read_line(&mut self) -> anyhow::Result<Option<String>> {
+        match self.read_pkt()? {
+            Some(Pkt::Data(data)) => Ok(Some(text(data)?)),
+            Some(Pkt::Delim) => Err(anyhow!("Unexpected delimiter")),
+            Some(Pkt::Flush) | None => Ok(None),
+        }
+    }
+
+    fn read_pkt(&mut self) -> anyhow::Result<Option<Pkt>> {
+        let mut len = [0u8; 4];
+        match self.input.read_exact(&mut len) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let len = usize::from_str_radix(std::str::from_utf8(&len)?, 16)
+            .context("Invalid pkt-line length")?;
+        match len {
+            0 => Ok(Some(Pkt::Flush)),
+            1 => Ok(Some(Pkt::Delim)),
+            2 | 3 => Err(anyhow!("Invalid pkt-line length {}", len)),
+            _ => {
+                let mut data = vec![0; len - 4];
+                self.input.read_exact(&mut data)?;
+                Ok(Some(Pkt::Data(data)))
+            }
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> anyhow::Result<()> {
+        self.write_pkt(format!("{}\n", line).as_bytes())
+    }
+
+    fn write_pkt(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        write!(self.output, "{:04x}", data.len() + 4)?;
+        self.output.write_all(data)?;
+        Ok(())
+    }
+
+    fn write_delim(&mut self) -> anyhow::Result<()> {
+        self.output.write_all(b"0001")?;
+        Ok(())
+    }
+
+    fn write_flush(&mut self) -> anyhow::Result<()> {
+        self.output.write_all(b"0000")?;
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+fn text(mut data: Vec<u8>) -> anyhow::Result<String> {
+    if data.last() == Some(&b'\n') {
+        data.pop();
+    }
+    String::from_utf8(data).context("Expected a text line")
+}
@@ -0,0 +1,137 @@
+//! The SSH server, which runs on its own as `gnostr-gnit-server` or alongside the web server in
+//! one process with `gnostr-gnit serve`.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::sync::{broadcast, Mutex};
+
+mod audit;
+mod certificates;
+mod config;
+mod daemon;
+mod firewall;
+mod git;
+mod import;
+mod lfs;
+mod nostr;
+mod policy;
+mod push_mirrors;
+mod quota;
+mod report;
+mod site;
+mod ssh;
+mod state;
+mod tui;
+mod utils;
+mod vars;
+mod webhooks;
+
+pub use audit::{read as read_audit_log, AuditEntry, AuditRef};
+pub use config::check::check_config;
+pub use config::repo::AccessLevel;
+pub use firewall::FirewallStats;
+pub use import::{import, ImportSource};
+pub use lfs::{is_oid as is_lfs_oid, object_path as lfs_object_path};
+pub use nostr::{npub, Event as NostrEvent, HTTP_AUTH_KIND, LOGIN_KIND};
+pub use push_mirrors::{status as push_mirror_status, PushMirrorStatus};
+pub use quota::{format_size, repo_size};
+pub use tui::{CommitLog, LogEntry};
+pub use vars::{SERVER_CONFIG_FILE, SERVER_CONFIG_REPO};
+
+use state::State;
+
+// The server runs itself for some jobs, such as being git's pre-receive hook. This runs the job
+// if that's what we were started for, returning its exit code.
+pub fn run_helper() -> Option<i32> {
+    match std::env::args().nth(1).as_deref() {
+        Some(vars::PRE_RECEIVE_ARG) => Some(policy::pre_receive()),
+        Some(vars::LFS_TRANSFER_ARG) => Some(lfs::transfer()),
+        _ => None,
+    }
+}
+
+// The server's config and caches, shared by SSH sessions and, when running together, the web
+// server. Paths are relative to the current directory, which should be the scan path.
+#[derive(Clone)]
+pub struct SshServer {
+    state: Arc<Mutex<State>>,
+}
+
+impl SshServer {
+    // Loads the server config, first moving `initial_config` into the config repo if there
+    // isn't one yet.
+    pub async fn load(initial_config: &Path) -> anyhow::Result<Self> {
+        let state = State::new(initial_config).await?;
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    // Listens for SSH connections, and git:// ones if the server config has a [git_daemon] table.
+    // Empty arguments fall back to the server config.
+    pub async fn run(
+        &self,
+        listen: Vec<SocketAddr>,
+        host_keys: Vec<PathBuf>,
+    ) -> anyhow::Result<()> {
+        tokio::try_join!(
+            ssh::start_server(self.state.clone(), listen, host_keys),
+            daemon::run(self.state.clone()),
+        )?;
+        Ok(())
+    }
+
+    // What a user, or an anonymous one if there's no username, can do with a repo. Repos
+    // without a readable config can't be accessed at all.
+    pub async fn access_level(&self, repo_path: &Path, username: Option<&str>) -> AccessLevel {
+        let configs = self.state.lock().await.configs();
+        configs
+            .access_level(repo_path, username)
+            .await
+            .unwrap_or(AccessLevel::None)
+    }
+
+    // Who someone logging in to the web with a Nostr key is: the user with that npub, or
+    // otherwise the npub itself, which repos can grant access to like a username.
+    pub async fn nostr_user(&self, pubkey: &str) -> String {
+        self.state
+            .lock()
+            .await
+            .server_config
+            .user_by_pubkey(pubkey)
+            .map_or_else(|| nostr::npub(pubkey), str::to_string)
+    }
+
+    // The most space a repo's git objects may take up, if its quota limits that.
+    pub async fn repo_size_limit(&self, repo_path: &Path) -> Option<u64> {
+        let configs = self.state.lock().await.configs();
+        let repo_quota = configs
+            .repo_config(repo_path)
+            .await
+            .ok()
+            .and_then(|config| config.quota);
+        quota::repo_limits(&configs.server_config, repo_path, repo_quota.as_ref())
+            .ok()?
+            .repo_size
+    }
+
+    // How many clients are connected, banned or turned away, for the web server's metrics.
+    pub async fn firewall_stats(&self) -> FirewallStats {
+        self.state.lock().await.firewall.stats()
+    }
+
+    // Lets the terminal UI read logs from somewhere quicker than git, like the web server's
+    // index.
+    pub async fn set_commit_log(&self, commit_log: Arc<dyn CommitLog>) {
+        self.state.lock().await.commit_log = Some(commit_log);
+    }
+
+    // Gets the path of each repo once a push to it has finished.
+    pub async fn pushes(&self) -> broadcast::Receiver<PathBuf> {
+        self.state.lock().await.pushes.subscribe()
+    }
+}
@@ -1,33 +1,123 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, path::PathBuf};
 
-mod state;
+use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
 use env_logger::Env;
+use gnit_ssh::{ImportSource, SshServer};
 use log::{error, info};
-use state::State;
-use tokio::sync::Mutex;
 
-mod config;
-mod git;
-mod site;
-mod ssh;
-mod utils;
-mod vars;
+#[derive(Parser, Debug)]
+#[clap(author, version, about)]
+struct Args {
+    /// The directory holding the repositories and the server's own state, which the web server
+    /// should be given as its --scan-path
+    #[clap(short, long, value_parser, default_value = ".")]
+    scan_path: PathBuf,
+    /// A server config to move into the config repository the first time the server starts
+    #[clap(short, long, value_parser, default_value = gnit_ssh::SERVER_CONFIG_FILE)]
+    config: PathBuf,
+    /// An address to listen on (eg. "[::]:2222"), may be given more than once. Overrides
+    /// `listen` in server.toml
+    #[clap(short, long)]
+    listen: Vec<SocketAddr>,
+    /// An OpenSSH private host key, may be given more than once for different key types.
+    /// Overrides `host_keys` in server.toml
+    #[clap(long, value_parser)]
+    host_key: Vec<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Imports users and permissions from another server into the config repos. Only shows the
+    /// changes unless --apply is given
+    Import {
+        #[clap(value_enum)]
+        from: ImportFrom,
+        /// A gitolite-admin checkout or its gitolite.conf, or an authorized_keys file
+        path: PathBuf,
+        /// Commit the changes rather than only showing them
+        #[clap(long)]
+        apply: bool,
+    },
+    /// Checks server.toml and repo.toml files for mistakes, without changing anything. Exits
+    /// with an error if any were found
+    CheckConfig {
+        /// Files to check, as repo configs if they're called repo.toml and as server configs
+        /// otherwise. By default, checks the config repository and every repository's config,
+        /// or --config if the server hasn't started yet
+        files: Vec<PathBuf>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ImportFrom {
+    Gitolite,
+    AuthorizedKeys,
+}
+
+async fn start(args: Args) -> anyhow::Result<()> {
+    // Paths given on the command line are relative to where we were started, everything else is
+    // relative to the scan path.
+    let config = std::path::absolute(&args.config)?;
+    let host_keys = args
+        .host_key
+        .iter()
+        .map(std::path::absolute)
+        .collect::<Result<Vec<_>, _>>()?;
+    let import = match &args.command {
+        Some(Command::Import { from, path, apply }) => {
+            let path = std::path::absolute(path)?;
+            let source = match from {
+                ImportFrom::Gitolite => ImportSource::Gitolite(path),
+                ImportFrom::AuthorizedKeys => ImportSource::AuthorizedKeys(path),
+            };
+            Some((source, *apply))
+        }
+        _ => None,
+    };
+    let check_files = match &args.command {
+        Some(Command::CheckConfig { files }) => Some(
+            files
+                .iter()
+                .map(std::path::absolute)
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+        _ => None,
+    };
+    std::env::set_current_dir(&args.scan_path)
+        .with_context(|| format!("Couldn't change to {}", args.scan_path.display()))?;
+
+    if let Some((source, apply)) = import {
+        return gnit_ssh::import(&source, apply).await;
+    }
+    if let Some(files) = check_files {
+        if !gnit_ssh::check_config(&files, &config).await? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-async fn start() -> anyhow::Result<()> {
     info!("Loading state...");
-    let state = State::new().await?;
-    let state = Arc::new(Mutex::new(state));
+    let server = SshServer::load(&config).await?;
 
     info!("Starting server...");
     let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
-    ssh::start_server(state).await?;
+    server.run(args.listen, host_keys).await?;
     Ok(())
 }
 
 #[tokio::main]
 async fn main() {
+    if let Some(code) = gnit_ssh::run_helper() {
+        std::process::exit(code);
+    }
+
+    let args = Args::parse();
+
     env_logger::init_from_env(Env::default().default_filter_or("info"));
-    if let Err(e) = start().await {
+    if let Err(e) = start(args).await {
         error!("{:#}", e);
     }
 }
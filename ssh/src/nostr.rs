@@ -0,0 +1,101 @@
+// Nostr events (NIP-01), which people sign with their Nostr key to prove who they are: to tie
+// an SSH key to their npub, or to log in to the web server.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context};
+use bech32::{Bech32, Hrp};
+use k256::schnorr::{signature::hazmat::PrehashVerifier, Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+// Ties an SSH key, given in an "ssh-key" tag, to the npub that signed it.
+pub const SSH_KEY_BINDING_KIND: u64 = 27236;
+// NIP-98 HTTP auth, sent with each request.
+pub const HTTP_AUTH_KIND: u64 = 27235;
+// NIP-42 style auth, signed by a NIP-07 browser extension to log in to the web server.
+pub const LOGIN_KIND: u64 = 22242;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Event {
+    pub id: String,
+    pub pubkey: String,
+    pub created_at: u64,
+    pub kind: u64,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    pub sig: String,
+}
+
+impl Event {
+    // Parses an event from JSON, checking it's signed by its pubkey.
+    pub fn parse(json: &str) -> anyhow::Result<Self> {
+        let event: Event = serde_json::from_str(json).context("That isn't a Nostr event")?;
+        event.verify()?;
+        Ok(event)
+    }
+
+    // Checks the id is the hash of the event and the signature is the pubkey's.
+    pub fn verify(&self) -> anyhow::Result<()> {
+        let serialized = serde_json::to_string(&json!([
+            0,
+            self.pubkey,
+            self.created_at,
+            self.kind,
+            self.tags,
+            self.content
+        ]))?;
+        let id = Sha256::digest(serialized.as_bytes());
+        if hex::encode(id) != self.id.to_ascii_lowercase() {
+            bail!("The event's id doesn't match its contents.");
+        }
+
+        let pubkey = hex::decode(&self.pubkey).context("The event's pubkey isn't hex")?;
+        let key = VerifyingKey::from_bytes(&pubkey).context("The event's pubkey isn't valid")?;
+        let sig = hex::decode(&self.sig).context("The event's signature isn't hex")?;
+        let sig =
+            Signature::try_from(sig.as_slice()).context("The event's signature isn't valid")?;
+        key.verify_prehash(&id, &sig)
+            .map_err(|_| anyhow!("The event isn't signed by its pubkey."))
+    }
+
+    // The value of the first tag with the given name.
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|tag| tag.first().is_some_and(|v| v == name))
+            .and_then(|tag| tag.get(1))
+            .map(String::as_str)
+    }
+
+    // Checks the event was made within `window` seconds of now, so it can't be replayed later.
+    pub fn check_fresh(&self, window: u64) -> anyhow::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        if now.abs_diff(self.created_at) > window {
+            bail!("The event is too old, or your clock is wrong.");
+        }
+        Ok(())
+    }
+}
+
+// Parses a Nostr public key given as an npub or in hex, returning it in hex like events have it.
+pub fn parse_pubkey(key: &str) -> Option<String> {
+    if key.starts_with("npub1") {
+        let (hrp, data) = bech32::decode(key).ok()?;
+        return (hrp.as_str() == "npub" && data.len() == 32).then(|| hex::encode(data));
+    }
+
+    (key.len() == 64 && key.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| key.to_ascii_lowercase())
+}
+
+// Shows a hex public key as an npub, which is how people share them.
+pub fn npub(pubkey: &str) -> String {
+    hex::decode(pubkey)
+        .ok()
+        .and_then(|data| bech32::encode::<Bech32>(Hrp::parse_unchecked("npub"), &data).ok())
+        .unwrap_or_else(|| pubkey.to_string())
+}
@@ -0,0 +1,542 @@
+use std::{
+    ffi::OsString,
+    fs::{read_dir, read_to_string, set_permissions, write, Permissions},
+    io::{Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use tempfile::{tempdir, TempDir};
+
+use crate::{
+    config::{
+        check::{check_repo_config, check_server_config},
+        server::ServerConfig,
+    },
+    quota::{dir_size, format_size, repo_size, Limits},
+    vars::*,
+};
+
+const ZERO_OID: &str = "0000000000000000000000000000000000000000";
+
+// Rules for pushes to a repo, from the [policy] table in repo.toml.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct PushPolicy {
+    // The biggest file a push can add, like "512KiB" or "10MB".
+    pub max_file_size: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protect: Vec<ProtectedRefs>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProtectedRefs {
+    // A full ref name or a branch name, where "*" matches anything, e.g. "main" or "release/*".
+    pub refs: String,
+    #[serde(default)]
+    pub allow_force_push: bool,
+    #[serde(default)]
+    pub allow_delete: bool,
+    // Users or "@group"s allowed to update matching refs. Anyone with write access if unset.
+    pub pushers: Option<Vec<String>>,
+    // New commits must be signed by an SSH key registered on the server. GPG and X.509
+    // signatures aren't accepted, as the server has no keyring to check them against.
+    #[serde(default)]
+    pub require_signed_commits: bool,
+}
+
+// A config file a push may change, which is checked before the push is let in.
+#[derive(Serialize, Deserialize)]
+pub enum ConfigCheck {
+    // server.toml in the config repo, pushed by `username`.
+    Server { username: String },
+    // A repo's repo.toml, with the server config its grants go by.
+    Repo { server_config: Box<ServerConfig> },
+}
+
+// Everything the hook needs, worked out by the server ahead of time.
+#[derive(Serialize, Deserialize)]
+struct HookContext {
+    rules: Vec<Rule>,
+    max_file_size: Option<u64>,
+    max_repo_size: Option<u64>,
+    allowed_signers: Vec<String>,
+    config_check: Option<ConfigCheck>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Rule {
+    pattern: String,
+    allow_force_push: bool,
+    allow_delete: bool,
+    may_push: bool,
+    require_signed_commits: bool,
+}
+
+// A pre-receive hook enforcing a push policy and quota, which lives as long as this does.
+pub struct PreReceiveHook {
+    dir: TempDir,
+    // The most git-receive-pack takes in, so a pack too big for the repo's quota is refused
+    // before it's written rather than after.
+    max_input_size: Option<u64>,
+}
+
+impl PreReceiveHook {
+    pub fn new(
+        policy: &PushPolicy,
+        limits: &Limits,
+        server_config: &ServerConfig,
+        username: &str,
+        repo_path: &Path,
+        config_check: Option<ConfigCheck>,
+    ) -> anyhow::Result<Self> {
+        let max_file_size = policy
+            .max_file_size
+            .as_deref()
+            .map(parse_size)
+            .transpose()?
+            .into_iter()
+            .chain(limits.blob_size)
+            .min();
+
+        let rules = policy
+            .protect
+            .iter()
+            .map(|protected| Rule {
+                pattern: full_ref_pattern(&protected.refs),
+                allow_force_push: protected.allow_force_push,
+                allow_delete: protected.allow_delete,
+                may_push: protected.pushers.as_ref().is_none_or(|pushers| {
+                    pushers
+                        .iter()
+                        .any(|entry| server_config.grant_matches(entry, username))
+                }),
+                require_signed_commits: protected.require_signed_commits,
+            })
+            .collect();
+
+        let allowed_signers = server_config
+            .owned_keys()
+            .map(|(name, kind, data)| format!("{} namespaces=\"git\" {} {}", name, kind, data))
+            .collect();
+
+        let context = HookContext {
+            rules,
+            max_file_size,
+            max_repo_size: limits.repo_size,
+            allowed_signers,
+            config_check,
+        };
+
+        let dir = tempdir()?;
+        write(
+            dir.path().join(HOOK_CONTEXT_FILE),
+            serde_json::to_vec(&context)?,
+        )?;
+
+        // Setting the hooks path hides the repo's own hooks, so link them in. Its pre-receive
+        // hook is run by ours if the push passes.
+        if let Ok(entries) = read_dir(repo_path.join("hooks")) {
+            for entry in entries.flatten() {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if name == "pre-receive" || name.ends_with(".sample") {
+                    continue;
+                }
+                std::os::unix::fs::symlink(
+                    entry.path().canonicalize()?,
+                    dir.path().join(name.as_ref()),
+                )?;
+            }
+        }
+
+        let exe = std::env::current_exe().context("Couldn't find the server executable")?;
+        let script = format!(
+            "#!/bin/sh\nexec '{}' {}\n",
+            exe.to_string_lossy().replace('\'', "'\\''"),
+            PRE_RECEIVE_ARG
+        );
+        let hook = dir.path().join("pre-receive");
+        write(&hook, script)?;
+        set_permissions(&hook, Permissions::from_mode(0o755))?;
+
+        // The room the repo has left. To git no limit is 0, so a full repo gets 1 byte.
+        let max_input_size = limits
+            .repo_size
+            .map(|limit| limit.saturating_sub(repo_size(repo_path)).max(1));
+
+        Ok(Self {
+            dir,
+            max_input_size,
+        })
+    }
+
+    // Environment variables to run git-receive-pack with.
+    pub fn env(&self) -> Vec<(&'static str, OsString)> {
+        let mut env = vec![
+            ("GIT_CONFIG_COUNT", "1".into()),
+            ("GIT_CONFIG_KEY_0", "core.hooksPath".into()),
+            ("GIT_CONFIG_VALUE_0", self.dir.path().into()),
+            (HOOK_DIR_ENV, self.dir.path().into()),
+        ];
+        if let Some(max_input_size) = self.max_input_size {
+            env[0].1 = "2".into();
+            env.push(("GIT_CONFIG_KEY_1", "receive.maxInputSize".into()));
+            env.push(("GIT_CONFIG_VALUE_1", max_input_size.to_string().into()));
+        }
+        env
+    }
+
+    // Why the push was rejected, if it was.
+    pub fn rejections(&self) -> Vec<String> {
+        read_to_string(self.dir.path().join(HOOK_REJECTIONS_FILE))
+            .map(|text| text.lines().map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+}
+
+// Runs as git's pre-receive hook, returning the exit code.
+pub fn pre_receive() -> i32 {
+    let mut updates = String::new();
+    if let Err(e) = std::io::stdin().read_to_string(&mut updates) {
+        eprintln!("Failed to read ref updates: {}", e);
+        return 1;
+    }
+
+    match check_push(&updates) {
+        Ok(true) => run_repo_hook(&updates),
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("Failed to check push policy: {:#}", e);
+            1
+        }
+    }
+}
+
+fn check_push(input: &str) -> anyhow::Result<bool> {
+    let dir = PathBuf::from(std::env::var_os(HOOK_DIR_ENV).context("Not run by the server")?);
+    let context: HookContext = serde_json::from_str(&read_to_string(dir.join(HOOK_CONTEXT_FILE))?)?;
+
+    let signers_file = dir.join("allowed_signers");
+    write(&signers_file, context.allowed_signers.join("\n") + "\n")?;
+
+    let mut updates = Vec::new();
+    for line in input.lines() {
+        let mut parts = line.split(' ');
+        if let (Some(old), Some(new), Some(name)) = (parts.next(), parts.next(), parts.next()) {
+            updates.push((old.to_string(), new.to_string(), name.to_string()));
+        }
+    }
+
+    let mut rejections = Vec::new();
+    for (old, new, name) in &updates {
+        for rule in context
+            .rules
+            .iter()
+            .filter(|rule| glob_matches(&rule.pattern, name))
+        {
+            if !rule.may_push {
+                rejections.push(format!("You aren't allowed to push to {}.", name));
+                continue;
+            }
+
+            if new == ZERO_OID {
+                if !rule.allow_delete {
+                    rejections.push(format!("{} is protected from deletion.", name));
+                }
+                continue;
+            }
+
+            if old != ZERO_OID && !rule.allow_force_push && !is_ancestor(old, new)? {
+                rejections.push(format!(
+                    "{} is protected from force-pushes, pull and merge first.",
+                    name
+                ));
+            }
+
+            if rule.require_signed_commits {
+                for commit in new_commits(new)? {
+                    if !is_signed(&commit, &signers_file)? {
+                        rejections.push(format!(
+                            "Commit {} on {} isn't signed by an SSH key registered on this server.",
+                            &commit[..commit.len().min(10)],
+                            name
+                        ));
+                    }
+                }
+            }
+        }
+
+        if let (Some(limit), false) = (context.max_file_size, new == ZERO_OID) {
+            for (path, size) in large_files(new, limit)? {
+                rejections.push(format!(
+                    "{} in {} is {} bytes, over the limit of {}.",
+                    path, name, size, limit
+                ));
+            }
+        }
+    }
+
+    if let Some(config_check) = &context.config_check {
+        rejections.extend(check_config_change(config_check, &updates)?);
+    }
+
+    // New objects wait in a quarantine directory inside the repo's until the push is accepted,
+    // so they're counted too. Pushes that add nothing, like deleting a branch, are always let in.
+    if let Some(limit) = context.max_repo_size {
+        let incoming = std::env::var_os("GIT_QUARANTINE_PATH")
+            .map_or(0, |quarantine| dir_size(Path::new(&quarantine)));
+        let git_dir = std::env::var_os("GIT_DIR").unwrap_or_else(|| ".".into());
+        let size = repo_size(Path::new(&git_dir));
+        if incoming > 0 && size > limit {
+            rejections.push(format!(
+                "This push would bring the repository to {}, over its quota of {}.",
+                format_size(size),
+                format_size(limit)
+            ));
+        }
+    }
+
+    rejections.dedup();
+    if rejections.is_empty() {
+        return Ok(true);
+    }
+
+    write(dir.join(HOOK_REJECTIONS_FILE), rejections.join("\n") + "\n")?;
+    Ok(false)
+}
+
+// Checks the config file on the branch the server reads it from, if the push changes it.
+// Warnings are passed on to the pusher, and errors are returned as rejections.
+fn check_config_change(
+    config_check: &ConfigCheck,
+    updates: &[(String, String, String)],
+) -> anyhow::Result<Vec<String>> {
+    let file = match config_check {
+        ConfigCheck::Server { .. } => SERVER_CONFIG_FILE,
+        ConfigCheck::Repo { .. } => REPO_CONFIG_FILE,
+    };
+
+    let head = Command::new("git")
+        .args(["symbolic-ref", "-q", "HEAD"])
+        .output()?;
+    let head = String::from_utf8_lossy(&head.stdout).trim().to_string();
+    let Some((old, new, _)) = updates.iter().find(|(_, _, name)| *name == head) else {
+        return Ok(Vec::new());
+    };
+
+    if new == ZERO_OID {
+        return Ok(vec![format!(
+            "{} can't be deleted, as it holds {}.",
+            head, file
+        )]);
+    }
+    let Some(blob) = blob_id(new, file)? else {
+        return Ok(vec![format!("{} can't be removed from {}.", file, head)]);
+    };
+    if old != ZERO_OID && blob_id(old, file)?.as_ref() == Some(&blob) {
+        return Ok(Vec::new());
+    }
+
+    let output = Command::new("git")
+        .args(["cat-file", "blob", &blob])
+        .output()?;
+    let Ok(text) = String::from_utf8(output.stdout) else {
+        return Ok(vec![format!("{} isn't valid UTF-8.", file)]);
+    };
+
+    let problems = match config_check {
+        ConfigCheck::Server { username } => check_server_config(&text, Some(username)),
+        ConfigCheck::Repo { server_config } => check_repo_config(&text, Some(server_config)),
+    };
+    let mut rejections = Vec::new();
+    for problem in problems {
+        if problem.is_error {
+            rejections.push(problem.describe(file));
+        } else {
+            eprintln!("{}", problem.describe(file));
+        }
+    }
+    Ok(rejections)
+}
+
+// The id of `file` in `commit`, if it's there.
+fn blob_id(commit: &str, file: &str) -> anyhow::Result<Option<String>> {
+    let output = Command::new("git")
+        .args([
+            "rev-parse",
+            "--verify",
+            "-q",
+            &format!("{}:{}", commit, file),
+        ])
+        .output()?;
+    Ok(output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string()))
+}
+
+// Hands the push to the repo's own pre-receive hook, if it has one.
+fn run_repo_hook(updates: &str) -> i32 {
+    let git_dir = std::env::var_os("GIT_DIR").unwrap_or_else(|| ".".into());
+    let hook = Path::new(&git_dir).join("hooks").join("pre-receive");
+    if !hook.is_file() {
+        return 0;
+    }
+
+    let result = Command::new(&hook)
+        .stdin(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(updates.as_bytes())?;
+            }
+            child.wait()
+        });
+
+    match result {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(e) => {
+            eprintln!("Failed to run pre-receive hook: {}", e);
+            1
+        }
+    }
+}
+
+fn is_ancestor(old: &str, new: &str) -> anyhow::Result<bool> {
+    let status = Command::new("git")
+        .args(["merge-base", "--is-ancestor", old, new])
+        .status()?;
+    match status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => Err(anyhow!("Couldn't compare {} with {}", old, new)),
+    }
+}
+
+// The commits a push adds to the repo.
+fn new_commits(new: &str) -> anyhow::Result<Vec<String>> {
+    let output = Command::new("git")
+        .args(["rev-list", new, "--not", "--all"])
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!("Couldn't list new commits in {}", new));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+// Whether `commit` has an SSH signature by one of the allowed signers. Other kinds of signature
+// would be checked against whatever keyring the server happens to have, so don't count.
+fn is_signed(commit: &str, signers_file: &Path) -> anyhow::Result<bool> {
+    let object = Command::new("git")
+        .args(["cat-file", "commit", commit])
+        .output()?;
+    let header = String::from_utf8_lossy(&object.stdout);
+    let header = header.split("\n\n").next().unwrap_or_default();
+    if !header.contains("\ngpgsig -----BEGIN SSH SIGNATURE-----") {
+        return Ok(false);
+    }
+
+    let status = Command::new("git")
+        .arg("-c")
+        .arg(format!(
+            "gpg.ssh.allowedSignersFile={}",
+            signers_file.to_string_lossy()
+        ))
+        .args(["verify-commit", commit])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    Ok(status.success())
+}
+
+// The files a push adds that are bigger than `limit`, with their sizes.
+fn large_files(new: &str, limit: u64) -> anyhow::Result<Vec<(String, u64)>> {
+    let objects = Command::new("git")
+        .args(["rev-list", "--objects", new, "--not", "--all"])
+        .output()?;
+    if !objects.status.success() {
+        return Err(anyhow!("Couldn't list new objects in {}", new));
+    }
+
+    let mut batch = Command::new("git")
+        .args([
+            "cat-file",
+            "--batch-check=%(objecttype) %(objectsize) %(rest)",
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = batch.stdin.take().context("No stdin for git cat-file")?;
+    let input = objects.stdout;
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = batch.wait_with_output()?;
+    writer
+        .join()
+        .map_err(|_| anyhow!("Failed to write to git cat-file"))??;
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, ' ');
+            let (kind, size) = (parts.next()?, parts.next()?.parse().ok()?);
+            (kind == "blob" && size > limit)
+                .then(|| (parts.next().unwrap_or_default().to_string(), size))
+        })
+        .collect())
+}
+
+fn full_ref_pattern(refs: &str) -> String {
+    if refs.starts_with("refs/") {
+        refs.to_string()
+    } else {
+        format!("refs/heads/{}", refs)
+    }
+}
+
+// Matches `text` against a pattern where "*" stands for any run of characters.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=text.len())
+                .filter(|i| text.is_char_boundary(*i))
+                .any(|i| glob_matches(rest, &text[i..]))
+        }
+    }
+}
+
+// Parses sizes like "1048576", "512KiB" or "10MB".
+pub fn parse_size(size: &str) -> anyhow::Result<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid size {:?}", size))?;
+
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "kib" => 1 << 10,
+        "m" | "mb" => 1000 * 1000,
+        "mib" => 1 << 20,
+        "g" | "gb" => 1000 * 1000 * 1000,
+        "gib" => 1 << 30,
+        _ => return Err(anyhow!("Unknown unit in size {:?}", size)),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("Size {:?} is too big", size))
+}
@@ -0,0 +1,195 @@
+use std::{
+    fs::read_dir,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::server::ServerConfig, git::find_repos, policy::parse_size, vars::*};
+
+// Limits on the space repos take up. The server's [quota] table sets the defaults, each user's
+// own overrides them, and a repo's can only make them stricter.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Quota {
+    // The most a repo's git objects can take up, like "1GiB". LFS objects have their own quota.
+    pub repo_size: Option<String>,
+    // The biggest file a push can add.
+    pub blob_size: Option<String>,
+    // How many repos a user can have in their personal directory. Ignored in repo.toml.
+    pub repos: Option<usize>,
+}
+
+// A quota with its sizes parsed, None meaning unlimited.
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    pub repo_size: Option<u64>,
+    pub blob_size: Option<u64>,
+    pub repos: Option<usize>,
+}
+
+impl Quota {
+    fn limits(&self) -> anyhow::Result<Limits> {
+        let size = |size: &Option<String>| size.as_deref().map(parse_size).transpose();
+        Ok(Limits {
+            repo_size: size(&self.repo_size).context("Invalid repo_size")?,
+            blob_size: size(&self.blob_size).context("Invalid blob_size")?,
+            repos: self.repos,
+        })
+    }
+}
+
+impl Limits {
+    // Whether pushes need checking at all.
+    pub fn limits_pushes(&self) -> bool {
+        self.repo_size.is_some() || self.blob_size.is_some()
+    }
+
+    // Takes the lower of each limit.
+    fn tighten(self, other: Limits) -> Limits {
+        fn min<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+
+        Limits {
+            repo_size: min(self.repo_size, other.repo_size),
+            blob_size: min(self.blob_size, other.blob_size),
+            repos: min(self.repos, other.repos),
+        }
+    }
+}
+
+fn server_limits(server_config: &ServerConfig) -> anyhow::Result<Limits> {
+    Ok(server_config
+        .quota
+        .as_ref()
+        .map(Quota::limits)
+        .transpose()
+        .context("The server's quota is invalid")?
+        .unwrap_or_default())
+}
+
+// The server's defaults, overridden by whatever the user's own quota sets.
+pub fn user_limits(server_config: &ServerConfig, username: &str) -> anyhow::Result<Limits> {
+    let defaults = server_limits(server_config)?;
+
+    let Some(quota) = server_config
+        .users
+        .get(username)
+        .and_then(|user| user.quota.as_ref())
+    else {
+        return Ok(defaults);
+    };
+
+    let own = quota
+        .limits()
+        .with_context(|| format!("{}'s quota is invalid", username))?;
+    Ok(Limits {
+        repo_size: own.repo_size.or(defaults.repo_size),
+        blob_size: own.blob_size.or(defaults.blob_size),
+        repos: own.repos.or(defaults.repos),
+    })
+}
+
+// The limits on a repo, which go by whose personal directory it's in, or the server's defaults
+// for repos outside of those. The server config repo is never limited.
+pub fn repo_limits(
+    server_config: &ServerConfig,
+    repo_path: &Path,
+    repo_quota: Option<&Quota>,
+) -> anyhow::Result<Limits> {
+    if repo_path == Path::new(SERVER_CONFIG_REPO) {
+        return Ok(Limits::default());
+    }
+
+    let limits = match owner(server_config, repo_path) {
+        Some(owner) => user_limits(server_config, owner)?,
+        None => server_limits(server_config)?,
+    };
+
+    let Some(quota) = repo_quota else {
+        return Ok(limits);
+    };
+    let own = quota
+        .limits()
+        .context("This repository's quota is invalid")?;
+    Ok(limits.tighten(Limits { repos: None, ..own }))
+}
+
+// Checks a user's quota has room for another repo in their personal directory. Admins can
+// have as many as they like.
+pub fn check_new_repo(server_config: &ServerConfig, username: &str) -> anyhow::Result<()> {
+    if server_config.is_admin(username) {
+        return Ok(());
+    }
+
+    let Some(limit) = user_limits(server_config, username)?.repos else {
+        return Ok(());
+    };
+    let count = user_repos(username).len();
+    if count >= limit {
+        bail!(
+            "Your quota allows {} repositories, and you already have {}.",
+            limit,
+            count
+        );
+    }
+
+    Ok(())
+}
+
+// The user whose personal directory a repo is in, if it's in one.
+pub fn owner<'a>(server_config: &ServerConfig, repo_path: &'a Path) -> Option<&'a str> {
+    let first = repo_path.components().next()?.as_os_str().to_str()?;
+    (repo_path.components().count() > 1 && server_config.users.contains_key(first)).then_some(first)
+}
+
+// The repos in a user's personal directory.
+pub fn user_repos(username: &str) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    find_repos(Path::new(username), Path::new(username), &mut found);
+    found.sort();
+    found
+}
+
+// How much space a repo's git objects take up, including any a push in progress has added.
+pub fn repo_size(repo_path: &Path) -> u64 {
+    dir_size(&repo_path.join("objects"))
+}
+
+pub fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => dir_size(&entry.path()),
+            Ok(_) => entry.metadata().map(|m| m.len()).unwrap_or(0),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+// Formats a size for people, like "12.3 MiB".
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+
+    let mut value = size as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+    format!("{:.1} {}", value, unit)
+}
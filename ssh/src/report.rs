@@ -0,0 +1,159 @@
+// The summary shown after a push: what happened to each ref, and where to see it on the web.
+
+use std::{collections::BTreeMap, path::Path};
+
+use log::warn;
+
+use crate::git::Repo;
+
+// The first few characters of a commit id, as git shows them.
+const SHORT_ID: usize = 7;
+
+// Describes how a push to `repo_path` moved its refs from `before` to `after`, one line per
+// message. Links go to the web server at `web_url`, if it's known.
+pub async fn push_report(
+    repo_path: &Path,
+    before: &BTreeMap<String, String>,
+    after: &BTreeMap<String, String>,
+    web_url: Option<&str>,
+) -> Vec<String> {
+    let repo = repo_path.to_str().unwrap_or_default();
+    let base = web_url.map(|url| format!("{}/{}", url.trim_end_matches('/'), encode(repo)));
+
+    // New branches are reviewed against the default branch.
+    let default_branch = match Repo::head_ref(repo_path).await {
+        Ok(head) => head.and_then(|head| head.strip_prefix("refs/heads/").map(str::to_string)),
+        Err(e) => {
+            warn!("Couldn't read HEAD of {}: {:#}", repo, e);
+            None
+        }
+    };
+    let existing: Vec<&str> = before.values().map(String::as_str).collect();
+
+    let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    let mut lines = Vec::new();
+    for name in names {
+        let (old, new) = match (before.get(name), after.get(name)) {
+            (Some(old), Some(new)) if old == new => continue,
+            (old, new) => (old.map(String::as_str), new.map(String::as_str)),
+        };
+
+        let branch = name.strip_prefix("refs/heads/");
+        let label = match (branch, name.strip_prefix("refs/tags/")) {
+            (Some(branch), _) => branch.to_string(),
+            (None, Some(tag)) => format!("tag {}", tag),
+            (None, None) => name.clone(),
+        };
+
+        let (old, new) = match (old, new) {
+            (Some(old), None) => {
+                lines.push(format!("{}: deleted (was {})", label, short(old)));
+                continue;
+            }
+            (None, Some(new)) if branch.is_none() => {
+                lines.push(format!("{}: new at {}", label, short(new)));
+                continue;
+            }
+            (old, Some(new)) => (old, new),
+            (None, None) => continue,
+        };
+
+        // Only branches have commits worth counting.
+        let exclude = old.map_or(existing.clone(), |old| vec![old]);
+        let count = if branch.is_some() {
+            count_commits(repo_path, new, &exclude).await
+        } else {
+            String::new()
+        };
+
+        let Some(old) = old else {
+            lines.push(format!("{}: new branch at {}{}", label, short(new), count));
+            let Some(branch) = branch else {
+                continue;
+            };
+            if let Some(base) = &base {
+                lines.push(format!("  log: {}/log?h={}", base, encode(branch)));
+            }
+            if let Some(default) = default_branch.as_deref().filter(|v| *v != branch) {
+                if let Some(base) = &base {
+                    lines.push(format!(
+                        "  review: {}/compare?from={}&to={}",
+                        base,
+                        encode(default),
+                        encode(branch)
+                    ));
+                }
+                lines.push(format!(
+                    "  To propose it as a NIP-34 patch: ngit send {}..{}",
+                    default, branch
+                ));
+            }
+            continue;
+        };
+
+        let forced = !Repo::is_ancestor(repo_path, old, new).await.unwrap_or(true);
+        if forced {
+            lines.push(format!(
+                "{}: {}...{} (forced update){}",
+                label,
+                short(old),
+                short(new),
+                count
+            ));
+        } else {
+            lines.push(format!(
+                "{}: {}..{}{}",
+                label,
+                short(old),
+                short(new),
+                count
+            ));
+        }
+
+        if let Some(base) = &base {
+            lines.push(format!(
+                "  compare: {}/compare?from={}&to={}",
+                base, old, new
+            ));
+            if let Some(branch) = branch {
+                lines.push(format!("  log: {}/log?h={}", base, encode(branch)));
+            }
+        }
+    }
+
+    lines
+}
+
+// ", 3 new commits", or nothing if there aren't any or they couldn't be counted.
+async fn count_commits(repo_path: &Path, new: &str, exclude: &[&str]) -> String {
+    match Repo::count_commits(repo_path, new, exclude).await {
+        Ok(0) => String::new(),
+        Ok(1) => ", 1 new commit".to_string(),
+        Ok(count) => format!(", {} new commits", count),
+        Err(e) => {
+            warn!("Couldn't count commits in {}: {:#}", repo_path.display(), e);
+            String::new()
+        }
+    }
+}
+
+fn short(id: &str) -> &str {
+    id.get(..SHORT_ID).unwrap_or(id)
+}
+
+// Percent-encodes anything in a repo path or ref name that can't go in a URL as it is. Ref names
+// can contain characters like "#" and "&".
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
@@ -1,29 +1,33 @@
 use std::{
-    fs::{create_dir_all, read_to_string, write},
+    fs::{create_dir_all, write},
     path::{Path, PathBuf},
 };
 
 use anyhow::Context as AnyhowContext;
 use comrak::{markdown_to_html, ComrakOptions};
-use tempfile::tempdir;
 use tera::{Context, Tera};
 
-use crate::{config::repo::RepoConfig, git::Repo, state::State, vars::*};
+use crate::{git::Repo, state::Configs};
 
-impl State {
+// Where the generated page for a repo lives.
+pub fn static_path(repo_path: &Path) -> PathBuf {
+    let mut static_path = PathBuf::from("static").join(repo_path);
+    if let Some(ext) = static_path.extension() {
+        if ext == "git" {
+            static_path.set_extension("");
+        }
+    }
+    static_path
+}
+
+impl Configs {
     pub async fn rebuild_site(&self, repo_path: &Path) -> anyhow::Result<()> {
-        let config_name = PathBuf::from(REPO_CONFIG_FILE);
         let readmes = ["README.md", "readme.me"];
 
-        let temp_dir = tempdir()?;
-        let clone_dir = temp_dir.path().join(repo_path);
-        Repo::clone(repo_path, &clone_dir).await?;
-
-        let config: RepoConfig = {
-            let text =
-                read_to_string(clone_dir.join(&config_name)).context("Couldn't read eejit.toml")?;
-            toml::from_str(&text)?
+        let Some(head) = Repo::head_id(repo_path).await? else {
+            return Ok(());
         };
+        let config = self.repo_config(repo_path).await?;
 
         if !config.public {
             return Ok(());
@@ -31,18 +35,17 @@ impl State {
 
         let mut readme = None;
         for r in readmes {
-            let path = clone_dir.join(r);
-            if path.exists() {
-                readme = Some(path);
+            if let Some(data) = Repo::read_file(repo_path, head, Path::new(r)).await? {
+                readme = Some(data);
                 break;
             }
         }
 
-        if readme.is_none() {
+        let Some(readme) = readme else {
             return Ok(());
-        }
+        };
 
-        let readme = read_to_string(readme.unwrap())?;
+        let readme = String::from_utf8_lossy(&readme);
         let body = markdown_to_html(&readme, &ComrakOptions::default());
 
         let mut context = Context::new();
@@ -60,7 +63,10 @@ impl State {
 
         let template = {
             if let Some(path) = config.web_template {
-                read_to_string(clone_dir.join(path)).context("Couldn't read user template")?
+                let data = Repo::read_file(repo_path, head, Path::new(&path))
+                    .await?
+                    .context("Couldn't read user template")?;
+                String::from_utf8(data).context("User template isn't valid UTF-8")?
             } else {
                 include_str!("default.html").to_string()
             }
@@ -68,13 +74,7 @@ impl State {
 
         let result = Tera::one_off(&template, &context, true)?;
 
-        let mut static_path = PathBuf::from("static").join(repo_path);
-        if let Some(ext) = static_path.extension() {
-            if ext == "git" {
-                static_path.set_extension("");
-            }
-        }
-
+        let static_path = static_path(repo_path);
         if !static_path.exists() {
             create_dir_all(&static_path)?;
         }
@@ -0,0 +1,995 @@
+use std::{
+    fs::{create_dir_all, remove_dir_all, rename},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{error, info};
+use russh::keys::HashAlg;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+
+use crate::config::repo::{new_repo_config, update_repo_config, AccessLevel, RepoConfig};
+use crate::config::server::{update_server_config, ServerConfig};
+use crate::git::{find_repos, Repo};
+use crate::nostr::{npub, parse_pubkey, Event, SSH_KEY_BINDING_KIND};
+use crate::push_mirrors::{self, status_of_mirrors, PushMirror};
+use crate::quota::{check_new_repo, format_size, repo_limits, repo_size, user_limits, user_repos};
+use crate::site::static_path;
+use crate::state::State;
+use crate::tui::age;
+use crate::vars::*;
+
+use super::commands::{check_new_repo_path, parse_repo_path, Knob, CREATING_REPOS};
+use super::Handler;
+
+const USAGE: &str = "\
+Commands:
+  info                                        List the repositories you can access
+  whoami                                      Show who you're authenticated as
+  repo create <repo>                          Create an empty repository
+  repo delete <repo>                          Delete a repository
+  repo rename <repo> <new path>               Move a repository
+  repo fork <repo> <new path>                 Copy a repository somewhere you can push to
+  repo set-public <repo> <true|false>         Choose whether anyone can read a repository
+  repo members add <repo> <user|@group> [read|write|admin]
+                                              Grant access to a repository, write by default
+  repo members remove <repo> <user|@group>    Revoke access to a repository
+  repo mirrors <repo>                         Show how pushing to a repository's mirrors went
+  repo mirrors push <repo>                    Push a repository to its mirrors now
+  user add-key <user> <public key>            Let another key log in as a user
+  user remove-key <user> <key|fingerprint>    Stop a key from logging in as a user
+  user set-npub <user> <npub>                 Set the Nostr public key a user logs in to the web with
+  user bind-key <event>                       Let a key log in as the user whose npub signed the
+                                              binding event, given as JSON or base64
+  quota [user]                                Show how much of a quota a user's repositories use
+
+Add --json to any command for machine-readable output.
+";
+
+// What a command prints, both for people and for scripts.
+struct Output {
+    text: String,
+    json: Value,
+}
+
+impl Output {
+    fn new(text: impl Into<String>, json: Value) -> Self {
+        Self {
+            text: text.into(),
+            json,
+        }
+    }
+}
+
+// Runs a single command on behalf of the connected user.
+struct Admin {
+    state: Arc<Mutex<State>>,
+    server_config: ServerConfig,
+    username: Option<String>,
+}
+
+impl Handler {
+    pub async fn handle_admin_command(
+        &mut self,
+        knob: Knob,
+        mut args: Vec<String>,
+        // Interactive logins have a terminal that won't return the carriage by itself.
+        crlf: bool,
+    ) -> anyhow::Result<()> {
+        let json = args.iter().any(|arg| arg == "--json");
+        args.retain(|arg| arg != "--json");
+
+        let state = self.state.clone();
+        let username = self.username.clone();
+
+        let fut = async move {
+            let server_config = state.lock().await.server_config.clone();
+            let admin = Admin {
+                state,
+                server_config,
+                username,
+            };
+
+            let status = match admin.run(&args).await {
+                Ok(output) => {
+                    let mut text = if json {
+                        format!("{}\n", output.json)
+                    } else {
+                        output.text
+                    };
+                    if crlf {
+                        text = text.replace('\n', "\r\n");
+                    }
+                    knob.data(text.as_bytes()).await?;
+                    0
+                }
+                Err(e) => {
+                    if json {
+                        let text = format!("{}\n", json!({ "error": format!("{:#}", e) }));
+                        knob.data(text.as_bytes()).await?;
+                    } else {
+                        knob.error(&format!("{:#}", e)).await?;
+                    }
+                    1
+                }
+            };
+
+            knob.exit_status(status).await?;
+            knob.eof().await?;
+            knob.close().await?;
+            Ok::<(), anyhow::Error>(())
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = fut.await {
+                error!("{:#}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Admin {
+    async fn run(&self, args: &[String]) -> anyhow::Result<Output> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+        match args.as_slice() {
+            [] | ["help"] => Ok(Output::new(USAGE, json!({ "usage": USAGE }))),
+            ["info"] => self.info().await,
+            ["whoami"] => Ok(self.whoami()),
+            ["repo", "create", repo] => self.create(repo).await,
+            ["repo", "delete", repo] => self.delete(repo).await,
+            ["repo", "rename", repo, to] => self.rename(repo, to).await,
+            ["repo", "fork", repo, to] => self.fork(repo, to).await,
+            ["repo", "set-public", repo, public] => self.set_public(repo, public).await,
+            ["repo", "members", "add", repo, member] => {
+                self.add_member(repo, member, AccessLevel::Write).await
+            }
+            ["repo", "members", "add", repo, member, level] => {
+                let level = match *level {
+                    "read" => AccessLevel::Read,
+                    "write" => AccessLevel::Write,
+                    "admin" => AccessLevel::Admin,
+                    _ => bail!("The access level must be read, write or admin."),
+                };
+                self.add_member(repo, member, level).await
+            }
+            ["repo", "members", "remove", repo, member] => self.remove_member(repo, member).await,
+            ["repo", "mirrors", "push", repo] => self.push_mirrors(repo).await,
+            ["repo", "mirrors", repo] => self.mirrors(repo).await,
+            // Keys get split up by the shell, so put them back together.
+            ["user", "add-key", user, key @ ..] if !key.is_empty() => {
+                self.add_key(user, &key.join(" ")).await
+            }
+            ["user", "remove-key", user, key @ ..] if !key.is_empty() => {
+                self.remove_key(user, &key.join(" ")).await
+            }
+            ["user", "set-npub", user, npub] => self.set_npub(user, npub).await,
+            ["user", "bind-key", event @ ..] if !event.is_empty() => {
+                self.bind_key(&event.join(" ")).await
+            }
+            ["quota"] => self.quota(self.username()?).await,
+            ["quota", user] => self.quota(user).await,
+            _ => bail!("Unknown command, run `help` to see what's available."),
+        }
+    }
+
+    async fn info(&self) -> anyhow::Result<Output> {
+        let username = self.username.as_deref().unwrap_or(GUEST_USERNAME);
+
+        let mut found = Vec::new();
+        find_repos(Path::new("."), Path::new(""), &mut found);
+        found.sort();
+
+        let mut repos = Vec::new();
+        for repo_path in found {
+            match self.access(&repo_path).await {
+                Ok(level) if level >= AccessLevel::Read => repos.push((repo_path, level)),
+                Ok(_) => {}
+                Err(e) => error!("Couldn't check access to {}: {:#}", repo_path.display(), e),
+            }
+        }
+
+        let mut text = format!(
+            "hello {}, this is {}\n\n",
+            username, self.server_config.name
+        );
+        for (repo_path, level) in &repos {
+            let flag = |required, flag| if *level >= required { flag } else { " " };
+            text.push_str(&format!(
+                " {} {} {}  {}\n",
+                flag(AccessLevel::Read, "R"),
+                flag(AccessLevel::Write, "W"),
+                flag(AccessLevel::Admin, "A"),
+                repo_path.display()
+            ));
+        }
+
+        let json = json!({
+            "username": username,
+            "server": self.server_config.name,
+            "repos": repos
+                .iter()
+                .map(|(repo_path, level)| json!({
+                    "path": repo_path,
+                    "access": level.as_str(),
+                }))
+                .collect::<Vec<_>>(),
+        });
+
+        Ok(Output::new(text, json))
+    }
+
+    fn whoami(&self) -> Output {
+        let Some(username) = &self.username else {
+            return Output::new(
+                format!("{} (your key isn't registered)\n", GUEST_USERNAME),
+                json!({ "username": GUEST_USERNAME, "guest": true }),
+            );
+        };
+
+        let user = self.server_config.users.get(username);
+        let is_admin = self.server_config.is_admin(username);
+        let can_create_repos = user.and_then(|user| user.can_create_repos).unwrap_or(false);
+        let groups = self.server_config.groups_of(username);
+        let keys = user.map_or(0, |user| user.keys().count() + user.bound_keys().len());
+        let npub = user
+            .and_then(|user| user.pubkey())
+            .map(|pubkey| npub(&pubkey));
+
+        let mut text = username.to_string();
+        if is_admin {
+            text.push_str(" (admin)");
+        }
+        text.push('\n');
+        if let Some(npub) = &npub {
+            text.push_str(&format!("npub: {}\n", npub));
+        }
+        if !groups.is_empty() {
+            text.push_str(&format!("groups: {}\n", groups.join(", ")));
+        }
+        text.push_str(&format!(
+            "can create repos: {}\nkeys: {}\n",
+            if can_create_repos || is_admin {
+                "yes"
+            } else {
+                "no"
+            },
+            keys
+        ));
+
+        let json = json!({
+            "username": username,
+            "guest": false,
+            "admin": is_admin,
+            "can_create_repos": can_create_repos || is_admin,
+            "groups": groups,
+            "keys": keys,
+            "npub": npub,
+        });
+
+        Output::new(text, json)
+    }
+
+    async fn create(&self, repo: &str) -> anyhow::Result<Output> {
+        let username = self.username()?;
+        let creating = CREATING_REPOS.lock().await;
+        let repo_path = self.new_repo_path(repo)?;
+        check_new_repo(&self.server_config, username)?;
+
+        Repo::create_bare(&repo_path).await?;
+        drop(creating);
+        new_repo_config(&repo_path, username).await?;
+        info!("{} created {}", username, repo_path.display());
+
+        Ok(Output::new(
+            format!("Created {}\n", repo_path.display()),
+            json!({ "created": repo_path }),
+        ))
+    }
+
+    async fn delete(&self, repo: &str) -> anyhow::Result<Output> {
+        let username = self.username()?;
+        let repo_path = self.existing_repo_path(repo, AccessLevel::Admin).await?;
+
+        remove_dir_all(&repo_path)?;
+        self.state.lock().await.forget_repo_config(&repo_path);
+        let site = static_path(&repo_path);
+        if site.exists() {
+            remove_dir_all(site)?;
+        }
+        info!("{} deleted {}", username, repo_path.display());
+
+        Ok(Output::new(
+            format!("Deleted {}\n", repo_path.display()),
+            json!({ "deleted": repo_path }),
+        ))
+    }
+
+    async fn rename(&self, repo: &str, to: &str) -> anyhow::Result<Output> {
+        let username = self.username()?;
+        let repo_path = self.existing_repo_path(repo, AccessLevel::Admin).await?;
+        let new_path = self.new_repo_path(to)?;
+
+        if let Some(parent) = new_path.parent() {
+            create_dir_all(parent)?;
+        }
+        rename(&repo_path, &new_path)?;
+        self.state.lock().await.forget_repo_config(&repo_path);
+        info!(
+            "{} renamed {} to {}",
+            username,
+            repo_path.display(),
+            new_path.display()
+        );
+
+        let site = static_path(&repo_path);
+        if site.exists() {
+            remove_dir_all(site)?;
+        }
+
+        if Repo::mirror_of(&new_path).await?.is_none() {
+            // New repos are named after their path, so keep that up to date.
+            let old_name = repo_path.to_string_lossy().to_string();
+            let new_name = new_path.to_string_lossy().to_string();
+            let configs = self.state.lock().await.configs();
+            let config = configs.repo_config(&new_path).await?;
+            if config.name == old_name {
+                update_repo_config(
+                    &new_path,
+                    &self.server_config.author(username),
+                    &format!("chore: rename to {}", new_name),
+                    |config| {
+                        config.name = new_name.clone();
+                        Ok(())
+                    },
+                )
+                .await?;
+            }
+
+            self.rebuild_site(&new_path).await;
+        }
+
+        Ok(Output::new(
+            format!(
+                "Renamed {} to {}\n",
+                repo_path.display(),
+                new_path.display()
+            ),
+            json!({ "renamed": repo_path, "to": new_path }),
+        ))
+    }
+
+    async fn fork(&self, repo: &str, to: &str) -> anyhow::Result<Output> {
+        let username = self.username()?;
+        let repo_path = self.existing_repo_path(repo, AccessLevel::Read).await?;
+        let creating = CREATING_REPOS.lock().await;
+        let new_path = self.new_repo_path(to)?;
+        check_new_repo(&self.server_config, username)?;
+
+        let size = repo_size(&repo_path);
+        if let Some(limit) = repo_limits(&self.server_config, &new_path, None)?.repo_size {
+            if size > limit {
+                bail!(
+                    "{} is {}, over the quota of {} for {}.",
+                    repo_path.display(),
+                    format_size(size),
+                    format_size(limit),
+                    new_path.display()
+                );
+            }
+        }
+
+        Repo::clone_bare(&repo_path, &new_path).await?;
+        drop(creating);
+        new_repo_config(&new_path, username).await?;
+        info!(
+            "{} forked {} to {}",
+            username,
+            repo_path.display(),
+            new_path.display()
+        );
+
+        Ok(Output::new(
+            format!("Forked {} to {}\n", repo_path.display(), new_path.display()),
+            json!({ "forked": repo_path, "to": new_path }),
+        ))
+    }
+
+    async fn set_public(&self, repo: &str, public: &str) -> anyhow::Result<Output> {
+        let public = match public {
+            "true" | "yes" => true,
+            "false" | "no" => false,
+            _ => bail!("Say true or false."),
+        };
+
+        let repo_path = self.existing_repo_path(repo, AccessLevel::Admin).await?;
+        self.edit_repo_config(
+            &repo_path,
+            &format!(
+                "chore: make repo {}",
+                if public { "public" } else { "private" }
+            ),
+            |config| {
+                config.public = public;
+                Ok(())
+            },
+        )
+        .await?;
+
+        if public {
+            self.rebuild_site(&repo_path).await;
+        } else {
+            let site = static_path(&repo_path);
+            if site.exists() {
+                remove_dir_all(site)?;
+            }
+        }
+
+        Ok(Output::new(
+            format!(
+                "{} is now {}\n",
+                repo_path.display(),
+                if public { "public" } else { "private" }
+            ),
+            json!({ "repo": repo_path, "public": public }),
+        ))
+    }
+
+    async fn add_member(
+        &self,
+        repo: &str,
+        member: &str,
+        level: AccessLevel,
+    ) -> anyhow::Result<Output> {
+        let repo_path = self.existing_repo_path(repo, AccessLevel::Admin).await?;
+
+        // People without an account can be granted access by their npub, to use on the web.
+        let npub = parse_pubkey(member).map(|pubkey| npub(&pubkey));
+        let member = npub.as_deref().unwrap_or(member);
+        match member.strip_prefix('@') {
+            Some(group) if !self.server_config.groups.contains_key(group) => {
+                bail!("There's no group called {}.", group)
+            }
+            None if npub.is_none() && !self.server_config.users.contains_key(member) => {
+                bail!("There's no user called {}.", member)
+            }
+            _ => {}
+        }
+
+        self.edit_repo_config(
+            &repo_path,
+            &format!("chore: grant {} {} access", member, level.as_str()),
+            |config| {
+                revoke(config, member);
+                let grants = match level {
+                    AccessLevel::Read => &mut config.access.read,
+                    AccessLevel::Write => &mut config.access.write,
+                    _ => &mut config.access.admin,
+                };
+                grants.push(member.to_string());
+                Ok(())
+            },
+        )
+        .await?;
+
+        Ok(Output::new(
+            format!(
+                "Granted {} {} access to {}\n",
+                member,
+                level.as_str(),
+                repo_path.display()
+            ),
+            json!({ "repo": repo_path, "member": member, "access": level.as_str() }),
+        ))
+    }
+
+    async fn remove_member(&self, repo: &str, member: &str) -> anyhow::Result<Output> {
+        let repo_path = self.existing_repo_path(repo, AccessLevel::Admin).await?;
+
+        self.edit_repo_config(
+            &repo_path,
+            &format!("chore: revoke access for {}", member),
+            |config| {
+                if revoke(config, member) {
+                    Ok(())
+                } else {
+                    Err(anyhow!("{} hasn't been granted access.", member))
+                }
+            },
+        )
+        .await?;
+
+        Ok(Output::new(
+            format!("Revoked {}'s access to {}\n", member, repo_path.display()),
+            json!({ "repo": repo_path, "member": member, "access": AccessLevel::None.as_str() }),
+        ))
+    }
+
+    async fn mirrors(&self, repo: &str) -> anyhow::Result<Output> {
+        let repo_path = self.existing_repo_path(repo, AccessLevel::Write).await?;
+        let mirrors = self.push_mirrors_of(&repo_path).await?;
+
+        let statuses = status_of_mirrors(&repo_path, &mirrors);
+        let mut text = String::new();
+        for status in &statuses {
+            let last_success = match status.last_success {
+                Some(time) => format!("pushed {}", age(time as i64)),
+                None => "never pushed".to_string(),
+            };
+            text.push_str(&format!("{}  {}", status.url, last_success));
+            if status.pending {
+                text.push_str(if status.last_error.is_some() {
+                    ", retrying"
+                } else {
+                    ", pushing"
+                });
+            }
+            text.push('\n');
+            if let Some(error) = &status.last_error {
+                text.push_str(&format!("  last error: {}\n", error));
+            }
+        }
+
+        let json = json!({
+            "repo": repo_path,
+            "mirrors": statuses,
+        });
+        Ok(Output::new(text, json))
+    }
+
+    async fn push_mirrors(&self, repo: &str) -> anyhow::Result<Output> {
+        let repo_path = self.existing_repo_path(repo, AccessLevel::Write).await?;
+        let mirrors = self.push_mirrors_of(&repo_path).await?;
+        let urls: Vec<String> = status_of_mirrors(&repo_path, &mirrors)
+            .into_iter()
+            .map(|status| status.url)
+            .collect();
+
+        let config = self.server_config.push_mirrors.clone().unwrap_or_default();
+        push_mirrors::push(&repo_path, mirrors, config).await;
+
+        Ok(Output::new(
+            format!(
+                "Pushing {} to {} in the background\n",
+                repo_path.display(),
+                urls.join(", ")
+            ),
+            json!({ "repo": repo_path, "pushing": urls }),
+        ))
+    }
+
+    // The repo's push mirrors, which it has to have some of to be worth asking about.
+    async fn push_mirrors_of(&self, repo_path: &Path) -> anyhow::Result<Vec<PushMirror>> {
+        let configs = self.state.lock().await.configs();
+        let mirrors = configs
+            .repo_config(repo_path)
+            .await?
+            .push_mirrors
+            .unwrap_or_default();
+        if mirrors.is_empty() {
+            bail!("That repository has no push mirrors.");
+        }
+        Ok(mirrors)
+    }
+
+    async fn add_key(&self, user: &str, key: &str) -> anyhow::Result<Output> {
+        self.check_can_manage(user)?;
+
+        let mut parts = key.split_whitespace();
+        let (Some(_), Some(data)) = (parts.next(), parts.next()) else {
+            bail!("Give the whole public key, like \"ssh-ed25519 AAAA... you@example.com\".");
+        };
+        let fingerprint = fingerprint(data).ok_or_else(|| anyhow!("That key isn't valid."))?;
+
+        if let Some((owner, _)) = self.server_config.get_user(data) {
+            bail!("That key already belongs to {}.", owner);
+        }
+
+        self.edit_server_config(&format!("chore: add key for {}", user), |config| {
+            config
+                .users
+                .get_mut(user)
+                .ok_or_else(|| anyhow!("There's no user called {}.", user))?
+                .public_keys
+                .push(key.trim().to_string());
+            Ok(())
+        })
+        .await?;
+
+        Ok(Output::new(
+            format!("Added key {} for {}\n", fingerprint, user),
+            json!({ "user": user, "added": fingerprint }),
+        ))
+    }
+
+    async fn remove_key(&self, user: &str, key: &str) -> anyhow::Result<Output> {
+        self.check_can_manage(user)?;
+
+        // Accept the whole key, just its data, or its fingerprint.
+        let key = key.trim();
+        let wanted = key.split_whitespace().nth(1).unwrap_or(key);
+        let matches = |public_key: &String| {
+            let data = public_key.split(' ').nth(1).unwrap_or_default();
+            data == wanted || fingerprint(data).is_some_and(|fingerprint| fingerprint == wanted)
+        };
+
+        let mut removed = None;
+        self.edit_server_config(&format!("chore: remove key for {}", user), |config| {
+            let user_config = config
+                .users
+                .get_mut(user)
+                .ok_or_else(|| anyhow!("There's no user called {}.", user))?;
+
+            let found = user_config
+                .keys()
+                .map(String::as_str)
+                .chain(user_config.bound_keys())
+                .find(|public_key| matches(&public_key.to_string()))
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("{} doesn't have that key.", user))?;
+            if user_config.keys().count() + user_config.bound_keys().len() == 1 {
+                bail!("That's {}'s only key, add another one first.", user);
+            }
+
+            if user_config.public_key.as_ref() == Some(&found) {
+                user_config.public_key = None;
+            }
+            user_config
+                .public_keys
+                .retain(|public_key| public_key != &found);
+            user_config
+                .key_bindings
+                .retain(|event| event.tag("ssh-key") != Some(found.as_str()));
+
+            removed = found.split(' ').nth(1).and_then(fingerprint);
+            Ok(())
+        })
+        .await?;
+
+        let removed = removed.unwrap_or_default();
+        Ok(Output::new(
+            format!("Removed key {} from {}\n", removed, user),
+            json!({ "user": user, "removed": removed }),
+        ))
+    }
+
+    async fn set_npub(&self, user: &str, npub_or_hex: &str) -> anyhow::Result<Output> {
+        self.check_can_manage(user)?;
+
+        let pubkey = parse_pubkey(npub_or_hex)
+            .ok_or_else(|| anyhow!("That isn't an npub, like \"npub1...\"."))?;
+        let npub = npub(&pubkey);
+        if let Some(owner) = self
+            .server_config
+            .user_by_pubkey(&pubkey)
+            .filter(|owner| *owner != user)
+        {
+            bail!("That npub already belongs to {}.", owner);
+        }
+
+        let mut dropped = 0;
+        self.edit_server_config(&format!("chore: set npub for {}", user), |config| {
+            let user_config = config
+                .users
+                .get_mut(user)
+                .ok_or_else(|| anyhow!("There's no user called {}.", user))?;
+            user_config.npub = Some(npub.clone());
+
+            // Keys bound by the old npub don't belong to the user any more.
+            let before = user_config.key_bindings.len();
+            user_config
+                .key_bindings
+                .retain(|event| event.pubkey.eq_ignore_ascii_case(&pubkey));
+            dropped = before - user_config.key_bindings.len();
+            Ok(())
+        })
+        .await?;
+
+        let mut text = format!("Set {}'s npub to {}\n", user, npub);
+        if dropped > 0 {
+            text.push_str(&format!(
+                "Dropped {} key binding(s) signed by the old npub\n",
+                dropped
+            ));
+        }
+        Ok(Output::new(
+            text,
+            json!({ "user": user, "npub": npub, "dropped_bindings": dropped }),
+        ))
+    }
+
+    // Anyone can send a binding, even with an unregistered key, since it's signed by the npub
+    // of the user it binds the key to.
+    async fn bind_key(&self, event: &str) -> anyhow::Result<Output> {
+        let event = event.trim();
+        let json = if event.starts_with('{') {
+            event.to_string()
+        } else {
+            let data = STANDARD
+                .decode(event)
+                .context("Give the event as JSON or base64")?;
+            String::from_utf8(data).context("Give the event as JSON or base64")?
+        };
+        let event = Event::parse(&json)?;
+
+        if event.kind != SSH_KEY_BINDING_KIND {
+            bail!(
+                "A key binding is a kind {} event, not kind {}.",
+                SSH_KEY_BINDING_KIND,
+                event.kind
+            );
+        }
+        let key = event
+            .tag("ssh-key")
+            .ok_or_else(|| anyhow!("The event has no \"ssh-key\" tag with the key to bind."))?
+            .trim()
+            .to_string();
+        let data = key
+            .split_whitespace()
+            .nth(1)
+            .ok_or_else(|| anyhow!("The \"ssh-key\" tag should hold the whole public key."))?;
+        let fingerprint = fingerprint(data).ok_or_else(|| anyhow!("That key isn't valid."))?;
+
+        let user = self
+            .server_config
+            .user_by_pubkey(&event.pubkey.to_ascii_lowercase())
+            .ok_or_else(|| anyhow!("No user has the npub {}.", npub(&event.pubkey)))?
+            .to_string();
+        if let Some((owner, _)) = self.server_config.get_user(data) {
+            if owner != user {
+                bail!("That key already belongs to {}.", owner);
+            }
+        }
+
+        let output = Output::new(
+            format!("Bound key {} to {}\n", fingerprint, user),
+            json!({ "user": user, "bound": fingerprint }),
+        );
+        let already_bound = self.server_config.users[&user]
+            .key_bindings
+            .iter()
+            .any(|binding| binding.id == event.id);
+        if already_bound {
+            return Ok(output);
+        }
+
+        let author = self.server_config.author(&user);
+        self.edit_server_config_as(
+            &author,
+            &format!("chore: bind key for {}", user),
+            |config| {
+                let user_config = config
+                    .users
+                    .get_mut(&user)
+                    .ok_or_else(|| anyhow!("There's no user called {}.", user))?;
+                // A newer binding for the same key replaces the old one.
+                user_config
+                    .key_bindings
+                    .retain(|binding| binding.tag("ssh-key").map(str::trim) != Some(key.as_str()));
+                user_config.key_bindings.push(event);
+                Ok(())
+            },
+        )
+        .await?;
+
+        Ok(output)
+    }
+
+    async fn quota(&self, user: &str) -> anyhow::Result<Output> {
+        if self.username()? != user && !self.is_admin() {
+            bail!("Only admins can see other users' quotas.");
+        }
+        if !self.server_config.users.contains_key(user) {
+            bail!("There's no user called {}.", user);
+        }
+
+        let limits = user_limits(&self.server_config, user)?;
+        let limit_text = |limit: Option<u64>| match limit {
+            Some(limit) => format!(" of {}", format_size(limit)),
+            None => String::new(),
+        };
+
+        let repos = user_repos(user);
+        let mut text = format!("{}: {} repositories", user, repos.len());
+        if let Some(limit) = limits.repos {
+            text.push_str(&format!(" of {}", limit));
+        }
+        text.push('\n');
+        if let Some(limit) = limits.blob_size {
+            text.push_str(&format!("max file size: {}\n", format_size(limit)));
+        }
+
+        let mut usage = Vec::new();
+        for repo_path in repos {
+            // Mirrors and empty repos have no repo config, so only the user's quota applies.
+            let configs = self.state.lock().await.configs();
+            let repo_quota = configs
+                .repo_config(&repo_path)
+                .await
+                .ok()
+                .and_then(|config| config.quota);
+            let limit =
+                repo_limits(&self.server_config, &repo_path, repo_quota.as_ref())?.repo_size;
+            let size = repo_size(&repo_path);
+
+            text.push_str(&format!(
+                "  {}  {}{}\n",
+                repo_path.display(),
+                format_size(size),
+                limit_text(limit)
+            ));
+            usage.push(json!({ "path": repo_path, "size": size, "max_size": limit }));
+        }
+
+        let json = json!({
+            "user": user,
+            "max_repos": limits.repos,
+            "max_blob_size": limits.blob_size,
+            "repos": usage,
+        });
+
+        Ok(Output::new(text, json))
+    }
+
+    fn username(&self) -> anyhow::Result<&str> {
+        self.username
+            .as_deref()
+            .ok_or_else(|| anyhow!("Your key isn't registered on this server."))
+    }
+
+    fn is_admin(&self) -> bool {
+        self.username
+            .as_deref()
+            .is_some_and(|username| self.server_config.is_admin(username))
+    }
+
+    // Users can manage their own keys, and admins can manage anyone's.
+    fn check_can_manage(&self, user: &str) -> anyhow::Result<()> {
+        if self.username()? != user && !self.is_admin() {
+            bail!("Only admins can change other users' keys.");
+        }
+        Ok(())
+    }
+
+    // Works out what the user can do with an existing repo.
+    async fn access(&self, repo_path: &Path) -> anyhow::Result<AccessLevel> {
+        let configs = self.state.lock().await.configs();
+        configs
+            .access_level(repo_path, self.username.as_deref())
+            .await
+    }
+
+    // Parses a repo path and checks the user has at least `required` access to it.
+    async fn existing_repo_path(
+        &self,
+        repo: &str,
+        required: AccessLevel,
+    ) -> anyhow::Result<PathBuf> {
+        let repo_path = parse_repo_path(repo).ok_or_else(|| anyhow!("Invalid repository path."))?;
+
+        if !repo_path.exists() {
+            bail!("That repository doesn't exist :(");
+        }
+        if repo_path == Path::new(SERVER_CONFIG_REPO) {
+            bail!("The server config repository can't be managed this way.");
+        }
+
+        let level = self.access(&repo_path).await?;
+        if level < AccessLevel::Read {
+            // Don't give away that private repos exist.
+            bail!("That repository doesn't exist :(");
+        }
+        if level < required {
+            bail!(
+                "You need {} access to this repository to do that.",
+                required.as_str()
+            );
+        }
+
+        Ok(repo_path)
+    }
+
+    // Parses a repo path and checks the user can create a repo there.
+    fn new_repo_path(&self, repo: &str) -> anyhow::Result<PathBuf> {
+        let username = self.username()?;
+        let repo_path = parse_repo_path(repo).ok_or_else(|| anyhow!("Invalid repository path."))?;
+
+        let can_create_repos = self
+            .server_config
+            .users
+            .get(username)
+            .and_then(|user| user.can_create_repos)
+            .unwrap_or(false);
+        if !(can_create_repos || self.is_admin()) {
+            bail!("You're not allowed to create repositories.");
+        }
+        check_new_repo_path(&repo_path, username, self.is_admin()).map_err(|e| anyhow!(e))?;
+
+        if repo_path.exists() {
+            bail!("That repository already exists.");
+        }
+        if repo_path.starts_with(".gnostr") {
+            bail!("That path is reserved.");
+        }
+
+        Ok(repo_path)
+    }
+
+    async fn edit_repo_config(
+        &self,
+        repo_path: &Path,
+        message: &str,
+        update: impl FnOnce(&mut RepoConfig) -> anyhow::Result<()>,
+    ) -> anyhow::Result<RepoConfig> {
+        if let Some(upstream) = Repo::mirror_of(repo_path).await? {
+            bail!(
+                "This repository is a mirror of {}, so has no config of its own.",
+                upstream
+            );
+        }
+
+        let author = self.server_config.author(self.username()?);
+        let config = update_repo_config(repo_path, &author, message, update).await?;
+        self.state.lock().await.forget_repo_config(repo_path);
+        Ok(config)
+    }
+
+    async fn edit_server_config(
+        &self,
+        message: &str,
+        update: impl FnOnce(&mut ServerConfig) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let author = self.server_config.author(self.username()?);
+        self.edit_server_config_as(&author, message, update).await
+    }
+
+    async fn edit_server_config_as(
+        &self,
+        author: &str,
+        message: &str,
+        update: impl FnOnce(&mut ServerConfig) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let server_config = update_server_config(author, message, update).await?;
+
+        info!("Reloading server config...");
+        self.state.lock().await.server_config = server_config;
+        Ok(())
+    }
+
+    async fn rebuild_site(&self, repo_path: &Path) {
+        let configs = self.state.lock().await.configs();
+        if let Err(e) = configs.rebuild_site(repo_path).await {
+            error!(
+                "Failed to rebuild site for {}: {:#}",
+                repo_path.display(),
+                e
+            );
+        }
+    }
+}
+
+// Removes every grant for a user or group, returning whether there were any.
+fn revoke(config: &mut RepoConfig, member: &str) -> bool {
+    let mut removed = false;
+    for grants in [
+        &mut config.members,
+        &mut config.access.read,
+        &mut config.access.write,
+        &mut config.access.admin,
+    ] {
+        let before = grants.len();
+        grants.retain(|grant| grant != member);
+        removed |= grants.len() != before;
+    }
+    removed
+}
+
+fn fingerprint(key_data: &str) -> Option<String> {
+    russh::keys::parse_public_key_base64(key_data)
+        .ok()
+        .map(|key| key.fingerprint(HashAlg::Sha256).to_string())
+}
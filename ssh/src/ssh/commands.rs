@@ -1,23 +1,38 @@
 use std::ffi::OsStr;
 use std::path::Component;
 use std::str::from_utf8;
-use std::{path::PathBuf, process::Stdio};
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
 
 use anyhow::Context;
+use bytes::Bytes;
 use clean_path::Clean;
-use log::info;
-use russh::{server::Handle, ChannelId, CryptoVec};
+use log::{error, info, warn};
+use russh::{server::Handle, ChannelId};
 use shellwords::split;
-use tokio::{io::AsyncReadExt, process::Command};
+use tokio::{io::AsyncReadExt, process::Command, sync::Mutex};
 
-use crate::config::repo::{load_repo_config, new_repo_config};
+use crate::audit::AuditEntry;
+use crate::config::repo::{new_repo_config, AccessLevel};
 use crate::config::server::load_server_config;
+use crate::firewall::Firewall;
 use crate::git::Repo;
+use crate::policy::{parse_size, ConfigCheck, PreReceiveHook};
+use crate::push_mirrors;
+use crate::quota::{check_new_repo, repo_limits, Limits};
+use crate::report::push_report;
 use crate::utils::CustomContext;
 use crate::vars::*;
+use crate::webhooks::{self, PushEvent};
 
 use super::Handler;
 
+// Held while checking a user has room for a new repo and making it, so pushes and the create and
+// fork commands at the same time can't both take the last place in their quota.
+pub static CREATING_REPOS: Mutex<()> = Mutex::const_new(());
+
 #[derive(Clone)]
 pub struct Knob {
     pub handle: Handle,
@@ -32,37 +47,54 @@ impl Handler {
         command: &[u8],
     ) -> anyhow::Result<()> {
         let server_config = self.state.lock().await.server_config.clone();
+        let mut webhooks = server_config.webhooks.clone().unwrap_or_default();
+        let web_url = server_config.web_url.clone();
+        let push_mirrors_config = server_config.push_mirrors.clone().unwrap_or_default();
         let knob = Knob { handle, channel };
 
         let command = from_utf8(command).context("Failed to parse command bytes into a string")?;
         let command = split(command).context("Could not split command into words.")?;
 
-        // Politely decline non-git commands.
-        if !GIT_COMMANDS.contains(&command[0].as_str()) {
-            knob.close().await?;
-            return Ok(());
+        // Anything that isn't git is an admin command.
+        if !command.first().is_some_and(|program| {
+            GIT_COMMANDS.contains(&program.as_str()) || program == LFS_TRANSFER_COMMAND
+        }) {
+            return self.handle_admin_command(knob, command, false).await;
         }
 
-        // The git plumbing commands give the repo like this: '/repo.git'.
-        let mut repo_path = PathBuf::from(&command[1]);
-        repo_path = repo_path.strip_prefix("/")?.into();
-        repo_path = repo_path.clean();
-
-        // Reject repo paths outside eejit's dir.
-        if repo_path.components().next() == Some(Component::ParentDir) {
+        let Some(repo_path) = command.get(1).and_then(|path| parse_repo_path(path)) else {
             knob.close().await?;
             return Ok(());
-        }
+        };
 
-        // Enforce a .git extension.
-        if repo_path.extension().unwrap_or(OsStr::new("")) != "git" {
-            repo_path.set_file_name(format!(
-                "{}.git",
-                repo_path.file_name().unwrap().to_str().unwrap()
-            ));
-        }
+        let audit = AuditEntry::new(
+            self.username.clone(),
+            self.key_fingerprint.clone(),
+            self.address,
+            &command[0],
+            &repo_path,
+        );
+
+        // git-lfs-transfer says whether it's going to upload or download.
+        let lfs_operation = if command[0] == LFS_TRANSFER_COMMAND {
+            match command.get(2).map(String::as_str) {
+                Some(operation @ ("upload" | "download")) => Some(operation.to_string()),
+                _ => {
+                    return self
+                        .refuse(
+                            &knob,
+                            audit,
+                            "Expected git-lfs-transfer <repo> <upload|download>.",
+                        )
+                        .await;
+                }
+            }
+        } else {
+            None
+        };
 
         let command = command[0].clone();
+        let writes = command == GIT_PUSH_COMMAND || lfs_operation.as_deref() == Some("upload");
 
         let user = self.user.clone().unwrap_or_default();
         let username = self.username.clone().unwrap_or(GUEST_USERNAME.to_string());
@@ -70,90 +102,225 @@ impl Handler {
         let is_admin = user.is_admin.unwrap_or(false);
         let can_create_repos = user.can_create_repos.unwrap_or(false);
 
-        if let Some(welcome_message) = server_config.welcome_message {
+        if let Some(welcome_message) = &server_config.welcome_message {
             knob.info(&welcome_message.replace('%', &username)).await?;
         }
 
         // Deny non-admins access to the config repo.
-        if !is_admin && repo_path == PathBuf::from(SERVER_CONFIG_REPO) {
-            knob.error("Only admins are allowed to access this repository.")
-                .await?;
-            knob.close().await?;
-            return Ok(());
+        if !is_admin && repo_path == Path::new(SERVER_CONFIG_REPO) {
+            return self
+                .refuse(
+                    &knob,
+                    audit,
+                    "Only admins are allowed to access this repository.",
+                )
+                .await;
         }
 
         // Handle non-existent repos, including creating a new one on push for some users.
         let mut new_repo = false;
         if !repo_path.exists() {
             if command == GIT_PUSH_COMMAND && (can_create_repos || is_admin) {
-                // Non-admins can only make new repos in thier personal directory.
-                if !is_admin {
-                    let mut dir = None;
-                    if let Some(first_component) = repo_path.components().next() {
-                        dir = Some(first_component.as_os_str().to_str().unwrap());
-                    }
-                    if dir.unwrap_or("") != username {
-                        knob.error(
-                            "You can only create a new repository under your personal subdirectory.",
-                        )
-                        .await?;
-                        knob.close().await?;
-                        return Ok(());
-                    }
+                if let Err(message) = check_new_repo_path(&repo_path, &username, is_admin) {
+                    return self.refuse(&knob, audit, message).await;
                 }
 
-                knob.info("Creating a new repository...").await?;
-                Repo::create_bare(&repo_path).await?;
-                new_repo = true;
+                let creating = CREATING_REPOS.lock().await;
+                // Someone else may have just made it.
+                if !repo_path.exists() {
+                    if let Err(e) = check_new_repo(&server_config, &username) {
+                        drop(creating);
+                        return self.refuse(&knob, audit, &format!("{:#}", e)).await;
+                    }
+
+                    knob.info("Creating a new repository...").await?;
+                    Repo::create_bare(&repo_path).await?;
+                    new_repo = true;
+                }
             } else {
-                knob.error("That repository doesn't exist :(").await?;
-                knob.close().await?;
-                return Ok(());
+                return self
+                    .refuse(&knob, audit, "That repository doesn't exist :(")
+                    .await;
             }
         }
 
-        if !new_repo && repo_path != PathBuf::from(SERVER_CONFIG_REPO) {
-            let repo_config = load_repo_config(&repo_path).await?;
+        // Mirrors are kept up to date from their upstream, so can't be pushed to and have no
+        // repo config of their own.
+        let mirror_of = if new_repo {
+            None
+        } else {
+            Repo::mirror_of(&repo_path).await?
+        };
 
-            // Access control.
-            // TODO: don't load the repo config on every request.
-            if is_admin {
-                let is_member = repo_config.members.contains(&username);
+        if let Some(upstream) = &mirror_of {
+            if writes {
+                let message =
+                    format!("This repository is a mirror of {upstream}, push there instead.");
+                return self.refuse(&knob, audit, &message).await;
+            }
+        }
 
-                if command == GIT_PUSH_COMMAND && !is_member {
-                    knob.error("You don't have permission to push to this repository.")
-                        .await?;
+        let mut policy = None;
+        let mut repo_quota = None;
+        if !new_repo {
+            let configs = self.state.lock().await.configs();
+            let level = configs
+                .access_level(&repo_path, self.username.as_deref())
+                .await?;
 
-                    if let Some(message) = repo_config.failed_push_message {
-                        knob.repo_note(&message).await?;
+            // Mirrors and the server config have no repo config of their own.
+            let repo_config = if mirror_of.is_none() && repo_path != Path::new(SERVER_CONFIG_REPO) {
+                Some(configs.repo_config(&repo_path).await?)
+            } else {
+                None
+            };
+
+            if let Some(repo_config) = &repo_config {
+                for webhook in repo_config.webhooks.iter().flatten() {
+                    match webhooks::for_repo(webhook, &server_config) {
+                        Ok(webhook) => webhooks.push(webhook),
+                        Err(e) => {
+                            warn!("Not sending a webhook for {}: {:#}", repo_path.display(), e)
+                        }
                     }
+                }
+                policy = repo_config.policy.clone();
+                repo_quota = repo_config.quota.clone();
+            }
 
-                    knob.close().await?;
-                    return Ok(());
+            if writes && level < AccessLevel::Write {
+                let message = if level >= AccessLevel::Read {
+                    "You don't have permission to push to this repository."
+                } else {
+                    "You don't have permission to access this repository."
+                };
+                knob.error(message).await?;
+
+                if let Some(note) = repo_config
+                    .and_then(|repo_config| repo_config.failed_push_message)
+                    .filter(|_| level >= AccessLevel::Read)
+                {
+                    knob.repo_note(&note).await?;
                 }
 
-                if !(repo_config.public || is_member) {
-                    knob.error("You don't have permission to access this repository.")
-                        .await?;
+                knob.close().await?;
+                audit.refused(message).record().await;
+                return Ok(());
+            }
 
-                    knob.close().await?;
-                    return Ok(());
-                }
+            if level < AccessLevel::Read {
+                return self
+                    .refuse(
+                        &knob,
+                        audit,
+                        "You don't have permission to access this repository.",
+                    )
+                    .await;
             }
         }
 
-        let mut shell = Command::new(&command)
-            .arg(&repo_path)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()?;
+        // Remember where the refs were so the audit log and webhooks can say what the push
+        // changed.
+        let refs_before = if command == GIT_PUSH_COMMAND {
+            Some(Repo::refs(&repo_path).await?)
+        } else {
+            None
+        };
+
+        // Pushes are checked against the repo's policy and quota by a pre-receive hook.
+        let limits = if command == GIT_PUSH_COMMAND {
+            match repo_limits(&server_config, &repo_path, repo_quota.as_ref()) {
+                Ok(limits) => limits,
+                Err(e) => return self.refuse(&knob, audit, &format!("{:#}", e)).await,
+            }
+        } else {
+            Limits::default()
+        };
+
+        // Pushes to config files are checked too, so a bad one can't lock anyone out. New repos
+        // get a config of their own afterwards anyway.
+        let config_check = if command != GIT_PUSH_COMMAND || new_repo || mirror_of.is_some() {
+            None
+        } else if repo_path == Path::new(SERVER_CONFIG_REPO) {
+            Some(ConfigCheck::Server {
+                username: username.clone(),
+            })
+        } else {
+            Some(ConfigCheck::Repo {
+                server_config: Box::new(server_config.clone()),
+            })
+        };
+
+        let hook = if command == GIT_PUSH_COMMAND
+            && (policy.is_some() || limits.limits_pushes() || config_check.is_some())
+        {
+            let policy = policy.unwrap_or_default();
+            match PreReceiveHook::new(
+                &policy,
+                &limits,
+                &server_config,
+                &username,
+                &repo_path,
+                config_check,
+            ) {
+                Ok(hook) => Some(hook),
+                Err(e) => {
+                    let message = format!("This repository's push policy is invalid: {:#}", e);
+                    return self.refuse(&knob, audit, &message).await;
+                }
+            }
+        } else {
+            None
+        };
+
+        // Only so many git commands can run at once, however many clients are connected.
+        let firewall = self.state.lock().await.firewall.clone();
+        let firewall_config = server_config.firewall.clone().unwrap_or_default();
+        let Some(git_process) = Firewall::start_git_process(&firewall, &firewall_config) else {
+            let message = "The server is busy, try again in a moment.";
+            return self.refuse(&knob, audit, message).await;
+        };
+
+        // LFS transfers are handled by the server running itself, with the repo's quota.
+        let mut shell = if let Some(operation) = &lfs_operation {
+            let quota = match server_config.lfs_quota.as_deref().map(parse_size) {
+                Some(Ok(quota)) => Some(quota),
+                Some(Err(e)) => {
+                    error!("Invalid lfs_quota in the server config: {:#}", e);
+                    return self
+                        .refuse(&knob, audit, "The server's LFS quota is misconfigured.")
+                        .await;
+                }
+                None => None,
+            };
+
+            let mut shell = Command::new(std::env::current_exe()?);
+            shell
+                .arg(LFS_TRANSFER_ARG)
+                .arg(&repo_path)
+                .arg(operation)
+                .envs(quota.map(|quota| (LFS_QUOTA_ENV, quota.to_string())));
+            shell
+        } else {
+            let mut shell = Command::new(&command);
+            shell
+                .arg(&repo_path)
+                .envs(hook.iter().flat_map(PreReceiveHook::env));
+            shell
+        };
+        let mut shell = shell.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
 
         let stdin = shell.stdin.take().unwrap();
         self.stdin.insert(channel, stdin);
 
         let mut shell_stdout = shell.stdout.take().unwrap();
 
+        // A new repo that the push put nothing in, as when it was rejected or the client went
+        // away, would only take up a place in the user's quota.
+        let new_repo_path = new_repo.then(|| repo_path.clone());
+
         let state = self.state.clone();
+        let mut audit = audit;
         let fut = async move {
             const BUF_SIZE: usize = 1024 * 32;
             let mut buf = [0u8; BUF_SIZE];
@@ -166,60 +333,220 @@ impl Handler {
             }
 
             let status = shell.wait().await?.code().unwrap_or(128) as u32;
+
+            if let Some(hook) = &hook {
+                let rejections = hook.rejections();
+                for rejection in &rejections {
+                    knob.error(rejection).await?;
+                }
+                if !rejections.is_empty() {
+                    audit.error = Some(rejections.join("\n"));
+                }
+            }
             knob.exit_status(status).await?;
 
-            // Rebuild.
-            if command == GIT_PUSH_COMMAND && !new_repo {
-                if repo_path == PathBuf::from(SERVER_CONFIG_REPO) {
+            audit.status = Some(status);
+            let mut refs_after = None;
+            if let Some(refs_before) = &refs_before {
+                match Repo::refs(&repo_path).await {
+                    Ok(refs) => {
+                        audit.set_refs(refs_before, &refs);
+                        refs_after = Some(refs);
+                    }
+                    Err(e) => error!("Couldn't read refs for the audit log: {:#}", e),
+                }
+            }
+            audit.record().await;
+
+            // Left without a config, and removed once this is done, if the push put nothing in it.
+            let abandoned = new_repo && refs_after.as_ref().is_none_or(|refs| refs.is_empty());
+
+            // Say what the push changed, with links to it on the web.
+            if let (Some(refs_before), Some(refs_after)) = (&refs_before, &refs_after) {
+                if status == 0 && repo_path != Path::new(SERVER_CONFIG_REPO) {
+                    let report =
+                        push_report(&repo_path, refs_before, refs_after, web_url.as_deref()).await;
+                    for line in report {
+                        knob.info(&line).await?;
+                    }
+                }
+            }
+
+            // Rebuild, unless the push changed nothing, as when the pre-receive hook rejected it.
+            if command == GIT_PUSH_COMMAND && !new_repo && refs_after != refs_before {
+                if repo_path == Path::new(SERVER_CONFIG_REPO) {
                     info!("Reloading server config...");
                     knob.info("Reloading server config...").await?;
-                    state.lock().await.server_config = load_server_config().await?;
+                    state.lock().await.server_config = load_server_config(None).await?;
                 } else {
                     knob.info("Reloading repo information...").await?;
-                    state.lock().await.rebuild_site(&repo_path).await?;
+                    let configs = {
+                        let state = state.lock().await;
+                        state.forget_repo_config(&repo_path);
+                        state.configs()
+                    };
+                    configs.rebuild_site(&repo_path).await?;
                 }
             }
 
-            if new_repo {
+            let refs_changed = refs_after.is_some() && refs_after != refs_before;
+            if let Some(refs_before) = refs_before.filter(|_| status == 0 && !webhooks.is_empty()) {
+                let delivered = PushEvent::new(&repo_path, &username, &refs_before)
+                    .await
+                    .and_then(|event| {
+                        if event.refs.is_empty() {
+                            Ok(())
+                        } else {
+                            webhooks::deliver(webhooks, event)
+                        }
+                    });
+
+                if let Err(e) = delivered {
+                    error!("Failed to send webhooks: {:#}", e);
+                }
+            }
+
+            if new_repo && !abandoned {
                 new_repo_config(&repo_path, &username).await?;
                 knob.info("Created a new repo config - please pull.")
                     .await?;
             }
 
+            // Copy the push to the repo's mirrors, as they are in the config it was just given.
+            // Pushes that changed nothing are skipped, so a mirror on this server that lists the
+            // same mirrors doesn't push back and forth forever.
+            if command == GIT_PUSH_COMMAND
+                && status == 0
+                && refs_changed
+                && repo_path != Path::new(SERVER_CONFIG_REPO)
+            {
+                let configs = state.lock().await.configs();
+                let repo_config = configs.repo_config(&repo_path).await;
+                match repo_config {
+                    Ok(repo_config) => {
+                        let mirrors = repo_config.push_mirrors.unwrap_or_default();
+                        if !mirrors.is_empty() {
+                            knob.info("Pushing to mirrors in the background...").await?;
+                        }
+                        push_mirrors::push(&repo_path, mirrors, push_mirrors_config).await;
+                    }
+                    Err(e) => error!("Couldn't read push mirrors: {:#}", e),
+                }
+            }
+
+            // Let the web server know, if it's running alongside us.
+            if command == GIT_PUSH_COMMAND && status == 0 {
+                let _ = state.lock().await.pushes.send(repo_path.clone());
+            }
+
+            drop(git_process);
             knob.eof().await?;
             knob.close().await?;
             Ok::<(), anyhow::Error>(())
         };
 
-        tokio::spawn(fut);
+        tokio::spawn(async move {
+            let result = fut.await;
+            if let Some(repo_path) = new_repo_path {
+                if Repo::refs(&repo_path)
+                    .await
+                    .is_ok_and(|refs| refs.is_empty())
+                {
+                    if let Err(e) = tokio::fs::remove_dir_all(&repo_path).await {
+                        error!(
+                            "Couldn't remove the empty new repo {}: {:#}",
+                            repo_path.display(),
+                            e
+                        );
+                    }
+                }
+            }
+            result
+        });
         Ok(())
     }
 }
 
+impl Handler {
+    // Tells the user why they can't run a command, and records that they tried.
+    async fn refuse(&self, knob: &Knob, audit: AuditEntry, message: &str) -> anyhow::Result<()> {
+        knob.error(message).await?;
+        knob.close().await?;
+        audit.refused(message).record().await;
+        Ok(())
+    }
+}
+
+// Turns a repo path given by a client into one relative to the server's dir, or None if it's
+// outside of it. The git plumbing commands give the repo like this: '/repo.git'.
+pub fn parse_repo_path(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    let mut repo_path = path.strip_prefix("/").unwrap_or(path).clean();
+
+    // Reject repo paths outside eejit's dir.
+    if repo_path.as_os_str().is_empty()
+        || repo_path.is_absolute()
+        || repo_path.components().next() == Some(Component::ParentDir)
+    {
+        return None;
+    }
+
+    // Enforce a .git extension. The config repo's ".git" counts as one.
+    if repo_path.extension().unwrap_or(OsStr::new("")) != "git"
+        && repo_path.file_name() != Some(OsStr::new(".git"))
+    {
+        let file_name = repo_path.file_name()?.to_str()?.to_string();
+        repo_path.set_file_name(format!("{}.git", file_name));
+    }
+
+    Some(repo_path)
+}
+
+// Non-admins can only make new repos in their personal directory.
+pub fn check_new_repo_path(
+    repo_path: &Path,
+    username: &str,
+    is_admin: bool,
+) -> Result<(), &'static str> {
+    if is_admin {
+        return Ok(());
+    }
+
+    let dir = repo_path
+        .components()
+        .next()
+        .and_then(|first_component| first_component.as_os_str().to_str());
+    if dir.unwrap_or("") != username {
+        return Err("You can only create a new repository under your personal subdirectory.");
+    }
+
+    Ok(())
+}
+
 impl Knob {
-    async fn close(&self) -> anyhow::Result<()> {
+    pub async fn close(&self) -> anyhow::Result<()> {
         self.handle
             .close(self.channel)
             .await
             .context("Failed to close handle")?;
         Ok(())
     }
-    async fn data(&self, data: &[u8]) -> anyhow::Result<()> {
-        let buf = CryptoVec::from_slice(data);
+    pub async fn data(&self, data: &[u8]) -> anyhow::Result<()> {
+        let buf = Bytes::copy_from_slice(data);
         self.handle
             .data(self.channel, buf)
             .await
             .context("Failed to write data to channel")?;
         Ok(())
     }
-    async fn exit_status(&self, status: u32) -> anyhow::Result<()> {
+    pub async fn exit_status(&self, status: u32) -> anyhow::Result<()> {
         self.handle
             .exit_status_request(self.channel, status)
             .await
             .context("Failed to set exit status")?;
         Ok(())
     }
-    async fn eof(&self) -> anyhow::Result<()> {
+    pub async fn eof(&self) -> anyhow::Result<()> {
         self.handle
             .eof(self.channel)
             .await
@@ -1,39 +1,83 @@
 use std::{
     fs::{read_to_string, File},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use anyhow::Context;
-use russh_keys::{key::KeyPair, *};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use russh::keys::{
+    decode_secret_key, encode_pkcs8_pem, key::safe_rng, ssh_key::private::Ed25519Keypair,
+    Algorithm, PrivateKey,
+};
 
 const SERVER_KEY_LOCATION: &str = "server_key";
 
+// The ed25519 algorithm identifier, then the private key as an octet string wrapping the
+// 64 bytes of the seed and the public key.
+const LEGACY_ED25519_PREFIX: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x42, 0x04, 0x40];
+
 /// Loads the server's keys if it exists.
-fn load_server_keys() -> anyhow::Result<Option<KeyPair>> {
+fn load_server_keys() -> anyhow::Result<Option<PrivateKey>> {
     if !PathBuf::from(SERVER_KEY_LOCATION).exists() {
         return Ok(None);
     }
 
     let text =
         read_to_string(SERVER_KEY_LOCATION).context("Failed reading server key from file")?;
-    let keys = decode_secret_key(&text, None).context("Error decoding server key")?;
+    let keys = decode_secret_key(&text, None)
+        .or_else(|e| decode_legacy_ed25519(&text).ok_or(e))
+        .context("Error decoding server key")?;
     Ok(Some(keys))
 }
 
+/// Reads the PKCS#8 ed25519 keys written by earlier versions, which put the public key in a
+/// field current parsers reject, so that the server keeps the key clients already know.
+fn decode_legacy_ed25519(text: &str) -> Option<PrivateKey> {
+    let body: String = text
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    let der = STANDARD.decode(body).ok()?;
+    let start = der
+        .windows(LEGACY_ED25519_PREFIX.len())
+        .position(|window| window == LEGACY_ED25519_PREFIX)?
+        + LEGACY_ED25519_PREFIX.len();
+    let bytes = der.get(start..start + Ed25519Keypair::BYTE_SIZE)?;
+    let keypair = Ed25519Keypair::from_bytes(bytes.try_into().ok()?).ok()?;
+    Some(keypair.into())
+}
+
 /// Writes the server keys to the filesystem.
-fn write_server_keys(keys: &KeyPair) -> anyhow::Result<()> {
+fn write_server_keys(keys: &PrivateKey) -> anyhow::Result<()> {
     let file = File::create(SERVER_KEY_LOCATION).context("Could not create server key file")?;
     encode_pkcs8_pem(keys, file).context("Error writing server key to file")?;
     Ok(())
 }
 
 /// Get's the server keys, creaing new ones if needed.
-pub fn server_keys() -> anyhow::Result<KeyPair> {
+fn default_server_keys() -> anyhow::Result<PrivateKey> {
     if let Some(keys) = load_server_keys()? {
         Ok(keys)
     } else {
-        let keys = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+        let keys = PrivateKey::random(&mut safe_rng(), Algorithm::Ed25519)?;
         write_server_keys(&keys)?;
         Ok(keys)
     }
 }
+
+/// Loads a host key made by `ssh-keygen`, like sshd's.
+fn load_host_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let text = read_to_string(path)
+        .with_context(|| format!("Failed reading host key from {}", path.display()))?;
+    decode_secret_key(&text, None)
+        .with_context(|| format!("Error decoding host key {}", path.display()))
+}
+
+/// Loads the given host keys, or the default server key if there are none.
+pub fn server_keys(paths: &[PathBuf]) -> anyhow::Result<Vec<PrivateKey>> {
+    if paths.is_empty() {
+        return Ok(vec![default_server_keys()?]);
+    }
+
+    paths.iter().map(|path| load_host_key(path)).collect()
+}
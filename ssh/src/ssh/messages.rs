@@ -1,5 +1,5 @@
 use colored::{ColoredString, Colorize};
-use russh::{server::Handle, ChannelId, CryptoVec};
+use russh::{server::Handle, ChannelId};
 
 use crate::utils::CustomContext;
 
@@ -16,10 +16,9 @@ async fn send_message(
         "[".bold(),
         title.clone().bold(),
         "]".bold(),
-        textwrap::wrap(message, 40).join("\n")
+        textwrap::wrap(message, wrap_options()).join("\n")
     );
 
-    let text = CryptoVec::from(text);
     handle
         .extended_data(channel, 1, text)
         .await
@@ -27,6 +26,13 @@ async fn send_message(
     Ok(())
 }
 
+// Lines only break at spaces, so URLs are left whole and can still be clicked.
+fn wrap_options() -> textwrap::Options<'static> {
+    textwrap::Options::new(40)
+        .word_separator(textwrap::WordSeparator::AsciiSpace)
+        .break_words(false)
+}
+
 impl Knob {
     pub async fn info(&self, message: &str) -> anyhow::Result<()> {
         send_message(self.handle.clone(), self.channel, "INFO".green(), message).await
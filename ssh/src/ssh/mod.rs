@@ -1,31 +1,63 @@
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 
-use async_trait::async_trait;
-use russh::server::{Msg, Session};
+use anyhow::{bail, Context};
+use futures::future::try_join_all;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use russh::keys::{Certificate, HashAlg, PublicKey};
+use russh::server::{ChannelOpenHandle, Msg, Server as _, Session};
 use russh::*;
-use russh_keys::*;
 use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
 use tokio::process::ChildStdin;
 
-use log::error;
+use log::{error, info, warn};
 use tokio::sync::Mutex;
 
+use crate::certificates::certificate_user;
 use crate::config::server::ServerUser;
-use crate::State;
+use crate::firewall::{Firewall, FirewallConfig, Refusal};
+use crate::state::State;
+use crate::tui::Tui;
 
 mod keys;
 use self::keys::server_keys;
 
+mod admin;
 mod commands;
 mod messages;
+pub use self::commands::parse_repo_path;
+use self::commands::Knob;
+
+pub async fn start_server(
+    state: Arc<Mutex<State>>,
+    listen: Vec<SocketAddr>,
+    host_keys: Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    let server_config = state.lock().await.server_config.clone();
+
+    // Options given on the command line win over the server config.
+    let host_keys = if host_keys.is_empty() {
+        server_config.host_keys.unwrap_or_default()
+    } else {
+        host_keys
+    };
+    let listen = if listen.is_empty() {
+        server_config
+            .listen
+            .unwrap_or_else(|| vec![SocketAddr::from(([0, 0, 0, 0], server_config.port))])
+    } else {
+        listen
+    };
 
-pub async fn start_server(state: Arc<Mutex<State>>) -> anyhow::Result<()> {
     let config = russh::server::Config {
-        connection_timeout: Some(std::time::Duration::from_secs(3600)),
+        inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
         auth_rejection_time: std::time::Duration::from_secs(3),
         auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
-        keys: vec![server_keys()?],
+        keys: server_keys(&host_keys)?,
         ..Default::default()
     };
 
@@ -35,34 +67,99 @@ pub async fn start_server(state: Arc<Mutex<State>>) -> anyhow::Result<()> {
         state: state.clone(),
     };
 
-    let port = state.lock().await.server_config.port;
-
-    russh::server::run(config, ("0.0.0.0", port), sh).await?;
+    try_join_all(listen.into_iter().map(|addr| {
+        let config = config.clone();
+        let sh = sh.clone();
+        async move {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to listen on {}", addr))?;
+            info!("Listening on {}", addr);
+            accept(listener, config, sh).await
+        }
+    }))
+    .await?;
 
     Ok(())
 }
 
+// Runs a session for each client the firewall lets in. Those it doesn't are disconnected before
+// the SSH handshake, so they cost next to nothing.
+async fn accept(
+    listener: TcpListener,
+    config: Arc<russh::server::Config>,
+    mut sh: Server,
+) -> anyhow::Result<()> {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+
+        let (firewall, firewall_config) = {
+            let state = sh.state.lock().await;
+            let config = state.server_config.firewall.clone().unwrap_or_default();
+            (state.firewall.clone(), config)
+        };
+        let connection = match Firewall::connect(&firewall, &firewall_config, address.ip()) {
+            Ok(connection) => connection,
+            Err(Refusal::TooManyConnections) => {
+                warn!(
+                    "Refused a connection from {}: too many connections",
+                    address
+                );
+                continue;
+            }
+            // Denied and banned addresses are refused quietly, as scanners try again and again.
+            Err(_) => continue,
+        };
+
+        let handler = sh.new_client(Some(address));
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Ok(session) = russh::server::run_stream(config, stream, handler).await {
+                let _ = session.await;
+            }
+            drop(connection);
+        });
+    }
+}
+
+#[derive(Clone)]
 struct Server {
     state: Arc<Mutex<State>>,
 }
 
 impl server::Server for Server {
     type Handler = Handler;
-    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> Handler {
+    fn new_client(&mut self, address: Option<SocketAddr>) -> Handler {
         Handler {
             stdin: HashMap::default(),
+            ptys: HashMap::default(),
+            tuis: HashMap::default(),
             state: self.state.clone(),
             user: None,
             username: None,
+            address,
+            key_fingerprint: None,
         }
     }
 }
 
 struct Handler {
     stdin: HashMap<ChannelId, ChildStdin>,
+    // The terminal size of each channel that asked for one.
+    ptys: HashMap<ChannelId, (u32, u32)>,
+    tuis: HashMap<ChannelId, Tui>,
     state: Arc<Mutex<State>>,
     user: Option<ServerUser>,
     username: Option<String>,
+    // Who connected, for the audit log.
+    address: Option<SocketAddr>,
+    key_fingerprint: Option<String>,
 }
 
 impl Handler {
@@ -73,67 +170,207 @@ impl Handler {
 
         Ok(())
     }
+
+    async fn firewall(&self) -> (Arc<Firewall>, FirewallConfig) {
+        let state = self.state.lock().await;
+        let config = state.server_config.firewall.clone().unwrap_or_default();
+        (state.firewall.clone(), config)
+    }
+
+    // Counts a failed login, disconnecting the client if that gets it banned.
+    async fn auth_failed(&self) -> anyhow::Result<server::Auth> {
+        if let Some(address) = self.address {
+            let (firewall, config) = self.firewall().await;
+            if firewall.auth_failed(&config, address).await {
+                bail!("{} is banned", address.ip());
+            }
+        }
+        Ok(server::Auth::reject())
+    }
+
+    // Bans also end connections that were already open.
+    async fn check_banned(&self) -> anyhow::Result<()> {
+        if let Some(address) = self.address {
+            let (firewall, config) = self.firewall().await;
+            if firewall.is_banned(&config, address.ip()) {
+                bail!("{} is banned", address.ip());
+            }
+        }
+        Ok(())
+    }
 }
 
-#[async_trait]
 impl server::Handler for Handler {
     type Error = anyhow::Error;
 
     async fn channel_open_session(
-        mut self,
+        &mut self,
         _channel: Channel<Msg>,
-        session: Session,
-    ) -> anyhow::Result<(Self, bool, Session)> {
-        Ok((self, true, session))
+        reply: ChannelOpenHandle,
+        _session: &mut Session,
+    ) -> anyhow::Result<()> {
+        reply.accept().await;
+        Ok(())
     }
 
-    async fn auth_publickey(
-        mut self,
-        _: &str,
-        key: &key::PublicKey,
-    ) -> anyhow::Result<(Self, server::Auth)> {
-        let key = key.public_key_base64();
+    async fn auth_publickey(&mut self, _: &str, key: &PublicKey) -> anyhow::Result<server::Auth> {
+        self.check_banned().await?;
+
+        self.key_fingerprint = Some(key.fingerprint(HashAlg::Sha256).to_string());
+        let key = STANDARD.encode(key.to_bytes()?);
         if let Some(data) = self.state.lock().await.server_config.get_user(&key) {
             self.username = Some(data.0);
             self.user = Some(data.1);
         }
-        Ok((self, server::Auth::Accept))
+        Ok(server::Auth::Accept)
+    }
+
+    // Certificates log in as the user named by one of their principals. Ones that can't be used
+    // are turned down without counting as a failed login, as the client then tries its key.
+    async fn auth_openssh_certificate(
+        &mut self,
+        _: &str,
+        certificate: &Certificate,
+    ) -> anyhow::Result<server::Auth> {
+        self.check_banned().await?;
+
+        let server_config = self.state.lock().await.server_config.clone();
+        let address = self.address.map(|address| address.ip());
+        let username = match certificate_user(&server_config, certificate, address) {
+            Ok(username) => username,
+            Err(e) => {
+                info!(
+                    "Turned down certificate {:?}: {:#}",
+                    certificate.key_id(),
+                    e
+                );
+                return Ok(server::Auth::reject());
+            }
+        };
+
+        let fingerprint = certificate.public_key().fingerprint(HashAlg::Sha256);
+        self.key_fingerprint = Some(fingerprint.to_string());
+        self.user = server_config.users.get(&username).cloned();
+        self.username = Some(username);
+        Ok(server::Auth::Accept)
+    }
+
+    // Passwords never work, as users log in with keys, so trying one counts as a failed login.
+    async fn auth_password(&mut self, _: &str, _: &str) -> anyhow::Result<server::Auth> {
+        self.auth_failed().await
+    }
+
+    async fn auth_keyboard_interactive<'a>(
+        &'a mut self,
+        _: &str,
+        _: &str,
+        _: Option<server::Response<'a>>,
+    ) -> anyhow::Result<server::Auth> {
+        self.auth_failed().await
     }
 
     async fn data(
-        mut self,
+        &mut self,
         channel: ChannelId,
         data: &[u8],
-        session: Session,
-    ) -> anyhow::Result<(Self, Session)> {
-        self.send_stdin(channel, data).await?;
-        Ok((self, session))
+        session: &mut Session,
+    ) -> anyhow::Result<()> {
+        let Some(mut tui) = self.tuis.remove(&channel) else {
+            return self.send_stdin(channel, data).await;
+        };
+
+        let output = tui.input(data).await;
+        session.data(channel, output)?;
+        if tui.done() {
+            session.exit_status_request(channel, 0)?;
+            session.eof(channel)?;
+            session.close(channel)?;
+        } else {
+            self.tuis.insert(channel, tui);
+        }
+        Ok(())
     }
 
     async fn channel_eof(
-        mut self,
+        &mut self,
         channel: ChannelId,
-        session: Session,
-    ) -> Result<(Self, Session), Self::Error> {
+        _session: &mut Session,
+    ) -> anyhow::Result<()> {
         let stdin = self.stdin.remove(&channel);
         if let Some(mut stdin) = stdin {
             stdin.shutdown().await?;
         }
 
-        Ok((self, session))
+        Ok(())
     }
 
     async fn exec_request(
-        mut self,
+        &mut self,
         channel: ChannelId,
         data: &[u8],
-        session: Session,
-    ) -> anyhow::Result<(Self, Session)> {
+        session: &mut Session,
+    ) -> anyhow::Result<()> {
         let handle = session.handle();
         if let Err(e) = self.handle_command(handle.clone(), channel, data).await {
             error!("{:#}", e);
             handle.close(channel).await.unwrap();
         }
-        Ok((self, session))
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn pty_request(
+        &mut self,
+        channel: ChannelId,
+        _term: &str,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _modes: &[(Pty, u32)],
+        session: &mut Session,
+    ) -> anyhow::Result<()> {
+        self.ptys.insert(channel, (col_width, row_height));
+        session.channel_success(channel)?;
+        Ok(())
+    }
+
+    async fn window_change_request(
+        &mut self,
+        channel: ChannelId,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        session: &mut Session,
+    ) -> anyhow::Result<()> {
+        self.ptys.insert(channel, (col_width, row_height));
+        if let Some(tui) = self.tuis.get_mut(&channel) {
+            session.data(channel, tui.resize(col_width, row_height))?;
+        }
+        Ok(())
+    }
+
+    // Logging in with a terminal opens a browser for the repos the user can see. Without one,
+    // it shows what they can access, like gitolite does.
+    async fn shell_request(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> anyhow::Result<()> {
+        if let Some(&(width, height)) = self.ptys.get(&channel) {
+            let tui = Tui::new(self.state.clone(), self.username.clone(), width, height).await;
+            session.channel_success(channel)?;
+            session.data(channel, tui.start())?;
+            self.tuis.insert(channel, tui);
+            return Ok(());
+        }
+
+        let knob = Knob {
+            handle: session.handle(),
+            channel,
+        };
+        self.handle_admin_command(knob, vec!["info".to_string()], true)
+            .await
     }
 }
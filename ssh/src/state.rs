@@ -1,15 +1,153 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use gix::ObjectId;
+use tokio::sync::broadcast;
+
+use crate::config::repo::{load_repo_config, AccessLevel, RepoConfig};
 use crate::config::server::{load_server_config, ServerConfig};
+use crate::firewall::Firewall;
+use crate::git::Repo;
+use crate::tui::CommitLog;
+use crate::vars::*;
+
+// How many repos' configs are kept in memory at once.
+const REPO_CONFIG_CACHE_SIZE: usize = 1024;
 
 pub struct State {
     pub server_config: ServerConfig,
+    // Shared with the Configs handed out, which fill it in without holding the state's lock.
+    repo_configs: Arc<Mutex<RepoConfigCache>>,
+    // Told about each repo once a push to it has finished.
+    pub pushes: broadcast::Sender<PathBuf>,
+    // Where the terminal UI reads logs from, if not git.
+    pub commit_log: Option<Arc<dyn CommitLog>>,
+    // Who's connected and who's banned, which outlives reloads of the server config.
+    pub firewall: Arc<Firewall>,
 }
 
 impl State {
-    pub async fn new() -> anyhow::Result<Self> {
+    pub async fn new(initial_config: &Path) -> anyhow::Result<Self> {
         let state = State {
-            server_config: load_server_config().await?,
+            server_config: load_server_config(Some(initial_config)).await?,
+            repo_configs: Arc::default(),
+            pushes: broadcast::channel(64).0,
+            commit_log: None,
+            firewall: Arc::default(),
         };
 
         Ok(state)
     }
+
+    // Takes what's needed to read repo configs and check access, so the state's lock can be
+    // released before going to the repos for them. Every login and access check waits on it.
+    pub fn configs(&self) -> Configs {
+        Configs {
+            server_config: self.server_config.clone(),
+            repo_configs: self.repo_configs.clone(),
+        }
+    }
+
+    // Drops the cached config, e.g. after a push.
+    pub fn forget_repo_config(&self, repo_path: &Path) {
+        self.repo_configs.lock().unwrap().entries.remove(repo_path);
+    }
+}
+
+// Repo configs along with the commit they were read from and when they were last used, so the
+// least recently used can make way when it's full.
+#[derive(Default)]
+struct RepoConfigCache {
+    entries: HashMap<PathBuf, (ObjectId, RepoConfig, u64)>,
+    uses: u64,
+}
+
+impl RepoConfigCache {
+    fn get(&mut self, repo_path: &Path, head: ObjectId) -> Option<RepoConfig> {
+        self.uses += 1;
+        let (commit, config, used) = self.entries.get_mut(repo_path)?;
+        if *commit != head {
+            return None;
+        }
+        *used = self.uses;
+        Some(config.clone())
+    }
+
+    fn insert(&mut self, repo_path: &Path, head: ObjectId, config: RepoConfig) {
+        if self.entries.len() >= REPO_CONFIG_CACHE_SIZE && !self.entries.contains_key(repo_path) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, _, used))| *used)
+                .map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.uses += 1;
+        self.entries
+            .insert(repo_path.to_path_buf(), (head, config, self.uses));
+    }
+}
+
+// The server config as of when it was taken from the state, along with the shared repo config
+// cache.
+#[derive(Clone)]
+pub struct Configs {
+    pub server_config: ServerConfig,
+    repo_configs: Arc<Mutex<RepoConfigCache>>,
+}
+
+impl Configs {
+    // Gets the config at the repo's HEAD, only reading it again if HEAD has moved.
+    pub async fn repo_config(&self, repo_path: &Path) -> anyhow::Result<RepoConfig> {
+        let head = Repo::head_id(repo_path)
+            .await?
+            .context("The repo has no commits, so no repo.toml")?;
+
+        if let Some(config) = self.repo_configs.lock().unwrap().get(repo_path, head) {
+            return Ok(config);
+        }
+
+        let config = load_repo_config(repo_path, head).await?;
+        self.repo_configs
+            .lock()
+            .unwrap()
+            .insert(repo_path, head, config.clone());
+        Ok(config)
+    }
+
+    // Works out what a user, or an anonymous one if there's no username, can do with an existing
+    // repo. Both SSH and the web go by this.
+    pub async fn access_level(
+        &self,
+        repo_path: &Path,
+        username: Option<&str>,
+    ) -> anyhow::Result<AccessLevel> {
+        if repo_path == Path::new(SERVER_CONFIG_REPO) {
+            let is_admin = username.is_some_and(|username| self.server_config.is_admin(username));
+            return Ok(if is_admin {
+                AccessLevel::Admin
+            } else {
+                AccessLevel::None
+            });
+        }
+
+        // Mirrors have no repo config, so go by the grants the operator gave them in the mirror
+        // config, leaving them to admins by default.
+        if let Some(mirror) = Repo::mirror(repo_path).await? {
+            let mut repo_config = RepoConfig::new(repo_path);
+            repo_config.public = mirror.public;
+            repo_config.access.read = mirror.members;
+            return Ok(repo_config.access_level(&self.server_config, username));
+        }
+
+        let repo_config = self.repo_config(repo_path).await?;
+        Ok(repo_config.access_level(&self.server_config, username))
+    }
 }
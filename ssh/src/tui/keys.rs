@@ -0,0 +1,66 @@
+// The keys we understand, from what a terminal sends for them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Enter,
+    Backspace,
+    Escape,
+    Interrupt,
+    Char(char),
+}
+
+// Splits what the client sent into keys. One packet can hold several, such as when a key is
+// held down or text is pasted.
+pub fn parse(data: &[u8]) -> Vec<Key> {
+    let text = String::from_utf8_lossy(data);
+    let mut chars = text.chars().peekable();
+    let mut keys = Vec::new();
+
+    while let Some(c) = chars.next() {
+        let key = match c {
+            '\x1b' if matches!(chars.peek(), Some('[' | 'O')) => {
+                chars.next();
+                // CSI sequences are parameters followed by a final letter or '~'.
+                let mut params = String::new();
+                let mut last = None;
+                for c in chars.by_ref() {
+                    if c.is_ascii_digit() || c == ';' {
+                        params.push(c);
+                    } else {
+                        last = Some(c);
+                        break;
+                    }
+                }
+
+                let first_param = params.split(';').next().unwrap_or_default();
+                match (last, first_param) {
+                    (Some('A'), _) => Key::Up,
+                    (Some('B'), _) => Key::Down,
+                    (Some('C'), _) => Key::Right,
+                    (Some('D'), _) => Key::Left,
+                    (Some('H'), _) | (Some('~'), "1" | "7") => Key::Home,
+                    (Some('F'), _) | (Some('~'), "4" | "8") => Key::End,
+                    (Some('~'), "5") => Key::PageUp,
+                    (Some('~'), "6") => Key::PageDown,
+                    _ => continue,
+                }
+            }
+            '\x1b' => Key::Escape,
+            '\r' | '\n' => Key::Enter,
+            '\x7f' | '\x08' => Key::Backspace,
+            '\x03' | '\x04' => Key::Interrupt,
+            c if c.is_control() => continue,
+            c => Key::Char(c),
+        };
+        keys.push(key);
+    }
+
+    keys
+}
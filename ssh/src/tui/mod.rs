@@ -0,0 +1,617 @@
+// A terminal UI for browsing the repos a user can read, shown when they log in interactively.
+// It draws everything itself with escape codes, so it works in any terminal.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use gix::ObjectId;
+use log::error;
+use tokio::sync::Mutex;
+use unicode_width::UnicodeWidthStr;
+
+use crate::config::{repo::AccessLevel, server::ServerConfig};
+use crate::git::{find_repos, Repo};
+use crate::quota::format_size;
+use crate::state::State;
+use crate::vars::*;
+
+mod keys;
+mod render;
+mod source;
+
+pub use self::render::age;
+pub use self::source::{CommitLog, LogEntry};
+
+use self::keys::Key;
+use self::render::*;
+
+// How many commits the log loads at a time.
+const LOG_PAGE: usize = 100;
+// Bigger files and diffs are cut short rather than highlighted.
+const MAX_TEXT_SIZE: usize = 1024 * 1024;
+
+pub struct Tui {
+    state: Arc<Mutex<State>>,
+    server_config: ServerConfig,
+    username: Option<String>,
+    width: usize,
+    height: usize,
+    // What's being looked at, with the screens to go back to under it.
+    screens: Vec<Screen>,
+    // Shown in the status bar until the next key.
+    message: Option<String>,
+    // Escape codes to send before the next redraw.
+    pending: Vec<u8>,
+    done: bool,
+}
+
+struct Screen {
+    title: String,
+    repo: Option<PathBuf>,
+    view: View,
+    lines: Vec<String>,
+    // Lists have a selected line, while text just scrolls.
+    selected: Option<usize>,
+    scroll: usize,
+}
+
+enum View {
+    Repos(Vec<PathBuf>),
+    Log {
+        ids: Vec<String>,
+        more: bool,
+    },
+    Tree {
+        commit: ObjectId,
+        dir: PathBuf,
+        entries: Vec<(String, String)>,
+    },
+    Text,
+}
+
+impl Screen {
+    fn list(title: String, repo: Option<PathBuf>, view: View, lines: Vec<String>) -> Self {
+        Screen {
+            title,
+            repo,
+            view,
+            lines,
+            selected: Some(0),
+            scroll: 0,
+        }
+    }
+
+    fn text(title: String, repo: PathBuf, lines: Vec<String>) -> Self {
+        Screen {
+            title,
+            repo: Some(repo),
+            view: View::Text,
+            lines,
+            selected: None,
+            scroll: 0,
+        }
+    }
+}
+
+impl Tui {
+    // Starts on the list of repos the user can read.
+    pub async fn new(
+        state: Arc<Mutex<State>>,
+        username: Option<String>,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let server_config = state.lock().await.server_config.clone();
+        let mut tui = Tui {
+            state,
+            server_config,
+            username,
+            width: width as usize,
+            height: height as usize,
+            screens: Vec::new(),
+            message: None,
+            pending: Vec::new(),
+            done: false,
+        };
+
+        let repos = tui.readable_repos().await;
+        let lines = repos
+            .iter()
+            .map(|(repo_path, level)| {
+                let flag = |required, flag| if *level >= required { flag } else { " " };
+                format!(
+                    " {}{} {} {}{}  {}",
+                    DIM,
+                    flag(AccessLevel::Read, "R"),
+                    flag(AccessLevel::Write, "W"),
+                    flag(AccessLevel::Admin, "A"),
+                    RESET,
+                    clean(&repo_path.to_string_lossy())
+                )
+            })
+            .collect();
+        let mut screen = Screen::list(
+            "repositories".to_string(),
+            None,
+            View::Repos(repos.into_iter().map(|(repo_path, _)| repo_path).collect()),
+            lines,
+        );
+        if screen.lines.is_empty() {
+            screen
+                .lines
+                .push(format!(" {}You can't see any repositories yet.", DIM));
+            screen.selected = None;
+        }
+        tui.screens.push(screen);
+
+        tui
+    }
+
+    // Switches to the terminal's alternate screen, so whatever was there comes back when we're
+    // done, and draws the first screen.
+    pub fn start(&self) -> Vec<u8> {
+        let mut output = b"\x1b[?1049h\x1b[?25l".to_vec();
+        output.extend(self.draw());
+        output
+    }
+
+    pub fn done(&self) -> bool {
+        self.done
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) -> Vec<u8> {
+        self.width = width as usize;
+        self.height = height as usize;
+        if let Some(screen) = self.screens.last_mut() {
+            keep_in_view(screen, self.height.saturating_sub(2));
+        }
+        self.draw()
+    }
+
+    // Handles what the user typed, returning what to send back.
+    pub async fn input(&mut self, data: &[u8]) -> Vec<u8> {
+        for key in keys::parse(data) {
+            self.message = None;
+            if let Err(e) = self.key(key).await {
+                error!("{:#}", e);
+                self.message = Some(clean(&format!(" {:#}", e)));
+            }
+            if self.done {
+                return b"\x1b[?25h\x1b[?1049l".to_vec();
+            }
+        }
+        let mut output = std::mem::take(&mut self.pending);
+        output.extend(self.draw());
+        output
+    }
+
+    async fn key(&mut self, key: Key) -> anyhow::Result<()> {
+        let page = self.height.saturating_sub(3).max(1);
+        match key {
+            Key::Interrupt => self.done = true,
+            Key::Char('q') | Key::Char('h') | Key::Escape | Key::Backspace | Key::Left => {
+                self.screens.pop();
+                self.done = self.screens.is_empty();
+            }
+            Key::Up | Key::Char('k') => self.move_by(-1),
+            Key::Down | Key::Char('j') => self.move_by(1),
+            Key::PageUp | Key::Char('b') => self.move_by(-(page as isize)),
+            Key::PageDown | Key::Char(' ') => self.move_by(page as isize),
+            Key::Home | Key::Char('g') => self.move_by(isize::MIN / 2),
+            Key::End | Key::Char('G') => self.move_by(isize::MAX / 2),
+            Key::Enter | Key::Right | Key::Char('l') => self.open().await?,
+            Key::Char('t') => self.open_root_tree().await?,
+            Key::Char('c') => self.copy_clone_url(),
+            _ => {}
+        }
+
+        // Keep loading the log as the user scrolls down it.
+        if let Some(screen) = self.screens.last() {
+            if let (View::Log { more: true, .. }, Some(selected)) = (&screen.view, screen.selected)
+            {
+                if selected + 1 >= screen.lines.len() {
+                    self.load_more_log().await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn move_by(&mut self, amount: isize) {
+        let body = self.body_height();
+        let Some(screen) = self.screens.last_mut() else {
+            return;
+        };
+
+        match screen.selected.as_mut() {
+            Some(selected) => {
+                *selected = selected
+                    .saturating_add_signed(amount)
+                    .min(screen.lines.len().saturating_sub(1));
+            }
+            None => {
+                screen.scroll = screen
+                    .scroll
+                    .saturating_add_signed(amount)
+                    .min(screen.lines.len().saturating_sub(body));
+            }
+        }
+        keep_in_view(screen, body);
+    }
+
+    // Opens the selected repo's log, a commit from a log, or an entry in a tree.
+    async fn open(&mut self) -> anyhow::Result<()> {
+        let Some(screen) = self.screens.last() else {
+            return Ok(());
+        };
+        let Some(selected) = screen.selected else {
+            return Ok(());
+        };
+
+        let next = match (&screen.view, &screen.repo) {
+            (View::Repos(repos), _) => match repos.get(selected) {
+                Some(repo_path) => self.log_screen(repo_path).await?,
+                None => return Ok(()),
+            },
+            (View::Log { ids, .. }, Some(repo_path)) => match ids.get(selected) {
+                Some(id) => commit_screen(repo_path, id).await?,
+                None => return Ok(()),
+            },
+            (
+                View::Tree {
+                    commit,
+                    dir,
+                    entries,
+                },
+                Some(repo_path),
+            ) => match entries.get(selected) {
+                Some((kind, name)) if kind == "tree" => {
+                    tree_screen(repo_path, *commit, &dir.join(name)).await?
+                }
+                Some((kind, name)) if kind == "blob" => {
+                    file_screen(repo_path, *commit, &dir.join(name)).await?
+                }
+                Some(_) => {
+                    self.message = Some(" Submodules can't be browsed here.".to_string());
+                    return Ok(());
+                }
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+
+        self.screens.push(next);
+        Ok(())
+    }
+
+    // Opens the files at HEAD of the repo being looked at.
+    async fn open_root_tree(&mut self) -> anyhow::Result<()> {
+        let Some(repo_path) = self.current_repo() else {
+            return Ok(());
+        };
+        let Some(head) = Repo::head_id(&repo_path).await? else {
+            self.message = Some(" This repository has no commits yet.".to_string());
+            return Ok(());
+        };
+
+        let screen = tree_screen(&repo_path, head, Path::new("")).await?;
+        self.screens.push(screen);
+        Ok(())
+    }
+
+    async fn log_screen(&self, repo_path: &Path) -> anyhow::Result<Screen> {
+        let index = self.state.lock().await.commit_log.clone();
+        let commits = source::commits(index, repo_path, 0, LOG_PAGE).await?;
+
+        let mut screen = Screen::list(
+            format!("{} › log", repo_path.display()),
+            Some(repo_path.to_path_buf()),
+            View::Log {
+                ids: Vec::new(),
+                more: commits.len() == LOG_PAGE,
+            },
+            Vec::new(),
+        );
+        add_commits(&mut screen, commits);
+        if screen.lines.is_empty() {
+            screen.lines.push(format!(" {}No commits yet.", DIM));
+            screen.selected = None;
+        }
+        Ok(screen)
+    }
+
+    async fn load_more_log(&mut self) -> anyhow::Result<()> {
+        let index = self.state.lock().await.commit_log.clone();
+        let Some(screen) = self.screens.last_mut() else {
+            return Ok(());
+        };
+        let Some(repo_path) = screen.repo.clone() else {
+            return Ok(());
+        };
+
+        let commits = source::commits(index, &repo_path, screen.lines.len(), LOG_PAGE).await?;
+        if let View::Log { more, .. } = &mut screen.view {
+            *more = commits.len() == LOG_PAGE;
+        }
+        add_commits(screen, commits);
+        Ok(())
+    }
+
+    // Puts the clone URL on the user's clipboard, for terminals that let us.
+    fn copy_clone_url(&mut self) {
+        let Some(url) = self.clone_url() else {
+            return;
+        };
+
+        self.pending
+            .extend(format!("\x1b]52;c;{}\x07", STANDARD.encode(&url)).into_bytes());
+        self.message = Some(format!(" Copied {}, if your terminal allows it", url));
+    }
+
+    // The repo that's selected in the list, or being browsed.
+    fn current_repo(&self) -> Option<PathBuf> {
+        let screen = self.screens.last()?;
+        match &screen.view {
+            View::Repos(repos) => repos.get(screen.selected?).cloned(),
+            _ => screen.repo.clone(),
+        }
+    }
+
+    fn clone_url(&self) -> Option<String> {
+        Some(format!(
+            "ssh://{}:{}/{}",
+            self.server_config.hostname,
+            self.server_config.port,
+            clean(&self.current_repo()?.to_string_lossy())
+        ))
+    }
+
+    async fn readable_repos(&self) -> Vec<(PathBuf, AccessLevel)> {
+        let mut found = Vec::new();
+        find_repos(Path::new("."), Path::new(""), &mut found);
+        found.sort();
+
+        let configs = self.state.lock().await.configs();
+        let mut repos = Vec::new();
+        for repo_path in found {
+            let level = configs
+                .access_level(&repo_path, self.username.as_deref())
+                .await;
+            match level {
+                Ok(level) if level >= AccessLevel::Read => repos.push((repo_path, level)),
+                Ok(_) => {}
+                Err(e) => error!("Couldn't check access to {}: {:#}", repo_path.display(), e),
+            }
+        }
+        repos
+    }
+
+    fn body_height(&self) -> usize {
+        self.height.saturating_sub(2)
+    }
+
+    // Draws the whole screen: a header saying where we are, what's being looked at, and a status
+    // bar with the keys to press and the clone URL.
+    fn draw(&self) -> Vec<u8> {
+        let Some(screen) = self.screens.last() else {
+            return Vec::new();
+        };
+        if self.width == 0 || self.height < 3 {
+            return Vec::new();
+        }
+
+        let mut rows = Vec::with_capacity(self.height);
+
+        let user = self.username.as_deref().unwrap_or(GUEST_USERNAME);
+        let title = clean(&format!(" {} › {}", self.server_config.name, screen.title));
+        let padding = self.width.saturating_sub(title.width() + user.width() + 1);
+        rows.push(fit(
+            &format!(
+                "{}{}{}{}{} ",
+                REVERSE,
+                BOLD,
+                title,
+                " ".repeat(padding),
+                user
+            ),
+            self.width,
+        ));
+
+        let body = self.body_height();
+        for i in screen.scroll..screen.scroll + body {
+            let row = match screen.lines.get(i) {
+                Some(line) if screen.selected == Some(i) => {
+                    // The line's own colours would show through the selection bar.
+                    format!("{}{}", REVERSE, strip_colours(line))
+                }
+                Some(line) => line.clone(),
+                None => String::new(),
+            };
+            rows.push(fit(&row, self.width));
+        }
+
+        let status = match &self.message {
+            Some(message) => message.clone(),
+            None => {
+                let keys = match screen.view {
+                    View::Repos(_) => "⏎ log  t files  c copy URL  q quit",
+                    View::Log { .. } => "⏎ diff  t files  c copy URL  q back",
+                    View::Tree { .. } => "⏎ open  c copy URL  q back",
+                    View::Text => "↑↓ scroll  space page  q back",
+                };
+                match self.clone_url() {
+                    Some(url) => format!(" {}  {}git clone {}", keys, DIM, url),
+                    None => format!(" {}", keys),
+                }
+            }
+        };
+        rows.push(fit(&status, self.width));
+
+        format!("\x1b[H{}", rows.join("\r\n")).into_bytes()
+    }
+}
+
+// Scrolls just enough for the selected line to be on screen.
+fn keep_in_view(screen: &mut Screen, body: usize) {
+    let Some(selected) = screen.selected else {
+        return;
+    };
+    if selected < screen.scroll {
+        screen.scroll = selected;
+    } else if body > 0 && selected >= screen.scroll + body {
+        screen.scroll = selected + 1 - body;
+    }
+}
+
+fn strip_colours(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+fn add_commits(screen: &mut Screen, commits: Vec<LogEntry>) {
+    let View::Log { ids, .. } = &mut screen.view else {
+        return;
+    };
+
+    for commit in commits {
+        screen.lines.push(format!(
+            " {}{}{}  {}{:>8}{}  {}{}  {}",
+            YELLOW,
+            commit.id.get(..8).unwrap_or(&commit.id),
+            RESET,
+            GREEN,
+            age(commit.time),
+            RESET,
+            CYAN,
+            fit(&clean(&commit.author), 16),
+            clean(&commit.summary)
+        ));
+        ids.push(commit.id);
+    }
+}
+
+async fn commit_screen(repo_path: &Path, id: &str) -> anyhow::Result<Screen> {
+    let mut show = Repo::show(repo_path, id).await?;
+    let truncated = show.len() > MAX_TEXT_SIZE;
+    if truncated {
+        let mut end = MAX_TEXT_SIZE;
+        while !show.is_char_boundary(end) {
+            end -= 1;
+        }
+        show.truncate(end);
+    }
+
+    let mut lines = tokio::task::spawn_blocking(move || highlight_diff(&show)).await?;
+    if truncated {
+        lines.push(format!(
+            "{}The rest of this diff is too big to show here.",
+            DIM
+        ));
+    }
+
+    Ok(Screen::text(
+        format!("{} › {}", repo_path.display(), id.get(..8).unwrap_or(id)),
+        repo_path.to_path_buf(),
+        lines,
+    ))
+}
+
+async fn tree_screen(repo_path: &Path, commit: ObjectId, dir: &Path) -> anyhow::Result<Screen> {
+    let mut entries = Repo::tree(repo_path, commit, dir).await?;
+    // Directories first, like most file browsers.
+    entries.sort_by_key(|(kind, _, _)| kind != "tree");
+
+    let lines = entries
+        .iter()
+        .map(|(kind, size, name)| match kind.as_str() {
+            "tree" => format!(" {:>10}  {}{}{}/", "", BLUE, BOLD, clean(name)),
+            "blob" => format!(
+                " {}{:>10}{}  {}",
+                DIM,
+                size.map(format_size).unwrap_or_default(),
+                RESET,
+                clean(name)
+            ),
+            _ => format!(" {:>10}  {}{} (submodule)", "", clean(name), DIM),
+        })
+        .collect();
+
+    let title = if dir == Path::new("") {
+        format!("{} › files", repo_path.display())
+    } else {
+        format!("{} › {}", repo_path.display(), dir.display())
+    };
+    Ok(Screen::list(
+        title,
+        Some(repo_path.to_path_buf()),
+        View::Tree {
+            commit,
+            dir: dir.to_path_buf(),
+            entries: entries
+                .into_iter()
+                .map(|(kind, _, name)| (kind, name))
+                .collect(),
+        },
+        lines,
+    ))
+}
+
+async fn file_screen(repo_path: &Path, commit: ObjectId, file: &Path) -> anyhow::Result<Screen> {
+    let data = Repo::read_file(repo_path, commit, file)
+        .await?
+        .unwrap_or_default();
+
+    let lines = if data.len() > MAX_TEXT_SIZE {
+        vec![format!(
+            "{}This file is too big to show here ({}).",
+            DIM,
+            format_size(data.len() as u64)
+        )]
+    } else if data.iter().take(8000).any(|b| *b == 0) {
+        vec![format!(
+            "{}Binary file ({}).",
+            DIM,
+            format_size(data.len() as u64)
+        )]
+    } else {
+        let name = file
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let text = String::from_utf8_lossy(&data).to_string();
+        let lines = tokio::task::spawn_blocking(move || highlight_file(&name, &text)).await?;
+        let number_width = lines.len().to_string().len();
+        lines
+            .into_iter()
+            .enumerate()
+            .map(|(i, line)| {
+                format!(
+                    "{}{:>width$}{} {}",
+                    DIM,
+                    i + 1,
+                    RESET,
+                    line,
+                    width = number_width
+                )
+            })
+            .collect()
+    };
+
+    Ok(Screen::text(
+        format!("{} › {}", repo_path.display(), file.display()),
+        repo_path.to_path_buf(),
+        lines,
+    ))
+}
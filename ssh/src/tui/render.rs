@@ -0,0 +1,220 @@
+use std::sync::OnceLock;
+
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    parsing::{SyntaxReference, SyntaxSet},
+    util::as_24_bit_terminal_escaped,
+};
+use unicode_width::UnicodeWidthChar;
+
+pub const RESET: &str = "\x1b[0m";
+pub const BOLD: &str = "\x1b[1m";
+pub const DIM: &str = "\x1b[2m";
+pub const REVERSE: &str = "\x1b[7m";
+pub const RED: &str = "\x1b[31m";
+pub const GREEN: &str = "\x1b[32m";
+pub const YELLOW: &str = "\x1b[33m";
+pub const BLUE: &str = "\x1b[34m";
+pub const CYAN: &str = "\x1b[36m";
+
+// Backgrounds for added and removed lines, dark enough for the theme's colours to show.
+const ADDED: &str = "\x1b[48;2;24;56;32m";
+const REMOVED: &str = "\x1b[48;2;64;28;32m";
+
+const TAB_WIDTH: usize = 4;
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEME: OnceLock<Theme> = OnceLock::new();
+    THEME.get_or_init(|| {
+        let mut themes = ThemeSet::load_defaults().themes;
+        themes.remove("base16-ocean.dark").unwrap_or_default()
+    })
+}
+
+fn syntax_for(file_name: &str, first_line: &str) -> &'static SyntaxReference {
+    let syntaxes = syntaxes();
+    let extension = file_name.rsplit('.').next().unwrap_or(file_name);
+    syntaxes
+        .find_syntax_by_extension(extension)
+        .or_else(|| syntaxes.find_syntax_by_token(file_name))
+        .or_else(|| syntaxes.find_syntax_by_first_line(first_line))
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text())
+}
+
+// Makes text from a repo safe to send to a terminal, so a file can't move the cursor or change
+// the title, and expands tabs since we count columns ourselves.
+pub fn clean(text: &str) -> String {
+    let mut cleaned = String::with_capacity(text.len());
+    let mut column = 0;
+    for c in text.chars() {
+        match c {
+            '\t' => {
+                let spaces = TAB_WIDTH - column % TAB_WIDTH;
+                cleaned.extend(std::iter::repeat_n(' ', spaces));
+                column += spaces;
+            }
+            '\n' => {
+                cleaned.push(c);
+                column = 0;
+            }
+            // Windows line endings.
+            '\r' => {}
+            c if c.is_control() => {
+                cleaned.push('\u{fffd}');
+                column += 1;
+            }
+            c => {
+                cleaned.push(c);
+                column += c.width().unwrap_or(0);
+            }
+        }
+    }
+    cleaned
+}
+
+fn highlight_line(highlighter: &mut HighlightLines, line: &str) -> String {
+    // The syntaxes expect each line to end in a newline.
+    let line = format!("{}\n", line);
+    match highlighter.highlight_line(&line, syntaxes()) {
+        Ok(regions) => as_24_bit_terminal_escaped(&regions, false)
+            .trim_end_matches('\n')
+            .to_string(),
+        Err(_) => line.trim_end_matches('\n').to_string(),
+    }
+}
+
+// Colours a file's lines by its syntax, going by its name or else its first line.
+pub fn highlight_file(file_name: &str, text: &str) -> Vec<String> {
+    let text = clean(text);
+    let first_line = text.lines().next().unwrap_or_default();
+    let mut highlighter = HighlightLines::new(syntax_for(file_name, first_line), theme());
+    text.lines()
+        .map(|line| highlight_line(&mut highlighter, line))
+        .collect()
+}
+
+// Colours `git show` output: the diffstat in red and green, and each file's hunks by its syntax
+// on a red or green background for removed and added lines.
+pub fn highlight_diff(text: &str) -> Vec<String> {
+    let text = clean(text);
+    let mut highlighter: Option<HighlightLines> = None;
+    let mut in_hunk = false;
+    let mut lines = Vec::new();
+
+    for line in text.lines() {
+        if let Some(files) = line.strip_prefix("diff --git ") {
+            let file_name = files.rsplit('/').next().unwrap_or(files);
+            highlighter = Some(HighlightLines::new(syntax_for(file_name, ""), theme()));
+            in_hunk = false;
+            lines.push(format!("{}{}", BOLD, line));
+            continue;
+        }
+
+        let Some(highlighter) = highlighter.as_mut() else {
+            // The commit's header and diffstat.
+            if line.starts_with("commit ") {
+                lines.push(format!("{}{}", YELLOW, line));
+            } else if let Some((file, change)) = line.split_once(" | ") {
+                let change = change
+                    .replace('+', &format!("{}+", GREEN))
+                    .replace('-', &format!("{}-", RED));
+                lines.push(format!("{} | {}", file, change));
+            } else {
+                lines.push(line.to_string());
+            }
+            continue;
+        };
+
+        if let Some(range) = line.strip_prefix("@@") {
+            in_hunk = true;
+            match range.find("@@") {
+                Some(end) => {
+                    let (range, context) = line.split_at(end + 4);
+                    lines.push(format!("{}{}{}{}", CYAN, range, RESET, context));
+                }
+                None => lines.push(format!("{}{}", CYAN, line)),
+            }
+            continue;
+        }
+        if !in_hunk {
+            lines.push(format!("{}{}", BOLD, line));
+            continue;
+        }
+
+        let (background, marker, content) = match line.chars().next() {
+            Some('+') => (ADDED, "+", &line[1..]),
+            Some('-') => (REMOVED, "-", &line[1..]),
+            Some(' ') => ("", " ", &line[1..]),
+            _ => {
+                // "\ No newline at end of file"
+                lines.push(format!("{}{}", DIM, line));
+                continue;
+            }
+        };
+        lines.push(format!(
+            "{}{}{}",
+            background,
+            marker,
+            highlight_line(highlighter, content)
+        ));
+    }
+
+    lines
+}
+
+// Cuts a line down to `width` columns, or pads it out to them so that backgrounds and the
+// selection bar reach the edge. The only escape codes in it should be our own colours.
+pub fn fit(line: &str, width: usize) -> String {
+    let mut fitted = String::with_capacity(line.len() + width);
+    let mut used = 0;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            fitted.push(c);
+            for c in chars.by_ref() {
+                fitted.push(c);
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+            continue;
+        }
+
+        let c_width = c.width().unwrap_or(0);
+        if used + c_width > width {
+            break;
+        }
+        fitted.push(c);
+        used += c_width;
+    }
+
+    fitted.extend(std::iter::repeat_n(' ', width - used));
+    fitted.push_str(RESET);
+    fitted
+}
+
+// How long ago a time was, briefly, like "3d ago".
+pub fn age(time: i64) -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs() as i64);
+    let seconds = (now - time).max(0);
+
+    let (amount, unit) = match seconds {
+        0..=59 => return "just now".to_string(),
+        60..=3599 => (seconds / 60, "m"),
+        3600..=86399 => (seconds / 3600, "h"),
+        86400..=1209599 => (seconds / 86400, "d"),
+        1209600..=5183999 => (seconds / 604800, "w"),
+        5184000..=31535999 => (seconds / 2592000, "mo"),
+        _ => (seconds / 31536000, "y"),
+    };
+    format!("{}{} ago", amount, unit)
+}
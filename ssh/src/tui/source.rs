@@ -0,0 +1,65 @@
+use std::{path::Path, sync::Arc};
+
+use log::warn;
+
+use crate::git::Repo;
+
+// A commit as listed in the log.
+pub struct LogEntry {
+    pub id: String,
+    pub author: String,
+    // When it was authored, in seconds since the epoch.
+    pub time: i64,
+    pub summary: String,
+}
+
+// Somewhere other than git to read logs from, like the web server's index when it runs in the
+// same process. It's called from a blocking thread.
+pub trait CommitLog: Send + Sync {
+    // Up to `limit` commits on `branch`, a full ref name, after skipping the first `skip`,
+    // newest first, or None if it doesn't know the branch and git should be asked instead.
+    fn commits(
+        &self,
+        repo_path: &Path,
+        branch: &str,
+        skip: usize,
+        limit: usize,
+    ) -> anyhow::Result<Option<Vec<LogEntry>>>;
+}
+
+// Reads a page of the log of a repo's HEAD, from the index if there is one and it has the branch.
+pub async fn commits(
+    index: Option<Arc<dyn CommitLog>>,
+    repo_path: &Path,
+    skip: usize,
+    limit: usize,
+) -> anyhow::Result<Vec<LogEntry>> {
+    if let (Some(index), Some(branch)) = (index, Repo::head_ref(repo_path).await?) {
+        let path = repo_path.to_path_buf();
+        let found =
+            tokio::task::spawn_blocking(move || index.commits(&path, &branch, skip, limit)).await?;
+        match found {
+            Ok(Some(entries)) => return Ok(entries),
+            Ok(None) => {}
+            Err(e) => warn!(
+                "Couldn't read the log of {} from the index: {:#}",
+                repo_path.display(),
+                e
+            ),
+        }
+    }
+
+    if Repo::head_id(repo_path).await?.is_none() {
+        return Ok(Vec::new());
+    }
+    Ok(Repo::log(repo_path, skip, limit)
+        .await?
+        .into_iter()
+        .map(|(id, author, time, summary)| LogEntry {
+            id,
+            author,
+            time,
+            summary,
+        })
+        .collect())
+}
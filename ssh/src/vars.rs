@@ -1,8 +1,24 @@
 pub const GUEST_USERNAME: &str = "guest";
 pub const SERVER_CONFIG_REPO: &str = ".gnostr/.git";
 pub const SERVER_CONFIG_FILE: &str = "server.toml";
+pub const WEBHOOK_LOG_FILE: &str = ".gnostr/webhooks.log";
+pub const AUDIT_LOG_FILE: &str = ".gnostr/audit.log";
 
 pub const REPO_CONFIG_FILE: &str = "repo.toml";
 
 pub const GIT_COMMANDS: [&str; 3] = ["git-receive-pack", "git-upload-archive", "git-upload-pack"];
 pub const GIT_PUSH_COMMAND: &str = "git-receive-pack";
+pub const GIT_FETCH_COMMAND: &str = "git-upload-pack";
+
+// The server runs itself as git's pre-receive hook to enforce push policies.
+pub const PRE_RECEIVE_ARG: &str = "pre-receive";
+pub const HOOK_DIR_ENV: &str = "GNIT_HOOK_DIR";
+pub const HOOK_CONTEXT_FILE: &str = "context.json";
+pub const HOOK_REJECTIONS_FILE: &str = "rejections";
+
+// LFS objects are transferred over SSH by the server running itself.
+pub const LFS_TRANSFER_COMMAND: &str = "git-lfs-transfer";
+pub const LFS_TRANSFER_ARG: &str = "lfs-transfer";
+pub const LFS_QUOTA_ENV: &str = "GNIT_LFS_QUOTA";
+pub const LFS_OBJECTS_DIR: &str = "lfs/objects";
+pub const LFS_TMP_DIR: &str = "lfs/tmp";
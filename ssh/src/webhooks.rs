@@ -0,0 +1,287 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    audit::append_line, config::server::ServerConfig, git::Repo, utils::url_host, vars::*,
+};
+
+const ZERO_OID: &str = "0000000000000000000000000000000000000000";
+
+// The most commits listed for each ref, so a huge push doesn't make a huge payload.
+const MAX_COMMITS: usize = 20;
+
+const MAX_ATTEMPTS: u32 = 5;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    // Signs each delivery with HMAC-SHA256, sent in the X-Gnit-Signature-256 header. Only
+    // webhooks in server.toml can have one, as anyone who can read a repo can read its repo.toml.
+    pub secret: Option<String>,
+    // Signs each delivery with the secret of this name in the server's webhook_secrets.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret_name: Option<String>,
+}
+
+// Checks the server lets a repo send `webhook`, returning it with its secret filled in from the
+// server's webhook_secrets. Its host has to be one the server allows, so repos can't have it make
+// requests into its own network.
+pub fn for_repo(
+    webhook: &WebhookConfig,
+    server_config: &ServerConfig,
+) -> anyhow::Result<WebhookConfig> {
+    let host = url_host(&webhook.url).context("The webhook's URL has no host")?;
+    if !server_config
+        .webhook_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(&host))
+    {
+        bail!(
+            "The server doesn't let repos send webhooks to {}, which isn't in its webhook_hosts",
+            host
+        );
+    }
+    if webhook.secret.is_some() {
+        bail!("{}", REPO_SECRET_MESSAGE);
+    }
+
+    let secret = match &webhook.secret_name {
+        Some(name) => Some(
+            server_config
+                .webhook_secrets
+                .get(name)
+                .with_context(|| format!("The server has no webhook secret called {}", name))?
+                .clone(),
+        ),
+        None => None,
+    };
+    Ok(WebhookConfig {
+        url: webhook.url.clone(),
+        secret,
+        secret_name: None,
+    })
+}
+
+// Why a repo's webhook can't have its own secret.
+pub const REPO_SECRET_MESSAGE: &str =
+    "Anyone who can read the repo can read its webhook's secret, \
+    so ask an admin to add it to the server's webhook_secrets and give its name as secret_name";
+
+#[derive(Serialize)]
+pub struct PushEvent {
+    pub pusher: String,
+    pub repository: String,
+    pub refs: Vec<RefUpdate>,
+}
+
+#[derive(Serialize)]
+pub struct RefUpdate {
+    #[serde(rename = "ref")]
+    pub name: String,
+    pub before: String,
+    pub after: String,
+    pub commits: Vec<CommitSummary>,
+}
+
+#[derive(Serialize)]
+pub struct CommitSummary {
+    pub id: String,
+    pub summary: String,
+    pub author: Author,
+}
+
+#[derive(Serialize)]
+pub struct Author {
+    pub name: String,
+    pub email: String,
+}
+
+// A line in the delivery log.
+#[derive(Serialize)]
+struct Delivery<'a> {
+    id: &'a str,
+    timestamp: u64,
+    url: &'a str,
+    repository: &'a str,
+    attempt: u32,
+    status: Option<u16>,
+    error: Option<String>,
+}
+
+impl PushEvent {
+    /// Describes a push to `repo_path` by comparing its refs to those from before the push.
+    pub async fn new(
+        repo_path: &Path,
+        pusher: &str,
+        before: &BTreeMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let after = Repo::refs(repo_path).await?;
+
+        let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+        names.sort();
+        names.dedup();
+
+        // New refs only list the commits that weren't already in the repo.
+        let existing: Vec<&str> = before.values().map(String::as_str).collect();
+
+        let mut refs = Vec::new();
+        for name in names {
+            let old = before.get(name).map_or(ZERO_OID, String::as_str);
+            let new = after.get(name).map_or(ZERO_OID, String::as_str);
+
+            if old == new {
+                continue;
+            }
+
+            let commits = if new == ZERO_OID {
+                Vec::new()
+            } else {
+                let exclude = if old == ZERO_OID {
+                    existing.clone()
+                } else {
+                    vec![old]
+                };
+
+                match Repo::commits(repo_path, new, &exclude, MAX_COMMITS).await {
+                    Ok(commits) => commits
+                        .into_iter()
+                        .map(|(id, name, email, summary)| CommitSummary {
+                            id,
+                            summary,
+                            author: Author { name, email },
+                        })
+                        .collect(),
+                    Err(e) => {
+                        warn!("Couldn't list commits for {}: {:#}", name, e);
+                        Vec::new()
+                    }
+                }
+            };
+
+            refs.push(RefUpdate {
+                name: name.clone(),
+                before: old.to_string(),
+                after: new.to_string(),
+                commits,
+            });
+        }
+
+        Ok(Self {
+            pusher: pusher.to_string(),
+            repository: repo_path.to_str().unwrap_or_default().to_string(),
+            refs,
+        })
+    }
+}
+
+/// Sends `event` to each of `webhooks` in the background, retrying failed deliveries with
+/// exponential backoff.
+pub fn deliver(webhooks: Vec<WebhookConfig>, event: PushEvent) -> anyhow::Result<()> {
+    let body = serde_json::to_vec(&event)?;
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        // A redirect could go anywhere, including hosts repos aren't allowed to call.
+        .redirect(reqwest::redirect::Policy::none())
+        .user_agent(concat!("gnostr-gnit-webhooks/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .context("Failed to build HTTP client")?;
+
+    for webhook in webhooks {
+        let client = client.clone();
+        let body = body.clone();
+        let repository = event.repository.clone();
+
+        tokio::spawn(async move {
+            let id = uuid::Uuid::new_v4().to_string();
+
+            for attempt in 1..=MAX_ATTEMPTS {
+                let result = send(&client, &webhook, &id, &body).await;
+
+                let (status, error) = match &result {
+                    Ok(status) if status.is_success() => (Some(status.as_u16()), None),
+                    Ok(status) => (Some(status.as_u16()), Some(format!("HTTP {}", status))),
+                    Err(e) => (None, Some(format!("{:#}", e))),
+                };
+                let delivered = error.is_none();
+
+                if let Err(e) = log_delivery(Delivery {
+                    id: &id,
+                    timestamp: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |v| v.as_secs()),
+                    url: &webhook.url,
+                    repository: &repository,
+                    attempt,
+                    status,
+                    error: error.clone(),
+                })
+                .await
+                {
+                    error!("Failed to write webhook delivery log: {:#}", e);
+                }
+
+                if delivered {
+                    info!("Delivered webhook {} to {}", id, webhook.url);
+                    return;
+                }
+
+                warn!(
+                    "Webhook {} to {} failed (attempt {}/{}): {}",
+                    id,
+                    webhook.url,
+                    attempt,
+                    MAX_ATTEMPTS,
+                    error.unwrap_or_default()
+                );
+
+                if attempt < MAX_ATTEMPTS {
+                    tokio::time::sleep(Duration::from_secs(1 << (attempt - 1))).await;
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn send(
+    client: &reqwest::Client,
+    webhook: &WebhookConfig,
+    id: &str,
+    body: &[u8],
+) -> anyhow::Result<reqwest::StatusCode> {
+    let mut request = client
+        .post(&webhook.url)
+        .header("Content-Type", "application/json")
+        .header("X-Gnit-Event", "push")
+        .header("X-Gnit-Delivery", id)
+        .body(body.to_vec());
+
+    if let Some(secret) = &webhook.secret {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret.as_bytes()).context("Invalid webhook secret")?;
+        mac.update(body);
+        request = request.header(
+            "X-Gnit-Signature-256",
+            format!("sha256={}", hex::encode(mac.finalize().into_bytes())),
+        );
+    }
+
+    Ok(request.send().await?.status())
+}
+
+async fn log_delivery(delivery: Delivery<'_>) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(&delivery)?;
+    line.push(b'\n');
+    append_line(Path::new(WEBHOOK_LOG_FILE), &line).await
+}
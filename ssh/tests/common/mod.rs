@@ -0,0 +1,391 @@
+//! Runs the SSH server in this process, against a temporary scan path, and
+//! talks to it with the stock `git` and `ssh` clients or a russh client.
+
+#![allow(dead_code)] // not every test uses every helper
+
+use std::{
+    io::Write,
+    net::{SocketAddr, TcpListener, TcpStream},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use gnit_ssh::{ImportSource, SshServer};
+use russh::{
+    client,
+    keys::{load_secret_key, PrivateKeyWithHashAlg, PublicKeyOrCertificate},
+    ChannelMsg, Disconnect,
+};
+use tokio::runtime::Runtime;
+
+/// Runs each test in turn, or only those whose names contain the first
+/// argument, and exits with an error if any failed.
+///
+/// The server works relative to the current directory and runs this
+/// executable as git's pre-receive hook, so the tests can't use the standard
+/// harness, which runs them in parallel threads.
+pub fn run(tests: &[(&str, fn())]) {
+    if let Some(code) = gnit_ssh::run_helper() {
+        std::process::exit(code);
+    }
+
+    // messages are compared as text, and git shouldn't pick up any user or
+    // system configuration, on our side or the server's
+    std::env::set_var("NO_COLOR", "1");
+    std::env::set_var("GIT_CONFIG_NOSYSTEM", "1");
+    std::env::set_var("GIT_CONFIG_GLOBAL", "/dev/null");
+    std::env::set_var("GIT_AUTHOR_NAME", "test");
+    std::env::set_var("GIT_AUTHOR_EMAIL", "test@example.com");
+    std::env::set_var("GIT_COMMITTER_NAME", "test");
+    std::env::set_var("GIT_COMMITTER_EMAIL", "test@example.com");
+    std::env::set_var("GIT_TERMINAL_PROMPT", "0");
+
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with('-'));
+    let tests: Vec<_> = tests
+        .iter()
+        .filter(|(name, _)| filter.as_ref().is_none_or(|filter| name.contains(filter)))
+        .collect();
+
+    println!("\nrunning {} tests", tests.len());
+    let mut failed = Vec::new();
+    for (name, test) in &tests {
+        let passed = catch_unwind(AssertUnwindSafe(test)).is_ok();
+        println!("test {name} ... {}", if passed { "ok" } else { "FAILED" });
+        if !passed {
+            failed.push(*name);
+        }
+    }
+
+    println!(
+        "\ntest result: {}. {} passed; {} failed\n",
+        if failed.is_empty() { "ok" } else { "FAILED" },
+        tests.len() - failed.len(),
+        failed.len()
+    );
+    if !failed.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// A port nothing is listening on yet.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// An OpenSSH key pair made with `ssh-keygen`.
+pub struct Key {
+    pub path: PathBuf,
+    /// The public key as it's written in server.toml.
+    pub public: String,
+}
+
+impl Key {
+    pub fn generate(dir: &Path, name: &str) -> Self {
+        std::fs::create_dir_all(dir).unwrap();
+        let path = dir.join(name);
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", name, "-f"])
+            .arg(&path)
+            .status()
+            .expect("failed to run ssh-keygen");
+        assert!(status.success(), "ssh-keygen failed: {status}");
+
+        let public = std::fs::read_to_string(path.with_extension("pub")).unwrap();
+        Self {
+            path,
+            public: public.trim().to_string(),
+        }
+    }
+}
+
+/// What a client got back from a command.
+pub struct Output {
+    /// None if the server closed the channel without saying.
+    pub status: Option<u32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Output {
+    pub fn success(&self) -> bool {
+        self.status == Some(0)
+    }
+
+    /// Whether the server sent `message`, which it wraps over several lines, breaking
+    /// after hyphens too.
+    pub fn says(&self, message: &str) -> bool {
+        let words = |text: &str| {
+            text.replace("-\n", "-")
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        words(&self.stderr).contains(&words(message))
+    }
+}
+
+impl From<std::process::Output> for Output {
+    fn from(output: std::process::Output) -> Self {
+        Self {
+            status: output.status.code().map(|code| code as u32),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "exit status {:?}\n--- stdout\n{}--- stderr\n{}",
+            self.status, self.stdout, self.stderr
+        )
+    }
+}
+
+/// The SSH server, serving `root/scan` until dropped. The scan path is the
+/// current directory meanwhile.
+pub struct Server {
+    runtime: Option<Runtime>,
+    pub scan: PathBuf,
+    pub port: u16,
+}
+
+impl Server {
+    /// Starts a server with `config` as its initial server.toml, kept outside the scan path.
+    pub fn start(root: &Path, config: &str) -> Self {
+        let initial_config = root.join("server.toml");
+        std::fs::write(&initial_config, config).unwrap();
+        Self::start_from(root, &initial_config)
+    }
+
+    /// Starts a server that moves in `initial_config`, with `root/scan` as its scan path.
+    pub fn start_from(root: &Path, initial_config: &Path) -> Self {
+        let scan = root.join("scan");
+        std::fs::create_dir_all(&scan).unwrap();
+        std::env::set_current_dir(&scan).unwrap();
+
+        let host_key = Key::generate(&root.join("host"), "ssh_host_ed25519_key");
+        let port = free_port();
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+
+        let runtime = Runtime::new().unwrap();
+        let server = runtime
+            .block_on(SshServer::load(initial_config))
+            .expect("failed to load the server config");
+        runtime.spawn(async move {
+            if let Err(e) = server.run(vec![address], vec![host_key.path]).await {
+                eprintln!("the server failed: {e:#}");
+            }
+        });
+
+        eventually("the server to listen", || {
+            TcpStream::connect(address).is_ok()
+        });
+
+        Self {
+            runtime: Some(runtime),
+            scan,
+            port,
+        }
+    }
+
+    pub fn url(&self, repo: &str) -> String {
+        format!("ssh://git@127.0.0.1:{}/{repo}", self.port)
+    }
+
+    /// Runs `git` in `dir`, connecting to the server with `key`.
+    pub fn git(&self, key: &Key, dir: &Path, args: &[&str]) -> Output {
+        let output = Command::new("git")
+            .current_dir(dir)
+            .args(args)
+            .env(
+                "GIT_SSH_COMMAND",
+                format!("ssh {}", ssh_args(key).join(" ")),
+            )
+            .output()
+            .expect("failed to run git");
+        Output::from(output)
+    }
+
+    /// Runs `command` on the server with the stock `ssh` client.
+    pub fn ssh(&self, key: &Key, command: &str) -> Output {
+        self.ssh_input(key, command, &[])
+    }
+
+    /// Runs `command` on the server with the stock `ssh` client, sending it
+    /// `input` on stdin.
+    pub fn ssh_input(&self, key: &Key, command: &str, input: &[u8]) -> Output {
+        let mut child = Command::new("ssh")
+            .args(ssh_args(key))
+            .args(["-p", &self.port.to_string(), "git@127.0.0.1", command])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to run ssh");
+        let mut stdin = child.stdin.take().unwrap();
+        let input = input.to_vec();
+        let writer = thread::spawn(move || stdin.write_all(&input));
+        let output = child.wait_with_output().expect("failed to run ssh");
+        writer.join().unwrap().expect("failed to write to ssh");
+        Output::from(output)
+    }
+
+    /// Runs `command` on the server with a russh client logged in with `key`.
+    pub fn exec(&self, key: &Key, command: &str) -> Output {
+        let runtime = self.runtime.as_ref().unwrap();
+        runtime
+            .block_on(exec(self.port, key, command))
+            .expect("failed to run the command over SSH")
+    }
+
+    /// Logs in with `key` and a terminal, typing each of `keys` once the
+    /// server has redrawn the screen after the last one.
+    pub fn terminal(&self, key: &Key, keys: &[&str]) -> Output {
+        let runtime = self.runtime.as_ref().unwrap();
+        runtime
+            .block_on(terminal(self.port, key, keys))
+            .expect("failed to open a terminal over SSH")
+    }
+
+    /// Imports users and permissions the way `gnostr-gnit import --apply` does.
+    pub fn import(&self, source: &ImportSource) -> anyhow::Result<()> {
+        let runtime = self.runtime.as_ref().unwrap();
+        runtime.block_on(gnit_ssh::import(source, true))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_timeout(Duration::from_secs(5));
+        }
+    }
+}
+
+/// Arguments for `ssh` to log in with `key`, offering its certificate first
+/// if `ssh-keygen -s` made one next to it.
+fn ssh_args(key: &Key) -> Vec<String> {
+    let options = [
+        "IdentitiesOnly=yes",
+        "StrictHostKeyChecking=no",
+        "UserKnownHostsFile=/dev/null",
+        "LogLevel=ERROR",
+    ];
+    let mut args = vec!["-i".to_string(), key.path.display().to_string()];
+    for option in options {
+        args.extend(["-o".to_string(), option.to_string()]);
+    }
+    let certificate = PathBuf::from(format!("{}-cert.pub", key.path.display()));
+    if certificate.exists() {
+        args.extend([
+            "-o".to_string(),
+            format!("CertificateFile={}", certificate.display()),
+        ]);
+    }
+    args
+}
+
+struct Client;
+
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        _server_public_key: &PublicKeyOrCertificate,
+    ) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+async fn connect(port: u16, key: &Key) -> anyhow::Result<client::Handle<Client>> {
+    let key_pair = load_secret_key(&key.path, None)?;
+    let config = Arc::new(client::Config {
+        inactivity_timeout: Some(Duration::from_secs(10)),
+        ..Default::default()
+    });
+
+    let mut session = client::connect(config, ("127.0.0.1", port), Client).await?;
+    let key_pair = PrivateKeyWithHashAlg::new(Arc::new(key_pair), None);
+    let accepted = session.authenticate_publickey("git", key_pair).await?;
+    anyhow::ensure!(accepted.success(), "the server didn't accept the key");
+    Ok(session)
+}
+
+async fn exec(port: u16, key: &Key, command: &str) -> anyhow::Result<Output> {
+    let session = connect(port, key).await?;
+    let channel = session.channel_open_session().await?;
+    channel.exec(true, command).await?;
+    finish(session, channel, &[]).await
+}
+
+async fn terminal(port: u16, key: &Key, keys: &[&str]) -> anyhow::Result<Output> {
+    let session = connect(port, key).await?;
+    let channel = session.channel_open_session().await?;
+    channel
+        .request_pty(true, "xterm", 80, 24, 0, 0, &[])
+        .await?;
+    channel.request_shell(true).await?;
+    finish(session, channel, keys).await
+}
+
+// Collects what the server sends until it closes the channel, typing the next
+// of `keys` after each lot of output.
+async fn finish(
+    session: client::Handle<Client>,
+    mut channel: russh::Channel<client::Msg>,
+    keys: &[&str],
+) -> anyhow::Result<Output> {
+    let mut keys = keys.iter();
+    let mut output = Output {
+        status: None,
+        stdout: String::new(),
+        stderr: String::new(),
+    };
+    while let Some(message) = channel.wait().await {
+        match message {
+            ChannelMsg::Data { data } => {
+                output.stdout += &String::from_utf8_lossy(&data);
+                if let Some(key) = keys.next() {
+                    channel.data(key.as_bytes()).await?;
+                }
+            }
+            ChannelMsg::ExtendedData { data, ext: 1 } => {
+                output.stderr += &String::from_utf8_lossy(&data);
+            }
+            ChannelMsg::ExitStatus { exit_status } => output.status = Some(exit_status),
+            _ => {}
+        }
+    }
+
+    session
+        .disconnect(Disconnect::ByApplication, "", "English")
+        .await?;
+    Ok(output)
+}
+
+/// Polls `check` until it passes, panicking after a minute.
+pub fn eventually(what: &str, mut check: impl FnMut() -> bool) {
+    let started = Instant::now();
+
+    while started.elapsed() < Duration::from_secs(60) {
+        if check() {
+            return;
+        }
+
+        thread::sleep(Duration::from_millis(250));
+    }
+
+    panic!("timed out waiting for {what}");
+}
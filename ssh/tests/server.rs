@@ -0,0 +1,1247 @@
+//! Drives the SSH server with real `git clone` and `git push`, and runs its
+//! commands with a russh client, to check who can do what and what they're
+//! told when they can't.
+
+mod common;
+
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    path::Path,
+};
+
+use common::{eventually, Key, Output, Server};
+use gnit_ssh::ImportSource;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+// Pairs each test with its name.
+macro_rules! tests {
+    ($($test:ident),* $(,)?) => {
+        &[$((stringify!($test), $test as fn())),*]
+    };
+}
+
+fn main() {
+    common::run(tests![
+        new_repos_are_made_where_users_may,
+        repo_access_follows_repo_config,
+        repo_configs_follow_head,
+        groups_and_every_key_get_their_grants,
+        certificates_log_in_as_their_principal,
+        only_admins_reach_the_config_repo,
+        mirrors_are_private_unless_granted,
+        push_mirrors_stay_apart_and_off_the_network,
+        repo_webhooks_only_go_to_allowed_hosts,
+        webhook_secrets_stay_on_the_server,
+        push_policies_guard_protected_refs,
+        quotas_refuse_big_packs_and_keep_no_empty_repos,
+        lfs_uploads_are_checked_before_their_data,
+        initial_configs_in_the_scan_path_are_moved_in,
+        config_pushes_are_checked_then_loaded,
+        config_edits_keep_comments,
+        imports_keep_comments,
+        gitolite_imports_grant_no_more_than_gitolite,
+        terminals_browse_readable_repos,
+        commands_say_what_went_wrong,
+    ]);
+}
+
+struct Users {
+    alice: Key,
+    bob: Key,
+    carol: Key,
+    // a key the server doesn't know, which logs in as the guest user
+    mallory: Key,
+}
+
+impl Users {
+    fn generate(root: &Path) -> Self {
+        let keys = root.join("keys");
+        Self {
+            alice: Key::generate(&keys, "alice"),
+            bob: Key::generate(&keys, "bob"),
+            carol: Key::generate(&keys, "carol"),
+            mallory: Key::generate(&keys, "mallory"),
+        }
+    }
+
+    /// alice is an admin, bob can make repos of his own and carol can't.
+    fn config(&self) -> String {
+        format!(
+            "name = \"test\"\nhostname = \"localhost\"\nport = 2222\n\n\
+             [users.alice]\nis_admin = true\npublic_key = \"{}\"\n\n\
+             [users.bob]\ncan_create_repos = true\npublic_key = \"{}\"\n\n\
+             [users.carol]\npublic_key = \"{}\"\n",
+            self.alice.public, self.bob.public, self.carol.public
+        )
+    }
+}
+
+fn start() -> (tempfile::TempDir, Users, Server) {
+    let dir = tempfile::tempdir().unwrap();
+    let users = Users::generate(dir.path());
+    let server = Server::start(dir.path(), &users.config());
+    (dir, users, server)
+}
+
+/// Makes a work tree at `path` with a commit on `main`.
+fn work_tree(server: &Server, key: &Key, path: &Path) {
+    std::fs::create_dir_all(path).unwrap();
+    assert!(server
+        .git(key, path, &["init", "-q", "-b", "main"])
+        .success());
+    std::fs::write(path.join("README.md"), "hello\n").unwrap();
+    assert!(server.git(key, path, &["add", "."]).success());
+    assert!(server
+        .git(key, path, &["commit", "-q", "-m", "hello"])
+        .success());
+}
+
+fn push(server: &Server, key: &Key, work: &Path, repo: &str) -> Output {
+    server.git(key, work, &["push", &server.url(repo), "main"])
+}
+
+fn clone(server: &Server, key: &Key, root: &Path, repo: &str, to: &str) -> Output {
+    server.git(key, root, &["clone", &server.url(repo), to])
+}
+
+fn new_repos_are_made_where_users_may() {
+    let (dir, users, server) = start();
+    let work = dir.path().join("work");
+    work_tree(&server, &users.alice, &work);
+
+    // admins can make repos anywhere, and are made their admin
+    let output = push(&server, &users.alice, &work, "project.git");
+    assert!(output.success(), "{output:?}");
+    assert!(
+        output.says("[INFO] Creating a new repository..."),
+        "{output:?}"
+    );
+    assert!(
+        output.says("[INFO] Created a new repo config - please pull."),
+        "{output:?}"
+    );
+    assert!(server.scan.join("project.git").is_dir());
+
+    let output = clone(&server, &users.alice, dir.path(), "project.git", "project");
+    assert!(output.success(), "{output:?}");
+    let repo_config = std::fs::read_to_string(dir.path().join("project/repo.toml")).unwrap();
+    assert!(repo_config.contains("admin = [\"alice\"]"), "{repo_config}");
+
+    // others only under their own name, if they can at all
+    let output = push(&server, &users.bob, &work, "bob/tool.git");
+    assert!(output.success(), "{output:?}");
+    assert!(server.scan.join("bob/tool.git").is_dir());
+
+    let output = push(&server, &users.bob, &work, "tool.git");
+    assert!(!output.success(), "{output:?}");
+    assert!(
+        output
+            .says("[ERROR] You can only create a new repository under your personal subdirectory."),
+        "{output:?}"
+    );
+
+    let output = push(&server, &users.carol, &work, "carol/notes.git");
+    assert!(!output.success(), "{output:?}");
+    assert!(
+        output.says("[ERROR] That repository doesn't exist :("),
+        "{output:?}"
+    );
+    assert!(!server.scan.join("carol").exists());
+
+    // paths outside the scan path are never made
+    let output = push(&server, &users.alice, &work, "../escape.git");
+    assert!(!output.success(), "{output:?}");
+    assert!(!dir.path().join("escape.git").exists());
+}
+
+fn repo_access_follows_repo_config() {
+    let (dir, users, server) = start();
+    let work = dir.path().join("work");
+    work_tree(&server, &users.alice, &work);
+    assert!(push(&server, &users.alice, &work, "project.git").success());
+
+    // repos are private to their admins at first
+    for key in [&users.bob, &users.mallory] {
+        let output = clone(&server, key, dir.path(), "project.git", "denied");
+        assert!(!output.success(), "{output:?}");
+        assert!(
+            output.says("[ERROR] You don't have permission to access this repository."),
+            "{output:?}"
+        );
+    }
+
+    let output = server.exec(&users.alice, "repo members add project bob read");
+    assert!(output.success(), "{output:?}");
+
+    // readers can fetch but not push
+    let output = clone(&server, &users.bob, dir.path(), "project.git", "bob");
+    assert!(output.success(), "{output:?}");
+    let bob = dir.path().join("bob");
+    std::fs::write(bob.join("README.md"), "bob was here\n").unwrap();
+    let output = server.git(&users.bob, &bob, &["commit", "-q", "-am", "bob"]);
+    assert!(output.success(), "{output:?}");
+    let output = server.git(&users.bob, &bob, &["push", "origin", "main"]);
+    assert!(!output.success(), "{output:?}");
+    assert!(
+        output.says("[ERROR] You don't have permission to push to this repository."),
+        "{output:?}"
+    );
+
+    // the grant is a commit to repo.toml, which writers need before pushing
+    let output = server.exec(&users.alice, "repo members add project bob write");
+    assert!(output.success(), "{output:?}");
+    let output = server.git(&users.bob, &bob, &["pull", "-q", "--rebase"]);
+    assert!(output.success(), "{output:?}");
+    let output = server.git(&users.bob, &bob, &["push", "origin", "main"]);
+    assert!(output.success(), "{output:?}");
+    assert!(output.says(", 1 new commit"), "{output:?}");
+
+    // and public repos can be fetched by anyone
+    let output = clone(&server, &users.mallory, dir.path(), "project.git", "denied");
+    assert!(!output.success(), "{output:?}");
+    let output = server.exec(&users.alice, "repo set-public project true");
+    assert!(output.success(), "{output:?}");
+    let output = clone(
+        &server,
+        &users.mallory,
+        dir.path(),
+        "project.git",
+        "mallory",
+    );
+    assert!(output.success(), "{output:?}");
+    let readme = std::fs::read_to_string(dir.path().join("mallory/README.md")).unwrap();
+    assert_eq!(readme, "bob was here\n");
+}
+
+fn repo_configs_follow_head() {
+    let (dir, users, server) = start();
+    let work = dir.path().join("work");
+    work_tree(&server, &users.alice, &work);
+    assert!(push(&server, &users.alice, &work, "project.git").success());
+    let admin = dir.path().join("admin");
+    assert!(clone(&server, &users.alice, dir.path(), "project.git", "admin").success());
+    let repo_config = std::fs::read_to_string(admin.join("repo.toml")).unwrap();
+    assert!(repo_config.contains("public = false\n"), "{repo_config}");
+    std::fs::write(
+        admin.join("repo.toml"),
+        repo_config.replace("public = false\n", "public = true\n"),
+    )
+    .unwrap();
+    assert!(server
+        .git(&users.alice, &admin, &["commit", "-q", "-am", "public"])
+        .success());
+
+    // only the config at HEAD counts
+    let output = server.git(&users.alice, &admin, &["push", "origin", "main:draft"]);
+    assert!(output.success(), "{output:?}");
+    let output = clone(&server, &users.mallory, dir.path(), "project.git", "denied");
+    assert!(!output.success(), "{output:?}");
+
+    // and a push to HEAD is seen by the very next command
+    let output = server.git(&users.alice, &admin, &["push", "origin", "main"]);
+    assert!(output.success(), "{output:?}");
+    let output = clone(
+        &server,
+        &users.mallory,
+        dir.path(),
+        "project.git",
+        "mallory",
+    );
+    assert!(output.success(), "{output:?}");
+
+    // as is HEAD moving behind the server's back
+    let bare = dir.path().join("scan/project.git");
+    let status = std::process::Command::new("git")
+        .current_dir(&bare)
+        .args(["update-ref", "refs/heads/main", "main~1"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let output = clone(&server, &users.mallory, dir.path(), "project.git", "denied");
+    assert!(!output.success(), "{output:?}");
+    assert!(
+        output.says("[ERROR] You don't have permission to access this repository."),
+        "{output:?}"
+    );
+}
+
+fn groups_and_every_key_get_their_grants() {
+    let dir = tempfile::tempdir().unwrap();
+    let users = Users::generate(dir.path());
+    let laptop = Key::generate(&dir.path().join("keys"), "laptop");
+    let config = format!(
+        "anonymous = \"none\"\n{}\n[groups]\nreviewers = [\"carol\"]\n",
+        users.config().replace(
+            &format!("public_key = \"{}\"", users.bob.public),
+            &format!(
+                "public_keys = [\"{}\", \"{}\"]",
+                users.bob.public, laptop.public
+            ),
+        )
+    );
+    let server = Server::start(dir.path(), &config);
+
+    let work = dir.path().join("work");
+    work_tree(&server, &users.alice, &work);
+    assert!(push(&server, &users.alice, &work, "project.git").success());
+    let admin = dir.path().join("admin");
+    assert!(clone(&server, &users.alice, dir.path(), "project.git", "admin").success());
+    let repo_config = std::fs::read_to_string(admin.join("repo.toml")).unwrap();
+    let repo_config = repo_config.replace(
+        "[access]\n",
+        "[access]\nread = [\"@reviewers\"]\nwrite = [\"bob\"]\n",
+    );
+    std::fs::write(admin.join("repo.toml"), repo_config).unwrap();
+    assert!(server
+        .git(&users.alice, &admin, &["commit", "-q", "-am", "grants"])
+        .success());
+    assert!(server
+        .git(&users.alice, &admin, &["push", "-q", "origin", "main"])
+        .success());
+
+    // carol reads as a reviewer, but can't push
+    let output = clone(&server, &users.carol, dir.path(), "project.git", "carol");
+    assert!(output.success(), "{output:?}");
+    let carol = dir.path().join("carol");
+    std::fs::write(carol.join("README.md"), "carol was here\n").unwrap();
+    assert!(server
+        .git(&users.carol, &carol, &["commit", "-q", "-am", "carol"])
+        .success());
+    let output = server.git(&users.carol, &carol, &["push", "origin", "main"]);
+    assert!(!output.success(), "{output:?}");
+    assert!(
+        output.says("[ERROR] You don't have permission to push to this repository."),
+        "{output:?}"
+    );
+
+    // bob's second key is as good as his first
+    let output = clone(&server, &laptop, dir.path(), "project.git", "laptop");
+    assert!(output.success(), "{output:?}");
+    let bob = dir.path().join("laptop");
+    std::fs::write(bob.join("README.md"), "bob was here\n").unwrap();
+    assert!(server
+        .git(&laptop, &bob, &["commit", "-q", "-am", "bob"])
+        .success());
+    let output = server.git(&laptop, &bob, &["push", "origin", "main"]);
+    assert!(output.success(), "{output:?}");
+
+    // archives go by the same grants as fetches
+    let archive = |key: &Key| {
+        let url = server.url("project.git");
+        server.git(key, dir.path(), &["archive", "--remote", &url, "main"])
+    };
+    let output = archive(&users.carol);
+    assert!(output.success(), "{output:?}");
+    let output = archive(&users.mallory);
+    assert!(!output.success(), "{output:?}");
+
+    // and anonymous users can't read even public repos when the server says so
+    let output = server.exec(&users.alice, "repo set-public project true");
+    assert!(output.success(), "{output:?}");
+    let output = clone(&server, &users.mallory, dir.path(), "project.git", "denied");
+    assert!(!output.success(), "{output:?}");
+    let output = archive(&users.mallory);
+    assert!(!output.success(), "{output:?}");
+}
+
+fn certificates_log_in_as_their_principal() {
+    let dir = tempfile::tempdir().unwrap();
+    let users = Users::generate(dir.path());
+    let keys = dir.path().join("keys");
+    let ca = Key::generate(&keys, "ca");
+    let other_ca = Key::generate(&keys, "other-ca");
+    let config = format!(
+        "{}\n[certificates]\nauthorities = [\"{}\"]\nrevoked_keys = \"revoked_keys\"\n",
+        users.config(),
+        ca.public
+    );
+    let server = Server::start(dir.path(), &config);
+    let revoked_keys = dir.path().join("scan/revoked_keys");
+    std::fs::write(&revoked_keys, "").unwrap();
+
+    // makes a key with a certificate signed by `ca`, with ssh-keygen's `options`
+    let certify = |ca: &Key, name: &str, serial: &str, options: &[&str]| {
+        let key = Key::generate(&keys, name);
+        let status = std::process::Command::new("ssh-keygen")
+            .args(["-q", "-s"])
+            .arg(&ca.path)
+            .args(["-I", name, "-z", serial])
+            .args(options)
+            .arg(key.path.with_extension("pub"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        key
+    };
+    let whoami = |key: &Key| {
+        let output = server.ssh(key, "whoami");
+        assert!(output.success(), "{output:?}");
+        output.stdout.lines().next().unwrap_or_default().to_string()
+    };
+    let guest = "guest (your key isn't registered)";
+
+    // the first principal that's a user is who the certificate logs in as
+    let dave = certify(&ca, "dave", "1", &["-n", "carol"]);
+    assert_eq!(whoami(&dave), "carol");
+    let erin = certify(&ca, "erin", "2", &["-n", "nobody,bob"]);
+    assert_eq!(whoami(&erin), "bob");
+    let work = dir.path().join("work");
+    work_tree(&server, &erin, &work);
+    let output = push(&server, &erin, &work, "bob/certified.git");
+    assert!(output.success(), "{output:?}");
+
+    // anything else falls back to the key, which isn't registered
+    let nobody = certify(&ca, "nobody", "3", &["-n", "nobody"]);
+    assert_eq!(whoami(&nobody), guest);
+    let anyone = certify(&ca, "anyone", "4", &[]);
+    assert_eq!(whoami(&anyone), guest);
+    let stranger = certify(&other_ca, "stranger", "5", &["-n", "carol"]);
+    assert_eq!(whoami(&stranger), guest);
+    let expired = certify(
+        &ca,
+        "expired",
+        "6",
+        &["-n", "carol", "-V", "20200101:20200102"],
+    );
+    assert_eq!(whoami(&expired), guest);
+    let elsewhere = certify(
+        &ca,
+        "elsewhere",
+        "7",
+        &["-n", "carol", "-O", "source-address=10.0.0.0/8"],
+    );
+    assert_eq!(whoami(&elsewhere), guest);
+    let here = certify(
+        &ca,
+        "here",
+        "8",
+        &["-n", "carol", "-O", "source-address=10.0.0.0/8,127.0.0.1"],
+    );
+    assert_eq!(whoami(&here), "carol");
+    let forced = certify(
+        &ca,
+        "forced",
+        "9",
+        &["-n", "carol", "-O", "force-command=whoami"],
+    );
+    assert_eq!(whoami(&forced), guest);
+
+    // revocations apply at the next login, by serial and key ID in a KRL
+    let spec = dir.path().join("revoked");
+    std::fs::write(&spec, "serial: 1\nid: erin\n").unwrap();
+    let status = std::process::Command::new("ssh-keygen")
+        .args(["-q", "-k", "-f"])
+        .arg(&revoked_keys)
+        .arg("-s")
+        .arg(ca.path.with_extension("pub"))
+        .arg(&spec)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(whoami(&dave), guest);
+    assert_eq!(whoami(&erin), guest);
+    assert_eq!(whoami(&here), "carol");
+
+    // or by key in a plain list
+    let here_key = std::fs::read_to_string(here.path.with_extension("pub")).unwrap();
+    std::fs::write(&revoked_keys, here_key).unwrap();
+    assert_eq!(whoami(&dave), "carol");
+    assert_eq!(whoami(&here), guest);
+}
+
+fn only_admins_reach_the_config_repo() {
+    let (dir, users, server) = start();
+
+    for key in [&users.bob, &users.mallory] {
+        let output = clone(&server, key, dir.path(), ".gnostr/.git", "config");
+        assert!(!output.success(), "{output:?}");
+        assert!(
+            output.says("[ERROR] Only admins are allowed to access this repository."),
+            "{output:?}"
+        );
+    }
+
+    let output = clone(&server, &users.alice, dir.path(), ".gnostr/.git", "config");
+    assert!(output.success(), "{output:?}");
+    let config = std::fs::read_to_string(dir.path().join("config/server.toml")).unwrap();
+    assert_eq!(config, users.config());
+
+    // the initial config is copied in, not taken from wherever the operator keeps it
+    let initial = std::fs::read_to_string(dir.path().join("server.toml")).unwrap();
+    assert_eq!(initial, users.config());
+}
+
+fn mirrors_are_private_unless_granted() {
+    let (dir, users, server) = start();
+    let work = dir.path().join("work");
+    work_tree(&server, &users.alice, &work);
+    let upstream = dir.path().join("upstream.git");
+    let upstream = upstream.to_str().unwrap();
+    assert!(server
+        .git(
+            &users.alice,
+            dir.path(),
+            &["init", "-q", "--bare", upstream]
+        )
+        .success());
+    assert!(server
+        .git(&users.alice, &work, &["push", "-q", upstream, "main"])
+        .success());
+    assert!(server
+        .git(
+            &users.alice,
+            &server.scan,
+            &["clone", "-q", "--mirror", upstream, "vendor.git"]
+        )
+        .success());
+    let set = |args: &[&str]| {
+        let mirror = server.scan.join("vendor.git");
+        assert!(server.git(&users.alice, &mirror, args).success());
+    };
+
+    // the upstream may be private, so only admins can read a mirror at first
+    for key in [&users.bob, &users.mallory] {
+        let output = clone(&server, key, dir.path(), "vendor.git", "denied");
+        assert!(!output.success(), "{output:?}");
+        assert!(
+            output.says("[ERROR] You don't have permission to access this repository."),
+            "{output:?}"
+        );
+    }
+    let output = clone(&server, &users.alice, dir.path(), "vendor.git", "alice");
+    assert!(output.success(), "{output:?}");
+
+    // members can read it, but no one can push to it
+    set(&["config", "--add", "gnit.member", "bob"]);
+    let output = clone(&server, &users.bob, dir.path(), "vendor.git", "bob");
+    assert!(output.success(), "{output:?}");
+    let output = clone(&server, &users.mallory, dir.path(), "vendor.git", "denied");
+    assert!(!output.success(), "{output:?}");
+    let output = push(&server, &users.alice, &work, "vendor.git");
+    assert!(!output.success(), "{output:?}");
+
+    set(&["config", "gnit.public", "true"]);
+    let output = clone(&server, &users.mallory, dir.path(), "vendor.git", "mallory");
+    assert!(output.success(), "{output:?}");
+}
+
+fn push_mirrors_stay_apart_and_off_the_network() {
+    let dir = tempfile::tempdir().unwrap();
+    let users = Users::generate(dir.path());
+    let backups = dir.path().join("backups");
+    let config = format!(
+        "{}\n[push_mirrors]\nlocal_dirs = [\"{}\"]\nhosts = [\"git.example.org\"]\n",
+        users.config(),
+        backups.display()
+    );
+    let server = Server::start(dir.path(), &config);
+
+    let work = dir.path().join("work");
+    work_tree(&server, &users.bob, &work);
+    std::fs::write(
+        work.join("repo.toml"),
+        format!(
+            "[[push_mirrors]]\nurl = \"{}\"\n\n\
+             [[push_mirrors]]\nurl = \"http://127.0.0.1:9/internal.git\"\n\n\
+             [[push_mirrors]]\nurl = \"https://10.0.0.1/internal.git\"\n",
+            backups.display()
+        ),
+    )
+    .unwrap();
+    assert!(server.git(&users.bob, &work, &["add", "."]).success());
+    assert!(server
+        .git(&users.bob, &work, &["commit", "-q", "-m", "mirrors"])
+        .success());
+
+    // the first push is mirrored too, to the repo's own place in the directory
+    let output = push(&server, &users.bob, &work, "bob/notes.git");
+    assert!(output.success(), "{output:?}");
+    let mirror = backups.join("bob/notes.git");
+    eventually("the first push to be mirrored", || {
+        std::process::Command::new("git")
+            .args(["--git-dir", mirror.to_str().unwrap(), "rev-parse", "main"])
+            .output()
+            .is_ok_and(|output| output.status.success())
+    });
+
+    // but not over plain HTTP, or to hosts the server doesn't allow
+    let status = server.scan.join("bob/notes.git/push-mirrors.json");
+    eventually("the refused mirrors to be noted", || {
+        std::fs::read_to_string(&status).is_ok_and(|status| {
+            status.contains("HTTP mirrors have to use HTTPS")
+                && status.contains("push to 10.0.0.1, which isn't in its push_mirrors.hosts")
+        })
+    });
+}
+
+fn repo_webhooks_only_go_to_allowed_hosts() {
+    let dir = tempfile::tempdir().unwrap();
+    let users = Users::generate(dir.path());
+    let config = format!("webhook_hosts = [\"localhost\"]\n{}", users.config());
+    let server = Server::start(dir.path(), &config);
+
+    let work = dir.path().join("work");
+    work_tree(&server, &users.bob, &work);
+    assert!(push(&server, &users.bob, &work, "bob/hooks.git").success());
+    let pull = [
+        "pull",
+        "-q",
+        "--no-rebase",
+        &server.url("bob/hooks.git"),
+        "main",
+    ];
+    assert!(server.git(&users.bob, &work, &pull).success());
+
+    let mut repo_config = std::fs::read_to_string(work.join("repo.toml")).unwrap();
+    repo_config.push_str(
+        "\n[[webhooks]]\nurl = \"http://localhost:9/allowed\"\n\n\
+         [[webhooks]]\nurl = \"http://127.0.0.1:9/internal\"\n",
+    );
+    std::fs::write(work.join("repo.toml"), repo_config).unwrap();
+    assert!(server
+        .git(&users.bob, &work, &["commit", "-q", "-am", "webhooks"])
+        .success());
+    let output = push(&server, &users.bob, &work, "bob/hooks.git");
+    assert!(output.success(), "{output:?}");
+    assert!(
+        output.says("isn't in its webhook_hosts, so it won't be sent"),
+        "{output:?}"
+    );
+
+    std::fs::write(work.join("README.md"), "hooked\n").unwrap();
+    assert!(server
+        .git(&users.bob, &work, &["commit", "-q", "-am", "hooked"])
+        .success());
+    assert!(push(&server, &users.bob, &work, "bob/hooks.git").success());
+
+    let log = server.scan.join(".gnostr/webhooks.log");
+    eventually("the allowed webhook to be tried", || {
+        std::fs::read_to_string(&log).is_ok_and(|log| log.contains("localhost:9/allowed"))
+    });
+    let log = std::fs::read_to_string(&log).unwrap();
+    assert!(!log.contains("internal"), "{log}");
+}
+
+/// Answers one HTTP request on `listener` with a 200, returning its headers
+/// and body.
+fn receive_request(listener: &TcpListener) -> (String, Vec<u8>) {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+
+    let head = String::from_utf8(head).unwrap();
+    let length = head
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse().unwrap())
+        })
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).unwrap();
+    stream
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+        .unwrap();
+
+    (head, body)
+}
+
+fn webhook_secrets_stay_on_the_server() {
+    let dir = tempfile::tempdir().unwrap();
+    let users = Users::generate(dir.path());
+    let config = format!(
+        "webhook_hosts = [\"127.0.0.1\"]\n{}\n[webhook_secrets]\nci = \"s3cret\"\n",
+        users.config()
+    );
+    let server = Server::start(dir.path(), &config);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // a full delivery log, which the next delivery rotates out
+    let log = server.scan.join(".gnostr/webhooks.log");
+    std::fs::create_dir_all(log.parent().unwrap()).unwrap();
+    std::fs::File::create(&log)
+        .unwrap()
+        .set_len(16 * 1024 * 1024)
+        .unwrap();
+
+    let work = dir.path().join("work");
+    work_tree(&server, &users.bob, &work);
+    assert!(push(&server, &users.bob, &work, "bob/signed.git").success());
+    let pull = [
+        "pull",
+        "-q",
+        "--no-rebase",
+        &server.url("bob/signed.git"),
+        "main",
+    ];
+    assert!(server.git(&users.bob, &work, &pull).success());
+    let repo_config = std::fs::read_to_string(work.join("repo.toml")).unwrap();
+
+    // anyone who can read the repo could read a secret kept in it
+    let webhook = format!("\n[[webhooks]]\nurl = \"http://127.0.0.1:{port}/push\"\n");
+    std::fs::write(
+        work.join("repo.toml"),
+        format!("{repo_config}{webhook}secret = \"s3cret\"\n"),
+    )
+    .unwrap();
+    assert!(server
+        .git(&users.bob, &work, &["commit", "-q", "-am", "webhook"])
+        .success());
+    let output = push(&server, &users.bob, &work, "bob/signed.git");
+    assert!(!output.success(), "{output:?}");
+    assert!(
+        output.says("Anyone who can read the repo can read its webhook's secret"),
+        "{output:?}"
+    );
+
+    std::fs::write(
+        work.join("repo.toml"),
+        format!("{repo_config}{webhook}secret_name = \"ci\"\n"),
+    )
+    .unwrap();
+    assert!(server
+        .git(
+            &users.bob,
+            &work,
+            &["commit", "-q", "--amend", "-am", "webhook"]
+        )
+        .success());
+    assert!(push(&server, &users.bob, &work, "bob/signed.git").success());
+
+    std::fs::write(work.join("README.md"), "signed\n").unwrap();
+    assert!(server
+        .git(&users.bob, &work, &["commit", "-q", "-am", "signed"])
+        .success());
+    assert!(push(&server, &users.bob, &work, "bob/signed.git").success());
+
+    let (head, body) = receive_request(&listener);
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+    mac.update(&body);
+    let signature = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+    assert!(
+        head.to_ascii_lowercase()
+            .contains(&format!("x-gnit-signature-256: {signature}")),
+        "{head}"
+    );
+
+    eventually("the delivery to be logged", || {
+        std::fs::read_to_string(&log).is_ok_and(|log| log.contains("/push"))
+    });
+    let rotated = std::fs::metadata(server.scan.join(".gnostr/webhooks.log.1")).unwrap();
+    assert_eq!(rotated.len(), 16 * 1024 * 1024);
+}
+
+fn push_policies_guard_protected_refs() {
+    let (dir, users, server) = start();
+    let work = dir.path().join("work");
+    work_tree(&server, &users.bob, &work);
+    assert!(push(&server, &users.bob, &work, "bob/guarded.git").success());
+    let repo = dir.path().join("guarded");
+    assert!(clone(
+        &server,
+        &users.bob,
+        dir.path(),
+        "bob/guarded.git",
+        "guarded"
+    )
+    .success());
+    let repo_config = std::fs::read_to_string(repo.join("repo.toml")).unwrap();
+    let repo_config = repo_config.replace("[access]\n", "[access]\nwrite = [\"carol\"]\n")
+        + "\n[policy]\n\n\
+           [[policy.protect]]\nrefs = \"main\"\n\n\
+           [[policy.protect]]\nrefs = \"release/*\"\npushers = [\"bob\"]\n\n\
+           [[policy.protect]]\nrefs = \"signed/*\"\nallow_force_push = true\n\
+           allow_delete = true\nrequire_signed_commits = true\n";
+    std::fs::write(repo.join("repo.toml"), repo_config).unwrap();
+    let commit = |key: &Key, args: &[&str]| {
+        assert!(server
+            .git(
+                key,
+                &repo,
+                &[&["commit", "-q", "--allow-empty"], args].concat()
+            )
+            .success());
+    };
+    commit(&users.bob, &["-am", "policy"]);
+    let output = server.git(&users.bob, &repo, &["push", "origin", "main"]);
+    assert!(output.success(), "{output:?}");
+
+    // main can only move forward
+    assert!(server
+        .git(&users.bob, &repo, &["branch", "-q", "old", "HEAD"])
+        .success());
+    commit(&users.bob, &["-m", "ahead"]);
+    assert!(server
+        .git(&users.bob, &repo, &["push", "-q", "origin", "main"])
+        .success());
+    let output = server.git(&users.bob, &repo, &["push", "origin", "+old:main"]);
+    assert!(!output.success(), "{output:?}");
+    assert!(
+        output
+            .says("[ERROR] refs/heads/main is protected from force-pushes, pull and merge first."),
+        "{output:?}"
+    );
+
+    // release branches are bob's alone, and stay once pushed
+    let output = server.git(&users.bob, &repo, &["push", "origin", "main:release/1"]);
+    assert!(output.success(), "{output:?}");
+    let output = server.git(&users.bob, &repo, &["push", "origin", ":release/1"]);
+    assert!(!output.success(), "{output:?}");
+    assert!(
+        output.says("[ERROR] refs/heads/release/1 is protected from deletion."),
+        "{output:?}"
+    );
+    let output = server.git(&users.carol, &repo, &["push", "origin", "main:release/2"]);
+    assert!(!output.success(), "{output:?}");
+    assert!(
+        output.says("[ERROR] You aren't allowed to push to refs/heads/release/2."),
+        "{output:?}"
+    );
+    let output = server.git(&users.carol, &repo, &["push", "origin", "main:topic"]);
+    assert!(output.success(), "{output:?}");
+
+    // signed branches take commits signed by a user's SSH key, and nothing else
+    let sign_as = |key: &Key, message: &str| {
+        let signing_key = format!("user.signingkey={}", key.path.display());
+        let args = ["-c", "gpg.format=ssh", "-c", &signing_key, "commit", "-q"];
+        let args = [
+            &args[..],
+            &["--allow-empty", "--amend", "-S", "-m", message],
+        ]
+        .concat();
+        assert!(server.git(&users.bob, &repo, &args).success());
+    };
+    commit(&users.bob, &["-m", "unsigned"]);
+    let output = server.git(&users.bob, &repo, &["push", "origin", "HEAD:signed/1"]);
+    assert!(!output.success(), "{output:?}");
+    assert!(
+        output.says("isn't signed by an SSH key registered on this server."),
+        "{output:?}"
+    );
+
+    sign_as(&users.mallory, "stranger");
+    let output = server.git(&users.bob, &repo, &["push", "origin", "HEAD:signed/1"]);
+    assert!(!output.success(), "{output:?}");
+    assert!(
+        output.says("isn't signed by an SSH key registered on this server."),
+        "{output:?}"
+    );
+
+    sign_as(&users.bob, "signed");
+    let output = server.git(&users.bob, &repo, &["push", "origin", "HEAD:signed/1"]);
+    assert!(output.success(), "{output:?}");
+}
+
+fn quotas_refuse_big_packs_and_keep_no_empty_repos() {
+    let dir = tempfile::tempdir().unwrap();
+    let users = Users::generate(dir.path());
+    let config = format!(
+        "{}\n[quota]\nrepo_size = \"256KiB\"\nrepos = 1\n",
+        users.config()
+    );
+    let server = Server::start(dir.path(), &config);
+
+    // a pack bigger than the whole quota is turned away as it comes in
+    let big = dir.path().join("big");
+    work_tree(&server, &users.bob, &big);
+    let mut seed = 1u64;
+    let noise: Vec<u8> = (0..1024 * 1024)
+        .map(|_| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 56) as u8
+        })
+        .collect();
+    std::fs::write(big.join("noise.bin"), noise).unwrap();
+    assert!(server.git(&users.bob, &big, &["add", "."]).success());
+    assert!(server
+        .git(&users.bob, &big, &["commit", "-q", "-m", "noise"])
+        .success());
+    let output = push(&server, &users.bob, &big, "bob/big.git");
+    assert!(!output.success(), "{output:?}");
+
+    // and the repo it would have made doesn't take up a place in the quota
+    let abandoned = server.scan.join("bob/big.git");
+    eventually("the empty repo to be removed", || !abandoned.exists());
+    let small = dir.path().join("small");
+    work_tree(&server, &users.bob, &small);
+    let output = push(&server, &users.bob, &small, "bob/small.git");
+    assert!(output.success(), "{output:?}");
+
+    let output = push(&server, &users.bob, &small, "bob/other.git");
+    assert!(!output.success(), "{output:?}");
+    assert!(
+        output.says("Your quota allows 1 repositories, and you already have 1."),
+        "{output:?}"
+    );
+}
+
+fn lfs_uploads_are_checked_before_their_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let users = Users::generate(dir.path());
+    let config = format!("lfs_quota = \"1KiB\"\n{}", users.config());
+    let server = Server::start(dir.path(), &config);
+    let work = dir.path().join("work");
+    work_tree(&server, &users.bob, &work);
+    assert!(push(&server, &users.bob, &work, "bob/lfs.git").success());
+
+    // git-lfs-transfer speaks pkt-lines, with a delimiter before an object's data
+    let pkt = |data: &[u8]| [format!("{:04x}", data.len() + 4).as_bytes(), data].concat();
+    let put = |data: &[u8], size: usize| {
+        let oid = hex::encode(Sha256::digest(data));
+        let mut request = pkt(format!("put-object {oid}\n").as_bytes());
+        request.extend(pkt(format!("size={size}\n").as_bytes()));
+        request.extend(b"0001");
+        for chunk in data.chunks(500) {
+            request.extend(pkt(chunk));
+        }
+        request.extend(b"0000");
+        (oid, request)
+    };
+    let transfer = |operation: &str, requests: &[&[u8]]| {
+        let mut input = [pkt(b"version 1\n"), b"0000".to_vec()].concat();
+        for request in requests {
+            input.extend_from_slice(request);
+        }
+        input.extend([pkt(b"quit\n"), b"0000".to_vec()].concat());
+        let command = format!("git-lfs-transfer bob/lfs.git {operation}");
+        let output = server.ssh_input(&users.bob, &command, &input);
+        assert!(output.success(), "{output:?}");
+        output
+            .stdout
+            .match_indices("status ")
+            .map(|(i, _)| output.stdout[i + 7..i + 10].to_string())
+            .collect::<Vec<_>>()
+    };
+    let stored = |oid: &str| {
+        server
+            .scan
+            .join("bob/lfs.git/lfs/objects")
+            .join(&oid[0..2])
+            .join(&oid[2..4])
+            .join(oid)
+            .exists()
+    };
+
+    // objects past the quota, or sent with more data than they said, are turned down and
+    // the session carries on past their data
+    let (big, too_big) = put(&[b'a'; 2000], 2000);
+    let (long, too_long) = put(&[b'b'; 1000], 10);
+    let (small, fits) = put(&[b'c'; 100], 100);
+    assert_eq!(
+        transfer("upload", &[&too_big, &too_long, &fits]),
+        ["200", "507", "400", "200", "200"]
+    );
+    assert!(!stored(&big));
+    assert!(!stored(&long));
+    assert!(stored(&small));
+    let tmp = server.scan.join("bob/lfs.git/lfs/tmp");
+    assert_eq!(std::fs::read_dir(tmp).unwrap().count(), 0);
+
+    // and downloads can't upload
+    let (other, other_put) = put(&[b'd'; 100], 100);
+    assert_eq!(transfer("download", &[&other_put]), ["200", "403", "200"]);
+    assert!(!stored(&other));
+}
+
+fn initial_configs_in_the_scan_path_are_moved_in() {
+    let dir = tempfile::tempdir().unwrap();
+    let users = Users::generate(dir.path());
+    let scan = dir.path().join("scan");
+    std::fs::create_dir_all(&scan).unwrap();
+    let initial_config = scan.join("server.toml");
+    std::fs::write(&initial_config, users.config()).unwrap();
+    let server = Server::start_from(dir.path(), &initial_config);
+
+    // from then on only the config repo counts
+    assert!(!initial_config.exists());
+    let output = clone(&server, &users.alice, dir.path(), ".gnostr/.git", "config");
+    assert!(output.success(), "{output:?}");
+    let config = std::fs::read_to_string(dir.path().join("config/server.toml")).unwrap();
+    assert_eq!(config, users.config());
+    drop(server);
+
+    // while one kept elsewhere is left for the operator
+    let dir = tempfile::tempdir().unwrap();
+    let _server = Server::start(dir.path(), &users.config());
+    assert!(dir.path().join("server.toml").exists());
+}
+
+fn config_pushes_are_checked_then_loaded() {
+    let (dir, users, server) = start();
+    let output = clone(&server, &users.alice, dir.path(), ".gnostr/.git", "config");
+    assert!(output.success(), "{output:?}");
+    let config = dir.path().join("config");
+    let commit_and_push = |text: &str| {
+        std::fs::write(config.join("server.toml"), text).unwrap();
+        assert!(server
+            .git(&users.alice, &config, &["commit", "-q", "-am", "edit"])
+            .success());
+        server.git(&users.alice, &config, &["push", "origin", "HEAD"])
+    };
+
+    // a key that doesn't parse would lock bob out
+    let broken = users
+        .config()
+        .replace(&users.bob.public, "ssh-ed25519 AAAAnotakey bob");
+    let output = commit_and_push(&broken);
+    assert!(!output.success(), "{output:?}");
+    assert!(
+        output.says(
+            "[ERROR] server.toml:11:1: error: public_key of bob isn't a public key this server \
+             supports"
+        ),
+        "{output:?}"
+    );
+    let output = server.exec(&users.bob, "whoami");
+    assert!(output.stdout.starts_with("bob\n"), "{output:?}");
+    assert!(server
+        .git(
+            &users.alice,
+            &config,
+            &["reset", "-q", "--hard", "origin/HEAD"]
+        )
+        .success());
+
+    // a good config is used as soon as it's pushed
+    let promoted = users
+        .config()
+        .replace("[users.bob]\n", "[users.bob]\nis_admin = true\n")
+        .replace("port = 2222\n", "port = 2222\nwelcome_message = \"hi %\"\n");
+    let output = commit_and_push(&promoted);
+    assert!(output.success(), "{output:?}");
+    assert!(
+        output.says("[INFO] Reloading server config..."),
+        "{output:?}"
+    );
+
+    let output = server.exec(&users.bob, "whoami");
+    assert!(output.stdout.starts_with("bob (admin)\n"), "{output:?}");
+    let output = clone(&server, &users.bob, dir.path(), ".gnostr/.git", "bob");
+    assert!(output.success(), "{output:?}");
+    assert!(output.says("[INFO] hi bob"), "{output:?}");
+}
+
+fn config_edits_keep_comments() {
+    let (dir, users, server) = start();
+
+    let config = dir.path().join("config");
+    assert!(clone(&server, &users.alice, dir.path(), ".gnostr/.git", "config").success());
+    let commented = users
+        .config()
+        .replace("port = 2222\n", "port = 2222 # the usual\n")
+        .replace("[users.carol]\n", "# carol's on leave\n[users.carol]\n");
+    std::fs::write(config.join("server.toml"), commented).unwrap();
+    assert!(server
+        .git(&users.alice, &config, &["commit", "-q", "-am", "notes"])
+        .success());
+    assert!(server
+        .git(&users.alice, &config, &["push", "origin", "HEAD"])
+        .success());
+
+    let command = format!("user add-key carol {}", users.mallory.public);
+    let output = server.exec(&users.alice, &command);
+    assert!(output.success(), "{output:?}");
+    assert!(server
+        .git(&users.alice, &config, &["pull", "-q", "origin", "HEAD"])
+        .success());
+    let text = std::fs::read_to_string(config.join("server.toml")).unwrap();
+    assert!(text.contains("port = 2222 # the usual\n"), "{text}");
+    assert!(
+        text.contains("# carol's on leave\n[users.carol]\n"),
+        "{text}"
+    );
+    assert!(text.contains(&users.mallory.public), "{text}");
+
+    let work = dir.path().join("work");
+    work_tree(&server, &users.alice, &work);
+    assert!(push(&server, &users.alice, &work, "project.git").success());
+    let project = dir.path().join("project");
+    assert!(clone(&server, &users.alice, dir.path(), "project.git", "project").success());
+    let repo_config = std::fs::read_to_string(project.join("repo.toml")).unwrap();
+    std::fs::write(
+        project.join("repo.toml"),
+        format!("# what this is for\n{repo_config}"),
+    )
+    .unwrap();
+    assert!(server
+        .git(&users.alice, &project, &["commit", "-q", "-am", "notes"])
+        .success());
+    assert!(server
+        .git(&users.alice, &project, &["push", "-q", "origin", "HEAD"])
+        .success());
+
+    let output = server.exec(&users.alice, "repo members add project bob read");
+    assert!(output.success(), "{output:?}");
+    assert!(server
+        .git(&users.alice, &project, &["pull", "-q", "origin", "HEAD"])
+        .success());
+    let text = std::fs::read_to_string(project.join("repo.toml")).unwrap();
+    assert!(text.starts_with("# what this is for\n"), "{text}");
+    assert!(text.contains("bob"), "{text}");
+}
+
+fn imports_keep_comments() {
+    let (dir, users, server) = start();
+
+    let config = dir.path().join("config");
+    assert!(clone(&server, &users.alice, dir.path(), ".gnostr/.git", "config").success());
+    let commented = users
+        .config()
+        .replace("[users.carol]\n", "# carol's on leave\n[users.carol]\n");
+    std::fs::write(config.join("server.toml"), commented).unwrap();
+    assert!(server
+        .git(&users.alice, &config, &["commit", "-q", "-am", "notes"])
+        .success());
+    assert!(server
+        .git(&users.alice, &config, &["push", "origin", "HEAD"])
+        .success());
+
+    let dave = Key::generate(&dir.path().join("keys"), "dave");
+    let authorized_keys = dir.path().join("authorized_keys");
+    std::fs::write(&authorized_keys, format!("{}\n", dave.public)).unwrap();
+    server
+        .import(&ImportSource::AuthorizedKeys(authorized_keys))
+        .unwrap();
+
+    assert!(server
+        .git(&users.alice, &config, &["pull", "-q", "origin", "HEAD"])
+        .success());
+    let text = std::fs::read_to_string(config.join("server.toml")).unwrap();
+    assert!(
+        text.contains("# carol's on leave\n[users.carol]\n"),
+        "{text}"
+    );
+    assert!(text.contains(&dave.public), "{text}");
+}
+
+fn gitolite_imports_grant_no_more_than_gitolite() {
+    let (dir, users, server) = start();
+    let work = dir.path().join("work");
+    work_tree(&server, &users.alice, &work);
+    for repo in ["project.git", "shared.git"] {
+        assert!(push(&server, &users.alice, &work, repo).success());
+    }
+
+    let gitolite = dir.path().join("gitolite-admin");
+    std::fs::create_dir_all(gitolite.join("conf")).unwrap();
+    std::fs::create_dir_all(gitolite.join("keydir")).unwrap();
+    std::fs::write(
+        gitolite.join("conf/gitolite.conf"),
+        "@devs = bob carol\n\n\
+         repo project\n    RW+ = alice\n    RW main = bob\n    R = carol\n\n\
+         repo shared\n    - = carol\n    RW = @devs\n",
+    )
+    .unwrap();
+    server.import(&ImportSource::Gitolite(gitolite)).unwrap();
+
+    let repo_config = |repo: &str| {
+        let to = dir.path().join(repo);
+        assert!(clone(&server, &users.alice, dir.path(), repo, repo).success());
+        let text = std::fs::read_to_string(to.join("repo.toml")).unwrap();
+        text.parse::<toml::Table>().unwrap()
+    };
+
+    // bob could only push to main, which is protected for him and the repo's writers, and he
+    // can't push to the rest of the repo
+    let project = repo_config("project.git");
+    assert_eq!(project["access"]["write"], toml::Value::from(vec!["alice"]));
+    assert_eq!(
+        project["access"]["read"],
+        toml::Value::from(vec!["bob", "carol"])
+    );
+    let protect = project["policy"]["protect"].as_array().unwrap();
+    assert_eq!(protect.len(), 1, "{protect:?}");
+    assert_eq!(protect[0]["refs"].as_str(), Some("refs/heads/main*"));
+    assert_eq!(
+        protect[0]["pushers"],
+        toml::Value::from(vec!["alice", "bob"])
+    );
+    assert_eq!(protect[0]["allow_force_push"].as_bool(), Some(false));
+    let output = push(&server, &users.bob, &work, "project.git");
+    assert!(!output.success(), "{output:?}");
+
+    // carol is denied, so the group she's in can only read, and as the group couldn't
+    // force-push, nobody can
+    let shared = repo_config("shared.git");
+    assert!(shared["access"].get("write").is_none(), "{shared:?}");
+    assert_eq!(shared["access"]["read"], toml::Value::from(vec!["@devs"]));
+    let protect = shared["policy"]["protect"].as_array().unwrap();
+    assert_eq!(protect[0]["refs"].as_str(), Some("refs/*"));
+    assert_eq!(protect[0]["allow_force_push"].as_bool(), Some(false));
+    assert!(protect[0].get("pushers").is_none(), "{protect:?}");
+    let output = push(&server, &users.bob, &work, "shared.git");
+    assert!(!output.success(), "{output:?}");
+}
+
+fn terminals_browse_readable_repos() {
+    let (dir, users, server) = start();
+    let work = dir.path().join("work");
+    work_tree(&server, &users.alice, &work);
+    assert!(push(&server, &users.alice, &work, "project.git").success());
+    assert!(push(&server, &users.bob, &work, "bob/tool.git").success());
+
+    // the browser opens on the repos bob can read, and enter opens their log
+    let output = server.terminal(&users.bob, &["\r", "q", "q"]);
+    assert_eq!(output.status, Some(0), "{output:?}");
+    assert!(output.stdout.contains("bob/tool.git"), "{output:?}");
+    assert!(!output.stdout.contains("project.git"), "{output:?}");
+    assert!(output.stdout.contains("hello"), "{output:?}");
+    // and leaving it gives the terminal its screen back
+    assert!(
+        output.stdout.ends_with("\x1b[?25h\x1b[?1049l"),
+        "{output:?}"
+    );
+
+    let output = server.terminal(&users.alice, &["\x03"]);
+    assert_eq!(output.status, Some(0), "{output:?}");
+    assert!(output.stdout.contains("project.git"), "{output:?}");
+    assert!(output.stdout.contains("bob/tool.git"), "{output:?}");
+}
+
+fn commands_say_what_went_wrong() {
+    let (_dir, users, server) = start();
+
+    let output = server.exec(&users.alice, "whoami");
+    assert!(output.success(), "{output:?}");
+    assert!(output.stdout.starts_with("alice (admin)\n"), "{output:?}");
+
+    let output = server.exec(&users.mallory, "whoami --json");
+    assert!(output.success(), "{output:?}");
+    let whoami: serde_json::Value = serde_json::from_str(&output.stdout).unwrap();
+    assert_eq!(whoami["username"], "guest");
+    assert_eq!(whoami["guest"], true);
+
+    let output = server.exec(&users.alice, "frobnicate");
+    assert_eq!(output.status, Some(1), "{output:?}");
+    assert!(
+        output.says("[ERROR] Unknown command, run `help` to see what's available."),
+        "{output:?}"
+    );
+
+    let output = server.exec(&users.carol, "repo create shared");
+    assert_eq!(output.status, Some(1), "{output:?}");
+    assert!(
+        output.says("[ERROR] You're not allowed to create repositories."),
+        "{output:?}"
+    );
+    assert!(!server.scan.join("shared.git").exists());
+
+    // git commands for repos outside the scan path are hung up on
+    let output = server.exec(&users.alice, "git-upload-pack '../../etc'");
+    assert_eq!(output.status, None, "{output:?}");
+    assert!(output.stdout.is_empty(), "{output:?}");
+
+    let output = server.exec(&users.alice, "git-upload-pack 'missing.git'");
+    assert!(
+        output.says("[ERROR] That repository doesn't exist :("),
+        "{output:?}"
+    );
+}
@@ -0,0 +1,130 @@
+//! Clones over HTTP and checks the fetch is recorded in the audit log, which
+//! only admins can browse.
+
+mod common;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::{eventually, git, init_work_tree, Server};
+
+#[test]
+fn fetches_are_audited() {
+    let dir = tempfile::tempdir().unwrap();
+    let scan = dir.path().join("scan");
+    let bare = scan.join("audited.git");
+    let work = dir.path().join("work");
+    std::fs::create_dir_all(&bare).unwrap();
+
+    git(&bare, &["init", "--bare", "-b", "master"]);
+    init_work_tree(&work, 1);
+    git(&work, &["push", bare.to_str().unwrap(), "master"]);
+    git(&bare, &["pack-refs", "--all"]);
+
+    let server = Server::start(dir.path(), &scan, &["--admin-token", "hunter2"]);
+
+    eventually("the repository to be indexed", || {
+        server
+            .get("/audited.git")
+            .is_some_and(|(status, _)| status == 200)
+    });
+
+    git(dir.path(), &["clone", &server.url("audited.git"), "cloned"]);
+
+    let log = std::fs::read_to_string(scan.join(".gnostr/audit.log")).unwrap();
+    let entry: serde_json::Value = serde_json::from_str(log.lines().last().unwrap()).unwrap();
+    assert_eq!(entry["transport"], "http");
+    assert_eq!(entry["command"], "git-upload-pack");
+    assert_eq!(entry["repo"], "audited.git");
+    assert_eq!(entry["status"], 200);
+    assert!(entry["address"].as_str().unwrap().starts_with("127.0.0.1:"));
+
+    let (status, _) = server.get("/admin/audit").unwrap();
+    assert_eq!(status, 401);
+
+    let wrong = format!("Basic {}", STANDARD.encode("admin:hunter3"));
+    let (status, _) = server
+        .get_with("/admin/audit", &[("Authorization", &wrong)])
+        .unwrap();
+    assert_eq!(status, 401);
+
+    let right = format!("Basic {}", STANDARD.encode("admin:hunter2"));
+    let (status, body) = server
+        .get_with(
+            "/admin/audit?command=git-upload-pack",
+            &[("Authorization", &right)],
+        )
+        .unwrap();
+    assert_eq!(status, 200);
+    assert!(body.contains("audited.git"), "{body}");
+
+    let (_, body) = server
+        .get_with("/admin/audit?user=nobody", &[("Authorization", &right)])
+        .unwrap();
+    assert!(!body.contains("audited.git"), "{body}");
+}
+
+#[test]
+fn audit_log_is_paged_and_rotated() {
+    let dir = tempfile::tempdir().unwrap();
+    let scan = dir.path().join("scan");
+    let bare = scan.join("audited.git");
+    let work = dir.path().join("work");
+    std::fs::create_dir_all(&bare).unwrap();
+
+    git(&bare, &["init", "--bare", "-b", "master"]);
+    init_work_tree(&work, 1);
+    git(&work, &["push", bare.to_str().unwrap(), "master"]);
+    git(&bare, &["pack-refs", "--all"]);
+
+    // a full log, which the next entry rotates out, and an older one before it
+    let logs = scan.join(".gnostr");
+    std::fs::create_dir_all(&logs).unwrap();
+    let old: String = (0..150)
+        .map(|i| {
+            format!(
+                "{{\"timestamp\":{i},\"transport\":\"ssh\",\"user\":\"alice\",\"key_fingerprint\":null,\"address\":null,\"command\":\"git-upload-pack\",\"repo\":\"old.git\",\"status\":0}}\n"
+            )
+        })
+        .collect();
+    std::fs::write(logs.join("audit.log.1"), old).unwrap();
+    std::fs::File::create(logs.join("audit.log"))
+        .unwrap()
+        .set_len(16 * 1024 * 1024)
+        .unwrap();
+
+    let server = Server::start(dir.path(), &scan, &["--admin-token", "hunter2"]);
+
+    eventually("the repository to be indexed", || {
+        server
+            .get("/audited.git")
+            .is_some_and(|(status, _)| status == 200)
+    });
+
+    git(dir.path(), &["clone", &server.url("audited.git"), "cloned"]);
+
+    let log = std::fs::read_to_string(logs.join("audit.log.2")).unwrap();
+    assert_eq!(log.matches("\"repo\":\"old.git\"").count(), 150);
+    assert_eq!(
+        std::fs::metadata(logs.join("audit.log.1")).unwrap().len(),
+        16 * 1024 * 1024
+    );
+    let log = std::fs::read_to_string(logs.join("audit.log")).unwrap();
+    assert!(log.contains("\"repo\":\"audited.git\""), "{log}");
+
+    let auth = format!("Basic {}", STANDARD.encode("admin:hunter2"));
+    let (status, first) = server
+        .get_with("/admin/audit?page=0", &[("Authorization", &auth)])
+        .unwrap();
+    assert_eq!(status, 200);
+    assert!(first.contains("audited.git"), "{first}");
+    assert!(first.contains("?page=1"), "{first}");
+
+    let (status, second) = server
+        .get_with("/admin/audit?page=1", &[("Authorization", &auth)])
+        .unwrap();
+    assert_eq!(status, 200);
+    assert!(!second.contains("audited.git"), "{second}");
+    assert!(!second.contains("?page=2"), "{second}");
+
+    let listed = |body: &str| body.matches("href=\"/old.git\"").count();
+    assert_eq!(listed(&first) + listed(&second), 150);
+}
@@ -0,0 +1,83 @@
+//! Revalidates repository pages with their `ETag`s, and checks which of them
+//! may be cached for good, and that they're compressed when asked.
+
+mod common;
+
+use common::{eventually, git, init_work_tree, Server};
+
+/// The value of the response header `name`, which is matched in any case.
+fn header<'a>(headers: &'a str, name: &str) -> Option<&'a str> {
+    headers.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+#[test]
+fn pages_are_revalidated_and_compressed() {
+    let dir = tempfile::tempdir().unwrap();
+    let scan = dir.path().join("scan");
+    let bare = scan.join("cached.git");
+    let work = dir.path().join("work");
+    std::fs::create_dir_all(&bare).unwrap();
+
+    git(&bare, &["init", "--bare", "-b", "master"]);
+    init_work_tree(&work, 2);
+    git(&work, &["push", bare.to_str().unwrap(), "master"]);
+    git(&bare, &["pack-refs", "--all"]);
+    let head = git(&work, &["rev-parse", "HEAD"]).trim().to_string();
+
+    let server = Server::start(dir.path(), &scan, &[]);
+
+    eventually("the repository to be indexed", || {
+        server
+            .get("/cached.git")
+            .is_some_and(|(status, _)| status == 200)
+    });
+
+    // a page pinned to a full id can't change
+    let pinned = format!("/cached.git/commit?id={head}");
+    let (status, headers, _) = server.exchange("GET", &pinned, &[], "").unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(
+        header(&headers, "cache-control"),
+        Some("public, max-age=31536000, immutable")
+    );
+    let etag = header(&headers, "etag").unwrap().to_string();
+
+    let (status, headers, body) = server
+        .exchange("GET", &pinned, &[("If-None-Match", &etag)], "")
+        .unwrap();
+    assert_eq!(status, 304);
+    assert_eq!(header(&headers, "etag"), Some(etag.as_str()));
+    assert!(body.is_empty(), "{body}");
+
+    let (status, _, _) = server
+        .exchange("GET", &pinned, &[("If-None-Match", "\"stale\"")], "")
+        .unwrap();
+    assert_eq!(status, 200);
+
+    // but one following the branch can
+    let (status, headers, _) = server
+        .exchange("GET", "/cached.git/commit", &[], "")
+        .unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(
+        header(&headers, "cache-control"),
+        Some("public, max-age=60")
+    );
+    assert_ne!(header(&headers, "etag"), Some(etag.as_str()));
+
+    let (status, headers, _) = server
+        .exchange("GET", &pinned, &[("Accept-Encoding", "gzip")], "")
+        .unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(header(&headers, "content-encoding"), Some("gzip"));
+    assert!(
+        header(&headers, "vary").is_some_and(|v| v.contains("accept-encoding")),
+        "{headers}"
+    );
+
+    let (_, headers, _) = server.exchange("GET", &pinned, &[], "").unwrap();
+    assert_eq!(header(&headers, "content-encoding"), None);
+}
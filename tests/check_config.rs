@@ -0,0 +1,106 @@
+//! Checks config files offline, as an admin would before pushing them, and
+//! expects mistakes to be reported with where they are.
+
+use std::{
+    path::Path,
+    process::{Command, Output},
+};
+
+const ALICE_KEY: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG4rgfjG92YMXv5i+m4BGTboWZ62ZLp2yVpY9P6a+/hi alice";
+
+fn check_config(scan: &Path, files: &[&Path]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_gnostr-gnit"))
+        .arg("-d")
+        .arg(scan.join("db"))
+        .arg("-s")
+        .arg(scan)
+        .arg("check-config")
+        .args(files)
+        .output()
+        .expect("failed to run check-config")
+}
+
+#[test]
+fn reports_mistakes_with_their_location() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = dir.path().join("server.toml");
+    let repo = dir.path().join("repo.toml");
+
+    std::fs::write(
+        &server,
+        format!(
+            "name = \"test\"\nhostname = \"localhost\"\nport = 2222\n\n\
+             [users.alice]\nis_admin = true\npublic_key = \"{ALICE_KEY}\"\n"
+        ),
+    )
+    .unwrap();
+    std::fs::write(
+        &repo,
+        "name = \"test\"\npublic = true\nmembers = [\"alice\"]\n",
+    )
+    .unwrap();
+
+    let output = check_config(dir.path(), &[&server, &repo]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{stdout}");
+    assert!(
+        stdout.contains("Checked 2 files: 0 errors, 0 warnings"),
+        "{stdout}"
+    );
+
+    // a key that doesn't parse would lock alice out, a misspelt field is
+    // silently ignored
+    std::fs::write(
+        &server,
+        "name = \"test\"\nhostname = \"localhost\"\nport = 2222\nwelcome = \"hi\"\n\n\
+         [users.alice]\nis_admin = true\npublic_key = \"ssh-ed25519 AAAAnotakey\"\n",
+    )
+    .unwrap();
+    std::fs::write(
+        &repo,
+        "name = \"test\"\npublic = true\nmembers = [\"alice\"]\n\n\
+         [policy]\nmax_file_size = \"3 furlongs\"\n",
+    )
+    .unwrap();
+
+    let output = check_config(dir.path(), &[&server, &repo]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success(), "{stdout}");
+    let server = server.display();
+    let repo = repo.display();
+    assert!(
+        stdout.contains(&format!(
+            "{server}:4:1: warning: Unknown field welcome, which is ignored"
+        )),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains(&format!("{server}:8:1: error: public_key of alice")),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains(&format!("{server}: error: No admin has a valid SSH key")),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains(&format!(
+            "{repo}:6:1: error: Unknown unit in size \"3 furlongs\""
+        )),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("Checked 2 files: 3 errors, 1 warning"),
+        "{stdout}"
+    );
+
+    // syntax errors point at the character
+    std::fs::write(dir.path().join("server.toml"), "name = \"test\n").unwrap();
+    let output = check_config(dir.path(), &[&dir.path().join("server.toml")]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!output.status.success(), "{stdout}");
+    assert!(
+        stdout.contains(&format!("{server}:1:13: error:")),
+        "{stdout}"
+    );
+}
@@ -0,0 +1,250 @@
+//! Helpers shared by the integration tests, which drive a real server binary
+//! with the stock `git` client.
+
+#![allow(dead_code)] // not every test uses every helper
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use gnit_ssh::NostrEvent;
+use k256::schnorr::SigningKey;
+use sha2::{Digest, Sha256};
+
+/// Runs `git` in `dir` without picking up any user or system configuration.
+pub fn git(dir: &Path, args: &[&str]) -> String {
+    let output = git_command(dir, args).output().expect("failed to run git");
+
+    assert!(
+        output.status.success(),
+        "git {args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Runs `git` like [`git`], expecting it to fail, and returns what it said.
+pub fn git_fails(dir: &Path, args: &[&str]) -> String {
+    let output = git_command(dir, args).output().expect("failed to run git");
+
+    assert!(!output.status.success(), "git {args:?} succeeded");
+
+    String::from_utf8(output.stderr).unwrap()
+}
+
+fn git_command(dir: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new("git");
+    command
+        .current_dir(dir)
+        .args(["-c", "protocol.version=2"])
+        .args(args)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .env("GIT_AUTHOR_NAME", "test")
+        .env("GIT_AUTHOR_EMAIL", "test@example.com")
+        .env("GIT_COMMITTER_NAME", "test")
+        .env("GIT_COMMITTER_EMAIL", "test@example.com")
+        .env("GIT_TERMINAL_PROMPT", "0");
+    command
+}
+
+/// Creates a non-bare repository at `path` with `commits` commits on `master`.
+pub fn init_work_tree(path: &Path, commits: usize) {
+    std::fs::create_dir_all(path.join("src/nested")).unwrap();
+    git(path, &["init", "-b", "master"]);
+
+    for i in 0..commits {
+        std::fs::write(path.join("README.md"), format!("revision {i}\n")).unwrap();
+        std::fs::write(
+            path.join("src/nested/data.txt"),
+            format!("{i}\n").repeat(1000 * (i + 1)),
+        )
+        .unwrap();
+        git(path, &["add", "."]);
+        git(path, &["commit", "-m", &format!("commit {i}")]);
+    }
+}
+
+/// Polls `check` until it passes, panicking after a minute.
+pub fn eventually(what: &str, mut check: impl FnMut() -> bool) {
+    let started = Instant::now();
+
+    while started.elapsed() < Duration::from_secs(60) {
+        if check() {
+            return;
+        }
+
+        thread::sleep(Duration::from_millis(250));
+    }
+
+    panic!("timed out waiting for {what}");
+}
+
+/// A port nothing is listening on yet.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// A Nostr key, made from a fixed secret so the tests are repeatable.
+pub struct Key(SigningKey);
+
+impl Key {
+    pub fn new(secret: u8) -> Self {
+        Self(SigningKey::from_bytes(&[secret; 32]).unwrap())
+    }
+
+    pub fn pubkey(&self) -> String {
+        hex::encode(self.0.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, kind: u64, tags: &[[&str; 2]]) -> String {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let tags: Vec<Vec<String>> = tags
+            .iter()
+            .map(|tag| tag.iter().map(ToString::to_string).collect())
+            .collect();
+
+        let serialized =
+            serde_json::json!([0, self.pubkey(), created_at, kind, tags, ""]).to_string();
+        let id = Sha256::digest(serialized.as_bytes());
+        let sig = self.0.sign_raw(&id, &[0; 32]).unwrap();
+
+        serde_json::to_string(&NostrEvent {
+            id: hex::encode(id),
+            pubkey: self.pubkey(),
+            created_at,
+            kind,
+            tags,
+            content: String::new(),
+            sig: hex::encode(sig.to_bytes()),
+        })
+        .unwrap()
+    }
+
+    /// A NIP-98 `Authorization` header for a `GET` of `path`.
+    pub fn http_auth(&self, path: &str) -> String {
+        let url = format!("http://localhost{path}");
+        let event = self.sign(gnit_ssh::HTTP_AUTH_KIND, &[["u", &url], ["method", "GET"]]);
+        format!("Nostr {}", STANDARD.encode(event))
+    }
+}
+
+/// A running `gnostr-gnit` web server, killed on drop.
+pub struct Server {
+    child: Child,
+    pub port: u16,
+}
+
+impl Server {
+    /// Starts the server against `scan`, storing its database under `root`.
+    pub fn start(root: &Path, scan: &Path, extra_args: &[&str]) -> Self {
+        let port = free_port();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_gnostr-gnit"))
+            .arg("-d")
+            .arg(root.join("db"))
+            .arg("-s")
+            .arg(scan)
+            .arg("-b")
+            .arg(format!("127.0.0.1:{port}"))
+            .args(extra_args)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start server");
+
+        let mut server = Self { child, port };
+
+        eventually("the server to start", || {
+            if let Some(status) = server.child.try_wait().unwrap() {
+                panic!("server exited before becoming ready: {status}");
+            }
+
+            server.get("/").is_some_and(|(status, _)| status == 200)
+        });
+
+        server
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}/{path}", self.port)
+    }
+
+    /// Makes a plain HTTP/1.1 `GET` request, returning the status and body.
+    pub fn get(&self, path: &str) -> Option<(u16, String)> {
+        self.request("GET", path, &[], "")
+    }
+
+    /// Makes a `GET` request with extra headers.
+    pub fn get_with(&self, path: &str, headers: &[(&str, &str)]) -> Option<(u16, String)> {
+        self.request("GET", path, headers, "")
+    }
+
+    /// Makes a plain HTTP/1.1 `POST` request, returning the status and body.
+    pub fn post(&self, path: &str, body: &str) -> Option<(u16, String)> {
+        self.request("POST", path, &[], body)
+    }
+
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> Option<(u16, String)> {
+        let (status, _, body) = self.exchange(method, path, headers, body)?;
+        Some((status, body))
+    }
+
+    /// Makes a request, returning the status, response headers and body.
+    pub fn exchange(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> Option<(u16, String, String)> {
+        let headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}\r\n"))
+            .collect();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", self.port)).ok()?;
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+             Content-Length: {}\r\n{headers}\r\n{body}",
+            body.len()
+        )
+        .ok()?;
+
+        // compressed bodies aren't text, but the headers before them are
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).ok()?;
+        let response = String::from_utf8_lossy(&response);
+
+        let status = response.split(' ').nth(1)?.parse().ok()?;
+        let (headers, body) = response.split_once("\r\n\r\n")?;
+        Some((status, headers.to_string(), body.to_string()))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _res = self.child.kill();
+        let _res = self.child.wait();
+    }
+}
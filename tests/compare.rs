@@ -0,0 +1,79 @@
+//! Compares two revisions on the web, as linked from the summary shown after
+//! a push over SSH.
+
+mod common;
+
+use common::{eventually, git, init_work_tree, Server};
+
+#[test]
+fn branches_can_be_compared() {
+    let dir = tempfile::tempdir().unwrap();
+    let scan = dir.path().join("scan");
+    let bare = scan.join("test.git");
+    let work = dir.path().join("work");
+    std::fs::create_dir_all(&bare).unwrap();
+
+    git(&bare, &["init", "--bare", "-b", "master"]);
+    init_work_tree(&work, 3);
+    let base = git(&work, &["rev-parse", "HEAD"]);
+
+    git(&work, &["checkout", "-b", "feature"]);
+    std::fs::write(work.join("feature.txt"), "a new feature\n").unwrap();
+    git(&work, &["add", "."]);
+    git(&work, &["commit", "-m", "add a feature"]);
+    let feature = git(&work, &["rev-parse", "HEAD"]);
+
+    // master moves on too, which the comparison shouldn't include
+    git(&work, &["checkout", "master"]);
+    std::fs::write(work.join("README.md"), "moved on\n").unwrap();
+    git(&work, &["commit", "-am", "unrelated change"]);
+
+    git(&work, &["push", bare.to_str().unwrap(), "--all"]);
+    git(&bare, &["pack-refs", "--all"]);
+
+    let server = Server::start(dir.path(), &scan, &[]);
+    eventually("the repository to be indexed", || {
+        server
+            .get("/test.git")
+            .is_some_and(|(status, _)| status == 200)
+    });
+
+    let (status, body) = server
+        .get("/test.git/compare?from=master&to=feature")
+        .unwrap();
+    assert_eq!(status, 200, "{body}");
+    assert!(body.contains("add a feature"), "{body}");
+    assert!(body.contains("feature.txt"), "{body}");
+    assert!(!body.contains("unrelated change"), "{body}");
+    assert!(!body.contains("commit 2"), "{body}");
+
+    // commit ids work as well, like the ones in push summaries
+    let (status, body) = server
+        .get(&format!(
+            "/test.git/compare?from={}&to={}",
+            base.trim(),
+            feature.trim()
+        ))
+        .unwrap();
+    assert_eq!(status, 200, "{body}");
+    assert!(body.contains("add a feature"), "{body}");
+
+    let (status, _) = server
+        .get("/test.git/compare?from=master&to=missing")
+        .unwrap();
+    assert_eq!(status, 404);
+
+    // once master is merged in, the fork point is master itself
+    git(&work, &["checkout", "feature"]);
+    git(&work, &["merge", "--no-edit", "master"]);
+    git(&work, &["push", bare.to_str().unwrap(), "feature"]);
+
+    let (status, body) = server
+        .get("/test.git/compare?from=master&to=feature")
+        .unwrap();
+    assert_eq!(status, 200, "{body}");
+    assert!(body.contains("add a feature"), "{body}");
+    assert!(body.contains("Merge branch"), "{body}");
+    assert!(!body.contains("unrelated change"), "{body}");
+    assert!(!body.contains("moved on"), "{body}");
+}
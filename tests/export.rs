@@ -0,0 +1,133 @@
+//! Exports a repository as static HTML and checks every link in it leads to
+//! a file that was exported.
+
+mod common;
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use common::{git, init_work_tree};
+
+fn export(root: &Path, scan: &Path, out: &Path) {
+    let status = Command::new(env!("CARGO_BIN_EXE_gnostr-gnit"))
+        .arg("-d")
+        .arg(root.join("db"))
+        .arg("-s")
+        .arg(scan)
+        .arg("export")
+        .arg(out)
+        .stdout(Stdio::null())
+        .status()
+        .expect("failed to run export");
+
+    assert!(status.success(), "export failed: {status}");
+}
+
+fn html_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            html_files(&path, files);
+        } else if path.extension().is_some_and(|v| v == "html") {
+            files.push(path);
+        }
+    }
+}
+
+/// Every `href` and `src` in a page, other than those to other sites.
+fn local_links(html: &str) -> Vec<String> {
+    let mut links = Vec::new();
+
+    for attribute in [" href=\"", " src=\""] {
+        for (i, _) in html.match_indices(attribute) {
+            let value = &html[i + attribute.len()..];
+            let value = &value[..value.find('"').unwrap()];
+            if !value.starts_with("http://") && !value.starts_with("https://") {
+                links.push(value.to_string());
+            }
+        }
+    }
+
+    links
+}
+
+#[test]
+fn exports_browsable_site() {
+    let dir = tempfile::tempdir().unwrap();
+    let scan = dir.path().join("scan");
+    let bare = scan.join("group/test.git");
+    let work = dir.path().join("work");
+    let out = dir.path().join("out");
+    std::fs::create_dir_all(&bare).unwrap();
+
+    git(&bare, &["init", "--bare", "-b", "master"]);
+    init_work_tree(&work, 3);
+    git(&work, &["tag", "-a", "v1", "-m", "first release"]);
+    let bare_path = bare.to_str().unwrap();
+    git(&work, &["push", bare_path, "--all"]);
+    git(&work, &["push", bare_path, "--tags"]);
+    git(&bare, &["pack-refs", "--all"]);
+
+    export(dir.path(), &scan, &out);
+
+    let site = out.join("group/test.git");
+    let head = git(&work, &["rev-parse", "HEAD"]);
+    let head = head.trim();
+    for file in [
+        "index.html",
+        "log.html",
+        "refs.html",
+        "tree.html",
+        "tree/src/nested.html",
+        "tree/src/nested/data.txt.html",
+        "raw/README.md",
+        "tag/v1.html",
+        &format!("commit/{head}.html"),
+        &format!("patch/{head}.patch"),
+    ] {
+        assert!(site.join(file).is_file(), "{file} wasn't exported");
+    }
+    assert_eq!(
+        std::fs::read_to_string(site.join("raw/README.md")).unwrap(),
+        "revision 2\n"
+    );
+
+    let index = std::fs::read_to_string(out.join("index.html")).unwrap();
+    assert!(index.contains("href=\"group/test.git/index.html\""));
+
+    let mut pages = Vec::new();
+    html_files(&out, &mut pages);
+    for page in &pages {
+        let html = std::fs::read_to_string(page).unwrap();
+        for link in local_links(&html) {
+            assert!(!link.starts_with('/'), "{} links to {link}", page.display());
+            assert!(
+                page.parent().unwrap().join(&link).is_file(),
+                "{} links to missing {link}",
+                page.display()
+            );
+        }
+    }
+
+    // commit pages are kept, while the rest follows the new head
+    let first_commit = site.join(format!("commit/{head}.html"));
+    std::fs::write(&first_commit, "kept").unwrap();
+
+    git(&work, &["rm", "-q", "src/nested/data.txt"]);
+    git(&work, &["commit", "-m", "remove data"]);
+    git(&work, &["push", bare_path, "master"]);
+
+    export(dir.path(), &scan, &out);
+
+    let new_head = git(&work, &["rev-parse", "HEAD"]);
+    assert!(site
+        .join(format!("commit/{}.html", new_head.trim()))
+        .is_file());
+    assert_eq!(std::fs::read_to_string(&first_commit).unwrap(), "kept");
+    assert!(!site.join("tree/src/nested/data.txt.html").exists());
+    assert!(std::fs::read_to_string(site.join("index.html"))
+        .unwrap()
+        .contains("remove data"));
+}
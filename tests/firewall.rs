@@ -0,0 +1,111 @@
+//! Connects to the SSH server's port with more clients than it allows, and
+//! from networks it refuses, which it disconnects before the handshake.
+
+mod common;
+
+use std::{
+    io::Read,
+    net::TcpStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use common::{eventually, free_port, Server};
+
+/// Starts the web server with the SSH server alongside it, configured with
+/// `firewall` as its `[firewall]` table. Returns it and the SSH port.
+fn start(dir: &Path, firewall: &str) -> (Server, u16) {
+    let scan = dir.join("scan");
+    std::fs::create_dir_all(&scan).unwrap();
+
+    let ssh_port = free_port();
+    let config: PathBuf = dir.join("server.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "name = \"test\"\nhostname = \"localhost\"\nport = {ssh_port}\n\n[users]\n\n\
+             [firewall]\n{firewall}\n"
+        ),
+    )
+    .unwrap();
+
+    let server = Server::start(
+        dir,
+        &scan,
+        &[
+            "serve",
+            "--config",
+            config.to_str().unwrap(),
+            "--listen",
+            &format!("127.0.0.1:{ssh_port}"),
+        ],
+    );
+    eventually("the ssh server to start", || {
+        TcpStream::connect(("127.0.0.1", ssh_port)).is_ok()
+    });
+
+    (server, ssh_port)
+}
+
+/// Connects to the SSH server, returning the connection if it was let in.
+fn connect(port: u16) -> Option<TcpStream> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+
+    let mut banner = [0; 8];
+    stream.read_exact(&mut banner).ok()?;
+    assert_eq!(&banner, b"SSH-2.0-");
+    Some(stream)
+}
+
+fn metric(server: &Server, name: &str) -> Option<String> {
+    let (_, body) = server.get("/metrics")?;
+    body.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .map(str::to_string)
+}
+
+#[test]
+fn connections_over_the_limit_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let (server, port) = start(dir.path(), "max_connections_per_ip = 1");
+
+    // the connection the server was checked with may take a moment to close
+    let first = {
+        let mut first = None;
+        eventually("a connection to be let in", || {
+            first = connect(port);
+            first.is_some()
+        });
+        first.unwrap()
+    };
+    assert!(connect(port).is_none(), "a second connection was let in");
+
+    drop(first);
+    eventually("the first connection's place to be freed", || {
+        connect(port).is_some()
+    });
+
+    let refused = metric(
+        &server,
+        "gnostr_web_ssh_refused_total{reason=\"too_many_connections\"}",
+    );
+    assert!(
+        refused.is_some_and(|v| v.parse::<u64>().unwrap() >= 1),
+        "the refusal wasn't counted"
+    );
+}
+
+#[test]
+fn denied_networks_are_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let (server, port) = start(dir.path(), "deny = [\"127.0.0.0/8\"]");
+
+    assert!(connect(port).is_none(), "a denied address was let in");
+    assert!(
+        metric(&server, "gnostr_web_ssh_refused_total{reason=\"denied\"}")
+            .is_some_and(|v| v != "0")
+    );
+}
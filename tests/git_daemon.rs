@@ -0,0 +1,99 @@
+//! Clones over git:// from the read-only listener the SSH server can run,
+//! which only serves repositories anonymous users can read.
+
+mod common;
+
+use std::{path::Path, process::Command};
+
+use common::{eventually, free_port, git, Server};
+
+/// Creates a bare repository at `path` whose repo config sets `public`.
+fn init_repository(path: &Path, work: &Path, public: bool) {
+    std::fs::create_dir_all(path).unwrap();
+    std::fs::create_dir_all(work).unwrap();
+
+    git(path, &["init", "--bare", "-b", "master"]);
+    git(work, &["init", "-b", "master"]);
+    std::fs::write(
+        work.join("repo.toml"),
+        format!("name = \"test\"\npublic = {public}\n"),
+    )
+    .unwrap();
+    git(work, &["add", "."]);
+    git(work, &["commit", "-m", "config"]);
+    git(work, &["push", path.to_str().unwrap(), "master"]);
+}
+
+/// Runs `git` expecting it to fail, returning what it printed.
+fn git_fails(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "git {args:?} succeeded");
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn public_repositories_are_served_over_git_protocol() {
+    let dir = tempfile::tempdir().unwrap();
+    let scan = dir.path().join("scan");
+    init_repository(&scan.join("open.git"), &dir.path().join("open"), true);
+    init_repository(&scan.join("closed.git"), &dir.path().join("closed"), false);
+
+    let daemon_port = free_port();
+    let config = dir.path().join("server.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "name = \"test\"\nhostname = \"localhost\"\nport = 2222\n\n[users]\n\n\
+             [git_daemon]\nlisten = [\"127.0.0.1:{daemon_port}\"]\n"
+        ),
+    )
+    .unwrap();
+
+    let _server = Server::start(
+        dir.path(),
+        &scan,
+        &[
+            "serve",
+            "--config",
+            config.to_str().unwrap(),
+            "--listen",
+            &format!("127.0.0.1:{}", free_port()),
+        ],
+    );
+    eventually("the git:// listener to start", || {
+        std::net::TcpStream::connect(("127.0.0.1", daemon_port)).is_ok()
+    });
+
+    // the ".git" suffix is optional, like over ssh
+    let url = format!("git://127.0.0.1:{daemon_port}/open");
+    git(dir.path(), &["clone", &url, "cloned"]);
+    let cloned = dir.path().join("cloned");
+    assert!(cloned.join("repo.toml").exists());
+
+    let error = git_fails(&cloned, &["push", &url, "master:other"]);
+    assert!(error.contains("use SSH instead"), "{error}");
+
+    let url = format!("git://127.0.0.1:{daemon_port}/closed.git");
+    let error = git_fails(dir.path(), &["clone", &url, "closed-clone"]);
+    assert!(error.contains("doesn't exist"), "{error}");
+
+    let url = format!("git://127.0.0.1:{daemon_port}/../escape.git");
+    let error = git_fails(dir.path(), &["clone", &url, "escape-clone"]);
+    assert!(error.contains("doesn't exist"), "{error}");
+
+    let log = std::fs::read_to_string(scan.join(".gnostr/audit.log")).unwrap();
+    let entries: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .filter(|entry: &serde_json::Value| entry["transport"] == "git")
+        .collect();
+    assert_eq!(entries[0]["repo"], "open.git");
+    assert_eq!(entries[0]["status"], 0);
+    assert!(entries[1]["status"].is_null());
+}
@@ -0,0 +1,89 @@
+//! Serves LFS objects stored alongside a repository through the batch API and
+//! in place of their pointers in the tree view.
+
+mod common;
+
+use common::{eventually, git, init_work_tree, Server};
+use sha2::{Digest, Sha256};
+
+#[test]
+fn lfs_objects_are_served() {
+    let dir = tempfile::tempdir().unwrap();
+    let scan = dir.path().join("scan");
+    let bare = scan.join("assets.git");
+    let work = dir.path().join("work");
+    std::fs::create_dir_all(&bare).unwrap();
+
+    let object = "not really a texture\n".repeat(100);
+    let oid = hex::encode(Sha256::digest(object.as_bytes()));
+
+    // objects are uploaded over ssh, so put this one where the ssh server would
+    let object_dir = bare.join("lfs/objects").join(&oid[0..2]).join(&oid[2..4]);
+    std::fs::create_dir_all(&object_dir).unwrap();
+    std::fs::write(object_dir.join(&oid), &object).unwrap();
+
+    git(&bare, &["init", "--bare", "-b", "master"]);
+    init_work_tree(&work, 1);
+    std::fs::write(
+        work.join("texture.png"),
+        format!(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:{oid}\nsize {}\n",
+            object.len()
+        ),
+    )
+    .unwrap();
+    git(&work, &["add", "."]);
+    git(&work, &["commit", "-m", "add texture"]);
+    git(&work, &["push", bare.to_str().unwrap(), "master"]);
+    git(&bare, &["pack-refs", "--all"]);
+
+    let server = Server::start(dir.path(), &scan, &[]);
+
+    eventually("the repository to be indexed", || {
+        server
+            .get("/assets.git")
+            .is_some_and(|(status, _)| status == 200)
+    });
+
+    let missing = "0".repeat(64);
+    let (status, body) = server
+        .post(
+            "/assets.git/info/lfs/objects/batch",
+            &format!(
+                r#"{{"operation":"download","transfers":["basic"],"objects":[{{"oid":"{oid}","size":{}}},{{"oid":"{missing}","size":1}}]}}"#,
+                object.len()
+            ),
+        )
+        .unwrap();
+    assert_eq!(status, 200, "{body}");
+    assert!(
+        body.contains(&format!(
+            "\"href\":\"http://localhost/assets.git/info/lfs/objects/{oid}\""
+        )),
+        "{body}"
+    );
+    assert!(
+        body.contains(&format!(
+            "\"oid\":\"{missing}\",\"size\":1,\"error\":{{\"code\":404"
+        )),
+        "{body}"
+    );
+
+    let (status, _) = server
+        .post(
+            "/assets.git/info/lfs/objects/batch",
+            r#"{"operation":"upload","objects":[]}"#,
+        )
+        .unwrap();
+    assert_eq!(status, 403);
+
+    let (status, body) = server
+        .get(&format!("/assets.git/info/lfs/objects/{oid}"))
+        .unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(body, object);
+
+    let (status, body) = server.get("/assets.git/tree/texture.png?raw=true").unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(body, object);
+}
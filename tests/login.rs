@@ -0,0 +1,135 @@
+//! Logs in to a private repository with Nostr keys, both with NIP-98 signed
+//! requests and with the session cookie the login page hands out.
+
+mod common;
+
+use common::{eventually, free_port, git, Key, Server};
+
+#[test]
+fn nostr_users_can_read_private_repositories() {
+    let alice = Key::new(1);
+    let bob = Key::new(2);
+    let eve = Key::new(3);
+
+    let dir = tempfile::tempdir().unwrap();
+    let scan = dir.path().join("scan");
+    let bare = scan.join("secret.git");
+    let work = dir.path().join("work");
+    std::fs::create_dir_all(&bare).unwrap();
+    std::fs::create_dir_all(&work).unwrap();
+
+    // alice has an account, bob is only known by his npub
+    let config = dir.path().join("server.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "name = \"test\"\nhostname = \"localhost\"\nport = 2222\n\n\
+             [users.alice]\nnpub = \"{}\"\n",
+            gnit_ssh::npub(&alice.pubkey())
+        ),
+    )
+    .unwrap();
+
+    git(&bare, &["init", "--bare", "-b", "master"]);
+    git(&work, &["init", "-b", "master"]);
+    std::fs::write(
+        work.join("repo.toml"),
+        format!(
+            "name = \"secret\"\npublic = false\nmembers = [\"alice\", \"{}\"]\n",
+            gnit_ssh::npub(&bob.pubkey())
+        ),
+    )
+    .unwrap();
+    git(&work, &["add", "."]);
+    git(&work, &["commit", "-m", "config"]);
+    git(&work, &["push", bare.to_str().unwrap(), "master"]);
+    git(&bare, &["pack-refs", "--all"]);
+
+    let ssh_port = free_port();
+    let server = Server::start(
+        dir.path(),
+        &scan,
+        &[
+            "serve",
+            "--config",
+            config.to_str().unwrap(),
+            "--listen",
+            &format!("127.0.0.1:{ssh_port}"),
+        ],
+    );
+
+    eventually("the repository to be indexed", || {
+        server
+            .get_with(
+                "/secret.git",
+                &[("Authorization", &bob.http_auth("/secret.git"))],
+            )
+            .is_some_and(|(status, _)| status == 200)
+    });
+
+    // anonymous users and strangers can't see it
+    let (status, _) = server.get("/secret.git").unwrap();
+    assert_eq!(status, 404);
+    let (_, body) = server.get("/").unwrap();
+    assert!(!body.contains("secret.git"), "{body}");
+    assert!(body.contains("href=\"/login\""), "{body}");
+
+    let (status, _) = server
+        .get_with(
+            "/secret.git",
+            &[("Authorization", &eve.http_auth("/secret.git"))],
+        )
+        .unwrap();
+    assert_eq!(status, 404);
+
+    // a signature for one page doesn't open another
+    let (status, _) = server
+        .get_with("/secret.git", &[("Authorization", &bob.http_auth("/"))])
+        .unwrap();
+    assert_eq!(status, 404);
+
+    // logging in with a browser extension
+    let (status, page) = server.get("/login").unwrap();
+    assert_eq!(status, 200);
+    let challenge = page
+        .split("data-challenge=\"")
+        .nth(1)
+        .and_then(|v| v.split('"').next())
+        .unwrap();
+
+    let forged = alice.sign(gnit_ssh::LOGIN_KIND, &[["challenge", "1.abcd"]]);
+    let (status, _, _) = server.exchange("POST", "/login", &[], &forged).unwrap();
+    assert_eq!(status, 400);
+
+    let login = alice.sign(gnit_ssh::LOGIN_KIND, &[["challenge", challenge]]);
+    let (status, headers, _) = server.exchange("POST", "/login", &[], &login).unwrap();
+    assert_eq!(status, 204);
+    let cookie = headers
+        .lines()
+        .find_map(|v| v.strip_prefix("set-cookie: "))
+        .and_then(|v| v.split(';').next())
+        .unwrap();
+
+    let (status, headers, body) = server
+        .exchange("GET", "/", &[("Cookie", cookie)], "")
+        .unwrap();
+    assert_eq!(status, 200);
+    assert!(body.contains("secret.git"), "{body}");
+    assert!(body.contains(">alice<"), "{body}");
+    assert!(headers.contains("private"), "{headers}");
+
+    // shared caches mustn't keep pages of private repositories
+    let (status, headers, _) = server
+        .exchange("GET", "/secret.git/commit", &[("Cookie", cookie)], "")
+        .unwrap();
+    assert_eq!(status, 200);
+    assert!(headers.contains("cache-control: private"), "{headers}");
+
+    let (status, _) = server
+        .get_with(
+            "/secret.git",
+            &[("Cookie", "gnostr_session=00.99999999999.00")],
+        )
+        .unwrap();
+    assert_eq!(status, 404);
+}
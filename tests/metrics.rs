@@ -0,0 +1,75 @@
+//! Scrapes `/metrics` from a server with a public and a private repository,
+//! which anyone may do, so only the public one may be named.
+
+mod common;
+
+use std::path::Path;
+
+use common::{eventually, free_port, git, Server};
+
+fn repository(scan: &Path, dir: &Path, name: &str, public: bool) {
+    let bare = scan.join(format!("{name}.git"));
+    let work = dir.join(name);
+    std::fs::create_dir_all(&bare).unwrap();
+    std::fs::create_dir_all(&work).unwrap();
+
+    git(&bare, &["init", "--bare", "-b", "master"]);
+    git(&work, &["init", "-b", "master"]);
+    std::fs::write(
+        work.join("repo.toml"),
+        format!("name = \"{name}\"\npublic = {public}\n"),
+    )
+    .unwrap();
+    git(&work, &["add", "."]);
+    git(&work, &["commit", "-m", "config"]);
+    git(&work, &["push", bare.to_str().unwrap(), "master"]);
+    git(&bare, &["pack-refs", "--all"]);
+}
+
+#[test]
+fn metrics_only_name_public_repositories() {
+    let dir = tempfile::tempdir().unwrap();
+    let scan = dir.path().join("scan");
+    repository(&scan, dir.path(), "open", true);
+    repository(&scan, dir.path(), "secret", false);
+
+    let config = dir.path().join("server.toml");
+    std::fs::write(
+        &config,
+        "name = \"test\"\nhostname = \"localhost\"\nport = 2222\n\n[users]\n",
+    )
+    .unwrap();
+
+    let server = Server::start(
+        dir.path(),
+        &scan,
+        &[
+            "serve",
+            "--config",
+            config.to_str().unwrap(),
+            "--listen",
+            &format!("127.0.0.1:{}", free_port()),
+        ],
+    );
+
+    // the whole pass is timed once every repository has been indexed
+    eventually("the repositories to be indexed", || {
+        server.get("/metrics").is_some_and(|(_, body)| {
+            body.contains("gnostr_web_indexer_run_duration_seconds_count{stage=\"total\"}")
+        })
+    });
+
+    let (status, body) = server.get("/metrics").unwrap();
+    assert_eq!(status, 200);
+    assert!(
+        body.contains("gnostr_web_indexer_commits_ingested_total{repository=\"open.git\"} 1"),
+        "{body}"
+    );
+    assert!(!body.contains("secret.git"), "{body}");
+    assert!(
+        body.contains(
+            "gnostr_web_http_requests_total{method=\"GET\",route=\"metrics\",status=\"200\"}"
+        ),
+        "{body}"
+    );
+}
@@ -0,0 +1,65 @@
+//! Mirrors a local `file://` upstream and checks updates make it through to
+//! the web interface.
+
+mod common;
+
+use common::{eventually, git, init_work_tree, Server};
+
+#[test]
+fn mirrors_follow_upstream() {
+    let dir = tempfile::tempdir().unwrap();
+    let scan = dir.path().join("scan");
+    let work = dir.path().join("work");
+    let upstream = dir.path().join("upstream.git");
+    let upstream_url = format!("file://{}", upstream.display());
+
+    std::fs::create_dir_all(&upstream).unwrap();
+    git(&upstream, &["init", "--bare", "-b", "master"]);
+    init_work_tree(&work, 3);
+    git(&work, &["push", upstream.to_str().unwrap(), "master"]);
+
+    // one mirror declared centrally, the other by a repo.toml under the scan path
+    let config = dir.path().join("mirrors.toml");
+    std::fs::write(
+        &config,
+        format!("[mirrors.\"vendor/upstream.git\"]\nurl = \"{upstream_url}\"\ninterval = \"1s\"\n"),
+    )
+    .unwrap();
+
+    std::fs::create_dir_all(scan.join("other.git")).unwrap();
+    std::fs::write(
+        scan.join("other.git/repo.toml"),
+        format!("[mirror]\nurl = \"{upstream_url}\"\ninterval = \"1s\"\n"),
+    )
+    .unwrap();
+
+    let server = Server::start(dir.path(), &scan, &["--mirrors", config.to_str().unwrap()]);
+
+    for repo in ["/vendor/upstream.git", "/other.git"] {
+        eventually("the mirror to be indexed", || {
+            server.get(repo).is_some_and(|(status, body)| {
+                status == 200 && body.contains("mirror of") && body.contains("commit 2")
+            })
+        });
+    }
+
+    std::fs::write(work.join("README.md"), "updated upstream\n").unwrap();
+    git(&work, &["commit", "-am", "updated upstream"]);
+    git(&work, &["push", upstream.to_str().unwrap(), "master"]);
+
+    eventually("the mirror to pick up the new commit", || {
+        server
+            .get("/vendor/upstream.git")
+            .is_some_and(|(_, body)| body.contains("updated upstream"))
+    });
+
+    let mirrored = git(
+        dir.path(),
+        &["ls-remote", &server.url("vendor/upstream.git")],
+    );
+    let head = git(&work, &["rev-parse", "HEAD"]);
+    assert!(
+        mirrored.contains(&format!("{}\trefs/heads/master", head.trim())),
+        "{mirrored}"
+    );
+}
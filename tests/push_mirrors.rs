@@ -0,0 +1,110 @@
+//! Shows how pushing to a repository's mirrors went on its summary page, but
+//! only to those who can push to it.
+
+mod common;
+
+use common::{eventually, free_port, git, Key, Server};
+
+#[test]
+fn writers_see_push_mirror_status() {
+    let alice = Key::new(1);
+
+    let dir = tempfile::tempdir().unwrap();
+    let scan = dir.path().join("scan");
+    let bare = scan.join("backed-up.git");
+    let work = dir.path().join("work");
+    std::fs::create_dir_all(&bare).unwrap();
+    std::fs::create_dir_all(&work).unwrap();
+
+    let config = dir.path().join("server.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "name = \"test\"\nhostname = \"localhost\"\nport = 2222\n\n\
+             [users.alice]\nnpub = \"{}\"\n\n\
+             [push_mirrors]\nlocal_dirs = [\"/srv/backups\"]\n",
+            gnit_ssh::npub(&alice.pubkey())
+        ),
+    )
+    .unwrap();
+
+    git(&bare, &["init", "--bare", "-b", "master"]);
+    git(&work, &["init", "-b", "master"]);
+    std::fs::write(
+        work.join("repo.toml"),
+        "name = \"backed-up\"\npublic = true\nmembers = [\"alice\"]\n\n\
+         [[push_mirrors]]\nurl = \"/srv/backups\"\n\n\
+         [[push_mirrors]]\nurl = \"git@example.com:backed-up.git\"\nkey = \"deploy\"\n",
+    )
+    .unwrap();
+    git(&work, &["add", "."]);
+    git(&work, &["commit", "-m", "config"]);
+    git(&work, &["push", bare.to_str().unwrap(), "master"]);
+    git(&bare, &["pack-refs", "--all"]);
+
+    // as the SSH server leaves it after pushing to the mirrors
+    std::fs::write(
+        bare.join("push-mirrors.json"),
+        r#"[
+            {
+                "url": "/srv/backups",
+                "last_attempt": 1700000000,
+                "last_success": 1700000000,
+                "last_error": null,
+                "pending": false
+            },
+            {
+                "url": "git@example.com:backed-up.git",
+                "last_attempt": 1700000000,
+                "last_success": null,
+                "last_error": "The server has no deploy key called deploy",
+                "pending": false
+            }
+        ]"#,
+    )
+    .unwrap();
+
+    let ssh_port = free_port();
+    let server = Server::start(
+        dir.path(),
+        &scan,
+        &[
+            "serve",
+            "--config",
+            config.to_str().unwrap(),
+            "--listen",
+            &format!("127.0.0.1:{ssh_port}"),
+        ],
+    );
+
+    eventually("the repository to be indexed", || {
+        server
+            .get("/backed-up.git")
+            .is_some_and(|(status, _)| status == 200)
+    });
+
+    // where the repository is copied to is none of a reader's business
+    let (_, body) = server.get("/backed-up.git").unwrap();
+    assert!(!body.contains("push-mirror"), "{body}");
+    assert!(!body.contains("/srv/backups"), "{body}");
+
+    let (status, body) = server
+        .get_with(
+            "/backed-up.git",
+            &[("Authorization", &alice.http_auth("/backed-up.git"))],
+        )
+        .unwrap();
+    assert_eq!(status, 200);
+    assert!(
+        body.contains("pushed to <code>/srv/backups</code>"),
+        "{body}"
+    );
+    assert!(
+        body.contains("not yet pushed to <code>git@example.com:backed-up.git</code>"),
+        "{body}"
+    );
+    assert!(
+        body.contains("last push failed: The server has no deploy key called deploy"),
+        "{body}"
+    );
+}
@@ -0,0 +1,101 @@
+//! Runs the SSH and web servers together with `serve`, pushing over SSH and
+//! checking the web follows each push straight away with the same access rules.
+
+mod common;
+
+use std::{net::TcpStream, process::Command};
+
+use common::{eventually, free_port, git, Server};
+
+#[test]
+fn pushes_over_ssh_show_up_on_the_web() {
+    let dir = tempfile::tempdir().unwrap();
+    let scan = dir.path().join("scan");
+    let work = dir.path().join("work");
+    std::fs::create_dir_all(&scan).unwrap();
+    std::fs::create_dir_all(&work).unwrap();
+
+    let key = dir.path().join("alice");
+    let status = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+        .arg(&key)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let public_key = std::fs::read_to_string(key.with_extension("pub")).unwrap();
+
+    let config = dir.path().join("server.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "name = \"test\"\nhostname = \"localhost\"\nport = 2222\n\n\
+             [users.alice]\nis_admin = true\npublic_key = \"{}\"\n",
+            public_key.trim()
+        ),
+    )
+    .unwrap();
+
+    // nothing is indexed on a timer, so only pushes can bring repositories in
+    let ssh_port = free_port();
+    let server = Server::start(
+        dir.path(),
+        &scan,
+        &[
+            "--refresh-interval",
+            "never",
+            "serve",
+            "--config",
+            config.to_str().unwrap(),
+            "--listen",
+            &format!("127.0.0.1:{ssh_port}"),
+        ],
+    );
+    eventually("the ssh server to start", || {
+        TcpStream::connect(("127.0.0.1", ssh_port)).is_ok()
+    });
+
+    let ssh_command = format!(
+        "core.sshCommand=ssh -i {} -o IdentitiesOnly=yes -o StrictHostKeyChecking=no \
+         -o UserKnownHostsFile=/dev/null -o LogLevel=ERROR",
+        key.display()
+    );
+    let url = format!("ssh://git@127.0.0.1:{ssh_port}/alice/notes.git");
+    let push = |message: &str| {
+        git(&work, &["commit", "-a", "-m", message]);
+        git(&work, &["-c", &ssh_command, "push", &url, "main"]);
+    };
+    let set_public = |from: bool, to: bool| {
+        let path = work.join("repo.toml");
+        let config = std::fs::read_to_string(&path).unwrap();
+        let config = config.replace(&format!("public = {from}"), &format!("public = {to}"));
+        std::fs::write(path, config).unwrap();
+    };
+
+    // new repositories are private, so it only shows up once a push makes it public
+    git(&work, &["init", "-b", "main"]);
+    std::fs::write(work.join("README.md"), "notes\n").unwrap();
+    git(&work, &["add", "."]);
+    push("first notes");
+    git(
+        &work,
+        &["-c", &ssh_command, "pull", "--ff-only", &url, "main"],
+    );
+    set_public(false, true);
+    push("share the notes");
+    eventually("the pushed repository to be indexed", || {
+        server
+            .get("/alice/notes.git/log")
+            .is_some_and(|(status, body)| status == 200 && body.contains("share the notes"))
+    });
+
+    // and the web hides it as soon as a push makes it private again, like the ssh server does
+    set_public(true, false);
+    push("hide the notes");
+    eventually("the repository to be hidden", || {
+        server
+            .get("/alice/notes.git")
+            .is_some_and(|(status, _)| status == 404)
+    });
+    let (_, body) = server.get("/").unwrap();
+    assert!(!body.contains("alice/notes.git"), "{body}");
+}
@@ -0,0 +1,216 @@
+//! Fetches from a running server with the stock `git` client over protocol v2,
+//! which is answered by the in-process upload-pack rather than the CGI.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use common::{eventually, git, git_fails, init_work_tree, Server};
+use tempfile::TempDir;
+
+struct Fixture {
+    _server: Server,
+    dir: TempDir,
+    work: PathBuf,
+    url: String,
+}
+
+impl Fixture {
+    /// Creates a repository with a little history and serves it.
+    fn start() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let scan = dir.path().join("scan");
+        let bare = scan.join("test.git");
+        let work = dir.path().join("work");
+        std::fs::create_dir_all(&bare).unwrap();
+
+        git(&bare, &["init", "--bare", "-b", "master"]);
+        init_work_tree(&work, 5);
+
+        git(&work, &["tag", "-a", "v1", "-m", "first release"]);
+        git(&work, &["checkout", "-b", "feature"]);
+        std::fs::write(work.join("feature.txt"), "feature\n").unwrap();
+        git(&work, &["add", "."]);
+        git(&work, &["commit", "-m", "feature"]);
+        git(&work, &["checkout", "master"]);
+
+        let bare_path = bare.to_str().unwrap();
+        git(&work, &["push", bare_path, "--all"]);
+        git(&work, &["push", bare_path, "--tags"]);
+        git(&bare, &["pack-refs", "--all"]);
+
+        let server = Server::start(dir.path(), &scan, &[]);
+        let url = server.url("test.git");
+
+        eventually("the repository to be indexed", || {
+            server
+                .get("/test.git")
+                .is_some_and(|(status, _)| status == 200)
+        });
+
+        Self {
+            _server: server,
+            dir,
+            work,
+            url,
+        }
+    }
+
+    fn clone(&self, name: &str, args: &[&str]) -> PathBuf {
+        let mut clone_args = vec!["clone"];
+        clone_args.extend_from_slice(args);
+        clone_args.extend_from_slice(&[&self.url, name]);
+        git(self.dir.path(), &clone_args);
+
+        self.dir.path().join(name)
+    }
+
+    /// Adds a commit to `master` on the server.
+    fn push_commit(&self, message: &str) {
+        std::fs::write(self.work.join("README.md"), format!("{message}\n")).unwrap();
+        git(&self.work, &["commit", "-am", message]);
+
+        let bare = self.dir.path().join("scan/test.git");
+        git(&self.work, &["push", bare.to_str().unwrap(), "master"]);
+    }
+}
+
+fn count(dir: &Path, rev: &str) -> usize {
+    git(dir, &["rev-list", "--count", rev])
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn ls_remote() {
+    let server = Fixture::start();
+
+    let remote = git(server.dir.path(), &["ls-remote", "--symref", &server.url]);
+    let local = git(
+        &server.work,
+        &["rev-parse", "master", "feature", "v1", "v1^{}"],
+    );
+    let local: Vec<_> = local.lines().collect();
+
+    assert!(remote.contains("ref: refs/heads/master\tHEAD"), "{remote}");
+    assert!(
+        remote.contains(&format!("{}\trefs/heads/master", local[0])),
+        "{remote}"
+    );
+    assert!(
+        remote.contains(&format!("{}\trefs/heads/feature", local[1])),
+        "{remote}"
+    );
+    assert!(
+        remote.contains(&format!("{}\trefs/tags/v1\n", local[2])),
+        "{remote}"
+    );
+    assert!(
+        remote.contains(&format!("{}\trefs/tags/v1^{{}}", local[3])),
+        "{remote}"
+    );
+}
+
+#[test]
+fn full_clone_and_incremental_fetch() {
+    let server = Fixture::start();
+    let clone = server.clone("full", &[]);
+
+    git(&clone, &["fsck", "--strict"]);
+    assert_eq!(count(&clone, "origin/master"), 5);
+    assert_eq!(count(&clone, "origin/feature"), 6);
+    assert_eq!(git(&clone, &["cat-file", "-t", "v1"]).trim(), "tag");
+
+    server.push_commit("incremental");
+    git(&clone, &["fetch"]);
+
+    git(&clone, &["fsck", "--strict"]);
+    assert_eq!(count(&clone, "origin/master"), 6);
+}
+
+#[test]
+fn packed_repository_clone() {
+    let server = Fixture::start();
+    let lines: Vec<_> = (0..2000).map(|i| format!("line {i}\n")).collect();
+    for edit in ["first", "second"] {
+        std::fs::write(server.work.join("big.txt"), lines.concat() + edit).unwrap();
+        git(&server.work, &["add", "."]);
+        server.push_commit(edit);
+    }
+    git(&server.dir.path().join("scan/test.git"), &["gc", "-q"]);
+
+    // deltas come straight out of the repository's pack
+    let clone = server.clone("packed", &["--bare"]);
+    git(&clone, &["fsck", "--strict"]);
+    assert_eq!(count(&clone, "master"), 7);
+
+    let pack = std::fs::read_dir(clone.join("objects/pack"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|v| v == "idx"))
+        .unwrap();
+    let stats = git(&clone, &["verify-pack", "-v", pack.to_str().unwrap()]);
+    assert!(stats.contains("chain length = 1:"), "{stats}");
+}
+
+#[test]
+fn wants_must_be_reachable() {
+    let server = Fixture::start();
+    let clone = server.clone("wants", &[]);
+
+    // commits behind a ref can be fetched by id
+    let old = git(&server.work, &["rev-parse", "master~2"]);
+    git(&clone, &["fetch", "origin", old.trim()]);
+
+    // but not ones no ref leads to any more
+    let bare = server.dir.path().join("scan/test.git");
+    git(&server.work, &["checkout", "-b", "secret"]);
+    std::fs::write(server.work.join("secret.txt"), "secret\n").unwrap();
+    git(&server.work, &["add", "."]);
+    git(&server.work, &["commit", "-m", "secret"]);
+    git(&server.work, &["push", bare.to_str().unwrap(), "secret"]);
+    git(&bare, &["update-ref", "-d", "refs/heads/secret"]);
+
+    let secret = git(&server.work, &["rev-parse", "secret"]);
+    let error = git_fails(&clone, &["fetch", "origin", secret.trim()]);
+    assert!(error.contains("not our ref"), "{error}");
+}
+
+#[test]
+fn shallow_clone() {
+    let server = Fixture::start();
+    let clone = server.clone("shallow", &["--depth", "1", "--no-single-branch"]);
+
+    git(&clone, &["fsck"]);
+    assert_eq!(count(&clone, "origin/master"), 1);
+
+    git(&clone, &["fetch", "--deepen", "2"]);
+    git(&clone, &["fsck"]);
+    assert_eq!(count(&clone, "origin/master"), 3);
+
+    git(&clone, &["fetch", "--unshallow"]);
+    git(&clone, &["fsck", "--strict"]);
+    assert_eq!(count(&clone, "origin/master"), 5);
+    assert!(!clone.join(".git/shallow").exists());
+}
+
+#[test]
+fn partial_clone() {
+    let server = Fixture::start();
+    let clone = server.clone("partial", &["--filter=blob:none", "--no-checkout"]);
+
+    let missing = git(
+        &clone,
+        &["rev-list", "--objects", "--all", "--missing=print"],
+    );
+    assert!(missing.lines().any(|l| l.starts_with('?')), "{missing}");
+
+    // missing blobs are lazily fetched from the promisor remote on checkout
+    git(&clone, &["checkout", "master"]);
+    assert_eq!(
+        std::fs::read_to_string(clone.join("README.md")).unwrap(),
+        "revision 4\n"
+    );
+    git(&clone, &["fsck"]);
+}